@@ -0,0 +1,299 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Address Index
+//!
+//! An optional index of the UTXO set by script, for looking up the balance,
+//! unspent outputs and transactions of any address rather than only the
+//! wallets' own. It is built from the UTXO set at startup and kept up to
+//! date from chain events, and lives only in memory.
+//!
+//! The UTXO set forgets spent outputs, so the transactions known for an
+//! address are those with outputs to it still unspent when the index was
+//! built, and any paying to or spending from it since.
+//!
+//! Scripts may also be looked up by their SHA256 hash, as Electrum clients
+//! name them.
+//!
+
+use std::collections::{DList, Deque, HashMap, HashSet};
+use std::sync::{Arc, RWLock};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::utxoset::UtxoSet;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use bitcoind::{Notice, Warning};
+use constants::ADDRESS_INDEX_UNDO_DEPTH;
+use events::{BlockConnected, BlockDisconnected, Chain, EventBus};
+use user_data::NetworkConfig;
+
+/// An unspent output, as returned from the index
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct IndexedOutput {
+  /// Transaction the output is in
+  pub txid: Sha256dHash,
+  /// Index of the output within the transaction
+  pub vout: u32,
+  /// Value, in satoshi
+  pub value: u64,
+  /// Height of the block the transaction is in
+  pub height: uint
+}
+
+/// An output spent by a block, kept so that it can be restored if the
+/// block is disconnected
+struct SpentOutput {
+  script: Vec<u8>,
+  output: IndexedOutput
+}
+
+/// The unspent outputs and transaction history of each script
+pub struct AddressIndex {
+  /// Unspent outputs by script
+  utxos: HashMap<Vec<u8>, HashMap<(Sha256dHash, u32), IndexedOutput>>,
+  /// Script of each unspent output, to find it again when it is spent
+  scripts: HashMap<(Sha256dHash, u32), Vec<u8>>,
+  /// Transactions paying to or spending from each script, with the height
+  /// of the block each is in
+  history: HashMap<Vec<u8>, Vec<(Sha256dHash, uint)>>,
+  /// Each script with a history, by its SHA256 hash
+  by_hash: HashMap<Vec<u8>, Vec<u8>>,
+  /// Outputs spent by each of the last few connected blocks, newest last
+  undo: DList<(Sha256dHash, Vec<SpentOutput>)>,
+  /// The last block reflected in the index
+  last_hash: Sha256dHash
+}
+
+impl AddressIndex {
+  /// Indexes every output of a UTXO set
+  pub fn build(utxo_set: &UtxoSet) -> AddressIndex {
+    let mut ret = AddressIndex {
+      utxos: HashMap::new(),
+      scripts: HashMap::new(),
+      history: HashMap::new(),
+      by_hash: HashMap::new(),
+      undo: DList::new(),
+      last_hash: utxo_set.last_hash()
+    };
+    for (txid, vout, txo, height) in utxo_set.iter() {
+      let script = txo.script_pubkey.as_slice();
+      ret.add_output(script, IndexedOutput { txid: txid, vout: vout, value: txo.value,
+                                             height: height });
+      ret.record(script, txid, height);
+    }
+    ret
+  }
+
+  fn add_output(&mut self, script: &[u8], output: IndexedOutput) {
+    let key = (output.txid, output.vout);
+    self.scripts.insert(key, script.to_vec());
+    self.utxos.find_or_insert_with(script.to_vec(), |_| HashMap::new()).insert(key, output);
+  }
+
+  fn remove_output(&mut self, txid: Sha256dHash, vout: u32) -> Option<SpentOutput> {
+    let script = match self.scripts.pop(&(txid, vout)) {
+      Some(script) => script,
+      None => { return None; }
+    };
+    let (output, now_empty) = match self.utxos.find_mut(&script) {
+      Some(outputs) => (outputs.pop(&(txid, vout)), outputs.is_empty()),
+      None => (None, false)
+    };
+    if now_empty {
+      self.utxos.remove(&script);
+    }
+    output.map(|output| SpentOutput { script: script, output: output })
+  }
+
+  /// Adds a transaction to a script's history, if it is not already last
+  fn record(&mut self, script: &[u8], txid: Sha256dHash, height: uint) {
+    if !self.history.contains_key(&script.to_vec()) {
+      self.by_hash.insert(script_hash(script), script.to_vec());
+    }
+    let txids = self.history.find_or_insert_with(script.to_vec(), |_| vec![]);
+    if txids.last().map(|&(last, _)| last) != Some(txid) {
+      txids.push((txid, height));
+    }
+  }
+
+  /// Removes a transaction from a script's history
+  fn forget(&mut self, script: &[u8], txid: Sha256dHash) {
+    let now_empty = match self.history.find_mut(&script.to_vec()) {
+      Some(txids) => {
+        txids.retain(|&(t, _)| t != txid);
+        txids.is_empty()
+      }
+      None => false
+    };
+    if now_empty {
+      self.history.remove(&script.to_vec());
+      self.by_hash.remove(&script_hash(script));
+    }
+  }
+
+  /// The last block reflected in the index
+  pub fn last_hash(&self) -> Sha256dHash {
+    self.last_hash
+  }
+
+  /// Updates the index for a block connected to the UTXO set
+  pub fn block_connected(&mut self, block: &Block, height: uint) {
+    let mut spent = vec![];
+    for tx in block.txdata.iter() {
+      let txid = tx.bitcoin_hash();
+      for input in tx.input.iter() {
+        match self.remove_output(input.prev_hash, input.prev_index) {
+          Some(out) => {
+            self.record(out.script.as_slice(), txid, height);
+            spent.push(out);
+          }
+          None => {}
+        }
+      }
+      for (vout, txo) in tx.output.iter().enumerate() {
+        let script = txo.script_pubkey.as_slice();
+        self.add_output(script, IndexedOutput { txid: txid, vout: vout as u32,
+                                                value: txo.value, height: height });
+        self.record(script, txid, height);
+      }
+    }
+    self.last_hash = block.bitcoin_hash();
+    self.undo.push((self.last_hash, spent));
+    if self.undo.len() > ADDRESS_INDEX_UNDO_DEPTH {
+      self.undo.pop_front();
+    }
+  }
+
+  /// Updates the index for a block disconnected from the UTXO set by a
+  /// reorg. Returns false if the block is too old to undo, in which case
+  /// the index must be rebuilt.
+  pub fn block_disconnected(&mut self, block: &Block) -> bool {
+    let hash = block.bitcoin_hash();
+    match self.undo.back() {
+      Some(&(ref last, _)) if *last == hash => {}
+      _ => { return false; }
+    }
+    let (_, spent) = self.undo.pop().unwrap();
+    let txids: HashSet<Sha256dHash> = block.txdata.iter().map(|tx| tx.bitcoin_hash()).collect();
+    for tx in block.txdata.iter() {
+      let txid = tx.bitcoin_hash();
+      for (vout, txo) in tx.output.iter().enumerate() {
+        self.remove_output(txid, vout as u32);
+        self.forget(txo.script_pubkey.as_slice(), txid);
+      }
+    }
+    for out in spent.move_iter() {
+      for txid in txids.iter() {
+        self.forget(out.script.as_slice(), *txid);
+      }
+      // Outputs both created and spent in the block go with it
+      if !txids.contains(&out.output.txid) {
+        self.add_output(out.script.as_slice(), out.output);
+      }
+    }
+    self.last_hash = block.header.prev_blockhash;
+    true
+  }
+
+  /// The unspent outputs to a script, oldest first
+  pub fn utxos(&self, script: &[u8]) -> Vec<IndexedOutput> {
+    let mut ret: Vec<IndexedOutput> = match self.utxos.find(&script.to_vec()) {
+      Some(outputs) => outputs.values().map(|out| out.clone()).collect(),
+      None => vec![]
+    };
+    ret.sort_by(|a, b| a.height.cmp(&b.height));
+    ret
+  }
+
+  /// The total value of the unspent outputs to a script
+  pub fn balance(&self, script: &[u8]) -> u64 {
+    match self.utxos.find(&script.to_vec()) {
+      Some(outputs) => outputs.values().fold(0, |sum, out| sum + out.value),
+      None => 0
+    }
+  }
+
+  /// The transactions paying to or spending from a script, oldest first,
+  /// with the height of the block each is in
+  pub fn history(&self, script: &[u8]) -> Vec<(Sha256dHash, uint)> {
+    let mut ret = match self.history.find(&script.to_vec()) {
+      Some(txids) => txids.clone(),
+      None => vec![]
+    };
+    // Sort stably, since transactions in one block are recorded in order
+    ret.sort_by(|&(_, a), &(_, b)| a.cmp(&b));
+    ret
+  }
+
+  /// The transactions paying to or spending from a script, oldest first
+  pub fn txids(&self, script: &[u8]) -> Vec<Sha256dHash> {
+    self.history(script).move_iter().map(|(txid, _)| txid).collect()
+  }
+
+  /// Finds a script with a history from its SHA256 hash
+  pub fn script_by_hash(&self, hash: &[u8]) -> Option<Vec<u8>> {
+    self.by_hash.find(&hash.to_vec()).map(|script| script.clone())
+  }
+}
+
+/// The SHA256 hash of a script, by which `script_by_hash` finds it
+pub fn script_hash(script: &[u8]) -> Vec<u8> {
+  let mut hasher = Sha256::new();
+  hasher.input(script);
+  let mut ret = Vec::from_elem(32, 0u8);
+  hasher.result(ret.as_mut_slice());
+  ret
+}
+
+/// Subscribes the index to chain events and spawns a task to keep it up
+/// to date as blocks are connected and disconnected. A reorg deeper than
+/// the index can undo rebuilds it from the UTXO set. Events for blocks the
+/// index does not follow on from, which a rebuild may already reflect,
+/// are skipped.
+pub fn follow_chain(config: NetworkConfig, index: Arc<RWLock<AddressIndex>>,
+                    utxo_set: Arc<RWLock<UtxoSet>>, events: EventBus) {
+  let rx = events.subscribe(vec![Chain]);
+  spawn(proc() {
+    for event in rx.iter() {
+      match event {
+        BlockConnected(block, height) => {
+          let mut index = index.write();
+          if block.header.prev_blockhash == index.last_hash() {
+            index.block_connected(&*block, height);
+          }
+        }
+        BlockDisconnected(block) => {
+          let handled = {
+            let mut index = index.write();
+            block.bitcoin_hash() != index.last_hash() || index.block_disconnected(&*block)
+          };
+          if !handled {
+            debug!((config.network, config.debug_level), Warning,
+                   "Address index: reorg too deep to undo, rebuilding from the UTXO set.");
+            let utxo_set = utxo_set.read();
+            *index.write() = AddressIndex::build(&*utxo_set);
+            debug!((config.network, config.debug_level), Notice, "Address index rebuilt.");
+          }
+        }
+        _ => {}
+      }
+    }
+  });
+}
+
@@ -16,61 +16,375 @@
 //!
 //! Main network listener and idle loop.
 
-use std::collections::{DList, Deque};
+use std::ascii::StrAsciiExt;
+use std::cmp;
+use std::collections::{DList, Deque, HashMap};
 use std::default::Default;
-use std::io::{File, Open, Write, BufferedReader, BufferedWriter};
 use std::io::IoResult;
+use std::io::net::ip::SocketAddr;
 use std::io::timer::{mod, Timer};
-use std::sync::{Arc, RWLock};
+use std::rand;
+use std::sync::{Arc, Mutex, RWLock};
 use std::time::Duration;
 use serialize::json;
+use serialize::json::ToJson;
 use time;
 
 use jsonrpc;
 
+use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::blockchain::Blockchain;
-use bitcoin::blockdata::utxoset::{UtxoSet, ValidationLevel, TxoValidation, ScriptValidation};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::utxoset::UtxoSet;
 use bitcoin::network::constants::Network;
-use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable};
 use bitcoin::network::listener::Listener;
 use bitcoin::network::socket::Socket;
-use bitcoin::network::message::{mod, SocketResponse, NetworkMessage,
-                                MessageReceived, ConnectionFailed};
+use bitcoin::network::message::{mod, SocketResponse, NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory, InvBlock};
-use bitcoin::network::serialize::{BitcoinHash, RawEncoder, RawDecoder};
-use bitcoin::util::patricia_tree::PatriciaTree;
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
 use bitcoin::util::misc::consume_err;
-use bitcoin::wallet::wallet::Wallet;
 
+use address_index;
+use address_index::AddressIndex;
+use checkpoints;
 use coinjoin;
-use constants::BLOCKCHAIN_N_FULL_BLOCKS;
-use constants::UTXO_SYNC_N_BLOCKS;
-use constants::SAVE_FREQUENCY;
-use rpc_server::handle_rpc;
-use user_data::NetworkConfig;
-use wallet::load_or_create_wallet;
+use coinjoin::server::{SessionId, SessionState};
+use disk;
+use electrum;
+use constants::UTXO_SYNC_INITIAL_BLOCK_SIZE;
+use constants::PING_FREQUENCY;
+use constants::COINJOIN_WAIT_FREQUENCY;
+use constants::{MEMPOOL_EXPIRY, SCHEDULER_TICK};
+use constants::PENDING_TX_EXPIRY;
+use constants::{BLOCK_DOWNLOAD_TIMEOUT, HEADERS_SYNC_TIMEOUT, SYNC_STALL_TIMEOUT};
+use constants::{SAVE_RETRY_DELAY, SAVE_SPACE_MARGIN};
+use events::{mod, Chain, CoinjoinSession, Event, EventBus, NewTip, SyncState, SyncStateChanged};
+use events::{Synced, SyncingHeaders, SyncingUtxoSet, TxAccepted, TxRejected};
+use fee_estimator::FeeEstimator;
+use journal;
+use journal::{BlockConnected, BlockRewound, HeaderAdded, Journal};
+use mempool::{AlreadyHave, Mempool};
+use progress::SyncProgress;
+use scheduler::{mod, Scheduler, Task};
+use timelock::median_time_past;
+use message_router::{Command, Disconnected, Message, MessageRouter, Routed};
+use metrics::SaveStats;
+use rpc_http::RpcMessage;
+use rpc_server::{BlockWaiter, CoinjoinWaiter, RpcStats, handle_rpc, notify_block_waiters};
+use rpc_server::{notify_coinjoin_waiters, start_coinjoin_session};
+use user_data::{NetworkConfig, PeerAddress};
+use version;
+use wallet::{LoadedWallet, balances, follow_chain};
+use worker_pool::WorkerPool;
+
+/// A reloaded configuration, as passed from the main task to a network,
+/// with the reply channel of the `reloadconfig` call which asked for it
+pub type ConfigReload = (NetworkConfig, Option<Sender<jsonrpc::JsonResult<json::Json>>>);
+
+/// A request to the main task to reload the configuration file, with the
+/// network and reply channel of the `reloadconfig` call which made it, if
+/// it was not a SIGHUP
+pub type ReloadRequest = Option<(Network, Sender<jsonrpc::JsonResult<json::Json>>)>;
+
+/// Size of a message on the wire, header included
+fn message_size(msg: &NetworkMessage) -> u64 {
+  // The magic does not affect the size
+  let raw = RawNetworkMessage { magic: 0, payload: msg.clone() };
+  serialize(&raw).map(|v| v.len() as u64).unwrap_or(0)
+}
+
+/// What we know about the connected peer, for `getpeerinfo`
+pub struct PeerInfo {
+  /// The peer's address
+  pub addr: PeerAddress,
+  /// Time (seconds since the epoch) at which we connected
+  pub connected_at: i64,
+  /// Protocol version from the peer's `version` message
+  pub version: Option<u32>,
+  /// User agent from the peer's `version` message
+  pub user_agent: Option<String>,
+  /// Chain height the peer reported on connecting
+  pub start_height: Option<i32>,
+  /// Time (seconds since the epoch) at which we last received a message
+  pub last_recv: Option<i64>,
+  /// Number of messages received
+  pub messages_received: u64,
+  /// Bytes received, counting message headers
+  pub bytes_received: u64,
+  /// Bytes sent, counting message headers
+  pub bytes_sent: u64,
+  /// Nonce and time, in ns, of our outstanding ping
+  ping: Option<(u64, u64)>,
+  /// Round-trip time, in ns, of our last answered ping
+  pub ping_time: Option<u64>
+}
+
+impl PeerInfo {
+  /// Creates a record for a newly connected peer
+  pub fn new(addr: PeerAddress) -> PeerInfo {
+    PeerInfo {
+      addr: addr,
+      connected_at: time::get_time().sec,
+      version: None,
+      user_agent: None,
+      start_height: None,
+      last_recv: None,
+      messages_received: 0,
+      bytes_received: 0,
+      bytes_sent: 0,
+      ping: None,
+      ping_time: None
+    }
+  }
+
+  /// Records receipt of a message
+  fn received(&mut self, msg: &NetworkMessage) {
+    self.last_recv = Some(time::get_time().sec);
+    self.messages_received += 1;
+    self.bytes_received += message_size(msg);
+  }
+
+  /// Records a message being sent
+  fn sent(&mut self, msg: &NetworkMessage) {
+    self.bytes_sent += message_size(msg);
+  }
+
+  /// Records a ping being sent
+  fn ping_sent(&mut self, nonce: u64) {
+    self.ping = Some((nonce, time::precise_time_ns()));
+  }
+
+  /// Records a pong being received, measuring latency if it answers our ping
+  fn pong_received(&mut self, nonce: u64) {
+    match self.ping {
+      Some((ping_nonce, sent)) if ping_nonce == nonce => {
+        self.ping_time = Some(time::precise_time_ns() - sent);
+        self.ping = None;
+      }
+      _ => {}
+    }
+  }
+}
 
 /// Data used by an idling wallet.
 pub struct IdleState {
-  net_chan: Receiver<SocketResponse>,
+  /// Queues of messages from the peer, sorted by subsystem
+  router: MessageRouter,
   /// Socket used to send network messages
   pub sock: Socket,
+  /// What we know about the peer on the other end of `sock`
+  pub peer: PeerInfo,
+  /// Time (seconds since the epoch) at which we started
+  pub started_at: i64,
   /// Network that we're on
   pub config: NetworkConfig,
   /// Coinjoin server
   pub coinjoin: Option<coinjoin::server::Server>,
+  /// Chain events for the coinjoin server, which watches for its
+  /// participants' inputs being spent elsewhere
+  coinjoin_events: Receiver<Event>,
   /// Mutex for blockchain access
   pub blockchain: Arc<RWLock<Blockchain>>,
   /// Mutex for UTXO set access
   pub utxo_set: Arc<RWLock<UtxoSet>>,
-  /// The wallet
-  pub wallet: Wallet
+  /// Mutex for mempool access
+  pub mempool: Arc<RWLock<Mempool>>,
+  /// Mutex for fee estimator access
+  pub fee_estimator: Arc<RWLock<FeeEstimator>>,
+  /// Index of the UTXO set by address, if enabled
+  pub address_index: Option<Arc<RWLock<AddressIndex>>>,
+  /// Held while saving the blockchain and UTXO set to disk
+  save_lock: Arc<Mutex<()>>,
+  /// Timings of saves of the blockchain and UTXO set
+  pub save_stats: Arc<Mutex<SaveStats>>,
+  /// Changes to the blockchain and UTXO set since the last save
+  journal: Arc<Mutex<Journal>>,
+  /// Channel on which to ask the main task to shut everything down
+  pub shutdown_tx: Sender<()>,
+  /// Channel on which to ask the main task to reload the configuration
+  pub reload_tx: Sender<ReloadRequest>,
+  /// Channel on which worker tasks hand the idle loop transactions to
+  /// broadcast, naming the caller for the log
+  pub relay_tx: Sender<(Transaction, &'static str)>,
+  /// Transactions to broadcast, from worker tasks
+  relay_rx: Receiver<(Transaction, &'static str)>,
+  /// The wallets, the first being the default. Each is behind its own
+  /// lock, since RPC worker tasks use them too.
+  pub wallets: Vec<Arc<Mutex<LoadedWallet>>>,
+  /// Index of the wallet which RPC wallet commands act on
+  pub active_wallet: uint,
+  /// Reply channel for the RPC call being handled
+  pub rpc_reply: Option<Sender<jsonrpc::JsonResult<json::Json>>>,
+  /// Address of the caller of the RPC call being handled, if known
+  pub rpc_caller: Option<SocketAddr>,
+  /// Long-polling `coinjoin_wait` calls
+  pub coinjoin_waiters: Vec<CoinjoinWaiter>,
+  /// Long-polling `waitfornewblock` and `waitforblockheight` calls
+  pub block_waiters: Vec<BlockWaiter>,
+  /// Statistics on handled RPC calls
+  pub rpc_stats: Arc<Mutex<RpcStats>>,
+  /// Worker tasks for RPC calls which need not run on the idle loop
+  pub rpc_pool: WorkerPool,
+  /// Bus on which to publish events for subscribers
+  pub events: EventBus,
+  /// Chain tip as of the last published event
+  last_tip: Sha256dHash,
+  /// Coinjoin session states as of the last published events
+  coinjoin_states: HashMap<SessionId, SessionState>,
+  /// Sync state as of the last published event
+  pub sync_state: SyncState,
+  /// Number of times a state has given up waiting on the peer
+  pub stalls: u64,
+  /// Progress of the current sync stage
+  pub sync_progress: Arc<Mutex<SyncProgress>>,
+  /// Periodic and one-shot tasks for the idle loop
+  pub scheduler: Scheduler,
+  /// A change of peer asked for over RPC, which the idle loop makes once
+  /// the call has returned
+  pub peer_change: Option<PeerChange>
 }
 
-enum WalletAction {
+/// A change of peer asked for over RPC
+pub enum PeerChange {
+  /// The connected peer was removed from the list; move on to the next
+  LeavePeer,
+  /// Connect once to a peer outside the list, going back to the list if
+  /// it does not answer
+  TryPeer(PeerAddress)
+}
+
+/// The parts of the idle state which RPC calls may use from a worker task.
+/// Everything here is shared behind a lock, so a worker takes only the
+/// locks it needs and the idle loop carries on meanwhile. Locks must be
+/// taken in the same order as the idle loop takes them, i.e. blockchain,
+/// then UTXO set, then address index, then mempool, then fee estimator,
+/// then wallets, or the two may deadlock.
+#[deriving(Clone)]
+pub struct SharedState {
+  /// Network that we're on
+  pub config: NetworkConfig,
+  /// Time (seconds since the epoch) at which we started
+  pub started_at: i64,
+  /// Mutex for blockchain access
+  pub blockchain: Arc<RWLock<Blockchain>>,
+  /// Mutex for UTXO set access
+  pub utxo_set: Arc<RWLock<UtxoSet>>,
+  /// Mutex for mempool access
+  pub mempool: Arc<RWLock<Mempool>>,
+  /// Mutex for fee estimator access
+  pub fee_estimator: Arc<RWLock<FeeEstimator>>,
+  /// Index of the UTXO set by address, if enabled
+  pub address_index: Option<Arc<RWLock<AddressIndex>>>,
+  /// Channel on which to hand the idle loop transactions to broadcast,
+  /// since only it can reach the peer
+  pub relay_tx: Sender<(Transaction, &'static str)>,
+  /// The wallets, the first being the default
+  pub wallets: Vec<Arc<Mutex<LoadedWallet>>>,
+  /// Index of the wallet which RPC wallet commands act on
+  pub active_wallet: uint,
+  /// Statistics on handled RPC calls
+  pub rpc_stats: Arc<Mutex<RpcStats>>,
+  /// Progress of the current sync stage
+  pub sync_progress: Arc<Mutex<SyncProgress>>
+}
+
+impl IdleState {
+  /// Sends a message to the peer, counting it in the peer's traffic
+  pub fn send_message(&mut self, msg: NetworkMessage) -> IoResult<()> {
+    self.peer.sent(&msg);
+    self.sock.send_message(msg)
+  }
+
+  /// Takes handles on the state which worker tasks may use
+  pub fn shared(&self) -> SharedState {
+    SharedState {
+      config: self.config.clone(),
+      started_at: self.started_at,
+      blockchain: self.blockchain.clone(),
+      utxo_set: self.utxo_set.clone(),
+      mempool: self.mempool.clone(),
+      fee_estimator: self.fee_estimator.clone(),
+      address_index: self.address_index.clone(),
+      relay_tx: self.relay_tx.clone(),
+      wallets: self.wallets.clone(),
+      active_wallet: self.active_wallet,
+      rpc_stats: self.rpc_stats.clone(),
+      sync_progress: self.sync_progress.clone()
+    }
+  }
+}
+
+/// The states of the state machine. States are queued, and run one after
+/// the other; with none queued, the state machine idles, handling peer
+/// messages, RPC calls and timers, any of which may queue more states.
+enum WalletState {
+  /// Downloading block headers from the peer
   SyncBlockchain,
-  SyncUtxoSet(ValidationLevel),
+  /// Downloading blocks from the peer and connecting them to the UTXO set,
+  /// checking scripts only above the last checkpoint
+  SyncUtxoSet,
+  /// Saving everything to disk, mostly in the background
   SaveToDisk,
+  /// Dropping the peer and connecting afresh
+  RotatePeer,
+  /// Starting any scheduled coinjoin sessions which are due
+  RunCoinjoinSchedule,
+  /// Forgetting mempool transactions which have been waiting too long
+  ExpireMempool,
+  /// Writing the fee estimator's statistics to disk
+  FlushFeeEstimates,
+  /// Saving everything to disk and returning from `listen`
+  Shutdown
+}
+
+impl WalletState {
+  /// The name of the state, for logging
+  fn name(&self) -> &'static str {
+    match *self {
+      SyncBlockchain => "headers sync",
+      SyncUtxoSet => "UTXO sync",
+      SaveToDisk => "save",
+      RotatePeer => "peer rotation",
+      RunCoinjoinSchedule => "coinjoin schedule",
+      ExpireMempool => "mempool expiry",
+      FlushFeeEstimates => "fee estimates flush",
+      Shutdown => "shutdown"
+    }
+  }
+
+  /// The states which carry out a scheduled task
+  fn for_task(task: Task) -> Vec<WalletState> {
+    match task {
+      scheduler::SyncAndSave => vec![SyncBlockchain, SyncUtxoSet, SaveToDisk],
+      scheduler::Save => vec![SaveToDisk],
+      scheduler::RotatePeer => vec![RotatePeer],
+      scheduler::CoinjoinSchedule => vec![RunCoinjoinSchedule],
+      scheduler::ExpireMempool => vec![ExpireMempool],
+      scheduler::FlushFeeEstimates => vec![FlushFeeEstimates]
+    }
+  }
+
+  /// How long, in s, the state may wait for each message it needs from the
+  /// peer before it is considered stalled. Only states which talk to the
+  /// peer have one.
+  fn timeout(&self) -> Option<i64> {
+    match *self {
+      SyncBlockchain => Some(HEADERS_SYNC_TIMEOUT),
+      SyncUtxoSet => Some(BLOCK_DOWNLOAD_TIMEOUT),
+      _ => None
+    }
+  }
+
+  /// The states to queue, after reconnecting to the peer, when this one
+  /// stalls or fails
+  fn on_failure(&self) -> Vec<WalletState> {
+    match *self {
+      SyncBlockchain => vec![SyncBlockchain],
+      SyncUtxoSet => vec![SyncBlockchain, SyncUtxoSet],
+      _ => vec![]
+    }
+  }
 }
 
 user_enum!(
@@ -92,69 +406,196 @@ user_enum!(
   }
 )
 
+impl DebugLevel {
+  /// Parses a debug level as written in the configuration file, ignoring case
+  pub fn from_name(name: &str) -> Option<DebugLevel> {
+    match name.to_ascii_upper().as_slice() {
+      "DEBUG" => Some(Debug),
+      "NOTE" => Some(Notice),
+      "STATUS" => Some(Status),
+      "WARN" => Some(Warning),
+      "ERROR" => Some(Error),
+      "FATAL" => Some(Fatal),
+      _ => None
+    }
+  }
+}
+
+/// Notices when syncing stops making progress even though the peer keeps
+/// answering, e.g. by sending headers we already have, or trickling blocks
+/// just fast enough to beat the per-message timeouts
+struct SyncWatchdog {
+  /// How far sync had got as of the last progress: a tip hash, and a count
+  /// of blocks received
+  position: (Sha256dHash, uint),
+  /// Time (seconds since the epoch) of the last progress
+  last_progress: i64
+}
+
+impl SyncWatchdog {
+  /// Creates a watchdog for a sync starting from `position`
+  fn new(position: (Sha256dHash, uint)) -> SyncWatchdog {
+    SyncWatchdog { position: position, last_progress: time::get_time().sec }
+  }
+
+  /// Notes how far sync has got. Returns the time, in s, since it last
+  /// moved, if that is too long.
+  fn check(&mut self, position: (Sha256dHash, uint)) -> Option<i64> {
+    let now = time::get_time().sec;
+    if position != self.position {
+      self.position = position;
+      self.last_progress = now;
+      None
+    } else if now - self.last_progress > SYNC_STALL_TIMEOUT {
+      Some(now - self.last_progress)
+    } else {
+      None
+    }
+  }
+}
+
 /// The main Bitcoin network listener structure
 pub struct Bitcoind {
   /// Configuration for this network
   config: NetworkConfig,
   /// Receiver on which RPC commands come in
-  rpc_rx: Receiver<(jsonrpc::Request, Sender<jsonrpc::JsonResult<json::Json>>)>,
+  rpc_rx: Receiver<RpcMessage>,
+  /// Bus on which to publish events for subscribers
+  events: EventBus,
+  /// Receiver on which the main task tells us to shut down
+  stop_rx: Receiver<()>,
+  /// Channel on which to ask the main task to shut everything down
+  shutdown_tx: Sender<()>,
+  /// Receiver on which the main task passes us reloaded configuration
+  config_rx: Receiver<ConfigReload>,
+  /// Channel on which to ask the main task to reload the configuration
+  reload_tx: Sender<ReloadRequest>,
+  /// Index in `config.peers` of the peer we use
+  peer_index: uint,
+  /// A peer outside `config.peers` which we are trying to connect to
+  one_try: Option<PeerAddress>
 }
 
+// Waits for one of the given messages from the peer, in the given state.
+// Evaluates to true once one has been handled, or false if the peer did
+// not send one within the state's timeout or disconnected, in which case
+// it has been replaced with a fresh connection.
 macro_rules! with_next_message(
-  ( $bitcoind:expr, $idle_state:expr, $( $name:pat => $code:expr )* ) => (
+  ( $bitcoind:expr, $idle_state:expr, $timer:expr, $state:expr, $queue:ident,
+    $( $name:pat => $code:expr )* ) => (
     {
-      let mut ret;
+      let timeout_s = $state.timeout().expect("waiting on the peer in a state without a timeout");
+      let timeout = $timer.oneshot(Duration::seconds(timeout_s));
+      let mut ok = false;
       loop {
-        match $idle_state.net_chan.recv() {
-          MessageReceived(msg) => {
+        let mut routed = None;
+        nu_select!(
+          r from $idle_state.router.$queue => { routed = Some(r); },
+          () from timeout => {}
+        );
+        match routed {
+          Some(Message(msg)) => {
+            $idle_state.peer.received(&msg);
             match msg {
               $(
                 $name => {
-                  ret = $code;
+                  $code;
+                  ok = true;
                   break;
                 },
               )*
               _ => {}
             }
           },
-          ConnectionFailed(e, tx) => {
-            debug!($idle_state, Error, "Network error: `{}`, reconnecting.", e);
-            tx.send(());
-            let (chan, sock) = $bitcoind.loop_connect();
-            $idle_state.net_chan = chan;
-            $idle_state.sock = sock;
+          Some(Disconnected(e)) => {
+            debug!($idle_state, Error, "{}: network error: `{}`, reconnecting.", $state.name(), e);
+            break;
+          }
+          None => {
+            $idle_state.stalls += 1;
+            debug!($idle_state, Error, "{}: stalled, no reply from peer in {}s, reconnecting.",
+                   $state.name(), timeout_s);
+            break;
           }
         };
       }
-      ret
+      if !ok {
+        replace_peer!($bitcoind, $idle_state);
+      }
+      ok
+    }
+  )
+)
+
+// Drops the connection to the peer and connects afresh to the current one
+macro_rules! reconnect(
+  ($bitcoind:expr, $idle_state:expr) => (
+    {
+      let (chan, sock) = $bitcoind.loop_connect();
+      $idle_state.router = MessageRouter::start(chan, sock.clone());
+      $idle_state.sock = sock;
+      $idle_state.peer = PeerInfo::new($bitcoind.current_peer().clone());
+    }
+  )
+)
+
+// Drops the connection to the peer and tries one outside the configured
+// list, going back to the list if it does not answer
+macro_rules! try_peer(
+  ($bitcoind:expr, $idle_state:expr, $addr:expr) => (
+    {
+      $bitcoind.one_try = Some($addr);
+      let started = $bitcoind.start();
+      let addr = $bitcoind.one_try.take().unwrap();
+      match started {
+        Ok((chan, sock)) => {
+          debug!($idle_state, Status, "Connected to peer {}.", addr);
+          $idle_state.router = MessageRouter::start(chan, sock.clone());
+          $idle_state.sock = sock;
+          $idle_state.peer = PeerInfo::new(addr);
+        }
+        Err(e) => {
+          debug!($idle_state, Error, "Error connecting to {}: `{}`, going back to configured peers.",
+                 addr, e);
+          reconnect!($bitcoind, $idle_state);
+        }
+      }
+    }
+  )
+)
+
+// Drops the connection to the peer and connects to the next one
+macro_rules! replace_peer(
+  ($bitcoind:expr, $idle_state:expr) => (
+    {
+      $bitcoind.next_peer();
+      reconnect!($bitcoind, $idle_state);
     }
   )
 )
 
 macro_rules! fatal(
   ($network:expr, $fmt:expr $(, $arg:expr)*) => (
-    fail!(concat!("{} [{:6}] {}: ", $fmt),
-          time::now().rfc3339(),
-          Fatal, $network,
-          $($arg),*);
+    {
+      let message = format!($fmt, $($arg),*);
+      ::logging::log($network, Fatal, module_path!(), message.clone());
+      fail!("{}: {}", $network, message)
+    }
   )
 )
 
+// Logs a record through `logging`, if `$level` is at least the network's
+// debug level
 macro_rules! debug(
   (($network:expr, $debug_level:expr), $level:ident, $fmt:expr $(, $arg:expr)*) => (
     if $level >= $debug_level {
-      println!(concat!("{} [{:6}] {}: ", $fmt),
-               time::now().rfc3339(),
-               $level, $network,
-               $($arg),*);
+      ::logging::log($network, $level, module_path!(), format!($fmt, $($arg),*));
     }
   );
   ($bitcoind:expr, $level:ident, $fmt:expr $(, $arg:expr)*) => (
     if $level >= $bitcoind.config.debug_level {
-      println!(concat!("{} [{:6}] {}: ", $fmt),
-               time::now().rfc3339(),
-               $level, $bitcoind.config.network,
-               $($arg),*);
+      ::logging::log($bitcoind.config.network, $level, module_path!(),
+                     format!($fmt, $($arg),*));
     }
   );
 )
@@ -162,107 +603,294 @@ macro_rules! debug(
 impl Bitcoind {
   /// Constructor
   pub fn new(config: NetworkConfig,
-             rpc_rx: Receiver<(jsonrpc::Request, Sender<jsonrpc::JsonResult<json::Json>>)>)
+             rpc_rx: Receiver<RpcMessage>,
+             events: EventBus,
+             stop_rx: Receiver<()>,
+             shutdown_tx: Sender<()>,
+             config_rx: Receiver<ConfigReload>,
+             reload_tx: Sender<ReloadRequest>)
              -> Bitcoind {
     Bitcoind {
       config: config,
-      rpc_rx: rpc_rx
+      rpc_rx: rpc_rx,
+      events: events,
+      stop_rx: stop_rx,
+      shutdown_tx: shutdown_tx,
+      config_rx: config_rx,
+      reload_tx: reload_tx,
+      peer_index: 0,
+      one_try: None
     }
   }
 
-  fn loop_connect(&self) -> (Receiver<SocketResponse>, Socket) {
+  /// The peer we use
+  fn current_peer<'a>(&'a self) -> &'a PeerAddress {
+    match self.one_try {
+      Some(ref addr) => addr,
+      None => &self.config.peers[self.peer_index % self.config.peers.len()]
+    }
+  }
+
+  /// Moves on to the next configured peer, wrapping around
+  fn next_peer(&mut self) {
+    self.peer_index = (self.peer_index + 1) % self.config.peers.len();
+  }
+
+  /// Connects to the current peer, trying each configured peer in turn
+  /// until one answers
+  fn loop_connect(&mut self) -> (Receiver<SocketResponse>, Socket) {
     loop {
       timer::sleep(Duration::seconds(3));
       match self.start() {
-        Ok((chan, sock)) => { return (chan, sock); }
-        Err(e) => { debug!(self, Error, "Error connecting: `{}`, trying again..", e); }
+        Ok((chan, sock)) => {
+          debug!(self, Status, "Connected to peer {}.", self.current_peer());
+          return (chan, sock);
+        }
+        Err(e) => {
+          debug!(self, Error, "Error connecting to {}: `{}`, trying next peer..",
+                 self.current_peer(), e);
+          self.next_peer();
+        }
       }
     }
   }
 
+  /// Loads the cached blockchain and UTXO set, replays the save journal
+  /// onto them, and checks that they are whole and agree with each other.
+  /// Whatever is broken is started afresh, to be rebuilt from the peer,
+  /// rather than carrying on from a half-loaded state. Returns them, and
+  /// whether the journal on disk applies to them.
+  fn load_chain(&self) -> (Blockchain, UtxoSet, bool) {
+    let network = self.config.network;
+    // Finish replacing the cache files if the last save stopped partway,
+    // so that we do not load a new blockchain beside an old UTXO set
+    match disk::finish_commit(&self.config.blockchain_path) {
+      Ok(true) => { debug!(self, Notice, "Finished replacing cache files from an interrupted save."); }
+      Ok(false) => {}
+      Err(e) => { debug!(self, Error, "Failed to finish replacing cache files: {}", e); }
+    }
+    debug!(self, Status, "Loading blockchain...");
+    let mut blockchain = match disk::read_checksummed(&self.config.blockchain_path) {
+      Ok((blockchain, checked)) => {
+        if !checked {
+          debug!(self, Notice, "No checksum for blockchain cache, it will get one on next save.");
+        }
+        blockchain
+      }
+      Err(e) => {
+        debug!(self, Error, "Failed to load blockchain: {:}, starting from genesis.", e);
+        Blockchain::new(network)
+      }
+    };
+    debug!(self, Status, "Loading utxo set...");
+    let mut utxo_set = match disk::read_checksummed(&self.config.utxo_set_path) {
+      Ok((utxo_set, checked)) => {
+        if !checked {
+          debug!(self, Notice, "No checksum for UTXO set cache, it will get one on next save.");
+        }
+        utxo_set
+      }
+      Err(e) => {
+        debug!(self, Error, "Failed to load UTXO set: {:}, starting from genesis.", e);
+        UtxoSet::new(network, self.config.blockchain_n_full_blocks)
+      }
+    };
+    debug!(self, Status, "Replaying save journal...");
+    let mut journal_in_sync = match journal::replay(&self.config.chain_journal_path,
+                                                    &mut blockchain, &mut utxo_set) {
+      Ok((in_sync, n)) => {
+        debug!(self, Status, "Replayed {} journal entries.", n);
+        in_sync
+      }
+      Err(e) => {
+        debug!(self, Error, "Failed to replay save journal: {}, will next save in full.", e);
+        false
+      }
+    };
+
+    debug!(self, Status, "Verifying blockchain and UTXO set...");
+    // The best chain must lead back to genesis, or the file is mangled
+    let genesis_hash = blockchain.genesis_hash();
+    let reaches_genesis = blockchain.rev_iter(blockchain.best_tip_hash()).last()
+                                    .map_or(false, |node| node.block.bitcoin_hash() == genesis_hash);
+    if !reaches_genesis {
+      debug!(self, Error, "Blockchain cache: best tip {:x} does not lead back to genesis; \
+                           rebuilding blockchain and UTXO set from the peer.",
+             blockchain.best_tip_hash());
+      blockchain = Blockchain::new(network);
+      utxo_set = UtxoSet::new(network, self.config.blockchain_n_full_blocks);
+      journal_in_sync = false;
+    }
+    // The UTXO set must be at a block we know of, or UTXO sync cannot
+    // carry on from it
+    if blockchain.get_block(utxo_set.last_hash()).is_none() {
+      debug!(self, Error, "UTXO set cache: last block {:x} is not in the blockchain; \
+                           rebuilding UTXO set from the peer.", utxo_set.last_hash());
+      utxo_set = UtxoSet::new(network, self.config.blockchain_n_full_blocks);
+      journal_in_sync = false;
+    }
+    (blockchain, utxo_set, journal_in_sync)
+  }
+
   /// Run the state machine
   pub fn listen(&mut self) -> IoResult<()> {
     let mut timer = Timer::new().unwrap();  // TODO: can this fail? what should we do?
-    let save_timer = timer.periodic(Duration::seconds(SAVE_FREQUENCY));
+    let scheduler_timer = timer.periodic(Duration::seconds(SCHEDULER_TICK));
+    let wait_timer = timer.periodic(Duration::seconds(COINJOIN_WAIT_FREQUENCY));
+    let ping_timer = timer.periodic(Duration::seconds(PING_FREQUENCY));
+    // Separate, since setting a oneshot cancels a timer's other timeouts
+    let mut deadline_timer = Timer::new().unwrap();
+    // Failed background saves ask to be run again
+    let (save_retry_tx, save_retry_rx) = channel();
     let mut state_queue = DList::new();
+    let started_at = time::get_time().sec;
 
     // Startup
-    // Read wallet
-    debug!(self, Status, "Reading wallet...");
-    let mut wallet = match load_or_create_wallet(&self.config) {
-      Ok(w) => w,
-      Err(e) => fatal!(self.config.network, "Unable to read wallet: {}", e)
-    };
-    debug!(self, Status, "Loaded wallet.");
+    debug!(self, Status, "Starting {}, user agent {}.", version::version_string(),
+           version::user_agent());
+    // Read wallets
+    let mut wallets = Vec::with_capacity(self.config.wallets.len());
+    for wconfig in self.config.wallets.iter() {
+      debug!(self, Status, "Reading wallet `{}`...", wconfig.name);
+      match LoadedWallet::load(&self.config, wconfig) {
+        Ok(w) => wallets.push(w),
+        Err(e) => fatal!(self.config.network, "Unable to read wallet `{}`: {}", wconfig.name, e)
+      }
+    }
+    debug!(self, Status, "Loaded {} wallet(s).", wallets.len());
 
     // Open socket
     let (chan, sock) = self.loop_connect();
     // Load cached blockchain and UTXO set from disk
-    debug!(self, Status, "Loading blockchain...");
-    // Load blockchain from disk
-    let mut decoder = RawDecoder::new(BufferedReader::new(File::open(&self.config.blockchain_path)));
-    let blockchain = match ConsensusDecodable::consensus_decode(&mut decoder) {
-      Ok(blockchain) => blockchain,
-      Err(e) => {
-        debug!(self, Error, "Failed to load blockchain: {:}, starting from genesis.", e);
-        Blockchain::new(self.config.network)
-      }
-    };
-    debug!(self, Status, "Loading utxo set...");
-    // Load UTXO set from disk
-    let mut decoder = RawDecoder::new(BufferedReader::new(File::open(&self.config.utxo_set_path)));
-    let utxo_set = match ConsensusDecodable::consensus_decode(&mut decoder) {
-      Ok(utxo_set) => utxo_set,
+    let (blockchain, utxo_set, journal_in_sync) = self.load_chain();
+    debug!(self, Status, "Loading fee estimates...");
+    let fee_estimator = match FeeEstimator::load(&self.config.fee_estimates_path) {
+      Ok(fee_estimator) => fee_estimator,
       Err(e) => {
-        debug!(self, Error, "Failed to load UTXO set: {:}, starting from genesis.", e);
-        UtxoSet::new(self.config.network, BLOCKCHAIN_N_FULL_BLOCKS)
+        debug!(self, Error, "Failed to load fee estimates: {}, starting afresh.", e);
+        FeeEstimator::new()
       }
     };
 
-    debug!(self, Status, "Building address index for wallet.");
-    wallet.build_index(&utxo_set);
-    debug!(self, Status, "Done building address index.");
-    debug!(self, Debug, "Wallet coinjoin balance: {}", wallet.balance("coinjoin"));
-    debug!(self, Debug, "Wallet total balance: {}", wallet.total_balance());
+    let tip_hash = blockchain.best_tip_hash();
+    let tip_height = blockchain.get_block(tip_hash).unwrap().height;
+    for w in wallets.mut_iter() {
+      debug!(self, Status, "Building address index for wallet `{}`.", w.config.name);
+      w.wallet.build_index(&utxo_set);
+      w.mark_unspent_used();
+      debug!(self, Status, "Done building address index.");
+      debug!(self, Debug, "Wallet `{}` coinjoin balance: {}", w.config.name,
+             w.wallet.balance("coinjoin"));
+      let bal = balances(&w.wallet, &w.meta, tip_height, median_time_past(&blockchain));
+      debug!(self, Debug, "Wallet `{}` balance: {} unconfirmed, {} confirmed, {} safe",
+             w.config.name, bal.unconfirmed, bal.confirmed, bal.safe);
+    }
+    let address_index = if self.config.address_index {
+      debug!(self, Status, "Building address index for the UTXO set.");
+      let index = AddressIndex::build(&utxo_set);
+      debug!(self, Status, "Done building address index.");
+      Some(Arc::new(RWLock::new(index)))
+    } else {
+      None
+    };
     // Setup idle state
+    let router = MessageRouter::start(chan, sock.clone());
+    let (relay_tx, relay_rx) = channel();
     let mut idle_state = IdleState {
       sock: sock,
-      net_chan: chan,
+      peer: PeerInfo::new(self.current_peer().clone()),
+      started_at: started_at,
+      router: router,
       // TODO: I'd rather this clone be some sort of take, but we need `self.config`
       //       to be around for the `Listener` trait getters below. Rework this.
       config: self.config.clone(),
       blockchain: Arc::new(RWLock::new(blockchain)),
       utxo_set: Arc::new(RWLock::new(utxo_set)),
+      mempool: Arc::new(RWLock::new(Mempool::new(self.config.mempool_max_size))),
+      fee_estimator: Arc::new(RWLock::new(fee_estimator)),
+      address_index: address_index,
+      save_lock: Arc::new(Mutex::new(())),
+      save_stats: Arc::new(Mutex::new(Default::default())),
+      journal: Arc::new(Mutex::new(Journal::new(journal_in_sync))),
       coinjoin: None,
-      wallet: wallet
+      coinjoin_events: self.events.subscribe(vec![Chain]),
+      wallets: wallets.move_iter().map(|w| Arc::new(Mutex::new(w))).collect(),
+      active_wallet: 0,
+      rpc_reply: None,
+      rpc_caller: None,
+      coinjoin_waiters: vec![],
+      block_waiters: vec![],
+      rpc_stats: Arc::new(Mutex::new(RpcStats::new())),
+      rpc_pool: WorkerPool::new(self.config.rpc_workers),
+      events: self.events.clone(),
+      shutdown_tx: self.shutdown_tx.clone(),
+      reload_tx: self.reload_tx.clone(),
+      relay_tx: relay_tx,
+      relay_rx: relay_rx,
+      last_tip: tip_hash,
+      coinjoin_states: HashMap::new(),
+      sync_state: SyncingHeaders,
+      stalls: 0,
+      sync_progress: Arc::new(Mutex::new(SyncProgress::new())),
+      scheduler: Scheduler::with_periods(&self.config.task_periods, started_at),
+      peer_change: None
     };
+    follow_chain(idle_state.config.clone(), idle_state.wallets.clone(),
+                 idle_state.utxo_set.clone(), idle_state.events.clone());
+    match idle_state.address_index {
+      Some(ref index) => {
+        address_index::follow_chain(idle_state.config.clone(), index.clone(),
+                                    idle_state.utxo_set.clone(), idle_state.events.clone());
+      }
+      None => {}
+    }
+    match idle_state.config.electrum_port {
+      Some(port) => match electrum::start(idle_state.shared(), idle_state.events.clone()) {
+        Ok(()) => { debug!(idle_state, Status, "Electrum server listening on port {}.", port); }
+        Err(e) => { debug!(idle_state, Error, "Electrum server failed to start: {}", e); }
+      },
+      None => {}
+    }
 
     // Eternal state machine loop
     state_queue.push(SyncBlockchain);
-    state_queue.push(SyncUtxoSet(TxoValidation));  // for initial sync only do TXO validation
+    state_queue.push(SyncUtxoSet);
     state_queue.push(SaveToDisk);
     loop {
-      match state_queue.pop_front() {
+      let next = state_queue.pop_front();
+      match next {
+        Some(ref state) => { debug!(idle_state, Debug, "Entering state: {}", state.name()); }
+        None => {}
+      }
+      match next {
         // Synchronize the blockchain with the peer
         Some(SyncBlockchain) => {
+          let state = SyncBlockchain;
+          set_sync_state(&mut idle_state, SyncingHeaders);
           // Borrow the blockchain mutably
           let mut blockchain = idle_state.blockchain.write();
           debug!(idle_state, Status, "Syncing blockheaders: last best tip {:x}",
                  blockchain.best_tip_hash());
           // Do a headers-first sync of all blocks
           let mut done = false;
-          while !done {
+          let mut failed = false;
+          let mut watchdog = SyncWatchdog::new((blockchain.best_tip_hash(), 0));
+          {
+            let tip = blockchain.get_block(blockchain.best_tip_hash()).unwrap();
+            start_progress(&idle_state, SyncingHeaders, tip.height, tip.block.header.time);
+          }
+          while !done && !failed {
             debug!(idle_state, Notice, "Starting headers sync from {:x}",
                    blockchain.best_tip_hash());
 
             // Request headers
             consume_err("Headers sync: failed to send `headers` message",
-              idle_state.sock.send_message(message::GetHeaders(
+              idle_state.send_message(message::GetHeaders(
                   GetHeadersMessage::new(blockchain.locator_hashes(), Default::default()))));
             // Loop through received headers
             let mut received_headers = false;
-            while !received_headers {
-              with_next_message!(self, idle_state,
+            while !received_headers && !failed {
+              let ok = with_next_message!(self, idle_state, deadline_timer, state, headers,
                 message::Headers(headers) => {
                   for lone_header in headers.iter() {
                     match blockchain.add_header(lone_header.header) {
@@ -270,26 +898,47 @@ impl Bitcoind {
                         debug!(idle_state, Error, "Headers sync: failed to add {:x}: {}", 
                                lone_header.header.bitcoin_hash(), e);
                       }
-                       _ => {}
+                      _ => { idle_state.journal.lock().record(HeaderAdded(lone_header.header)); }
                     }
                   }
                   received_headers = true;
                   // We are done if this `headers` message did not update our status
                   done = headers.len() == 0;
-                }
-                message::Ping(nonce) => {
-                  consume_err("Warning: failed to send pong in response to ping",
-                    idle_state.sock.send_message(message::Pong(nonce)));
+                  let tip = blockchain.get_block(blockchain.best_tip_hash()).unwrap();
+                  report_progress(&idle_state, tip.height, tip.block.header.time);
                 }
               );
+              failed = !ok;
+              if ok && !done {
+                let tip = blockchain.best_tip_hash();
+                match watchdog.check((tip, 0)) {
+                  Some(stalled) => {
+                    idle_state.stalls += 1;
+                    debug!(idle_state, Error,
+                           "Headers sync: best tip {:x} unchanged for {}s although peer {} \
+                           (start height {}) is still sending headers, disconnecting.",
+                           tip, stalled, idle_state.peer.addr, idle_state.peer.start_height);
+                    replace_peer!(self, idle_state);
+                    failed = true;
+                  }
+                  None => {}
+                }
+              }
             }
           }
-          // Done!
-          debug!(idle_state, Status, "Done headers sync.");
+          if failed {
+            debug!(idle_state, Error, "Failed to sync headers, will try again.");
+            retry_state(&mut state_queue, &state);
+          } else {
+            debug!(idle_state, Status, "Done headers sync.");
+          }
         },
-        Some(SyncUtxoSet(validation_level)) => {
+        Some(SyncUtxoSet) => {
+          let state = SyncUtxoSet;
+          set_sync_state(&mut idle_state, SyncingUtxoSet);
           let mut failed = false;
-          let mut cache = Vec::with_capacity(UTXO_SYNC_N_BLOCKS);
+          // Transactions of rewound blocks, to return to the mempool after
+          let mut disconnected: Vec<Vec<Transaction>> = vec![];
           // Scope here to make sure we drop the read handle before we try to write
           {
             let blockchain = idle_state.blockchain.read();
@@ -301,86 +950,158 @@ impl Bitcoind {
               // Unwind any reorg'd blooks
               for block in blockchain.rev_stale_iter(last_hash) {
                 debug!(idle_state, Notice, "Rewinding stale block {}", block.bitcoin_hash());
-                if !utxo_set.rewind(block) {
+                if utxo_set.rewind(block) {
+                  idle_state.journal.lock().record(BlockRewound(block.clone()));
+                } else {
                   debug!(idle_state, Notice, " Failed to rewind stale block {}",
                          block.bitcoin_hash());
                 }
+                idle_state.events.publish(events::BlockDisconnected(Arc::new(block.clone())));
+                disconnected.push(block.txdata.iter().skip(1).map(|tx| tx.clone()).collect());
               }
               utxo_set.last_hash()
             };
-            // Loop through blockchain for new data
+            {
+              let node = blockchain.get_block(last_hash).unwrap();
+              start_progress(&idle_state, SyncingUtxoSet, node.height, node.block.header.time);
+            }
+            // Blocks buried under a checkpoint only get TXO validation
+            let trusted_height = checkpoints::trusted_height(idle_state.config.network, &*blockchain);
+            if trusted_height > 0 {
+              debug!(idle_state, Notice, "UTXO sync: skipping script validation up to checkpoint \
+                                          at height {}", trusted_height);
+            }
+            // Loop through blockchain for new data. Blocks are requested in
+            // batches sized so that those in flight or waiting to be
+            // connected fit in `utxo_sync_memory`, and connected as soon as
+            // they can be in chain order.
             let mut iter = blockchain.iter(last_hash).skip(1).peekable();
-            while !failed && !iter.is_empty() {
-              // Cache a bunch of blocks to minimize network messages (bitcoind puts delays into each one)
-              let mut height = 0;
-              for node in iter.by_ref().take(UTXO_SYNC_N_BLOCKS) {
-                cache.push(Inventory { inv_type: InvBlock, hash: node.block.bitcoin_hash() });
-                height = node.height;
+            let mut received = 0u;
+            let mut watchdog = SyncWatchdog::new((last_hash, received));
+            let memory_bound = idle_state.config.utxo_sync_memory;
+            let mut avg_block_size = UTXO_SYNC_INITIAL_BLOCK_SIZE;
+            // Requested blocks not yet connected, in chain order, with heights
+            let mut requested: DList<(Sha256dHash, uint)> = DList::new();
+            // Those of them which have arrived
+            let mut arrived: HashMap<Sha256dHash, Block> = HashMap::new();
+            while !failed && (!iter.is_empty() || !requested.is_empty()) {
+              // Top up the requests once half of the last batch is in
+              let budget = cmp::max(1, cmp::min(idle_state.config.utxo_sync_n_blocks,
+                                                memory_bound / avg_block_size));
+              if requested.len() <= budget / 2 && !iter.is_empty() {
+                let mut getdata = Vec::with_capacity(budget - requested.len());
+                let mut height = 0;
+                for node in iter.by_ref().take(budget - requested.len()) {
+                  // Headers do not cache their hash, so only compute it once
+                  let hash = node.block.bitcoin_hash();
+                  getdata.push(Inventory { inv_type: InvBlock, hash: hash });
+                  requested.push((hash, node.height));
+                  height = node.height;
+                }
+                {
+                  let utxo_set = idle_state.utxo_set.read();
+                  debug!(idle_state, Notice, "UTXO sync: requesting up to height {} ({} blocks of \
+                                              about {} bytes) n_utxos {} pruned {}",
+                         height, getdata.len(), avg_block_size, utxo_set.n_utxos(),
+                         utxo_set.n_pruned());
+                }
+                consume_err("UTXO sync: failed to send `getdata` message",
+                  idle_state.send_message(message::GetData(getdata)));
               }
 
-              // Once we've cached enough, send a message requesting them
-              let mut utxo_set = idle_state.utxo_set.write();
-              debug!(idle_state, Notice, "UTXO sync: height {} n_utxos {} pruned {}",
-                     height, utxo_set.n_utxos(), utxo_set.n_pruned());
-              consume_err("UTXO sync: failed to send `getdata` message",
-                idle_state.sock.send_message(message::GetData(cache.clone())));
-
-              let mut block_count = 0;
-              let mut recv_data = PatriciaTree::new();
-              while block_count < cache.len() {
-                with_next_message!(self, idle_state,
-                  message::Block(block) => {
-                    recv_data.insert(&block.bitcoin_hash().into_le().low_128(), 128, block);
-                    block_count += 1;
+              let ok = with_next_message!(self, idle_state, deadline_timer, state, blocks,
+                message::Block(block) => {
+                  let hash = block.bitcoin_hash();
+                  // Ignore blocks we did not ask for, e.g. newly announced ones
+                  if requested.iter().any(|&(h, _)| h == hash) && !arrived.contains_key(&hash) {
+                    let size = serialize(&block).map(|v| v.len()).unwrap_or(avg_block_size);
+                    avg_block_size = cmp::max(1, (avg_block_size * 7 + size) / 8);
+                    arrived.insert(hash, block);
+                    received += 1;
                   }
-                  message::NotFound(_) => {
-                    debug!(idle_state, Error,
-                           "UTXO sync: received `notfound` from sync peer, failing sync.");
-                    failed = true;
-                    block_count += 1;
-                  }
-                  message::Ping(nonce) => {
-                    consume_err("Warning: failed to send pong in response to ping",
-                      idle_state.sock.send_message(message::Pong(nonce)));
-                  }
-                )
+                }
+                message::NotFound(_) => {
+                  debug!(idle_state, Error,
+                         "UTXO sync: received `notfound` from sync peer, failing sync.");
+                  failed = true;
+                }
+              );
+              if !ok {
+                failed = true;
+                break;
               }
-              for (n, recv_inv) in cache.iter().enumerate() {
-                let block_opt = recv_data.lookup(&recv_inv.hash.into_le().low_128(), 128);
-                match block_opt {
-                  Some(block) => {
-                    let height = height as uint - UTXO_SYNC_N_BLOCKS + 1 + n;
-                    debug!(idle_state, Debug, "Updating UTXO set with block {}: {:x}",
-                           height, block.bitcoin_hash());
-                    match utxo_set.update(block, height, validation_level) {
-                      Ok(_) => {}
-                      Err(e) => {
-                        debug!(idle_state, Error,
-                               "Failed to update UTXO set with block {:x}: {}",
-                               block.bitcoin_hash(), e);
-                        failed = true;
-                        // If this block fails, the next one definitely will (since the prevhash
-                        // won't match) so just drop out ofthe loop now.
-                        break;
-                      }
+
+              // Connect whatever can now be connected in order. The UTXO set
+              // is locked per block rather than per batch, so that RPC and
+              // wallet lookups get a turn between blocks.
+              while !failed {
+                let (hash, height) = match requested.front() {
+                  Some(&(hash, height)) if arrived.contains_key(&hash) => (hash, height),
+                  _ => { break; }
+                };
+                requested.pop_front();
+                let block = arrived.pop(&hash).unwrap();
+                debug!(idle_state, Debug, "Updating UTXO set with block {}: {:x}", height, hash);
+                let validation_level = checkpoints::validation_level(height, trusted_height);
+                let result = idle_state.utxo_set.write().update(&block, height, validation_level);
+                match result {
+                  Ok(_) => {
+                    idle_state.journal.lock().record(BlockConnected(block.clone(), height));
+                    {
+                      let mut mempool = idle_state.mempool.write();
+                      mempool.block_connected(&block);
+                      let txids: Vec<Sha256dHash> = block.txdata.iter()
+                                                         .map(|tx| tx.bitcoin_hash())
+                                                         .collect();
+                      let mut fee_estimator = idle_state.fee_estimator.write();
+                      fee_estimator.block_connected(height, txids.as_slice(),
+                                                    |txid| mempool.get(txid).is_some());
                     }
+                    report_progress(&idle_state, height, block.header.time);
+                    idle_state.events.publish(events::BlockConnected(Arc::new(block), height));
                   }
-                  None => {
-                    debug!(idle_state, Error, "Uh oh, requested block {:x} but didn't get it!",
-                           recv_inv.hash);
+                  Err(e) => {
+                    debug!(idle_state, Error, "Failed to update UTXO set with block {:x}: {}",
+                           hash, e);
+                    // If this block fails, the next one definitely will (since the prevhash
+                    // won't match) so just drop out of the loop now.
                     failed = true;
                   }
                 }
               }
-              cache.clear();
+
+              let utxo_tip = idle_state.utxo_set.read().last_hash();
+              match watchdog.check((utxo_tip, received)) {
+                Some(stalled) => {
+                  idle_state.stalls += 1;
+                  debug!(idle_state, Error,
+                         "UTXO sync: no blocks received or connected for {}s, stuck at {:x} \
+                         with {} requested blocks outstanding from peer {}, disconnecting.",
+                         stalled, utxo_tip, requested.len() - arrived.len(),
+                         idle_state.peer.addr);
+                  replace_peer!(self, idle_state);
+                  failed = true;
+                }
+                None => {}
+              }
+              drain_coinjoin_events(&mut idle_state.coinjoin, &idle_state.config,
+                                    &*idle_state.utxo_set, &idle_state.coinjoin_events);
             }
           }
+          // Rewound blocks were rewound tip-first, but must be reinstated in
+          // chain order so that parents go in before their children
+          if !disconnected.is_empty() {
+            disconnected.reverse();
+            let utxo_set = idle_state.utxo_set.read();
+            let n = idle_state.mempool.write().reinstate(disconnected.as_slice(), &*utxo_set,
+                                                         time::get_time().sec);
+            debug!(idle_state, Notice, "Returned {} transactions from rewound blocks to mempool", n);
+          }
           if failed {
             debug!(idle_state, Error, "Failed to sync UTXO set, will resync chain and try again.");
             debug!(idle_state, Debug, "Pausing for 3 seconds.");
             timer::sleep(Duration::seconds(3));
-            state_queue.push(SyncBlockchain);
-            state_queue.push(SyncUtxoSet(validation_level));
+            retry_state(&mut state_queue, &state);
           } else {
             // Now that we're done with reorgs, update our cached block data
             let mut hashes_to_drop_data = vec![];
@@ -388,7 +1109,7 @@ impl Bitcoind {
             {
               let blockchain = idle_state.blockchain.read();
               for (n, node) in blockchain.rev_iter(blockchain.best_tip_hash()).enumerate() {
-                if n < BLOCKCHAIN_N_FULL_BLOCKS {
+                if n < idle_state.config.blockchain_n_full_blocks {
                   if !node.has_txdata {
                     inv_to_add_data.push(Inventory { inv_type: InvBlock,
                                                      hash: node.block.bitcoin_hash() });
@@ -400,7 +1121,7 @@ impl Bitcoind {
             }
             // Request new block data
             consume_err("UTXO sync: failed to send `getdata` message",
-              idle_state.sock.send_message(message::GetData(inv_to_add_data.clone())));
+              idle_state.send_message(message::GetData(inv_to_add_data.clone())));
             {
               let mut blockchain = idle_state.blockchain.write();
               // Delete old block data
@@ -414,7 +1135,7 @@ impl Bitcoind {
               // Receive new block data
               let mut block_count = 0;
               while block_count < inv_to_add_data.len() {
-                with_next_message!(self, idle_state,
+                let ok = with_next_message!(self, idle_state, deadline_timer, state, blocks,
                   message::Block(block) => {
                     debug!(idle_state, Notice, "Adding blockdata for {:x}", block.bitcoin_hash());
                     match blockchain.add_txdata(block) {
@@ -429,114 +1150,560 @@ impl Bitcoind {
                            will not be able to handle reorgs past this block.");
                     block_count += 1;
                   }
-                  message::Ping(nonce) => {
-                    consume_err("Warning: failed to send pong in response to ping",
-                    idle_state.sock.send_message(message::Pong(nonce)));
-                  }
-                )
+                );
+                if !ok {
+                  debug!(idle_state, Error,
+                         "Blockchain sync: gave up on full blockdata, \
+                         will not be able to handle reorgs past the missing blocks.");
+                  break;
+                }
               }
             }
             debug!(idle_state, Status, "Done UTXO sync.");
+            set_sync_state(&mut idle_state, Synced);
           }
         },
         // Idle loop
         None => {
           debug!(idle_state, Debug, "Idling...");
           let mut replace_socket = false;
+          let mut reconnect = false;
+          let mut try_addr = None;
           nu_select!(
-            response from idle_state.net_chan => {
-              match response {
-                MessageReceived(message) => idle_message(&mut state_queue, &mut idle_state, message),
-                ConnectionFailed(e, tx) => {
-                  debug!(idle_state, Error, "Network error: `{}`, reconnecting.", e);
-                  tx.send(());
-                  timer::sleep(Duration::seconds(1));
-                  replace_socket = true;
-                }
+            routed from idle_state.router.control => {
+              if !idle_routed(&mut state_queue, &mut idle_state, routed) { replace_socket = true; }
+            },
+            routed from idle_state.router.mempool => {
+              if !idle_routed(&mut state_queue, &mut idle_state, routed) { replace_socket = true; }
+            },
+            routed from idle_state.router.blocks => {
+              if !idle_routed(&mut state_queue, &mut idle_state, routed) { replace_socket = true; }
+            },
+            routed from idle_state.router.headers => {
+              if !idle_routed(&mut state_queue, &mut idle_state, routed) { replace_socket = true; }
+            },
+            routed from idle_state.router.pings => {
+              if !idle_routed(&mut state_queue, &mut idle_state, routed) { replace_socket = true; }
+            },
+            () from scheduler_timer => {
+              for task in idle_state.scheduler.due(time::get_time().sec).move_iter() {
+                state_queue.extend(WalletState::for_task(task).move_iter());
               }
             },
-            () from save_timer => {
-              state_queue.push(SyncBlockchain);
-              state_queue.push(SyncUtxoSet(ScriptValidation));
-              state_queue.push(SaveToDisk);
+            event from idle_state.coinjoin_events => {
+              coinjoin_chain_event(&mut idle_state.coinjoin, &idle_state.config,
+                                   &*idle_state.utxo_set, &event);
+            },
+            () from ping_timer => {
+              let nonce = rand::random();
+              idle_state.peer.ping_sent(nonce);
+              consume_err("Warning: failed to send ping",
+                idle_state.send_message(message::Ping(nonce)));
+            },
+            () from wait_timer => {
+              notify_coinjoin_waiters(&mut idle_state);
+              notify_block_waiters(&mut idle_state);
+              publish_events(&mut idle_state);
+            },
+            () from save_retry_rx => {
+              debug!(idle_state, Warning, "Will try saving again in {}s.", SAVE_RETRY_DELAY);
+              idle_state.scheduler.once(scheduler::Save, SAVE_RETRY_DELAY, time::get_time().sec);
             },
-            (request, tx) from self.rpc_rx => {
-              tx.send(handle_rpc(request, &mut idle_state));
+            () from self.stop_rx => {
+              state_queue.push(Shutdown);
+            },
+            (tx, caller) from idle_state.relay_rx => {
+              broadcast_transaction(&mut idle_state, tx, caller);
+            },
+            (config, reply) from self.config_rx => {
+              let new_peers = reload_config(&mut idle_state, config, reply);
+              // Keep our own copy in step, for `loop_connect`
+              self.config = idle_state.config.clone();
+              if new_peers {
+                self.peer_index = 0;
+                reconnect = true;
+              }
+            },
+            (request, caller, tx) from self.rpc_rx => {
+              handle_rpc(request, caller, tx, &mut idle_state);
+              // The call may have changed a session's state
+              notify_coinjoin_waiters(&mut idle_state);
+              publish_events(&mut idle_state);
+              // or the peer list, in which case keep our place in it
+              if idle_state.config.peers != self.config.peers {
+                self.config.peers = idle_state.config.peers.clone();
+                self.peer_index = self.config.peers.iter()
+                                      .position(|p| *p == idle_state.peer.addr)
+                                      .unwrap_or(0);
+              }
+              match idle_state.peer_change.take() {
+                Some(LeavePeer) => { reconnect = true; }
+                Some(TryPeer(addr)) => { try_addr = Some(addr); }
+                None => {}
+              }
             }
           );
           if replace_socket {
-            let (chan, sock) = self.loop_connect();
-            idle_state.net_chan = chan;
-            idle_state.sock = sock;
+            replace_peer!(self, idle_state);
+          } else if try_addr.is_some() {
+            try_peer!(self, idle_state, try_addr.take().unwrap());
+          } else if reconnect {
+            reconnect!(self, idle_state);
           }
         },
         // Temporary states
         Some(SaveToDisk) => {
+          save_wallets(&mut idle_state);
           let bc_arc = idle_state.blockchain.clone();
           let us_arc = idle_state.utxo_set.clone();
-          let blockchain_path = idle_state.config.blockchain_path.clone();
-          let utxo_set_path = idle_state.config.utxo_set_path.clone();
-          let network = idle_state.config.network;
-          let debug_level = idle_state.config.debug_level;
+          let save_lock = idle_state.save_lock.clone();
+          let save_stats = idle_state.save_stats.clone();
+          let journal = idle_state.journal.clone();
+          let config = idle_state.config.clone();
+          let retry_tx = save_retry_tx.clone();
           spawn(proc() {
-            // Lock the blockchain for reading while we are saving it.
-            {
-              let blockchain = bc_arc.read();
-              debug!((network, debug_level), Status, "Saving blockchain...");
-              let mut encoder = RawEncoder::new(BufferedWriter::new(File::open_mode(&blockchain_path, Open, Write)));
-              match blockchain.consensus_encode(&mut encoder) {
-                Ok(()) => { debug!((network, debug_level), Status,
-                                   "Done saving blockchain."); },
-                Err(e) => { debug!((network, debug_level), Error,
-                            "Failed to write blockchain: {}", e); }
-              }
+            if !save_chain(&config, bc_arc, us_arc, journal, save_lock, save_stats) {
+              let _ = retry_tx.send_opt(());
             }
-            // Lock the UTXO set for reading while we are saving it.
-            {
-              let utxo_set = us_arc.read();
-              debug!((network, debug_level), Status, "Saving UTXO set...");
-              let mut encoder = RawEncoder::new(BufferedWriter::new(File::open_mode(&utxo_set_path, Open, Write)));
-              match utxo_set.consensus_encode(&mut encoder) {
-                Ok(()) => { debug!((network, debug_level), Status,
-                                   "Done saving UTXO set.") },
-                Err(e) => { debug!((network, debug_level), Error,
-                                   "Failed to write UTXO set: {:}", e); }
+          });
+        }
+        Some(RotatePeer) => {
+          debug!(idle_state, Status, "Rotating peer: leaving {}.", idle_state.peer.addr);
+          replace_peer!(self, idle_state);
+        }
+        Some(RunCoinjoinSchedule) => {
+          run_coinjoin_schedule(&mut idle_state);
+        }
+        Some(ExpireMempool) => {
+          let n = idle_state.mempool.write().expire(time::get_time().sec, MEMPOOL_EXPIRY);
+          if n > 0 {
+            debug!(idle_state, Notice, "Dropped {} expired transactions from mempool.", n);
+          }
+        }
+        Some(FlushFeeEstimates) => {
+          save_fee_estimates(&idle_state);
+        }
+        // Final save before exiting, done synchronously so that the
+        // process does not exit mid-write
+        Some(Shutdown) => {
+          debug!(idle_state, Status, "Shutting down...");
+          match idle_state.coinjoin {
+            Some(ref server) => {
+              let active = server.sessions().iter().filter(|s| !s.state().is_final()).count();
+              if active > 0 {
+                debug!(idle_state, Warning, "Abandoning {} unfinished coinjoin session(s).", active);
               }
             }
-          });
+            None => {}
+          }
+          save_wallets(&mut idle_state);
+          save_fee_estimates(&idle_state);
+          if !save_chain(&idle_state.config, idle_state.blockchain.clone(),
+                         idle_state.utxo_set.clone(), idle_state.journal.clone(),
+                         idle_state.save_lock.clone(), idle_state.save_stats.clone()) {
+            debug!(idle_state, Error, "Failed to save blockchain and UTXO set; the journal \
+                                       and previous save are intact, so nothing is lost.");
+          }
+          debug!(idle_state, Status, "Shut down.");
+          // Dropping the idle state closes the socket
+          return Ok(());
         }
       };
     }
   }
 }
 
+/// Queues the states which follow a failure of `state`, to run next
+fn retry_state<S:Deque<WalletState>>(state_queue: &mut S, state: &WalletState) {
+  for next in state.on_failure().move_iter().rev() {
+    state_queue.push_front(next);
+  }
+}
+
+/// Applies a reloaded configuration, logging and replying with which
+/// settings changed. Returns true if the peers changed, in which case the
+/// caller must reconnect.
+fn reload_config(idle_state: &mut IdleState, config: NetworkConfig,
+                 reply: Option<Sender<jsonrpc::JsonResult<json::Json>>>) -> bool {
+  let report = idle_state.config.reload(&config);
+  if report.applied.is_empty() && report.needs_restart.is_empty() {
+    debug!(idle_state, Status, "Reloaded configuration: nothing changed.");
+  }
+  for name in report.applied.iter() {
+    debug!(idle_state, Status, "Reloaded configuration: applied new `{}`.", name);
+  }
+  for name in report.needs_restart.iter() {
+    debug!(idle_state, Warning, "Reloaded configuration: new `{}` needs a restart.", name);
+  }
+  if report.applied.iter().any(|&name| name == "task_periods") {
+    idle_state.scheduler.set_periods(&idle_state.config.task_periods, time::get_time().sec);
+  }
+  match reply {
+    // The client may have hung up, which is fine
+    Some(reply) => { let _ = reply.send_opt(Ok(report.to_json())); }
+    None => {}
+  }
+  report.applied.iter().any(|&name| name == "peers")
+}
+
+/// Expires old unconfirmed transactions and saves each wallet's metadata
+fn save_wallets(idle_state: &mut IdleState) {
+  let now = time::get_time().sec;
+  for w in idle_state.wallets.iter() {
+    let mut w = w.lock();
+    let n_expired = w.meta.expire_pending(now, PENDING_TX_EXPIRY);
+    if n_expired > 0 {
+      debug!(idle_state, Notice, "Forgot {} expired unconfirmed transactions in wallet `{}`.",
+             n_expired, w.config.name);
+    }
+    match w.save_metadata() {
+      Ok(()) => {}
+      Err(e) => { debug!(idle_state, Error, "Failed to write metadata for wallet `{}`: {}",
+                         w.config.name, e); }
+    }
+  }
+}
+
+/// Saves the fee estimator's statistics
+fn save_fee_estimates(idle_state: &IdleState) {
+  match idle_state.fee_estimator.read().save(&idle_state.config.fee_estimates_path) {
+    Ok(()) => {}
+    Err(e) => { debug!(idle_state, Error, "Failed to write fee estimates: {}", e); }
+  }
+}
+
+/// Saves the blockchain and UTXO set to disk: usually just the changes
+/// since the last save, appended to the journal, but occasionally both in
+/// full. `save_lock` is held throughout, so that two saves never write the
+/// same files at once. Returns whether the save succeeded.
+fn save_chain(config: &NetworkConfig, bc_arc: Arc<RWLock<Blockchain>>,
+              us_arc: Arc<RWLock<UtxoSet>>, journal_arc: Arc<Mutex<Journal>>,
+              save_lock: Arc<Mutex<()>>, save_stats: Arc<Mutex<SaveStats>>) -> bool {
+  let _guard = save_lock.lock();
+  let start = time::precise_time_ns();
+  let (full, ok) = write_chain(config, bc_arc, us_arc, journal_arc);
+  save_stats.lock().record(full, ok, time::precise_time_ns() - start);
+  ok
+}
+
+/// Does the work of `save_chain`, returning whether it saved in full, and
+/// whether it succeeded
+fn write_chain(config: &NetworkConfig, bc_arc: Arc<RWLock<Blockchain>>,
+               us_arc: Arc<RWLock<UtxoSet>>, journal_arc: Arc<Mutex<Journal>>)
+               -> (bool, bool) {
+  let (network, debug_level) = (config.network, config.debug_level);
+
+  let changes = {
+    let mut journal = journal_arc.lock();
+    if journal.needs_full_save() { None } else { Some(journal.take_pending()) }
+  };
+  match changes {
+    Some(changes) => {
+      debug!((network, debug_level), Status, "Journalling {} changes...", changes.len());
+      match journal::append(&config.chain_journal_path, changes.as_slice()) {
+        Ok(()) => {
+          debug!((network, debug_level), Status, "Done journalling changes.");
+          return (false, true);
+        }
+        Err(e) => {
+          debug!((network, debug_level), Error,
+                 "Failed to write journal: {}, saving in full.", e);
+        }
+      }
+    }
+    None => {}
+  }
+
+  // Make sure the new files will fit beside the old ones, since we only
+  // replace the old ones once the new ones are completely written
+  for path in [&config.blockchain_path, &config.utxo_set_path].iter() {
+    match disk::check_space(*path, SAVE_SPACE_MARGIN) {
+      Ok(()) => {}
+      Err(e) => {
+        debug!((network, debug_level), Warning,
+               "NOT SAVING blockchain and UTXO set: {}. Free some disk space!", e);
+        journal_arc.lock().force_full_save();
+        return (true, false);
+      }
+    }
+  }
+
+  // Hold both read locks throughout, so that the journal is emptied at
+  // exactly the state we write out
+  let blockchain = bc_arc.read();
+  let utxo_set = us_arc.read();
+  journal_arc.lock().reset();
+  let mut ok = true;
+  {
+    debug!((network, debug_level), Status, "Saving blockchain...");
+    match disk::write_checksummed(&config.blockchain_path, &*blockchain) {
+      Ok(()) => { debug!((network, debug_level), Status,
+                         "Done saving blockchain."); },
+      Err(e) => { debug!((network, debug_level), Error,
+                  "Failed to write blockchain: {}", e);
+                  ok = false; }
+    }
+  }
+  if ok {
+    debug!((network, debug_level), Status, "Saving UTXO set...");
+    match disk::write_checksummed(&config.utxo_set_path, &*utxo_set) {
+      Ok(()) => { debug!((network, debug_level), Status,
+                         "Done saving UTXO set.") },
+      Err(e) => { debug!((network, debug_level), Error,
+                         "Failed to write UTXO set: {:}", e);
+                  ok = false; }
+    }
+  }
+  // Only now replace the old files, so that a failure above leaves them,
+  // and the journal on top of them, as they were
+  if ok {
+    match disk::commit_group(&[&config.blockchain_path, &config.utxo_set_path]) {
+      Ok(()) => {}
+      Err(e) => { debug!((network, debug_level), Error, "Failed to replace cache files: {}", e);
+                  ok = false; }
+    }
+  }
+  // Start a new journal on top of what we just wrote
+  if ok {
+    match journal::truncate(&config.chain_journal_path, blockchain.best_tip_hash(),
+                            utxo_set.last_hash()) {
+      Ok(()) => { return (true, true); }
+      Err(e) => { debug!((network, debug_level), Error, "Failed to reset journal: {}", e); }
+    }
+  }
+  disk::abandon_checksummed(&config.blockchain_path);
+  disk::abandon_checksummed(&config.utxo_set_path);
+  journal_arc.lock().force_full_save();
+  (true, false)
+}
+
 impl Listener for Bitcoind {
   fn peer<'a>(&'a self) -> &'a str {
-    self.config.peer_addr.as_slice()
+    self.current_peer().host.as_slice()
   }
 
   fn port(&self) -> u16 {
-    self.config.peer_port
+    self.current_peer().port
   }
 
   fn network(&self) -> Network {
     self.config.network
   }
+
+  // As the default, except that the version message carries our own user
+  // agent, so that peers (and their logs) can tell which build we are
+  fn start(&self) -> IoResult<(Receiver<SocketResponse>, Socket)> {
+    let mut sock = Socket::new(self.network());
+    sock.user_agent = version::user_agent();
+    try!(sock.connect(self.peer(), self.port()));
+    let mut recv_sock = sock.clone();
+    let version_message = try!(sock.version_message(0));
+    try!(sock.send_message(message::Version(version_message)));
+
+    let (tx, rx) = channel();
+    spawn(proc() {
+      loop {
+        match recv_sock.receive_message() {
+          Ok(payload) => {
+            if tx.send_opt(message::MessageReceived(payload)).is_err() { break; }
+          }
+          Err(e) => {
+            // Wait for the failure to be acknowledged before hanging up,
+            // so that the channel is still open when it is read
+            let (ack_tx, ack_rx) = channel();
+            let _ = tx.send_opt(message::ConnectionFailed(e, ack_tx));
+            let _ = ack_rx.recv_opt();
+            break;
+          }
+        }
+      }
+    });
+    Ok((rx, sock))
+  }
+}
+
+/// Checks whether a transaction double-spends any coinjoin contributions.
+/// Unconfirmed transactions are checked against `utxo_set`.
+fn check_coinjoin_inputs(coinjoin: &mut Option<coinjoin::server::Server>,
+                         config: &NetworkConfig, tx: &Transaction,
+                         utxo_set: Option<&UtxoSet>) {
+  let affected = match *coinjoin {
+    Some(ref mut server) => server.transaction_seen(tx, utxo_set),
+    None => { return; }
+  };
+  for id in affected.iter() {
+    debug!((config.network, config.debug_level), Warning,
+           "Coinjoin session {} had an input spent by {:x}; banned its owner.",
+           id.to_json(), tx.bitcoin_hash());
+  }
+}
+
+/// Lets the coinjoin server see the transactions of a chain event
+fn coinjoin_chain_event(coinjoin: &mut Option<coinjoin::server::Server>,
+                        config: &NetworkConfig, utxo_set: &RWLock<UtxoSet>, event: &Event) {
+  match *event {
+    events::BlockConnected(ref block, _) => {
+      for tx in block.txdata.iter() {
+        check_coinjoin_inputs(coinjoin, config, tx, None);
+      }
+    }
+    // Rejected transactions may be forged, so must not count against anyone
+    TxAccepted(ref tx) => {
+      let utxo_set = utxo_set.read();
+      check_coinjoin_inputs(coinjoin, config, &**tx, Some(&*utxo_set));
+    }
+    _ => {}
+  }
+}
+
+/// Handles any chain events queued for the coinjoin server, for use while
+/// syncing, when the idle loop is not listening for them
+fn drain_coinjoin_events(coinjoin: &mut Option<coinjoin::server::Server>,
+                         config: &NetworkConfig, utxo_set: &RWLock<UtxoSet>,
+                         rx: &Receiver<Event>) {
+  loop {
+    match rx.try_recv() {
+      Ok(event) => coinjoin_chain_event(coinjoin, config, utxo_set, &event),
+      Err(_) => { break; }
+    }
+  }
+}
+
+/// Publishes a change of sync state, if it is one
+fn set_sync_state(idle_state: &mut IdleState, state: SyncState) {
+  if state == Synced {
+    idle_state.sync_progress.lock().stage = Synced;
+  }
+  if idle_state.sync_state != state {
+    idle_state.sync_state = state.clone();
+    idle_state.events.publish(SyncStateChanged(state));
+  }
+}
+
+/// Starts tracking the progress of a sync stage
+fn start_progress(idle_state: &IdleState, stage: SyncState, height: uint, block_time: u32) {
+  let peer_height = idle_state.peer.start_height.map(|h| h as uint);
+  idle_state.sync_progress.lock().start(stage, height, block_time as i64, peer_height);
+}
+
+/// Records the progress of a sync stage, logging it now and then
+fn report_progress(idle_state: &IdleState, height: uint, block_time: u32) {
+  let mut progress = idle_state.sync_progress.lock();
+  if progress.update(height, block_time as i64) {
+    let eta = match progress.eta() {
+      Some(eta) => format!("{}m{:02}s", eta / 60, eta % 60),
+      None => "unknown".to_string()
+    };
+    debug!(idle_state, Status, "{}: height {} of about {} ({:.1}%), ETA {}",
+           progress.stage.name(), height, progress.target_height(),
+           100.0 * progress.fraction(), eta);
+  }
+}
+
+/// Adds a transaction of our own to the mempool and sends it to our peer.
+/// It is sent even if the mempool refuses it, since the peer may know
+/// better, e.g. of inputs from blocks we have yet to sync.
+pub fn broadcast_transaction(idle_state: &mut IdleState, tx: Transaction, caller: &str) {
+  {
+    let tip_height = {
+      let blockchain = idle_state.blockchain.read();
+      blockchain.get_block(blockchain.best_tip_hash()).unwrap().height
+    };
+    let utxo_set = idle_state.utxo_set.read();
+    let mut mempool = idle_state.mempool.write();
+    match mempool.accept(tx.clone(), &*utxo_set, time::get_time().sec) {
+      Ok(txid) => {
+        let fee_rate = mempool.get(&txid).unwrap().fee_rate();
+        idle_state.fee_estimator.write().tx_entered(txid, fee_rate, tip_height);
+        idle_state.events.publish(TxAccepted(Arc::new(tx.clone())));
+      }
+      Err(AlreadyHave) => {}
+      Err(e) => {
+        debug!(idle_state, Warning, "{}: transaction {:x} refused by mempool: {}",
+               caller, tx.bitcoin_hash(), e);
+        idle_state.events.publish(TxRejected(Arc::new(tx.clone()), e.to_string()));
+      }
+    }
+  }
+  consume_err(format!("{}: failed to send `tx` message", caller).as_slice(),
+    idle_state.send_message(message::Tx(tx)));
+}
+
+/// Publishes any chain tip or coinjoin session changes since the last call
+fn publish_events(idle_state: &mut IdleState) {
+  let (tip_hash, tip_height) = {
+    let blockchain = idle_state.blockchain.read();
+    let hash = blockchain.best_tip_hash();
+    (hash, blockchain.get_block(hash).unwrap().height)
+  };
+  if tip_hash != idle_state.last_tip {
+    idle_state.last_tip = tip_hash;
+    idle_state.events.publish(NewTip(tip_hash, tip_height));
+  }
+
+  match idle_state.coinjoin {
+    Some(ref mut server) => {
+      server.update_all();
+      // Rebuild the state map each time so finished sessions drop out of it
+      let mut states = HashMap::new();
+      for session in server.sessions().iter() {
+        let (id, state) = (session.id(), session.state());
+        if idle_state.coinjoin_states.find(&id) != Some(&state) {
+          idle_state.events.publish(CoinjoinSession(id.clone(), state));
+        }
+        states.insert(id, state);
+      }
+      idle_state.coinjoin_states = states;
+    }
+    None => {}
+  }
+}
+
+/// Starts any scheduled coinjoin sessions which are due
+fn run_coinjoin_schedule(idle_state: &mut IdleState) {
+  if !idle_state.config.coinjoin.enabled {
+    return;
+  }
+  let schedule = idle_state.config.coinjoin.schedule.clone();
+  let join_duration = idle_state.config.coinjoin.join_duration;
+  let expiry_duration = idle_state.config.coinjoin.expiry_duration;
+  for sched in schedule.iter() {
+    match idle_state.coinjoin {
+      Some(ref mut server) => {
+        server.update_all();
+        if server.session_in_progress(sched.target) {
+          continue;
+        }
+        match (server.time_since_start(sched.target), sched.interval) {
+          (Some(elapsed), Some(interval)) if elapsed < Duration::seconds(interval) => continue,
+          _ => {}
+        }
+      }
+      None => {}
+    }
+    match start_coinjoin_session(idle_state, sched.target,
+                                 Duration::seconds(sched.join_duration.unwrap_or(join_duration)),
+                                 Duration::seconds(sched.expiry_duration.unwrap_or(expiry_duration)),
+                                 sched.options.clone().unwrap_or_default()) {
+      Ok(id) => { debug!(idle_state, Status, "Started scheduled coinjoin session {} for {} satoshi.",
+                         id.to_json(), sched.target); }
+      Err(e) => { debug!(idle_state, Error, "Failed to start scheduled coinjoin session for {} satoshi: {}",
+                         sched.target, e.message); }
+    }
+  }
 }
 
 /// Idle message handler
-fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
+fn idle_message<S:Deque<WalletState>>(state_queue: &mut S,
                                        idle_state: &mut IdleState,
                                        message: NetworkMessage) {
   match message {
-    message::Version(_) => {
-      // TODO: actually read version message
+    message::Version(version) => {
+      idle_state.peer.version = Some(version.version);
+      idle_state.peer.user_agent = Some(version.user_agent);
+      idle_state.peer.start_height = Some(version.start_height);
       consume_err("Warning: failed to send verack in response to version",
-        idle_state.sock.send_message(message::Verack));
-    }
-    message::Verack => {}
-    message::Addr(_) => {
-      // Ignore addr until we get multipeer support
+        idle_state.send_message(message::Verack));
     }
     message::Block(block) => {
       let mut lock = idle_state.blockchain.write();
@@ -544,11 +1711,12 @@ fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
       if lock.get_block(block.header.prev_blockhash).is_some() {
         // non-orphan, add it
         debug!(idle_state, Notice, "Received non-orphan, adding to blockchain...");
+        let header = block.header;
         match lock.add_block(block) {
           Err(e) => {
             debug!(idle_state, Error, "Failed to add block: {}", e);
           }
-          _ => {}
+          _ => { idle_state.journal.lock().record(HeaderAdded(header)); }
         }
         debug!(idle_state, Notice, "Done adding block.");
       } else {
@@ -556,7 +1724,7 @@ fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
         state_queue.push(SyncBlockchain);
       }
       // In either case we want to sync the UTXO set afterward
-      state_queue.push(SyncUtxoSet(ScriptValidation));
+      state_queue.push(SyncUtxoSet);
     },
     message::Headers(headers) => {
       for lone_header in headers.iter() {
@@ -569,20 +1737,58 @@ fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
       let sendmsg = message::GetData(inv);
       // Send
       consume_err("Warning: failed to send getdata in response to inv",
-        idle_state.sock.send_message(sendmsg));
+        idle_state.send_message(sendmsg));
     }
-    message::Tx(_) => {
-      debug!(idle_state, Debug, "Received tx, ignoring");
+    message::Tx(tx) => {
+      let tip_height = {
+        let blockchain = idle_state.blockchain.read();
+        blockchain.get_block(blockchain.best_tip_hash()).unwrap().height
+      };
+      let utxo_set = idle_state.utxo_set.read();
+      let mut mempool = idle_state.mempool.write();
+      match mempool.accept(tx.clone(), &*utxo_set, time::get_time().sec) {
+        Ok(txid) => {
+          debug!(idle_state, Debug, "Added transaction {:x} to mempool", txid);
+          let fee_rate = mempool.get(&txid).unwrap().fee_rate();
+          idle_state.fee_estimator.write().tx_entered(txid, fee_rate, tip_height);
+          idle_state.events.publish(TxAccepted(Arc::new(tx)));
+        }
+        // Already seen, and already published
+        Err(AlreadyHave) => {}
+        Err(e) => {
+          debug!(idle_state, Debug, "Not adding transaction {:x} to mempool: {}",
+                 tx.bitcoin_hash(), e);
+          idle_state.events.publish(TxRejected(Arc::new(tx), e.to_string()));
+        }
+      }
+    }
+    // Answered by the message router, so only its pong is counted here
+    message::Ping(nonce) => idle_state.peer.sent(&message::Pong(nonce)),
+    message::Pong(nonce) => idle_state.peer.pong_received(nonce),
+    // We serve nothing to the peer, and ignore addr until we get
+    // multipeer support
+    other => {
+      debug!(idle_state, Debug, "Received {}, ignoring.", Command::of(&other));
+    }
+  }
+}
+
+/// Handles something taken from one of the message router's queues while
+/// idling. Returns false if the connection failed and must be replaced.
+fn idle_routed<S:Deque<WalletState>>(state_queue: &mut S,
+                                      idle_state: &mut IdleState,
+                                      routed: Routed) -> bool {
+  match routed {
+    Message(message) => {
+      idle_state.peer.received(&message);
+      idle_message(state_queue, idle_state, message);
+      true
     }
-    message::GetData(_) => {}
-    message::NotFound(_) => {}
-    message::GetBlocks(_) => {}
-    message::GetHeaders(_) => {}
-    message::Ping(nonce) => {
-      consume_err("Warning: failed to send pong in response to ping",
-        idle_state.sock.send_message(message::Pong(nonce)));
+    Disconnected(e) => {
+      debug!(idle_state, Error, "Network error: `{}`, reconnecting.", e);
+      timer::sleep(Duration::seconds(1));
+      false
     }
-    message::Pong(_) => {}
   }
 }
 
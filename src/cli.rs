@@ -0,0 +1,252 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Command Line
+//!
+//! Options given on the command line. Apart from `--conf`, `--daemon`,
+//! `--dump-config` and `--help`, these override the corresponding settings
+//! of every network in the configuration file, including when it is
+//! reloaded. `--datadir` also
+//! moves the configuration file, pid file and daemon log into its directory,
+//! so that several instances can run side by side.
+//!
+
+use std::path::posix::Path;
+use getopts::{OptGroup, getopts, optflag, optmulti, optopt, usage};
+
+use bitcoin::network::constants::Network;
+
+use bitcoind::DebugLevel;
+use constants::DEFAULT_PEER_PORT;
+use user_data::{NetworkConfig, PeerAddress, config_path, log_path, network_from_name, pid_path};
+
+/// The options we accept, from which `--help` output is generated
+fn option_table() -> Vec<OptGroup> {
+  vec![
+    optflag("h", "help", "Print this help and exit"),
+    optflag("V", "version", "Print the version, git commit and build date and exit"),
+    optopt("", "conf", "Read configuration from FILE rather than the default path", "FILE"),
+    optopt("", "datadir", "Keep all wallets, caches, cookies and logs in DIR, and read the \
+                           configuration from DIR/wizards-wallet.conf unless --conf is given", "DIR"),
+    optmulti("", "network", "Only run NETWORK (bitcoin or testnet), even if it is disabled in \
+                             the configuration file; may be given more than once", "NETWORK"),
+    optopt("", "debug", "Log at LEVEL (DEBUG, NOTE, STATUS, WARN, ERROR or FATAL)", "LEVEL"),
+    optopt("", "rpcport", "Listen for RPC requests on PORT", "PORT"),
+    optmulti("", "connect", "Connect to the peer at HOST, or HOST:PORT, rather than the \
+                             configured peers; may be given more than once", "HOST[:PORT]"),
+    optflag("", "daemon", "Run in the background, writing a pid file and a log file"),
+    optflag("", "dump-config", "Write a commented default configuration to the configuration \
+                                file if there is none, or else to stdout, and exit"),
+    optflag("", "check-config", "Check the configuration and wallet files, print the settings \
+                                 each network would run with, and exit")
+  ]
+}
+
+/// What the program was asked to do
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Command {
+  /// Run the wallet in the foreground, or as a daemon with `--daemon`
+  Run,
+  /// Run the wallet as a daemon
+  Start,
+  /// Ask a running wallet to stop
+  Stop,
+  /// Summarize the state of a running wallet
+  Status,
+  /// Call an RPC method, with parameters, on a running wallet
+  Rpc(String, Vec<String>),
+  /// Show a live view of a running wallet in the terminal
+  Dashboard
+}
+
+/// Options parsed from the command line
+#[deriving(Clone)]
+pub struct Options {
+  /// What to do
+  pub command: Command,
+  /// Whether to print help and exit
+  pub help: bool,
+  /// Whether to print the version and exit
+  pub version: bool,
+  /// Whether to fork into the background
+  pub daemon: bool,
+  /// Whether to write out the default configuration and exit
+  pub dump_config: bool,
+  /// Whether to check the configuration and exit
+  pub check_config: bool,
+  /// Configuration file, if not the default
+  pub conf: Option<Path>,
+  /// Directory to keep all data files in
+  pub datadir: Option<Path>,
+  /// The only networks to run, if any are given
+  pub networks: Vec<Network>,
+  /// Debug level for all networks
+  pub debug_level: Option<DebugLevel>,
+  /// RPC server port
+  pub rpc_port: Option<u16>,
+  /// Peers to use instead of the configured ones, if any
+  pub connect: Vec<PeerAddress>
+}
+
+/// Parses the command line, given without the program name
+pub fn parse(args: &[String]) -> Result<Options, String> {
+  let matches = match getopts(args, option_table().as_slice()) {
+    Ok(matches) => matches,
+    Err(e) => { return Err(e.to_string()); }
+  };
+  let command = match matches.free.as_slice().head().map(|s| s.as_slice()) {
+    None => Run,
+    Some("start") => Start,
+    Some("stop") => Stop,
+    Some("status") => Status,
+    Some("dashboard") => Dashboard,
+    Some("rpc") => match matches.free.as_slice().get(1) {
+      Some(method) => Rpc(method.clone(), matches.free.slice_from(2).to_vec()),
+      None => { return Err("The rpc command needs a method to call".to_string()); }
+    },
+    Some(other) => { return Err(format!("Unknown command `{}`", other)); }
+  };
+  match command {
+    Rpc(_, _) => {}
+    _ => {
+      if matches.free.len() > 1 {
+        return Err(format!("Unexpected argument `{}`", matches.free[1]));
+      }
+    }
+  }
+
+  let mut networks = vec![];
+  for name in matches.opt_strs("network").iter() {
+    match network_from_name(name.as_slice()) {
+      Some(network) => networks.push(network),
+      None => { return Err(format!("Unknown network `{}`", name)); }
+    }
+  }
+  let debug_level = match matches.opt_str("debug") {
+    Some(name) => match DebugLevel::from_name(name.as_slice()) {
+      Some(level) => Some(level),
+      None => { return Err(format!("Unknown debug level `{}`", name)); }
+    },
+    None => None
+  };
+  let rpc_port = match matches.opt_str("rpcport") {
+    Some(port) => match from_str::<u16>(port.as_slice()) {
+      Some(port) => Some(port),
+      None => { return Err(format!("Invalid RPC port `{}`", port)); }
+    },
+    None => None
+  };
+  let mut connect = vec![];
+  for peer in matches.opt_strs("connect").iter() {
+    match PeerAddress::parse(peer.as_slice(), DEFAULT_PEER_PORT) {
+      Some(addr) => connect.push(addr),
+      None => { return Err(format!("Invalid peer port in `{}`", peer)); }
+    }
+  }
+
+  let daemon = matches.opt_present("daemon") || command == Start;
+  Ok(Options {
+    command: command,
+    help: matches.opt_present("help"),
+    version: matches.opt_present("version"),
+    daemon: daemon,
+    dump_config: matches.opt_present("dump-config"),
+    check_config: matches.opt_present("check-config"),
+    conf: matches.opt_str("conf").map(|s| Path::new(s)),
+    datadir: matches.opt_str("datadir").map(|s| Path::new(s)),
+    networks: networks,
+    debug_level: debug_level,
+    rpc_port: rpc_port,
+    connect: connect
+  })
+}
+
+/// Returns the `--help` text
+pub fn help(program: &str) -> String {
+  let brief = format!("Usage: {} [options] [command]\n\n\
+                       Commands:\n    \
+                       start     Run the wallet in the background, as with --daemon\n    \
+                       stop      Ask a running wallet to stop\n    \
+                       status    Summarize the state of each network of a running wallet\n    \
+                       dashboard Show sync progress, the peer, the mempool, balances and\n              \
+                       coinjoin sessions of a running wallet, redrawn until interrupted\n    \
+                       rpc <method> [params...]\n              \
+                       Call an RPC method on a running wallet, on the first\n              \
+                       network unless --network is given. Parameters are JSON,\n              \
+                       or else strings.\n\n\
+                       With no command, the wallet runs in the foreground.", program);
+  usage(brief.as_slice(), option_table().as_slice())
+}
+
+impl Options {
+  /// Returns the path of the configuration file to read
+  pub fn config_path(&self) -> Path {
+    match (&self.conf, &self.datadir) {
+      (&Some(ref path), _) => path.clone(),
+      (&None, &Some(ref dir)) => dir.join(config_path().filename().unwrap()),
+      (&None, &None) => config_path()
+    }
+  }
+
+  /// Returns the path of the pid file written when running as a daemon
+  pub fn pid_path(&self) -> Path {
+    match self.datadir {
+      Some(ref dir) => dir.join(pid_path().filename().unwrap()),
+      None => pid_path()
+    }
+  }
+
+  /// Returns the path of the log file written when running as a daemon
+  pub fn log_path(&self) -> Path {
+    match self.datadir {
+      Some(ref dir) => dir.join(log_path().filename().unwrap()),
+      None => log_path()
+    }
+  }
+
+  /// Whether a network from the configuration file should be run: any
+  /// network chosen on the command line, or else any enabled one
+  pub fn wants(&self, config: &NetworkConfig) -> bool {
+    if self.networks.is_empty() {
+      config.enabled
+    } else {
+      self.networks.contains(&config.network)
+    }
+  }
+
+  /// Overrides a network's configuration with the options given. A daemon
+  /// does not also log to stdout, which goes to the daemon's own log file,
+  /// unless it has no log file of its own.
+  pub fn apply(&self, config: &mut NetworkConfig) {
+    if self.daemon && config.log.path.is_some() {
+      config.log.stdout = false;
+    }
+    match self.datadir {
+      Some(ref dir) => config.set_datadir(dir),
+      None => {}
+    }
+    match self.debug_level {
+      Some(level) => { config.debug_level = level; }
+      None => {}
+    }
+    match self.rpc_port {
+      Some(port) => { config.rpc_server_port = port; }
+      None => {}
+    }
+    if !self.connect.is_empty() {
+      config.peers = self.connect.clone();
+    }
+  }
+}
+
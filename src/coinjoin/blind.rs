@@ -0,0 +1,384 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Blind Signatures
+//!
+//! RSA blind signatures, used to let coinjoin participants register their
+//! target outputs without the server being able to link them to inputs.
+//!
+//! A participant blinds the scriptpubkey of their target output and sends
+//! it along with their inputs. The server signs it blindly, and later, over
+//! an unlinkable connection, the participant presents the unblinded output
+//! and signature. The server can check that it signed the output, but not
+//! which inputs it signed it for.
+//!
+//! Keys are generated, and blinded messages signed, by OpenSSL. Only the
+//! joiner's side, which involves no secrets of the server's, is computed
+//! here.
+//!
+
+use std::collections::TreeMap;
+use std::num::{FromStrRadix, ToStrRadix};
+use std::ptr;
+use std::rand::Rng;
+use serialize::hex::{FromHex, ToHex};
+use serialize::json;
+use libc::{c_int, c_long, c_uchar, c_ulong, c_void};
+
+use num::bigint::{BigInt, BigUint, RandBigInt, ToBigInt};
+use num::{Integer, One, Signed, Zero};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use util::constant_time_eq;
+
+/// Size, in bits, of the modulus
+static MODULUS_BITS: c_int = 2048;
+/// Public exponent
+static PUBLIC_EXPONENT: c_ulong = 65537;
+/// `RSA_private_decrypt` padding mode for a bare private key operation
+static RSA_NO_PADDING: c_int = 3;
+
+#[link(name = "crypto")]
+extern {
+  fn BN_new() -> *mut c_void;
+  fn BN_free(bn: *mut c_void);
+  fn BN_set_word(bn: *mut c_void, w: c_ulong) -> c_int;
+  fn RSA_new() -> *mut c_void;
+  fn RSA_free(rsa: *mut c_void);
+  fn RSA_generate_key_ex(rsa: *mut c_void, bits: c_int, e: *mut c_void,
+                         cb: *mut c_void) -> c_int;
+  fn RSA_size(rsa: *const c_void) -> c_int;
+  fn RSA_private_decrypt(flen: c_int, from: *const c_uchar, to: *mut c_uchar,
+                         rsa: *mut c_void, padding: c_int) -> c_int;
+  fn i2d_RSAPrivateKey(rsa: *mut c_void, out: *mut *mut c_uchar) -> c_int;
+  fn d2i_RSAPrivateKey(out: *mut *mut c_void, data: *mut *const c_uchar,
+                       len: c_long) -> *mut c_void;
+}
+
+/// An RSA public key
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct PublicKey {
+  /// Modulus
+  pub n: BigUint,
+  /// Public exponent
+  pub e: BigUint
+}
+
+/// An RSA secret key
+pub struct SecretKey {
+  public: PublicKey,
+  /// DER-encoded RSAPrivateKey, handed to OpenSSL for each signature
+  der: Vec<u8>
+}
+
+/// Parses a hex-encoded integer, as produced by `to_hex`
+pub fn from_hex(s: &str) -> Option<BigUint> {
+  FromStrRadix::from_str_radix(s, 16)
+}
+
+/// Hex-encodes an integer
+pub fn to_hex(n: &BigUint) -> String {
+  n.to_str_radix(16)
+}
+
+/// Encodes an integer as `len` big-endian bytes. It must fit.
+fn to_bytes(n: &BigUint, len: uint) -> Vec<u8> {
+  let hex = to_hex(n);
+  let hex = if hex.len() % 2 == 1 { format!("0{}", hex) } else { hex };
+  let bytes = hex.as_slice().from_hex().unwrap();
+  let mut ret = Vec::from_elem(len - bytes.len(), 0u8);
+  ret.push_all(bytes.as_slice());
+  ret
+}
+
+/// Computes `base^exp mod m`. Not constant-time, so only for public
+/// exponents.
+fn mod_pow(base: &BigUint, exp: &BigUint, m: &BigUint) -> BigUint {
+  let mut result: BigUint = One::one();
+  let mut base = *base % *m;
+  let mut exp = exp.clone();
+  while !exp.is_zero() {
+    if exp.is_odd() {
+      result = (result * base) % *m;
+    }
+    base = (base * base) % *m;
+    exp = exp >> 1;
+  }
+  result
+}
+
+/// Computes the inverse of `a` modulo `m`, if it exists
+fn mod_inverse(a: &BigUint, m: &BigUint) -> Option<BigUint> {
+  let m_int = m.to_bigint().unwrap();
+  let (mut t, mut new_t): (BigInt, BigInt) = (Zero::zero(), One::one());
+  let (mut r, mut new_r) = (m_int.clone(), a.to_bigint().unwrap());
+  while !new_r.is_zero() {
+    let q = r / new_r;
+    let tmp_t = t - q * new_t;
+    t = new_t;
+    new_t = tmp_t;
+    let tmp_r = r - q * new_r;
+    r = new_r;
+    new_r = tmp_r;
+  }
+  if r != One::one() {
+    return None;
+  }
+  if t.is_negative() {
+    t = t + m_int;
+  }
+  t.to_biguint()
+}
+
+/// Reads a DER element with the given tag from the start of `data`,
+/// returning its contents and whatever follows it
+fn der_read<'a>(data: &'a [u8], tag: u8) -> Option<(&'a [u8], &'a [u8])> {
+  if data.len() < 2 || data[0] != tag {
+    return None;
+  }
+  let (len, header) = match data[1] {
+    n if n < 0x80 => (n as uint, 2),
+    0x81 if data.len() >= 3 => (data[2] as uint, 3),
+    0x82 if data.len() >= 4 => (data[2] as uint << 8 | data[3] as uint, 4),
+    _ => { return None; }
+  };
+  if data.len() < header + len {
+    return None;
+  }
+  Some((data.slice(header, header + len), data.slice_from(header + len)))
+}
+
+/// Reads the public half of a DER-encoded RSAPrivateKey, which starts
+/// with a version number, the modulus and the public exponent
+fn public_key_of(der: &[u8]) -> Option<PublicKey> {
+  let key = match der_read(der, 0x30) {
+    Some((key, _)) => key,
+    None => { return None; }
+  };
+  let rest = match der_read(key, 0x02) {
+    Some((_, rest)) => rest,
+    None => { return None; }
+  };
+  let (n, rest) = match der_read(rest, 0x02) {
+    Some(n) => n,
+    None => { return None; }
+  };
+  let e = match der_read(rest, 0x02) {
+    Some((e, _)) => e,
+    None => { return None; }
+  };
+  match (from_hex(n.to_hex().as_slice()), from_hex(e.to_hex().as_slice())) {
+    (Some(n), Some(e)) => Some(PublicKey { n: n, e: e }),
+    _ => None
+  }
+}
+
+/// Full-domain hash of a message to an integer less than `n`
+fn hash_to_int(msg: &[u8], n: &BigUint) -> BigUint {
+  // Use one byte fewer than the modulus so the result is always smaller
+  let n_bytes = (n.bits() + 7) / 8 - 1;
+  let mut out = Vec::with_capacity(n_bytes + 32);
+  let mut counter = 0u32;
+  while out.len() < n_bytes {
+    let mut sha = Sha256::new();
+    let mut block = [0u8, ..32];
+    sha.input([(counter >> 24) as u8, (counter >> 16) as u8, (counter >> 8) as u8, counter as u8]);
+    sha.input(msg);
+    sha.result(block.as_mut_slice());
+    out.push_all(block.as_slice());
+    counter += 1;
+  }
+  out.truncate(n_bytes);
+  from_hex(out.as_slice().to_hex().as_slice()).unwrap()
+}
+
+impl SecretKey {
+  /// Generates a new random keypair, or returns None if OpenSSL fails to
+  pub fn generate() -> Option<SecretKey> {
+    let mut der = vec![];
+    unsafe {
+      let rsa = RSA_new();
+      let e = BN_new();
+      if !rsa.is_null() && !e.is_null() && BN_set_word(e, PUBLIC_EXPONENT) == 1 &&
+         RSA_generate_key_ex(rsa, MODULUS_BITS, e, ptr::null_mut()) == 1 {
+        let len = i2d_RSAPrivateKey(rsa, ptr::null_mut());
+        if len > 0 {
+          der = Vec::from_elem(len as uint, 0u8);
+          let mut out = der.as_mut_ptr();
+          if i2d_RSAPrivateKey(rsa, &mut out) != len {
+            der.clear();
+          }
+        }
+      }
+      if !e.is_null() { BN_free(e); }
+      if !rsa.is_null() { RSA_free(rsa); }
+    }
+    let public = public_key_of(der.as_slice());
+    public.map(|public| SecretKey { public: public, der: der })
+  }
+
+  /// Accessor for the public half of the key
+  pub fn public_key<'a>(&'a self) -> &'a PublicKey { &self.public }
+
+  /// Signs a blinded message. The signer learns nothing about the message.
+  /// The private key operation is OpenSSL's, which is constant-time and
+  /// blinds the message again itself, so that the joiner cannot time the
+  /// signing of a value of their choosing. Returns None if the message is
+  /// not less than the modulus.
+  pub fn sign_blinded(&self, blinded: &BigUint) -> Option<BigUint> {
+    if *blinded >= self.public.n {
+      return None;
+    }
+    unsafe {
+      let mut data = self.der.as_ptr();
+      let rsa = d2i_RSAPrivateKey(ptr::null_mut(), &mut data, self.der.len() as c_long);
+      if rsa.is_null() {
+        return None;
+      }
+      let size = RSA_size(rsa as *const c_void) as uint;
+      let input = to_bytes(blinded, size);
+      let mut output = Vec::from_elem(size, 0u8);
+      let len = RSA_private_decrypt(size as c_int, input.as_ptr(), output.as_mut_ptr(),
+                                    rsa, RSA_NO_PADDING);
+      RSA_free(rsa);
+      if len != size as c_int {
+        return None;
+      }
+      from_hex(output.as_slice().to_hex().as_slice())
+    }
+  }
+}
+
+/// Starts a task which generates keypairs in the background, since each
+/// takes a while. One key is kept ready at a time; the task stops when the
+/// receiver is dropped, or at once if OpenSSL cannot generate keys.
+pub fn key_generator() -> Receiver<SecretKey> {
+  let (tx, rx) = sync_channel(1);
+  spawn(proc() {
+    loop {
+      match SecretKey::generate() {
+        Some(key) => {
+          if tx.send_opt(key).is_err() {
+            break;
+          }
+        }
+        None => { break; }
+      }
+    }
+  });
+  rx
+}
+
+impl PublicKey {
+  /// Blinds a message for signing (client side). Returns the blinded
+  /// message and the blinding factor needed to unblind the signature.
+  pub fn blind<R: Rng>(&self, msg: &[u8], rng: &mut R) -> (BigUint, BigUint) {
+    let one: BigUint = One::one();
+    let m = hash_to_int(msg, &self.n);
+    loop {
+      let r = rng.gen_biguint_range(&one, &self.n);
+      if r.gcd(&self.n) == one {
+        let blinded = (m * mod_pow(&r, &self.e, &self.n)) % self.n;
+        return (blinded, r);
+      }
+    }
+  }
+
+  /// Unblinds a signature on a blinded message (client side)
+  pub fn unblind(&self, blind_sig: &BigUint, r: &BigUint) -> Option<BigUint> {
+    mod_inverse(r, &self.n).map(|r_inv| (*blind_sig * r_inv) % self.n)
+  }
+
+  /// Checks an unblinded signature on a message. Signatures are bearer
+  /// tokens, so the comparison takes the same time wherever they differ.
+  pub fn verify(&self, msg: &[u8], sig: &BigUint) -> bool {
+    if *sig >= self.n {
+      return false;
+    }
+    // Pad to the modulus width, so lengths give nothing away either
+    let width = to_hex(&self.n).len();
+    let pad = |n: BigUint| {
+      let hex = to_hex(&n);
+      let mut ret = String::from_char(width - hex.len(), '0');
+      ret.push_str(hex.as_slice());
+      ret
+    };
+    let lhs = pad(mod_pow(sig, &self.e, &self.n));
+    let rhs = pad(hash_to_int(msg, &self.n));
+    constant_time_eq(lhs.as_bytes(), rhs.as_bytes())
+  }
+}
+
+impl json::ToJson for PublicKey {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("n".to_string(), json::String(to_hex(&self.n)));
+    obj.insert("e".to_string(), json::String(to_hex(&self.e)));
+    json::Object(obj)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::num::FromPrimitive;
+  use std::rand::task_rng;
+
+  use num::bigint::BigUint;
+
+  use super::{SecretKey, from_hex, to_hex, mod_inverse, mod_pow, to_bytes};
+
+  fn big(n: u64) -> BigUint {
+    FromPrimitive::from_u64(n).unwrap()
+  }
+
+  #[test]
+  fn test_hex() {
+    let n = from_hex("ffffffea00000055").unwrap();
+    assert_eq!(n, big(0xffffffea00000055));
+    assert_eq!(to_hex(&n), "ffffffea00000055".to_string());
+    assert_eq!(to_bytes(&big(0x1234), 4), vec![0, 0, 0x12, 0x34]);
+    assert!(from_hex("not hex").is_none());
+  }
+
+  #[test]
+  fn test_mod_arithmetic() {
+    assert_eq!(mod_pow(&big(4), &big(13), &big(497)), big(445));
+    assert_eq!(mod_inverse(&big(3), &big(11)), Some(big(4)));
+    assert_eq!(mod_inverse(&big(2), &big(4)), None);
+  }
+
+  #[test]
+  fn test_blind_sign() {
+    let mut rng = task_rng();
+    let key = SecretKey::generate().unwrap();
+    let public = key.public_key();
+    assert_eq!(public.n.bits(), 2048);
+    assert_eq!(public.e, big(65537));
+    let msg = b"a target scriptpubkey";
+
+    let (blinded, r) = public.blind(msg, &mut rng);
+    let blind_sig = key.sign_blinded(&blinded).unwrap();
+    let sig = public.unblind(&blind_sig, &r).unwrap();
+    assert!(public.verify(msg, &sig));
+
+    // Not for another message, nor if the signature is tampered with
+    assert!(!public.verify(b"another output", &sig));
+    assert!(!public.verify(msg, &(sig + big(1))));
+    assert!(!public.verify(msg, &(sig + public.n)));
+    // Nothing out of range is signed
+    assert!(key.sign_blinded(&public.n).is_none());
+  }
+}
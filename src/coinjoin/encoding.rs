@@ -0,0 +1,188 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Signature Encoding
+//!
+//! Joiners' wallets leave fingerprints in how they encode signatures, e.g.
+//! whether they produce low-S signatures. Since any variation in encoding
+//! would show which inputs of a coinjoin came from the same software, we
+//! put every signature into the same canonical form before merging.
+//!
+
+use std::num::{FromStrRadix, ToStrRadix};
+use serialize::hex::{FromHex, ToHex};
+
+use num::bigint::BigUint;
+
+use bitcoin::blockdata::script::Script;
+
+/// Order of the secp256k1 group
+static CURVE_ORDER: &'static str = "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+
+/// Splits a script into its pushes, or returns None if it contains any
+/// opcodes other than pushes (in which case we leave it alone)
+fn parse_pushes(script: &[u8]) -> Option<Vec<Vec<u8>>> {
+  let mut ret = vec![];
+  let mut i = 0;
+  while i < script.len() {
+    let op = script[i] as uint;
+    i += 1;
+    let (len, skip) = match op {
+      0x00...0x4b => (op, 0),
+      0x4c if i < script.len() => (script[i] as uint, 1),
+      0x4d if i + 1 < script.len() => (script[i] as uint | script[i + 1] as uint << 8, 2),
+      0x4e if i + 3 < script.len() => (script[i] as uint | script[i + 1] as uint << 8 |
+                                       script[i + 2] as uint << 16 | script[i + 3] as uint << 24, 4),
+      _ => { return None; }
+    };
+    i += skip;
+    if i + len > script.len() {
+      return None;
+    }
+    ret.push(script.slice(i, i + len).to_vec());
+    i += len;
+  }
+  Some(ret)
+}
+
+/// Encodes an unsigned big-endian integer as a DER integer body
+fn der_integer(n: &BigUint) -> Vec<u8> {
+  let mut hex = n.to_str_radix(16);
+  if hex.len() % 2 == 1 {
+    hex.unshift_char('0');
+  }
+  let mut bytes = hex.as_slice().from_hex().unwrap();
+  // Add a zero byte if the high bit is set, so it isn't read as negative
+  if bytes[0] & 0x80 != 0 {
+    bytes.unshift(0);
+  }
+  bytes
+}
+
+/// Re-encodes a DER signature (with trailing sighash byte) in strict DER
+/// with a low S value. Returns None if the data is not a signature.
+pub fn normalize_signature(sig: &[u8]) -> Option<Vec<u8>> {
+  // 0x30 <len> 0x02 <rlen> <r> 0x02 <slen> <s> <sighash>
+  if sig.len() < 9 || sig[0] != 0x30 || sig[2] != 0x02 {
+    return None;
+  }
+  let rlen = sig[3] as uint;
+  if 5 + rlen >= sig.len() || sig[4 + rlen] != 0x02 {
+    return None;
+  }
+  let slen = sig[5 + rlen] as uint;
+  if 6 + rlen + slen + 1 != sig.len() {
+    return None;
+  }
+  let r_bytes = sig.slice(4, 4 + rlen);
+  let s_bytes = sig.slice(6 + rlen, 6 + rlen + slen);
+  let sighash = sig[sig.len() - 1];
+
+  let r: BigUint = match FromStrRadix::from_str_radix(r_bytes.to_hex().as_slice(), 16) {
+    Some(r) => r,
+    None => { return None; }
+  };
+  let mut s: BigUint = match FromStrRadix::from_str_radix(s_bytes.to_hex().as_slice(), 16) {
+    Some(s) => s,
+    None => { return None; }
+  };
+  let order: BigUint = FromStrRadix::from_str_radix(CURVE_ORDER, 16).unwrap();
+  if s > order >> 1 {
+    s = order - s;
+  }
+
+  let r_der = der_integer(&r);
+  let s_der = der_integer(&s);
+  let mut ret = vec![0x30, (4 + r_der.len() + s_der.len()) as u8, 0x02, r_der.len() as u8];
+  ret.push_all(r_der.as_slice());
+  ret.push(0x02);
+  ret.push(s_der.len() as u8);
+  ret.push_all(s_der.as_slice());
+  ret.push(sighash);
+  Some(ret)
+}
+
+/// Normalizes every signature in a push-only scriptSig, re-encoding all
+/// pushes minimally. Scripts which are not push-only are returned as-is.
+pub fn normalize_script_sig(script_sig: &Script) -> Script {
+  let pushes = match parse_pushes(script_sig.as_slice()) {
+    Some(pushes) => pushes,
+    None => { return script_sig.clone(); }
+  };
+  let mut ret = Script::new();
+  for push in pushes.iter() {
+    match normalize_signature(push.as_slice()) {
+      Some(sig) => ret.push_slice(sig.as_slice()),
+      None => ret.push_slice(push.as_slice())
+    }
+  }
+  ret
+}
+
+#[cfg(test)]
+mod tests {
+  use serialize::hex::FromHex;
+
+  use bitcoin::blockdata::script::Script;
+
+  use super::{normalize_script_sig, normalize_signature};
+
+  // A strict DER signature with a low S value and SIGHASH_ALL
+  static LOW_S: &'static str = "3045022100c54349e422f05297191ead13e21d3db520e5abef52055e4964b82fb213f593a10220043a718774c572bd8a25adbeb1bfcd5c0256ae11cecf9f9c3f925d0e52beaf8901";
+  // The same with S replaced by the curve order minus S
+  static HIGH_S: &'static str = "3046022100c54349e422f05297191ead13e21d3db520e5abef52055e4964b82fb213f593a1022100fbc58e788b3a8d4275da52414e4032a2b8582ed4e079009f8040017e7d7791b801";
+  // The same with R padded by a needless zero byte
+  static PADDED_R: &'static str = "304602220000c54349e422f05297191ead13e21d3db520e5abef52055e4964b82fb213f593a10220043a718774c572bd8a25adbeb1bfcd5c0256ae11cecf9f9c3f925d0e52beaf8901";
+  static PUBKEY: &'static str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+  #[test]
+  fn test_normalize_signature() {
+    let low = LOW_S.from_hex().unwrap();
+    assert_eq!(normalize_signature(low.as_slice()), Some(low.clone()));
+    assert_eq!(normalize_signature(HIGH_S.from_hex().unwrap().as_slice()), Some(low.clone()));
+    assert_eq!(normalize_signature(PADDED_R.from_hex().unwrap().as_slice()), Some(low.clone()));
+    assert_eq!(normalize_signature(PUBKEY.from_hex().unwrap().as_slice()), None);
+    // Truncated
+    assert_eq!(normalize_signature(low.slice_to(40)), None);
+  }
+
+  #[test]
+  fn test_normalize_script_sig() {
+    let low = LOW_S.from_hex().unwrap();
+    let high = HIGH_S.from_hex().unwrap();
+    let pubkey = PUBKEY.from_hex().unwrap();
+    let mut expected = Script::new();
+    expected.push_slice(low.as_slice());
+    expected.push_slice(pubkey.as_slice());
+
+    let mut script_sig = Script::new();
+    script_sig.push_slice(high.as_slice());
+    script_sig.push_slice(pubkey.as_slice());
+    assert_eq!(normalize_script_sig(&script_sig), expected);
+
+    // Pushes are re-encoded minimally, here dropping an OP_PUSHDATA1
+    let mut raw = vec![0x4c, high.len() as u8];
+    raw.push_all(high.as_slice());
+    raw.push(pubkey.len() as u8);
+    raw.push_all(pubkey.as_slice());
+    assert_eq!(normalize_script_sig(&Script::from_vec(raw)), expected);
+
+    // Scripts with other opcodes are left alone
+    let mut raw = vec![high.len() as u8];
+    raw.push_all(high.as_slice());
+    raw.push(0x76);  // OP_DUP
+    let script_sig = Script::from_vec(raw);
+    assert_eq!(normalize_script_sig(&script_sig), script_sig);
+  }
+}
@@ -17,33 +17,67 @@
 //! Functions and data to join transactions and to manage a centralized
 //! coinjoin server.
 
+use std::collections::TreeMap;
+use std::io::net::ip::IpAddr;
+use serialize::hex::ToHex;
+use serialize::json;
+use serialize::json::ToJson;
+
 use bitcoin::util::hash::Sha256dHash;
 use bitcoin::blockdata::script::Script;
 
 use self::server::SessionState;
 
+pub mod blind;
+pub mod encoding;
+pub mod pow;
 pub mod server;
 
 /// A Coinjoin-related error
 #[deriving(Clone, PartialEq, Eq, Show)]
 pub enum CoinjoinError {
+  /// Tx had an input which is banned for failing to sign an earlier session
+  BannedInput(Sha256dHash, uint),
+  /// Tx was submitted from an address banned for failing to sign an
+  /// earlier session
+  BannedSource(IpAddr),
+  /// Output was registered with a blind signature which did not verify
+  BadBlindSignature,
+  /// Blinded output was not a valid value for the session's key
+  BadBlindedOutput,
+  /// Ownership proof did not carry a valid signature for this input
+  BadOwnershipProof(Sha256dHash, uint),
+  /// No blinding key is ready yet for a blinded session
+  BlindKeyPending,
+  /// A session is already accepting joiners for this target value
+  DenominationInUse(u64),
+  /// Output was already registered in this session
+  DuplicateOutput(Script),
   /// Tx had an input which already appears in the join
   DuplicateInput(Sha256dHash, uint),
-  /// Session is in the wrong state for this action (actual, expected)
+  /// Session is in the wrong state for this action (expected, actual)
   IncorrectState(SessionState, SessionState),
   /// Tx total input value exceeds the total output value -- these should match,
   /// and fees be added using the donation address
   InputsExceedOutputs(u64, u64),
   /// Not enough fee was sent to the donation address (received, expected)
   InsufficientFee(u64, u64),
+  /// Submission did not carry the required number of bits of proof of work
+  InsufficientWork(uint),
   /// Signed TX did not actually introduce new signed inputs
   NoNewSignedInputs,
   /// Tx had a nonzero locktime
   NonZeroLocktime(uint),
+  /// Ownership proof did not spend the tx's inputs and the session challenge
+  MalformedOwnershipProof,
+  /// Session target value is not one of the configured denominations
+  NonStandardDenomination(u64),
   /// Tx had no output of the target size (target in sat)
   NoTargetOutput(u64),
   /// Tx total output value exceed the total input value
   OutputsExceedInputs(u64, u64),
+  /// Session has reached its maximum number of participants
+  SessionFull(uint),
   /// Signed tx had an input that was not the expected one
   UnexpectedInput(Sha256dHash, uint),
   /// Signed tx had an output that was not the expected one
@@ -52,6 +86,9 @@ pub enum CoinjoinError {
   UnknownInput(Sha256dHash, uint),
   /// Tx had a version which the joiner did not understand
   UnknownVersion(uint),
+  /// A blinded output token was given to a session which does not use them
+  /// (false), or was missing for one which requires them (true)
+  WrongBlinding(bool),
   /// Signed tx had too many inputs
   WrongInputCount(uint),
   /// Signed tx had too many outputs
@@ -59,3 +96,117 @@ pub enum CoinjoinError {
 }
 
 
+
+// Helper for `CoinjoinError::to_json` describing an outpoint
+fn outpoint_json(obj: &mut TreeMap<String, json::Json>, txid: &Sha256dHash, vout: uint) {
+  obj.insert("txid".to_string(), txid.to_json());
+  obj.insert("vout".to_string(), vout.to_json());
+}
+
+impl ToJson for CoinjoinError {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    let kind = match *self {
+      BadBlindSignature => "bad_blind_signature",
+      BadBlindedOutput => "bad_blinded_output",
+      BadOwnershipProof(ref txid, vout) => {
+        outpoint_json(&mut obj, txid, vout);
+        "bad_ownership_proof"
+      }
+      BannedInput(ref txid, vout) => {
+        outpoint_json(&mut obj, txid, vout);
+        "banned_input"
+      }
+      BannedSource(ref ip) => {
+        obj.insert("address".to_string(), json::String(ip.to_string()));
+        "banned_source"
+      }
+      BlindKeyPending => "blind_key_pending",
+      DenominationInUse(target) => {
+        obj.insert("target_value".to_string(), target.to_json());
+        "denomination_in_use"
+      }
+      DuplicateOutput(ref script) => {
+        obj.insert("script_pubkey".to_string(), json::String(script.as_slice().to_hex()));
+        "duplicate_output"
+      }
+      DuplicateInput(ref txid, vout) => {
+        outpoint_json(&mut obj, txid, vout);
+        "duplicate_input"
+      }
+      IncorrectState(expected, actual) => {
+        obj.insert("expected_state".to_string(), expected.to_json());
+        obj.insert("actual_state".to_string(), actual.to_json());
+        "incorrect_state"
+      }
+      InputsExceedOutputs(inputs, outputs) => {
+        obj.insert("input_value".to_string(), inputs.to_json());
+        obj.insert("output_value".to_string(), outputs.to_json());
+        "inputs_exceed_outputs"
+      }
+      InsufficientFee(received, required) => {
+        obj.insert("received_fee".to_string(), received.to_json());
+        obj.insert("required_fee".to_string(), required.to_json());
+        "insufficient_fee"
+      }
+      InsufficientWork(bits) => {
+        obj.insert("required_bits".to_string(), bits.to_json());
+        "insufficient_work"
+      }
+      MalformedOwnershipProof => "malformed_ownership_proof",
+      NoNewSignedInputs => "no_new_signed_inputs",
+      NonZeroLocktime(locktime) => {
+        obj.insert("locktime".to_string(), locktime.to_json());
+        "nonzero_locktime"
+      }
+      NonStandardDenomination(target) => {
+        obj.insert("target_value".to_string(), target.to_json());
+        "nonstandard_denomination"
+      }
+      NoTargetOutput(target) => {
+        obj.insert("target_value".to_string(), target.to_json());
+        "no_target_output"
+      }
+      OutputsExceedInputs(outputs, inputs) => {
+        obj.insert("output_value".to_string(), outputs.to_json());
+        obj.insert("input_value".to_string(), inputs.to_json());
+        "outputs_exceed_inputs"
+      }
+      SessionFull(max) => {
+        obj.insert("max_participants".to_string(), max.to_json());
+        "session_full"
+      }
+      UnexpectedInput(ref txid, vout) => {
+        outpoint_json(&mut obj, txid, vout);
+        "unexpected_input"
+      }
+      UnexpectedOutput(ref script, value) => {
+        obj.insert("script_pubkey".to_string(), json::String(script.as_slice().to_hex()));
+        obj.insert("value".to_string(), value.to_json());
+        "unexpected_output"
+      }
+      UnknownInput(ref txid, vout) => {
+        outpoint_json(&mut obj, txid, vout);
+        "unknown_input"
+      }
+      UnknownVersion(version) => {
+        obj.insert("version".to_string(), version.to_json());
+        "unknown_version"
+      }
+      WrongBlinding(required) => {
+        obj.insert("blinding_required".to_string(), required.to_json());
+        "wrong_blinding"
+      }
+      WrongInputCount(count) => {
+        obj.insert("input_count".to_string(), count.to_json());
+        "wrong_input_count"
+      }
+      WrongOutputCount(count) => {
+        obj.insert("output_count".to_string(), count.to_json());
+        "wrong_output_count"
+      }
+    };
+    obj.insert("kind".to_string(), json::String(kind.to_string()));
+    json::Object(obj)
+  }
+}
@@ -0,0 +1,76 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Proof of Work
+//!
+//! Hashcash-style proofs of work which sessions may require of joiners, to
+//! make flooding a session with bogus contributions expensive. The work
+//! commits to both the session and the contributed transaction, so it
+//! cannot be reused.
+//!
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+
+/// Computes SHA256d(session id || txid || nonce), with integers little-endian
+fn work_hash(session_id: u64, tx: &Transaction, nonce: u64) -> [u8, ..32] {
+  let mut data = Vec::with_capacity(48);
+  for i in range(0u, 8) {
+    data.push((session_id >> (8 * i)) as u8);
+  }
+  data.push_all(serialize(&tx.bitcoin_hash()).unwrap().as_slice());
+  for i in range(0u, 8) {
+    data.push((nonce >> (8 * i)) as u8);
+  }
+
+  let mut ret = [0u8, ..32];
+  let mut sha = Sha256::new();
+  sha.input(data.as_slice());
+  sha.result(ret.as_mut_slice());
+  sha.reset();
+  sha.input(ret.as_slice());
+  sha.result(ret.as_mut_slice());
+  ret
+}
+
+/// Counts the leading zero bits of a hash
+fn leading_zero_bits(hash: &[u8]) -> uint {
+  let mut ret = 0;
+  for &byte in hash.iter() {
+    if byte == 0 {
+      ret += 8;
+    } else {
+      ret += byte.leading_zeros() as uint;
+      break;
+    }
+  }
+  ret
+}
+
+/// Checks that a nonce provides at least `bits` bits of work
+pub fn check(session_id: u64, tx: &Transaction, nonce: u64, bits: uint) -> bool {
+  leading_zero_bits(work_hash(session_id, tx, nonce).as_slice()) >= bits
+}
+
+/// Finds a nonce providing at least `bits` bits of work (client side)
+pub fn solve(session_id: u64, tx: &Transaction, bits: uint) -> u64 {
+  let mut nonce = 0;
+  while !check(session_id, tx, nonce, bits) {
+    nonce += 1;
+  }
+  nonce
+}
@@ -16,34 +16,49 @@
 //!
 //! Functions and data to manage a centralized coinjoin server.
 
+use std::cmp;
 use std::collections::{HashMap, TreeMap};
 use std::default::Default;
 use std::num::from_str_radix;
 use std::io::IoResult;
+use std::io::net::ip::IpAddr;
 use std::rand::{Rng, SeedableRng};
 use std::time::Duration;
 use serialize::json;
+use serialize::json::ToJson;
 use serialize::{Decodable, Decoder, Encodable, Encoder};
 use time::precise_time_ns;
 
-use bitcoin::blockdata::transaction::{Transaction, TxIn, PayToPubkeyHash};
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, PayToPubkeyHash};
 use bitcoin::blockdata::utxoset::UtxoSet;
 use bitcoin::network::serialize::{BitcoinHash, serialize_hex};
 use bitcoin::util::base58::ToBase58;
+use bitcoin::util::hash::Sha256dHash;
 use bitcoin::wallet::address::Address;
 
 use crypto::fortuna::Fortuna;
+use num::bigint::BigUint;
 
-use coinjoin::{CoinjoinError, DuplicateInput, IncorrectState, InsufficientFee,
-               NoNewSignedInputs, NonZeroLocktime, NoTargetOutput,
-               InputsExceedOutputs, OutputsExceedInputs, UnexpectedInput, UnexpectedOutput,
-               UnknownInput, UnknownVersion, WrongInputCount, WrongOutputCount};
+use constants::{COINJOIN_BAN_DURATION, EST_INPUT_SIZE, EST_OUTPUT_SIZE};
+use coinjoin::blind;
+use coinjoin::blind::SecretKey;
+use coinjoin::encoding::normalize_script_sig;
+use coinjoin::pow;
+use coinjoin::{BadBlindSignature, BadBlindedOutput, BadOwnershipProof, BannedInput,
+               BannedSource, CoinjoinError, DenominationInUse, DuplicateInput, DuplicateOutput, IncorrectState,
+               InsufficientFee, InsufficientWork, MalformedOwnershipProof, NoNewSignedInputs,
+               NonZeroLocktime, NoTargetOutput, InputsExceedOutputs, OutputsExceedInputs,
+               SessionFull, UnexpectedInput, UnexpectedOutput, UnknownInput, UnknownVersion,
+               WrongBlinding, WrongInputCount, WrongOutputCount};
 
 /// Current state of the session
 #[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
 pub enum SessionState {
   /// Collecting unsigned transactions
   Joining,
+  /// Collecting outputs registered under blind signatures
+  Registering,
   /// Collecting signed transactions
   Merging,
   /// Completed successfully
@@ -56,10 +71,37 @@ pub enum SessionState {
   Unmerged
 }
 
+impl SessionState {
+  /// Whether the session is finished, successfully or not
+  pub fn is_final(&self) -> bool {
+    match *self {
+      Joining | Registering | Merging => false,
+      Complete | Expired | Failed | Unmerged => true
+    }
+  }
+}
+
+impl<D: Decoder<E>, E> Decodable<D, E> for SessionState {
+  fn decode(d: &mut D) -> Result<SessionState, E> {
+    let st = try!(d.read_str());
+    match st.as_slice() {
+      "joining" => Ok(Joining),
+      "registering" => Ok(Registering),
+      "merging" => Ok(Merging),
+      "complete" => Ok(Complete),
+      "expired" => Ok(Expired),
+      "failed" => Ok(Failed),
+      "unmerged" => Ok(Unmerged),
+      _ => Err(d.error(format!("Unknown session state `{}`", st).as_slice()))
+    }
+  }
+}
+
 impl json::ToJson for SessionState {
   fn to_json(&self) -> json::Json {
     json::String(match *self {
       Joining => "joining",
+      Registering => "registering",
       Merging => "merging",
       Complete => "complete",
       Expired => "expired",
@@ -69,6 +111,93 @@ impl json::ToJson for SessionState {
   }
 }
 
+/// Options which may be set when starting a session
+#[deriving(Clone, PartialEq, Eq, Show, Default, Decodable)]
+pub struct SessionOptions {
+  /// Whether target outputs are registered under blind signatures
+  pub blinded: Option<bool>,
+  /// Whether joiners may contribute more input value than they take as
+  /// output, e.g. to pay the session's miner fees. May not be combined
+  /// with `allow_outputs_exceed_inputs`.
+  pub allow_inputs_exceed_outputs: Option<bool>,
+  /// Whether joiners may take more output value than they contribute, as
+  /// long as other joiners make up the difference, e.g. to receive payments
+  pub allow_outputs_exceed_inputs: Option<bool>,
+  /// Number of contributions needed before merging. If this is not reached
+  /// during the join window, the window is extended by up to the merge
+  /// duration. Values below 2 are treated as 2.
+  pub min_participants: Option<uint>,
+  /// Number of contributions after which the session stops accepting
+  /// more, and moves on to merging immediately
+  pub max_participants: Option<uint>,
+  /// Bits of proof of work, over the session ID and txid, required with
+  /// each contribution
+  pub pow_bits: Option<uint>
+}
+
+impl SessionOptions {
+  /// Returns these options, with any which are not set taken from `defaults`
+  pub fn or(&self, defaults: &SessionOptions) -> SessionOptions {
+    SessionOptions {
+      blinded: self.blinded.or(defaults.blinded),
+      allow_inputs_exceed_outputs: self.allow_inputs_exceed_outputs
+                                       .or(defaults.allow_inputs_exceed_outputs),
+      allow_outputs_exceed_inputs: self.allow_outputs_exceed_inputs
+                                       .or(defaults.allow_outputs_exceed_inputs),
+      min_participants: self.min_participants.or(defaults.min_participants),
+      max_participants: self.max_participants.or(defaults.max_participants),
+      pow_bits: self.pow_bits.or(defaults.pow_bits)
+    }
+  }
+
+  /// Whether target outputs are registered under blind signatures
+  pub fn blinded(&self) -> bool { self.blinded.unwrap_or(false) }
+
+  /// Whether joiners may contribute more input value than output value
+  pub fn allow_inputs_exceed_outputs(&self) -> bool {
+    self.allow_inputs_exceed_outputs.unwrap_or(false)
+  }
+
+  /// Whether joiners may take more output value than input value
+  pub fn allow_outputs_exceed_inputs(&self) -> bool {
+    self.allow_outputs_exceed_inputs.unwrap_or(false)
+  }
+
+  /// Number of contributions needed before merging
+  pub fn min_participants(&self) -> uint {
+    cmp::max(2, self.min_participants.unwrap_or(2))
+  }
+
+  /// Checks that the options can be used together. Surplus input value
+  /// may only be accepted when every joiner must balance, since otherwise
+  /// it would be claimed by whoever takes more than they put in.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.allow_inputs_exceed_outputs() && self.allow_outputs_exceed_inputs() {
+      return Err("allow_inputs_exceed_outputs and allow_outputs_exceed_inputs \
+                  may not both be set".to_string());
+    }
+    Ok(())
+  }
+}
+
+impl json::ToJson for SessionOptions {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("blinded".to_string(), self.blinded().to_json());
+    obj.insert("allow_inputs_exceed_outputs".to_string(),
+               self.allow_inputs_exceed_outputs().to_json());
+    obj.insert("allow_outputs_exceed_inputs".to_string(),
+               self.allow_outputs_exceed_inputs().to_json());
+    obj.insert("min_participants".to_string(), self.min_participants().to_json());
+    match self.max_participants {
+      Some(max) => { obj.insert("max_participants".to_string(), max.to_json()); }
+      None => {}
+    }
+    obj.insert("pow_bits".to_string(), self.pow_bits.unwrap_or(0).to_json());
+    json::Object(obj)
+  }
+}
+
 /// A Coinjoin session
 pub struct Session {
   id: SessionId,
@@ -81,7 +210,23 @@ pub struct Session {
   // Duration of every other phase before we expire or delete the session
   expiry_duration: Duration,
   target_value: u64,
+  // Fee rate, in satoshi per 1000 bytes, which joiners must contribute
+  fee_rate: u64,
+  options: SessionOptions,
+  // Random value which joiners must sign to prove they own their inputs
+  challenge: Sha256dHash,
   unsigned: Vec<Transaction>,
+  // Total input and output values of each unsigned transaction, including
+  // blindly-registered target outputs
+  values: Vec<(u64, u64)>,
+  // Address each unsigned transaction was submitted from, if known
+  sources: Vec<Option<IpAddr>>,
+  // Key used to blindly sign target outputs, for blinded sessions
+  blind_key: Option<SecretKey>,
+  // Number of blind signatures given out
+  blind_signatures_issued: uint,
+  // Target outputs registered under blind signatures
+  registered: Vec<TxOut>,
   merged: Option<Transaction>,
   signed: Option<Transaction>,
   donation_address: Address
@@ -105,9 +250,15 @@ impl json::ToJson for Session {
       Joining => {
         obj.insert("time_until_merge".to_string(),
                    (self.join_duration - time_since_switch).num_milliseconds().to_json());
+        obj.insert("ownership_challenge".to_string(), self.challenge.to_json());
         obj.insert("donation_address".to_string(),
                    json::String(self.donation_address.to_base58check()));
       }
+      Registering => {
+        obj.insert("time_until_merge".to_string(),
+                   (self.join_duration - time_since_switch).num_milliseconds().to_json());
+        obj.insert("registered_outputs".to_string(), self.registered.len().to_json());
+      }
       Complete => {
         obj.insert("txid".to_string(), self.signed.as_ref().unwrap().bitcoin_hash().to_json());
         obj.insert("time_until_deletion".to_string(),
@@ -119,6 +270,13 @@ impl json::ToJson for Session {
       }
     }
     obj.insert("target_value".to_string(), self.target_value.to_json());
+    obj.insert("fee_rate".to_string(), self.fee_rate.to_json());
+    obj.insert("options".to_string(), self.options.to_json());
+    obj.insert("participants".to_string(), self.unsigned.len().to_json());
+    match self.blind_key {
+      Some(ref key) => { obj.insert("blind_key".to_string(), key.public_key().to_json()); }
+      None => {}
+    }
     json::Object(obj)
   }
 }
@@ -152,11 +310,16 @@ impl json::ToJson for SessionId {
 }
 
 impl Session {
-  /// Creates a new session with a random ID
+  /// Creates a new session with a random ID. Blinded sessions need a
+  /// blinding key, which is slow to generate and so is taken from
+  /// `Server::take_blind_key` rather than made here.
   pub fn new(target_value: u64,
+             fee_rate: u64,
              join_duration: Duration,
              expiry_duration: Duration,
-             donation_address: Address)
+             donation_address: Address,
+             options: SessionOptions,
+             blind_key: Option<SecretKey>)
              -> IoResult<Session> {
     use std::rand;
     let mut csrng: Fortuna = {
@@ -166,15 +329,28 @@ impl Session {
       SeedableRng::from_seed(seed.as_slice())
     };
     let id = SessionId(csrng.gen());
+    let challenge = {
+      let mut data = [0u8, ..32];
+      csrng.fill_bytes(data.as_mut_slice());
+      Sha256dHash::from_data(data.as_slice())
+    };
     Ok(Session {
       id: id,
       rng: csrng,
       target_value: target_value,
+      fee_rate: fee_rate,
       state: Joining,
       switch_time: precise_time_ns(),
       join_duration: join_duration,
       expiry_duration: expiry_duration,
+      options: options,
+      challenge: challenge,
       unsigned: vec![],
+      values: vec![],
+      sources: vec![],
+      blind_key: blind_key,
+      blind_signatures_issued: 0,
+      registered: vec![],
       merged: None,
       signed: None,
       donation_address: donation_address
@@ -186,12 +362,88 @@ impl Session {
     self.id
   }
 
-  /// Adds an unsigned transaction to a coinjoin session
-  pub fn add_unsigned(&mut self, tx: &Transaction, utxo_set: &UtxoSet)
-                      -> Result<(), CoinjoinError> {
+  /// Adds an unsigned transaction to a coinjoin session. The ownership
+  /// proof is a transaction spending each of `tx`'s inputs, in order,
+  /// followed by an input spending output 0 of the session challenge,
+  /// with all but the last input signed. Since the challenge is not a
+  /// real transaction, the proof can never be mined. If the session
+  /// requires proof of work, `pow_nonce` must provide it. `source` is the
+  /// address the transaction came from, which is banned along with its
+  /// inputs if it fails to sign.
+  pub fn add_unsigned(&mut self, tx: &Transaction, proof: &Transaction, pow_nonce: u64,
+                      source: Option<IpAddr>, utxo_set: &UtxoSet) -> Result<(), CoinjoinError> {
+    if self.blind_key.is_some() {
+      return Err(WrongBlinding(true));
+    }
+    let (total_in, total_out) = try!(self.check_unsigned(tx, proof, pow_nonce, utxo_set));
+    self.unsigned.push(tx.clone());
+    self.values.push((total_in, total_out));
+    self.sources.push(source);
+    Ok(())
+  }
+
+  /// Adds an unsigned transaction to a blinded coinjoin session. Rather
+  /// than containing the target output, the transaction's inputs should
+  /// exceed its outputs by the target value, and the target output is
+  /// given as a blinded scriptpubkey. Returns the blind signature to be
+  /// unblinded and passed to `register_output`. The ownership proof,
+  /// proof of work and source are as for `add_unsigned`.
+  pub fn add_unsigned_blinded(&mut self, tx: &Transaction, proof: &Transaction, pow_nonce: u64,
+                              blinded_output: &BigUint, source: Option<IpAddr>,
+                              utxo_set: &UtxoSet) -> Result<BigUint, CoinjoinError> {
+    if self.blind_key.is_none() {
+      return Err(WrongBlinding(false));
+    }
+    let (total_in, total_out) = try!(self.check_unsigned(tx, proof, pow_nonce, utxo_set));
+    let sig = match self.blind_key.as_ref().unwrap().sign_blinded(blinded_output) {
+      Some(sig) => sig,
+      None => { return Err(BadBlindedOutput); }
+    };
+    self.unsigned.push(tx.clone());
+    self.values.push((total_in, total_out));
+    self.sources.push(source);
+    self.blind_signatures_issued += 1;
+    Ok(sig)
+  }
+
+  /// Registers a target output in a blinded coinjoin session, using an
+  /// unblinded signature obtained from `add_unsigned_blinded`
+  pub fn register_output(&mut self, script_pubkey: Script, sig: &BigUint)
+                         -> Result<(), CoinjoinError> {
+    if self.state != Registering {
+      return Err(IncorrectState(Registering, self.state));
+    }
+    if !self.blind_key.as_ref().unwrap().public_key().verify(script_pubkey.as_slice(), sig) {
+      return Err(BadBlindSignature);
+    }
+    if self.registered.iter().any(|o| o.script_pubkey == script_pubkey) {
+      return Err(DuplicateOutput(script_pubkey));
+    }
+    self.registered.push(TxOut { value: self.target_value, script_pubkey: script_pubkey });
+    Ok(())
+  }
+
+  // Checks an unsigned transaction against the session rules, returning
+  // its total input and output values
+  fn check_unsigned(&self, tx: &Transaction, proof: &Transaction, pow_nonce: u64,
+                    utxo_set: &UtxoSet) -> Result<(u64, u64), CoinjoinError> {
     if self.state != Joining {
       return Err(IncorrectState(Joining, self.state));
     }
+    // Check proof of work first, since it's cheap to check but expensive to fake
+    match self.options.pow_bits {
+      Some(bits) => {
+        let SessionId(id) = self.id;
+        if !pow::check(id, tx, pow_nonce, bits) {
+          return Err(InsufficientWork(bits));
+        }
+      }
+      None => {}
+    }
+    if self.is_full() {
+      return Err(SessionFull(self.unsigned.len()));
+    }
+    let blinded = self.blind_key.is_some();
 
     // Check for version, locktime
     if tx.version != 1 {
@@ -201,14 +453,18 @@ impl Session {
       return Err(NonZeroLocktime(tx.lock_time as uint));
     }
 
-    // Check for output of the correct size
-    if !tx.output.iter().any(|o| o.value == self.target_value) {
+    // Check for output of the correct size; in blinded sessions it is
+    // registered separately
+    if !blinded && !tx.output.iter().any(|o| o.value == self.target_value) {
       return Err(NoTargetOutput(self.target_value));
     }
 
     // Check for fee
     let mut received_fee = 0;
-    let required_fee = tx.input.len() as u64 * 200 + tx.output.len() as u64 * 50;
+    let n_outputs = if blinded { tx.output.len() + 1 } else { tx.output.len() };
+    let est_size = tx.input.len() as u64 * EST_INPUT_SIZE +
+                   n_outputs as u64 * EST_OUTPUT_SIZE;
+    let required_fee = (est_size * self.fee_rate + 999) / 1000;
     for out in tx.output.iter() {
       match out.classify(self.donation_address.network) {
         PayToPubkeyHash(ref addr) => {
@@ -223,15 +479,32 @@ impl Session {
       return Err(InsufficientFee(received_fee, required_fee));
     }
 
-    // Check that we know all the inputs, and that they have
-    // not already been used in this join
+    // Check that the ownership proof commits to our inputs and challenge
+    if proof.input.len() != tx.input.len() + 1 {
+      return Err(MalformedOwnershipProof);
+    }
+    let last = &proof.input[tx.input.len()];
+    if last.prev_hash != self.challenge || last.prev_index != 0 {
+      return Err(MalformedOwnershipProof);
+    }
+    for (input, proof_input) in tx.input.iter().zip(proof.input.iter()) {
+      if input.prev_hash != proof_input.prev_hash || input.prev_index != proof_input.prev_index {
+        return Err(MalformedOwnershipProof);
+      }
+    }
+
+    // Check that we know all the inputs, that they are owned by the
+    // joiner, and that they have not already been used in this join
     let mut total_in = 0;
-    for input in tx.input.iter() {
+    for (n, input) in tx.input.iter().enumerate() {
       let utxo = utxo_set.get_utxo(input.prev_hash, input.prev_index);
       match utxo {
         Some((_, out)) => { total_in += out.value; }
         None => { return Err(UnknownInput(input.prev_hash, input.prev_index as uint)); }
       }
+      if proof.input[n].validate(utxo_set, proof, n).is_err() {
+        return Err(BadOwnershipProof(input.prev_hash, input.prev_index as uint));
+      }
       for other_tx in self.unsigned.iter() { 
         for other_input in other_tx.input.iter() {
           if input.prev_hash  == other_input.prev_hash &&
@@ -241,21 +514,86 @@ impl Session {
         }
       }
     }
-    let total_out = tx.output.iter().fold(0, |acc, out| acc + out.value);
+    let mut total_out = tx.output.iter().fold(0, |acc, out| acc + out.value);
+    if blinded {
+      total_out += self.target_value;
+    }
 
-    // Check that input value is <= output value
-    // TODO: there should be a session option to allow this, for doing transfers
-    if total_in < total_out {
+    // Check that input and output values match, unless the session allows
+    // otherwise. (If outputs may exceed inputs, the totals are checked
+    // across all joiners before merging.)
+    if total_in < total_out && !self.options.allow_outputs_exceed_inputs() {
       return Err(OutputsExceedInputs(total_out, total_in));
     }
-    // TODO: there should be a session option to allow this and/or disabling donations
-    if total_in > total_out {
+    if total_in > total_out && !self.options.allow_inputs_exceed_outputs() {
       return Err(InputsExceedOutputs(total_in, total_out));
     }
 
-    // All Ok, add it
-    self.unsigned.push(tx.clone());
-    Ok(())
+    Ok((total_in, total_out))
+  }
+
+  // Whether the maximum number of participants has joined
+  fn is_full(&self) -> bool {
+    match self.options.max_participants {
+      Some(max) => self.unsigned.len() >= max,
+      None => false
+    }
+  }
+
+  // Whether the joined transactions, taken together, are fundable
+  fn balanced(&self) -> bool {
+    let total_in = self.values.iter().fold(0, |acc, &(i, _)| acc + i);
+    let total_out = self.values.iter().fold(0, |acc, &(_, o)| acc + o);
+    total_in >= total_out
+  }
+
+  /// Handles a transaction seen on the network or in a block. If it spends
+  /// any input contributed to this session, the session cannot complete:
+  /// while joining an unblinded session the offending contribution is
+  /// dropped, otherwise the session fails. Returns every input of the
+  /// offending contributions.
+  ///
+  /// Anyone can broadcast a transaction claiming to spend an input, so for
+  /// an unconfirmed transaction `utxo_set` must be given, and only spends
+  /// whose scripts validate against it count. Confirmed transactions are
+  /// passed with `None`, since their spends are already in the chain.
+  pub fn transaction_seen(&mut self, tx: &Transaction, utxo_set: Option<&UtxoSet>)
+                          -> Vec<(Sha256dHash, u32)> {
+    if self.state.is_final() {
+      return vec![];
+    }
+    let guilty: Vec<uint> = self.unsigned.iter().enumerate().filter(|&(_, contrib)| {
+      contrib.input.iter().any(|i| {
+        tx.input.iter().enumerate().any(|(n, t)| {
+          t.prev_hash == i.prev_hash && t.prev_index == i.prev_index &&
+            match utxo_set {
+              Some(utxo_set) => t.validate(utxo_set, tx, n).is_ok(),
+              None => true
+            }
+        })
+      })
+    }).map(|(n, _)| n).collect();
+    if guilty.is_empty() {
+      return vec![];
+    }
+
+    let mut ret = vec![];
+    for &n in guilty.iter() {
+      ret.extend(self.unsigned[n].input.iter().map(|i| (i.prev_hash, i.prev_index)));
+    }
+    // A blinded joiner already holds a signature for their output, so we
+    // can't just forget about them
+    if self.state == Joining && self.blind_key.is_none() {
+      for &n in guilty.iter().rev() {
+        self.unsigned.remove(n);
+        self.values.remove(n);
+        self.sources.remove(n);
+      }
+    } else {
+      self.state = Failed;
+      self.switch_time = precise_time_ns();
+    }
+    ret
   }
 
   // Merges all the transactions. Shouldn't be public, this should require
@@ -270,6 +608,10 @@ impl Session {
         self.unsigned.iter().fold(0, |acc, tx| acc + tx.output.len())),
     };
 
+    // Blindly-registered outputs first, so they are consolidated with
+    // any matching outputs below
+    merged.output.push_all(self.registered.as_slice());
+
     // We validated inputs and outputs when bringing them in
     for tx in self.unsigned.iter() {
       merged.input.push_all(tx.input.as_slice());
@@ -289,7 +631,12 @@ impl Session {
       }
     }
 
-    // TODO: Randomize DER encoding
+    // Use the same sequence number for every input, so that joiners'
+    // wallets can't be told apart by their choice of sequence numbers.
+    // (Signatures are normalized as they come in, in `add_signed`.)
+    for input in merged.input.mut_iter() {
+      input.sequence = 0xffffffff;
+    }
 
     // Randomize the input and output order
     self.rng.shuffle(merged.input.as_mut_slice());
@@ -350,7 +697,16 @@ impl Session {
     for (i, input) in tx.input.iter().enumerate() {
       if signed.input[i].script_sig == Default::default() {
         if input.validate(utxo_set, tx, i).is_ok() {
-          signed.input.get_mut(i).script_sig = input.script_sig.clone();
+          // Keep the normalized scriptSig only if it still validates, in
+          // case normalization broke something the original relied on
+          let mut normalized = tx.clone();
+          normalized.input.get_mut(i).script_sig = normalize_script_sig(&input.script_sig);
+          let script_sig = if normalized.input[i].validate(utxo_set, &normalized, i).is_ok() {
+            normalized.input[i].script_sig.clone()
+          } else {
+            input.script_sig.clone()
+          };
+          signed.input.get_mut(i).script_sig = script_sig;
           n_new_inputs += 1;
         } else {
           still_needed += 1;
@@ -373,14 +729,92 @@ impl Session {
   /// Accessor for the current state
   pub fn state(&self) -> SessionState { self.state }
 
+  /// Accessor for the target output value
+  pub fn target_value(&self) -> u64 { self.target_value }
+
+  /// A short description of the session, for listing many at once
+  pub fn summary_json(&self) -> json::Json {
+    let time_since_switch = Duration::nanoseconds(precise_time_ns() as i64 - self.switch_time as i64);
+    let phase_duration = match self.state {
+      Joining | Registering => self.join_duration,
+      _ => self.expiry_duration
+    };
+    let mut obj = TreeMap::new();
+    obj.insert("id".to_string(), self.id.to_json());
+    obj.insert("state".to_string(), self.state.to_json());
+    obj.insert("target_value".to_string(), self.target_value.to_json());
+    obj.insert("participants".to_string(), self.unsigned.len().to_json());
+    obj.insert("time_remaining".to_string(),
+               (phase_duration - time_since_switch).num_milliseconds().to_json());
+    obj.insert("phase_duration".to_string(), phase_duration.num_milliseconds().to_json());
+    json::Object(obj)
+  }
+
+  /// Whether a (partially-)signed transaction spends the same inputs, in
+  /// the same order, as this session's merged transaction
+  pub fn matches_merged(&self, tx: &Transaction) -> bool {
+    match self.merged {
+      Some(ref merged) => {
+        merged.input.len() == tx.input.len() &&
+        merged.input.iter().zip(tx.input.iter()).all(|(a, b)| a.prev_hash == b.prev_hash &&
+                                                             a.prev_index == b.prev_index)
+      }
+      None => false
+    }
+  }
+
   /// Accessor for the signed TX
   pub fn signed_transaction<'a>(&'a self) -> Option<&'a Transaction> { self.signed.as_ref() }
+
+  // Indices of the contributions whose joiners have not yet signed all
+  // of their inputs. With no signed transaction yet, nobody has signed.
+  fn blamed(&self) -> Vec<uint> {
+    let signed = match self.signed {
+      Some(ref tx) => tx,
+      None => { return range(0, self.unsigned.len()).collect(); }
+    };
+    let missing: Vec<&TxIn> = signed.input.iter()
+                                    .filter(|i| i.script_sig == Default::default())
+                                    .collect();
+    self.unsigned.iter().enumerate().filter(|&(_, tx)| {
+      tx.input.iter().any(|i| missing.iter().any(|m| m.prev_hash == i.prev_hash &&
+                                                     m.prev_index == i.prev_index))
+    }).map(|(n, _)| n).collect()
+  }
+
+  /// Returns every input contributed by joiners who have not yet signed
+  /// all of their inputs
+  pub fn blame(&self) -> Vec<(Sha256dHash, u32)> {
+    let mut ret = vec![];
+    for &n in self.blamed().iter() {
+      ret.extend(self.unsigned[n].input.iter().map(|i| (i.prev_hash, i.prev_index)));
+    }
+    ret
+  }
+
+  /// Returns the addresses, where known, of joiners who have not yet
+  /// signed all of their inputs
+  pub fn blame_sources(&self) -> Vec<IpAddr> {
+    self.blamed().iter().filter_map(|&n| self.sources[n]).collect()
+  }
 }
 
 /// A Coinjoin session manager
 pub struct Server {
-  sessions: HashMap<SessionId, Box<Session>>,
-  current: *mut Session
+  sessions: HashMap<SessionId, Session>,
+  // Sessions in the `Joining` state, indexed by target value
+  joining: HashMap<u64, SessionId>,
+  // Time at which the last session for each target value was started
+  started: HashMap<u64, u64>,
+  // Inputs whose owners failed to sign, and the time their bans expire
+  banned: HashMap<(Sha256dHash, u32), u64>,
+  // Addresses which submitted inputs their owners failed to sign, and the
+  // time their bans expire
+  banned_sources: HashMap<IpAddr, u64>,
+  // The most recently started session, if it still exists
+  current: Option<SessionId>,
+  // Blinding keys, generated in the background once first asked for
+  blind_keys: Option<Receiver<SecretKey>>
 }
 
 impl Server {
@@ -388,36 +822,172 @@ impl Server {
   pub fn new() -> Server {
     Server {
       sessions: HashMap::new(),
-      current: RawPtr::null()
+      joining: HashMap::new(),
+      started: HashMap::new(),
+      banned: HashMap::new(),
+      banned_sources: HashMap::new(),
+      current: None,
+      blind_keys: None
+    }
+  }
+
+  /// Takes a blinding key for a new session, if one is ready. The first
+  /// call starts generating keys in the background, so a caller finding
+  /// none should try again shortly.
+  pub fn take_blind_key(&mut self) -> Option<SecretKey> {
+    if self.blind_keys.is_none() {
+      self.blind_keys = Some(blind::key_generator());
+    }
+    self.blind_keys.as_ref().unwrap().try_recv().ok()
+  }
+
+  /// Retrieves the session accepting joiners for a given target value, if any
+  pub fn joining_session<'a>(&'a self, target_value: u64) -> Option<&'a Session> {
+    match self.joining.find(&target_value) {
+      Some(id) => self.session(id),
+      None => None
+    }
+  }
+
+  /// Whether any session for a given target value is still joining or merging
+  pub fn session_in_progress(&self, target_value: u64) -> bool {
+    self.sessions.values().any(|s| s.target_value == target_value &&
+                                   (s.state == Joining || s.state == Merging))
+  }
+
+  /// Time since the last session for a given target value was started, if any
+  pub fn time_since_start(&self, target_value: u64) -> Option<Duration> {
+    self.started.find(&target_value)
+        .map(|&t| Duration::nanoseconds(precise_time_ns() as i64 - t as i64))
+  }
+
+  /// Checks that none of a transaction's inputs are banned
+  pub fn check_banned(&self, tx: &Transaction, source: Option<IpAddr>)
+                      -> Result<(), CoinjoinError> {
+    match source {
+      Some(ip) if self.banned_sources.contains_key(&ip) => { return Err(BannedSource(ip)); }
+      _ => {}
+    }
+    for input in tx.input.iter() {
+      if self.banned.contains_key(&(input.prev_hash, input.prev_index)) {
+        return Err(BannedInput(input.prev_hash, input.prev_index as uint));
+      }
+    }
+    Ok(())
+  }
+
+  /// Lists banned inputs and addresses along with the time remaining on
+  /// their bans
+  pub fn bans(&self) -> json::Json {
+    let now = precise_time_ns();
+    let until = |expiry: u64| {
+      Duration::nanoseconds(expiry as i64 - now as i64).num_milliseconds().to_json()
+    };
+    let mut ret: Vec<json::Json> = self.banned.iter().map(|(&(txid, vout), &expiry)| {
+      let mut obj = TreeMap::new();
+      obj.insert("txid".to_string(), txid.to_json());
+      obj.insert("vout".to_string(), vout.to_json());
+      obj.insert("time_until_unban".to_string(), until(expiry));
+      json::Object(obj)
+    }).collect();
+    ret.extend(self.banned_sources.iter().map(|(ip, &expiry)| {
+      let mut obj = TreeMap::new();
+      obj.insert("address".to_string(), json::String(ip.to_string()));
+      obj.insert("time_until_unban".to_string(), until(expiry));
+      json::Object(obj)
+    }));
+    json::List(ret)
+  }
+
+  /// Finds the joining session whose target value matches one of the
+  /// outputs of an unsigned transaction
+  pub fn route_unsigned<'a>(&'a mut self, tx: &Transaction) -> Option<&'a mut Session> {
+    let id = tx.output.iter().filter_map(|o| self.joining.find(&o.value)).next().map(|id| *id);
+    match id {
+      Some(id) => self.session_mut(&id),
+      None => None
     }
   }
 
+  /// Finds the merging session whose merged transaction a signed
+  /// transaction corresponds to
+  pub fn route_signed<'a>(&'a mut self, tx: &Transaction) -> Option<&'a mut Session> {
+    self.sessions.mut_iter()
+                 .map(|(_, s)| s)
+                 .find(|s| s.state == Merging && s.matches_merged(tx))
+  }
+
+  /// Retrieves the ID of the current session, or None if there is not one
+  pub fn current_session_id(&self) -> Option<SessionId> {
+    self.current
+  }
+
   /// Retrieves the current session, or None if there is not one
   pub fn current_session<'a>(&'a self) -> Option<&'a Session> {
-    unsafe { self.current.as_ref() }
+    match self.current {
+      Some(id) => self.session(&id),
+      None => None
+    }
   }
 
   /// Retrieves the current session, or None if there is not one
   pub fn current_session_mut<'a>(&'a mut self) -> Option<&'a mut Session> {
-    unsafe { if self.current.is_not_null() { Some(&mut *self.current) } else { None } }
+    match self.current {
+      Some(id) => self.session_mut(&id),
+      None => None
+    }
   }
 
   /// Retrieves a specified session, or None if it is not available
   pub fn session<'a>(&'a self, key: &SessionId) -> Option<&'a Session> {
-    self.sessions.find(key).map(|r| &**r)
+    self.sessions.find(key)
   }
 
   /// Retrieves a specified session, or None if it is not available
   pub fn session_mut<'a>(&'a mut self, key: &SessionId) -> Option<&'a mut Session> {
-    self.sessions.find_mut(key).map(|r| &mut **r)
+    self.sessions.find_mut(key)
   }
 
-  /// Sets the current session
-  pub fn set_current_session(&mut self, sess: Session) {
-    let boxed = box sess;
-    let raw = &*boxed as *const _ as *mut _;
-    self.sessions.insert(boxed.id, boxed);
-    self.current = raw;
+  /// Lists all sessions which have not yet been deleted
+  pub fn sessions<'a>(&'a self) -> Vec<&'a Session> {
+    self.sessions.values().collect()
+  }
+
+  /// Adds a new session and makes it current. Fails if there is already a
+  /// session accepting joiners for the same target value.
+  pub fn set_current_session(&mut self, sess: Session) -> Result<(), CoinjoinError> {
+    if self.joining.contains_key(&sess.target_value) {
+      return Err(DenominationInUse(sess.target_value));
+    }
+    self.joining.insert(sess.target_value, sess.id);
+    self.started.insert(sess.target_value, precise_time_ns());
+    self.current = Some(sess.id);
+    self.sessions.insert(sess.id, sess);
+    Ok(())
+  }
+
+  /// Checks every session against a transaction seen on the network or in
+  /// a block, banning the inputs of any joiner whose contribution it
+  /// double-spends. Unconfirmed transactions must come with the UTXO set
+  /// to check their spends against; see `Session::transaction_seen`.
+  /// Returns the IDs of affected sessions.
+  pub fn transaction_seen(&mut self, tx: &Transaction, utxo_set: Option<&UtxoSet>)
+                          -> Vec<SessionId> {
+    let ban_expiry = precise_time_ns() + COINJOIN_BAN_DURATION as u64 * 1000000000;
+    let mut ret = vec![];
+    for (id, session) in self.sessions.mut_iter() {
+      let guilty = session.transaction_seen(tx, utxo_set);
+      if !guilty.is_empty() {
+        for outpoint in guilty.move_iter() {
+          self.banned.insert(outpoint, ban_expiry);
+        }
+        if session.state == Failed {
+          self.joining.remove(&session.target_value);
+        }
+        ret.push(*id);
+      }
+    }
+    ret
   }
 
   /// Updates all sessions
@@ -432,8 +1002,32 @@ impl Server {
 
       match session.state {
         Joining => {
+          let enough = session.unsigned.len() >= session.options.min_participants();
+          // Extend the join window, up to the merge duration, if we don't
+          // have enough participants yet
+          let window_closed = time_since_switch > session.join_duration &&
+                              (enough || time_since_switch > session.join_duration +
+                                                             session.expiry_duration);
+          if session.is_full() || window_closed {
+            if enough && session.balanced() {
+              if session.blind_key.is_some() {
+                session.state = Registering;
+              } else {
+                session.state = Merging;
+                session.merge_transactions();
+              }
+            } else {
+              session.state = Unmerged;
+            }
+            session.switch_time = now;
+            self.joining.remove(&session.target_value);
+          }
+        }
+        Registering => {
           if time_since_switch > session.join_duration {
-            if session.unsigned.len() > 1 {
+            // If anyone failed to register, the inputs would overpay the
+            // outputs, so there is nothing we can merge
+            if session.registered.len() == session.blind_signatures_issued {
               session.state = Merging;
               session.merge_transactions();
             } else {
@@ -445,8 +1039,18 @@ impl Server {
         state => {
           if time_since_switch > session.expiry_duration {
             session.state = match state {
-              Joining => unreachable!(),
-              Merging => Expired,
+              Joining | Registering => unreachable!(),
+              Merging => {
+                // Ban everyone who held up the session
+                let ban_expiry = now + COINJOIN_BAN_DURATION as u64 * 1000000000;
+                for outpoint in session.blame().move_iter() {
+                  self.banned.insert(outpoint, ban_expiry);
+                }
+                for ip in session.blame_sources().move_iter() {
+                  self.banned_sources.insert(ip, ban_expiry);
+                }
+                Expired
+              }
               Complete | Expired | Failed | Unmerged => { keys_to_delete.push(*key); Expired }
             };
             session.switch_time = now;
@@ -454,12 +1058,25 @@ impl Server {
         }
       }
     }
+    // Lift any expired bans
+    let unbanned: Vec<(Sha256dHash, u32)> = self.banned.iter()
+                                                .filter(|&(_, &expiry)| expiry < now)
+                                                .map(|(&outpoint, _)| outpoint)
+                                                .collect();
+    for outpoint in unbanned.iter() {
+      self.banned.remove(outpoint);
+    }
+    let unbanned: Vec<IpAddr> = self.banned_sources.iter()
+                                    .filter(|&(_, &expiry)| expiry < now)
+                                    .map(|(&ip, _)| ip)
+                                    .collect();
+    for ip in unbanned.iter() {
+      self.banned_sources.remove(ip);
+    }
     // Delete any old sessions
     for key in keys_to_delete.iter() {
-      unsafe {
-        if (*self.current).id == *key {
-          self.current = RawPtr::null();
-        }
+      if self.current == Some(*key) {
+        self.current = None;
       }
       self.sessions.remove(key);
     }
@@ -0,0 +1,465 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Configuration Schema
+//!
+//! The keys which the configuration file may contain, and what their values
+//! may be. The TOML decoder drops keys it does not know and reports type
+//! errors without saying where they are, so the parsed file is checked
+//! against this schema first. Unknown keys are warnings, since they are
+//! harmless apart from being ignored; everything else is an error.
+//!
+//! TOML tables do not remember where in the file they came from, so
+//! positions are found again by looking for the key in the text.
+//!
+
+use std::cmp;
+use std::path::posix::Path;
+use toml;
+
+use constants::DEFAULT_PEER_PORT;
+use user_data::{PeerAddress, network_from_name};
+use wallet::FeePolicy;
+
+/// What a configuration value may be
+enum Kind {
+  /// Any string
+  Str,
+  /// true or false
+  Bool,
+  /// Any integer
+  Int,
+  /// A nonnegative integer
+  UInt,
+  /// A TCP port
+  Port,
+  /// A path, whose directory must exist
+  File,
+  /// One of the given strings
+  OneOf(&'static [&'static str]),
+  /// A string accepted by the given function, which is described
+  Parsed(fn(&str) -> bool, &'static str),
+  /// An array of values of the given kind
+  List(&'static Kind),
+  /// A table with the given keys
+  Table(&'static [Field]),
+  /// An array of tables with the given keys
+  TableList(&'static [Field]),
+  /// A table of tables with the given keys, named by the user
+  NamedTables(&'static [Field])
+}
+
+/// A key which may appear in a table
+struct Field {
+  name: &'static str,
+  kind: Kind,
+  required: bool
+}
+
+fn valid_peer(s: &str) -> bool {
+  PeerAddress::parse(s, DEFAULT_PEER_PORT).is_some()
+}
+
+fn valid_fee_policy(s: &str) -> bool {
+  from_str::<FeePolicy>(s).is_some()
+}
+
+static PEER: Kind = Parsed(valid_peer, "a peer address, `host` or `host:port`");
+static DENOMINATION: Kind = UInt;
+
+static SESSION_OPTION_FIELDS: [Field, ..6] = [
+  Field { name: "blinded", kind: Bool, required: false },
+  Field { name: "allow_inputs_exceed_outputs", kind: Bool, required: false },
+  Field { name: "allow_outputs_exceed_inputs", kind: Bool, required: false },
+  Field { name: "min_participants", kind: UInt, required: false },
+  Field { name: "max_participants", kind: UInt, required: false },
+  Field { name: "pow_bits", kind: UInt, required: false }
+];
+
+static SCHEDULED_SESSION_FIELDS: [Field, ..5] = [
+  Field { name: "target", kind: UInt, required: true },
+  Field { name: "join_duration", kind: Int, required: false },
+  Field { name: "expiry_duration", kind: Int, required: false },
+  Field { name: "interval", kind: Int, required: false },
+  Field { name: "options", kind: Table(&SESSION_OPTION_FIELDS), required: false }
+];
+
+static COINJOIN_FIELDS: [Field, ..8] = [
+  Field { name: "enabled", kind: Bool, required: false },
+  Field { name: "denominations", kind: List(&DENOMINATION), required: false },
+  Field { name: "join_duration", kind: Int, required: false },
+  Field { name: "expiry_duration", kind: Int, required: false },
+  Field { name: "fee_policy",
+          kind: Parsed(valid_fee_policy, "\"economic\", \"fast\" or a number of satoshi per 1000 bytes"),
+          required: false },
+  Field { name: "donation_address", kind: Str, required: false },
+  Field { name: "options", kind: Table(&SESSION_OPTION_FIELDS), required: false },
+  Field { name: "schedule", kind: TableList(&SCHEDULED_SESSION_FIELDS), required: false }
+];
+
+static TASK_PERIOD_FIELDS: [Field, ..5] = [
+  Field { name: "save", kind: Int, required: false },
+  Field { name: "peer_rotation", kind: Int, required: false },
+  Field { name: "coinjoin_schedule", kind: Int, required: false },
+  Field { name: "mempool_expiry", kind: Int, required: false },
+  Field { name: "fee_estimates", kind: Int, required: false }
+];
+
+static WALLET_FIELDS: [Field, ..2] = [
+  Field { name: "path", kind: File, required: false },
+  Field { name: "meta_path", kind: File, required: false }
+];
+
+static NETWORK_FIELDS: [Field, ..44] = [
+  Field { name: "enabled", kind: Bool, required: false },
+  Field { name: "datadir", kind: File, required: false },
+  Field { name: "peer_addr", kind: Str, required: false },
+  Field { name: "peer_port", kind: Port, required: false },
+  Field { name: "peers", kind: List(&PEER), required: false },
+  Field { name: "rpc_server_addr", kind: Str, required: false },
+  Field { name: "rpc_server_port", kind: Port, required: false },
+  Field { name: "rpc_user", kind: Str, required: false },
+  Field { name: "rpc_password", kind: Str, required: false },
+  Field { name: "credentials_file", kind: File, required: false },
+  Field { name: "rpc_cookie_path", kind: File, required: false },
+  Field { name: "rpc_rate_limit", kind: UInt, required: false },
+  Field { name: "rpc_max_concurrent", kind: UInt, required: false },
+  Field { name: "rpc_workers", kind: UInt, required: false },
+  Field { name: "mempool_max_size", kind: UInt, required: false },
+  Field { name: "utxo_sync_memory", kind: UInt, required: false },
+  Field { name: "utxo_sync_n_blocks", kind: UInt, required: false },
+  Field { name: "blockchain_n_full_blocks", kind: UInt, required: false },
+  Field { name: "coinjoin_on", kind: Bool, required: false },
+  Field { name: "coinjoin_schedule", kind: TableList(&SCHEDULED_SESSION_FIELDS), required: false },
+  Field { name: "coinjoin_denominations", kind: List(&DENOMINATION), required: false },
+  Field { name: "coinjoin", kind: Table(&COINJOIN_FIELDS), required: false },
+  Field { name: "wallet_rpc", kind: Bool, required: false },
+  Field { name: "address_index", kind: Bool, required: false },
+  Field { name: "electrum_port", kind: Port, required: false },
+  Field { name: "blockchain_path", kind: File, required: false },
+  Field { name: "utxo_set_path", kind: File, required: false },
+  Field { name: "chain_journal_path", kind: File, required: false },
+  Field { name: "fee_estimates_path", kind: File, required: false },
+  Field { name: "wallet_path", kind: File, required: false },
+  Field { name: "wallet_meta_path", kind: File, required: false },
+  Field { name: "wallets", kind: NamedTables(&WALLET_FIELDS), required: false },
+  Field { name: "wallet_backup_dir", kind: File, required: false },
+  Field { name: "wallet_backup_count", kind: UInt, required: false },
+  Field { name: "fee_policy",
+          kind: Parsed(valid_fee_policy, "\"economic\", \"fast\" or a number of satoshi per 1000 bytes"),
+          required: false },
+  Field { name: "refuse_address_reuse", kind: Bool, required: false },
+  Field { name: "block_notify", kind: Str, required: false },
+  Field { name: "wallet_notify", kind: Str, required: false },
+  Field { name: "task_periods", kind: Table(&TASK_PERIOD_FIELDS), required: false },
+  Field { name: "log_to_file", kind: Bool, required: false },
+  Field { name: "log_file", kind: File, required: false },
+  Field { name: "log_max_size", kind: UInt, required: false },
+  Field { name: "log_files", kind: UInt, required: false },
+  Field { name: "log_stdout", kind: Bool, required: false },
+  Field { name: "log_format", kind: OneOf(&["text", "json"]), required: false },
+  Field { name: "debug_level", kind: OneOf(&["DEBUG", "NOTE", "STATUS", "WARN", "ERROR", "FATAL"]),
+          required: false }
+];
+
+/// A problem found in the configuration file
+pub struct Diagnostic {
+  /// Line and column, from 1, of the offending key, if it could be found
+  pub position: Option<(uint, uint)>,
+  /// Whether the problem stops the file being used
+  pub is_error: bool,
+  /// Description of the problem
+  pub message: String
+}
+
+impl Diagnostic {
+  /// Describes the problem, prefixed by the file name and position
+  pub fn describe(&self, file: &str) -> String {
+    let severity = if self.is_error { "error" } else { "warning" };
+    match self.position {
+      Some((line, col)) => format!("{}:{}:{}: {}: {}", file, line, col, severity, self.message),
+      None => format!("{}: {}: {}", file, severity, self.message)
+    }
+  }
+}
+
+/// A table's place in the file: its dotted name, and the line of its header
+#[deriving(Clone)]
+struct Scope {
+  path: Vec<String>,
+  header: Option<uint>
+}
+
+impl Scope {
+  fn child(&self, name: &str) -> Vec<String> {
+    let mut path = self.path.clone();
+    path.push(name.to_string());
+    path
+  }
+}
+
+/// Checks a parsed configuration file against the schema
+struct Checker<'a> {
+  lines: Vec<&'a str>,
+  diagnostics: Vec<Diagnostic>
+}
+
+/// Returns the number of leading whitespace characters of a line
+fn indent(line: &str) -> uint {
+  line.len() - line.trim_left().len()
+}
+
+/// The number of single-character edits between two strings
+fn edit_distance(a: &str, b: &str) -> uint {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<uint> = range(0, b.len() + 1).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diag = row[0];
+    *row.get_mut(0) = i + 1;
+    for j in range(0, b.len()) {
+      let above = row[j + 1];
+      let cost = if ca == b[j] { 0 } else { 1 };
+      *row.get_mut(j + 1) = cmp::min(cmp::min(above + 1, row[j] + 1), diag + cost);
+      diag = above;
+    }
+  }
+  row[b.len()]
+}
+
+impl<'a> Checker<'a> {
+  /// Finds the line of the `occurrence`th header for the table at `path`,
+  /// looking from line `from`
+  fn find_header(&self, path: &[String], from: uint, occurrence: uint) -> Option<uint> {
+    let mut seen = 0;
+    for n in range(from, self.lines.len()) {
+      let trimmed = self.lines[n].trim();
+      if !trimmed.starts_with("[") {
+        continue;
+      }
+      let name = trimmed.trim_left_chars('[');
+      let name = name.slice_to(name.find(']').unwrap_or(name.len()));
+      let names: Vec<&str> = name.split('.').map(|s| s.trim()).collect();
+      if names.len() == path.len() &&
+         names.iter().zip(path.iter()).all(|(a, b)| *a == b.as_slice()) {
+        if seen == occurrence {
+          return Some(n);
+        }
+        seen += 1;
+      }
+    }
+    None
+  }
+
+  /// Finds the line and column, from 1, at which `key` is set in a table.
+  /// Tables are found by their own headers.
+  fn find_key(&self, scope: &Scope, key: &str) -> Option<(uint, uint)> {
+    let start = match scope.header {
+      Some(n) => Some(n + 1),
+      None if scope.path.is_empty() => Some(0),
+      None => None
+    };
+    match start {
+      Some(start) => {
+        for n in range(start, self.lines.len()) {
+          let trimmed = self.lines[n].trim_left();
+          if trimmed.starts_with("[") {
+            break;
+          }
+          if trimmed.starts_with(key) &&
+             trimmed.slice_from(key.len()).trim_left().starts_with("=") {
+            return Some((n + 1, indent(self.lines[n]) + 1));
+          }
+        }
+      }
+      None => {}
+    }
+    let from = scope.header.unwrap_or(0);
+    self.find_header(scope.child(key).as_slice(), from, 0)
+        .map(|n| (n + 1, indent(self.lines[n]) + 1))
+  }
+
+  fn report(&mut self, scope: &Scope, key: &str, is_error: bool, message: String) {
+    let position = self.find_key(scope, key);
+    self.diagnostics.push(Diagnostic {
+      position: position,
+      is_error: is_error,
+      message: message
+    });
+  }
+
+  /// Checks the keys of a table against the fields it may have
+  fn check_table(&mut self, table: &toml::Table, fields: &'static [Field], scope: &Scope) {
+    for (key, value) in table.iter() {
+      match fields.iter().find(|f| f.name == key.as_slice()) {
+        Some(field) => { self.check_value(value, &field.kind, scope, key.as_slice()); }
+        None => {
+          let suggestion = fields.iter()
+                                 .map(|f| (edit_distance(key.as_slice(), f.name), f.name))
+                                 .min_by(|&(d, _)| d);
+          let message = match suggestion {
+            Some((d, name)) if d <= 2 => {
+              format!("unknown key `{}` is ignored; did you mean `{}`?", key, name)
+            }
+            _ => format!("unknown key `{}` is ignored", key)
+          };
+          self.report(scope, key.as_slice(), false, message);
+        }
+      }
+    }
+    for field in fields.iter().filter(|f| f.required) {
+      if !table.contains_key(&field.name.to_string()) {
+        let message = format!("missing key `{}` in `{}`", field.name, scope.path.connect("."));
+        let position = scope.header.map(|n| (n + 1, indent(self.lines[n]) + 1));
+        self.diagnostics.push(Diagnostic { position: position, is_error: true, message: message });
+      }
+    }
+  }
+
+  /// Checks that a value is of the kind its key calls for
+  fn check_value(&mut self, value: &toml::Value, kind: &'static Kind, scope: &Scope, key: &str) {
+    let name = if scope.path.is_empty() { key.to_string() }
+               else { format!("{}.{}", scope.path.connect("."), key) };
+    let problem = match (kind, value) {
+      (&Str, &toml::String(_)) => None,
+      (&Bool, &toml::Boolean(_)) => None,
+      (&Int, &toml::Integer(_)) => None,
+      (&UInt, &toml::Integer(n)) => {
+        if n < 0 { Some(format!("`{}` must not be negative, but is {}", name, n)) } else { None }
+      }
+      (&Port, &toml::Integer(n)) => {
+        if n < 1 || n > 65535 {
+          Some(format!("`{}` must be a port from 1 to 65535, but is {}", name, n))
+        } else {
+          None
+        }
+      }
+      (&File, &toml::String(ref s)) => {
+        let dir = Path::new(s.as_slice()).dir_path();
+        if dir.is_dir() {
+          None
+        } else {
+          Some(format!("`{}` is in directory {}, which does not exist", name, dir.display()))
+        }
+      }
+      (&OneOf(names), &toml::String(ref s)) => {
+        if names.iter().any(|n| *n == s.as_slice()) {
+          None
+        } else {
+          Some(format!("`{}` must be one of {}, but is \"{}\"", name, names.connect(", "), s))
+        }
+      }
+      (&Parsed(valid, description), &toml::String(ref s)) => {
+        if valid(s.as_slice()) {
+          None
+        } else {
+          Some(format!("`{}` must be {}, but is \"{}\"", name, description, s))
+        }
+      }
+      (&List(inner), &toml::Array(ref values)) => {
+        for value in values.iter() {
+          self.check_value(value, inner, scope, key);
+        }
+        None
+      }
+      (&Table(fields), &toml::Table(ref table)) => {
+        let path = scope.child(key);
+        let header = self.find_header(path.as_slice(), scope.header.unwrap_or(0), 0);
+        self.check_table(table, fields, &Scope { path: path, header: header });
+        None
+      }
+      (&TableList(fields), &toml::Array(ref values)) => {
+        let path = scope.child(key);
+        for (n, value) in values.iter().enumerate() {
+          match *value {
+            toml::Table(ref table) => {
+              let header = self.find_header(path.as_slice(), scope.header.unwrap_or(0), n);
+              self.check_table(table, fields, &Scope { path: path.clone(), header: header });
+            }
+            _ => {
+              self.report(scope, key, true,
+                          format!("`{}` must be an array of tables, but contains a {}",
+                                  name, value.type_str()));
+            }
+          }
+        }
+        None
+      }
+      (&NamedTables(fields), &toml::Table(ref tables)) => {
+        let inner_scope = Scope {
+          path: scope.child(key),
+          header: self.find_header(scope.child(key).as_slice(), scope.header.unwrap_or(0), 0)
+        };
+        for (inner_name, value) in tables.iter() {
+          match *value {
+            toml::Table(ref table) => {
+              let path = inner_scope.child(inner_name.as_slice());
+              let header = self.find_header(path.as_slice(), scope.header.unwrap_or(0), 0);
+              self.check_table(table, fields, &Scope { path: path, header: header });
+            }
+            _ => {
+              self.report(&inner_scope, inner_name.as_slice(), true,
+                          format!("`{}.{}` must be a table, but is a {}",
+                                  name, inner_name, value.type_str()));
+            }
+          }
+        }
+        None
+      }
+      (kind, value) => {
+        let expected = match *kind {
+          Str | File | OneOf(_) | Parsed(_, _) => "a string",
+          Bool => "a boolean",
+          Int | UInt | Port => "an integer",
+          List(_) => "an array",
+          Table(_) | NamedTables(_) => "a table",
+          TableList(_) => "an array of tables"
+        };
+        Some(format!("`{}` must be {}, but is a {}", name, expected, value.type_str()))
+      }
+    };
+    match problem {
+      Some(message) => { self.report(scope, key, true, message); }
+      None => {}
+    }
+  }
+}
+
+/// Checks a parsed configuration file, whose text is `contents`, against
+/// the schema, returning any problems in the order they were found
+pub fn check(contents: &str, table: &toml::Table) -> Vec<Diagnostic> {
+  let mut checker = Checker {
+    lines: contents.lines_any().collect(),
+    diagnostics: vec![]
+  };
+  let top = Scope { path: vec![], header: None };
+  for (key, value) in table.iter() {
+    match (network_from_name(key.as_slice()), value) {
+      (Some(_), &toml::Table(ref network)) => {
+        let path = top.child(key.as_slice());
+        let header = checker.find_header(path.as_slice(), 0, 0);
+        checker.check_table(network, &NETWORK_FIELDS, &Scope { path: path, header: header });
+      }
+      (Some(_), value) => {
+        checker.report(&top, key.as_slice(), true,
+                       format!("`{}` must be a table, but is a {}", key, value.type_str()));
+      }
+      (None, _) => {
+        checker.report(&top, key.as_slice(), true,
+                       format!("unknown network `{}`; expected \"bitcoin\" or \"testnet\"", key));
+      }
+    }
+  }
+  checker.diagnostics
+}
+
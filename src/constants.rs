@@ -16,17 +16,115 @@
 //!
 //! Defines compile-time constants which determine operation of the wallet.
 //! As a general rule, anything in here ought to be a user-configurable
-//! option, and is on the TODO list.
+//! option, and is on the TODO list; those named `DEFAULT_` already are.
 //!
 
-/// The number of blocks to request at once during UTXO sync
-pub static UTXO_SYNC_N_BLOCKS: uint = 500;
+/// Default for the most blocks to have requested at once during UTXO sync
+pub static DEFAULT_UTXO_SYNC_N_BLOCKS: uint = 500;
 
-/// The number of blocks to store full blockdata on in case of reorg
-pub static BLOCKCHAIN_N_FULL_BLOCKS: uint = 100;
+/// Block size, in bytes, assumed by UTXO sync until it has seen some blocks
+pub static UTXO_SYNC_INITIAL_BLOCK_SIZE: uint = 1000000;
 
-/// The save-to-disk frequency in s
-pub static SAVE_FREQUENCY: i64 = 600; // 10 minutes
+/// Default memory, in bytes, which UTXO sync may fill with blocks requested
+/// but not yet connected
+pub static DEFAULT_UTXO_SYNC_MEMORY: uint = 64000000; // 64 MB
+
+/// Default number of blocks to store full blockdata on in case of reorg
+pub static DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS: uint = 100;
+
+/// Fewest blocks to store full blockdata on, since we cannot follow a reorg
+/// any deeper than this
+pub static MIN_BLOCKCHAIN_N_FULL_BLOCKS: uint = 10;
+
+/// Default time, in s, between syncing and saving to disk
+pub static DEFAULT_SAVE_FREQUENCY: i64 = 600; // 10 minutes
+
+/// Shortest time, in s, allowed between syncing and saving to disk
+pub static MIN_SAVE_FREQUENCY: i64 = 60; // 1 minute
+
+/// Extra room, as a percentage of the last save's size, which must be free
+/// on disk before the blockchain or UTXO set is saved in full
+pub static SAVE_SPACE_MARGIN: u64 = 20;
+
+/// How long, in s, to wait before retrying a failed save
+pub static SAVE_RETRY_DELAY: i64 = 60;
+
+/// Number of saves between full rewrites of the blockchain and UTXO set;
+/// the saves in between only append changes to the journal
+pub static FULL_SAVE_FREQUENCY: uint = 6; // 1 hour
+
+/// Number of unsaved changes above which the next save rewrites everything
+/// rather than journalling them
+pub static JOURNAL_MAX_ENTRIES: uint = 1000;
+
+/// How long, in s, headers sync waits for each `headers` message before
+/// reconnecting and starting again
+pub static HEADERS_SYNC_TIMEOUT: i64 = 120; // 2 minutes
+
+/// How long, in s, UTXO sync waits for each requested block before
+/// reconnecting and starting again
+pub static BLOCK_DOWNLOAD_TIMEOUT: i64 = 300; // 5 minutes
+
+/// How long, in s, headers or UTXO sync may go without progress, even
+/// though the peer is answering, before we disconnect and start again
+pub static SYNC_STALL_TIMEOUT: i64 = 900; // 15 minutes
+
+/// How often, in s, to log sync progress
+pub static SYNC_PROGRESS_LOG_FREQUENCY: i64 = 30;
+
+/// Target time, in s, between blocks, for guessing how far behind we are
+pub static BLOCK_SPACING: i64 = 600; // 10 minutes
+
+/// How often, in s, to ping the peer to measure latency
+pub static PING_FREQUENCY: i64 = 120; // 2 minutes
+
+/// Default time, in s, for coinjoin sessions to collect unsigned transactions
+pub static DEFAULT_COINJOIN_JOIN_DURATION: i64 = 300; // 5 minutes
+
+/// Default time, in s, for coinjoin sessions to wait for signatures
+pub static DEFAULT_COINJOIN_EXPIRY_DURATION: i64 = 300; // 5 minutes
+
+/// How long, in s, to refuse inputs from coinjoin participants who failed to sign
+pub static COINJOIN_BAN_DURATION: i64 = 86400; // 1 day
+
+/// Maximum number of outputs `splitdenominations` will produce
+pub static MAX_DENOMINATION_SPLIT: uint = 1000;
+
+/// Maximum number of headers returned by a single `getblockheaders` call
+pub static MAX_HEADERS_RESULTS: uint = 2000;
+
+/// Default number of blocks over which `getmininginfo` estimates the
+/// network hash rate
+pub static NETWORK_HASHPS_BLOCKS: uint = 120;
+
+/// Default time, in s, that `coinjoin_wait` waits for a state change
+pub static COINJOIN_WAIT_TIMEOUT: i64 = 300; // 5 minutes
+
+/// Default time, in s, that `waitfornewblock` and `waitforblockheight` wait
+pub static BLOCK_WAIT_TIMEOUT: i64 = 600; // 10 minutes
+
+/// How often, in s, to check for coinjoin state changes to notify waiters of
+pub static COINJOIN_WAIT_FREQUENCY: i64 = 1;
+
+/// Default time, in s, between checks whether scheduled coinjoin sessions
+/// need starting
+pub static COINJOIN_SCHEDULE_FREQUENCY: i64 = 10;
+
+/// Default time, in s, between dropping the peer for a fresh connection;
+/// 0 never does so
+pub static PEER_ROTATION_FREQUENCY: i64 = 0;
+
+/// Default time, in s, between checks for expired mempool transactions
+pub static MEMPOOL_EXPIRY_FREQUENCY: i64 = 3600; // 1 hour
+
+/// Time in s after which transactions are dropped from the mempool
+pub static MEMPOOL_EXPIRY: i64 = 1209600; // 2 weeks
+
+/// Default time, in s, between writes of the fee estimator's statistics
+pub static FEE_ESTIMATES_FLUSH_FREQUENCY: i64 = 3600; // 1 hour
+
+/// How often, in s, the idle loop checks for scheduled tasks which are due
+pub static SCHEDULER_TICK: i64 = 1;
 
 /// Default peer address
 pub static DEFAULT_PEER_ADDR: &'static str = "localhost";
@@ -40,3 +138,124 @@ pub static DEFAULT_RPC_SERVER_ADDR: &'static str = "localhost";
 /// Default RPC server port
 pub static DEFAULT_RPC_SERVER_PORT: u16 = 8001;
 
+/// Number of recent RPC calls reported by `getrpcinfo`
+pub static RPC_RECENT_CALLS: uint = 50;
+
+/// Default maximum number of RPC requests to handle at once
+pub static DEFAULT_RPC_MAX_CONCURRENT: uint = 16;
+
+/// Default number of worker tasks handling RPC calls off the idle loop
+pub static DEFAULT_RPC_WORKERS: uint = 4;
+
+/// Default maximum total size, in bytes, of transactions in the mempool
+pub static DEFAULT_MEMPOOL_MAX_SIZE: uint = 50000000; // 50 MB
+
+/// Number of `tx` and `inv` messages the message router holds for the
+/// mempool before dropping new ones, e.g. while we are busy syncing
+pub static MEMPOOL_QUEUE_SIZE: uint = 1000;
+
+/// Number of recent blocks the address index can undo in a reorg; deeper
+/// reorgs rebuild it from the UTXO set
+pub static ADDRESS_INDEX_UNDO_DEPTH: uint = 100;
+
+/// Version of the Electrum protocol served on `electrum_port`
+pub static ELECTRUM_PROTOCOL_VERSION: &'static str = "1.4";
+
+/// Longest line, in bytes, accepted from an Electrum client
+pub static ELECTRUM_MAX_LINE: uint = 1000000;
+
+/// Time, in s, between checks of each Electrum client's subscribed scripts
+/// for changes, on top of the check made on each new tip
+pub static ELECTRUM_POLL_FREQUENCY: i64 = 10;
+
+/// Most scripts one Electrum client may be subscribed to at once
+pub static ELECTRUM_MAX_SUBSCRIPTIONS: uint = 1000;
+
+/// Time, in s, that an Electrum client's broadcast waits to hear whether
+/// the mempool accepted the transaction
+pub static ELECTRUM_BROADCAST_TIMEOUT: i64 = 30;
+
+/// Time, in ms, that command-line tools wait for the RPC server to answer
+pub static RPC_CLIENT_TIMEOUT: u64 = 30000; // 30 seconds
+
+/// Time, in ms, to wait for a merchant's payment protocol server
+pub static PAYMENT_REQUEST_TIMEOUT: u64 = 30000; // 30 seconds
+
+/// Largest payment request, in bytes, that will be accepted (per BIP70)
+pub static PAYMENT_REQUEST_MAX_SIZE: uint = 50000;
+
+/// Time, in ms, between redraws of the terminal dashboard
+pub static DASHBOARD_REFRESH_FREQUENCY: i64 = 2000; // 2 seconds
+
+/// Time, in seconds, after which the web dashboard reloads itself
+pub static WEB_DASHBOARD_REFRESH: uint = 10;
+
+/// Number of per-client rate limit records above which idle ones are pruned
+pub static RPC_RATE_LIMIT_PRUNE: uint = 1024;
+
+/// Default RPC server port on testnet, so both networks can run at once
+pub static DEFAULT_TESTNET_RPC_SERVER_PORT: u16 = 18001;
+
+/// Username used for RPC authentication with a cookie file
+pub static RPC_COOKIE_USER: &'static str = "__cookie__";
+
+/// Number of random bytes in a generated RPC cookie password
+pub static RPC_COOKIE_BYTES: uint = 32;
+
+/// Name of the wallet configured by the top-level `wallet_path` option,
+/// which is used for RPC calls that do not specify a wallet
+pub static DEFAULT_WALLET_NAME: &'static str = "default";
+
+/// Default number of rotating wallet backups to keep (0 disables them)
+pub static DEFAULT_WALLET_BACKUP_COUNT: uint = 0;
+
+/// The number of confirmations after which an output is considered safe
+/// from reorgs for balance reporting purposes
+pub static SAFE_CONFIRMATIONS: uint = 6;
+
+/// The number of blocks, up to the tip, whose median time lock times are
+/// compared against
+pub static MEDIAN_TIME_SPAN: uint = 11;
+
+/// Time in s after which unconfirmed wallet transactions are forgotten
+pub static PENDING_TX_EXPIRY: i64 = 1209600; // 2 weeks
+
+/// Fee rate (satoshi per 1000 bytes) used by the "economic" fee policy
+pub static ECONOMIC_FEE_RATE: u64 = 1000;
+
+/// Fee rate (satoshi per 1000 bytes) used by the "fast" fee policy
+pub static FAST_FEE_RATE: u64 = 20000;
+
+/// Confirmation target, in blocks, of the "economic" fee policy's estimate
+pub static ECONOMIC_CONFIRM_TARGET: uint = 12;
+
+/// Confirmation target, in blocks, of the "fast" fee policy's estimate
+pub static FAST_CONFIRM_TARGET: uint = 2;
+
+/// Maximum confirmation target, in blocks, for which fees are estimated
+pub static FEE_ESTIMATE_MAX_TARGET: uint = 25;
+
+/// Estimated serialized size of a signed pay-to-pubkey-hash input, in bytes
+pub static EST_INPUT_SIZE: u64 = 148;
+
+/// Estimated serialized size of a pay-to-pubkey-hash output, in bytes
+pub static EST_OUTPUT_SIZE: u64 = 34;
+
+/// Maximum number of used addresses to skip over when address reuse is refused
+pub static MAX_ADDRESS_REUSE_SKIP: uint = 100;
+
+/// Largest payload, in bytes, of an OP_RETURN output relayed as standard
+pub static MAX_NULL_DATA_SIZE: uint = 40;
+
+/// Outputs smaller than this (in satoshi) will not be created by the wallet
+pub static DUST_THRESHOLD: u64 = 546;
+
+/// Default size, in bytes, past which a log file is rotated
+pub static DEFAULT_LOG_MAX_SIZE: u64 = 10000000; // 10 MB
+
+/// Default number of rotated log files to keep
+pub static DEFAULT_LOG_FILES: uint = 5;
+
+/// How often, in ms, to check whether a signal has been caught
+pub static SIGNAL_POLL_FREQUENCY: i64 = 250;
+
@@ -0,0 +1,176 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Daemon Support
+//!
+//! Forking into the background, pid files and signal handling, so that
+//! the wallet can be run under a traditional init system.
+//!
+
+use std::c_str::ToCStr;
+use std::io::{File, FileNotFound, IoResult, IoError, PathAlreadyExists};
+use std::io::fs;
+use std::io::timer;
+use std::os;
+use std::path::posix::Path;
+use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+use std::time::Duration;
+use libc;
+
+use constants::SIGNAL_POLL_FREQUENCY;
+
+/// Set by the signal handler on SIGINT or SIGTERM
+static TERMINATE: AtomicBool = INIT_ATOMIC_BOOL;
+/// Set by the signal handler on SIGHUP
+static HANGUP: AtomicBool = INIT_ATOMIC_BOOL;
+
+/// A signal which we act on
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Signal {
+  /// SIGINT or SIGTERM: shut down
+  Terminate,
+  /// SIGHUP: reload the configuration file
+  Hangup
+}
+
+/// Forks into the background: the parent process exits, and the child
+/// starts a new session with stdin on `/dev/null` and stdout and stderr,
+/// i.e. all logging, appended to `log_path`. This must be called before
+/// any tasks are spawned, since only the calling thread survives a fork.
+pub fn daemonize(log_path: &Path) -> IoResult<()> {
+  unsafe {
+    match libc::fork() {
+      -1 => { return Err(IoError::last_error()); }
+      0 => {}
+      _ => { libc::exit(0); }
+    }
+    if libc::setsid() == -1 {
+      return Err(IoError::last_error());
+    }
+
+    let null = "/dev/null".with_c_str(|p| libc::open(p, libc::O_RDONLY, 0));
+    if null == -1 {
+      return Err(IoError::last_error());
+    }
+    let log = log_path.with_c_str(|p| libc::open(p, libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+                                                 libc::S_IRUSR | libc::S_IWUSR));
+    if log == -1 {
+      return Err(IoError::last_error());
+    }
+    if libc::dup2(null, libc::STDIN_FILENO) == -1 ||
+       libc::dup2(log, libc::STDOUT_FILENO) == -1 ||
+       libc::dup2(log, libc::STDERR_FILENO) == -1 {
+      return Err(IoError::last_error());
+    }
+    libc::close(null);
+    libc::close(log);
+  }
+  Ok(())
+}
+
+/// A file containing our process ID, which is deleted when we exit
+pub struct PidFile {
+  path: Path
+}
+
+impl PidFile {
+  /// Writes our process ID to `path`, replacing any stale pid file. Fails
+  /// if the pid file names a process which is still running, or if another
+  /// instance creates it first.
+  pub fn create(path: &Path) -> IoResult<PidFile> {
+    match File::open(path).read_to_string() {
+      Ok(old) => {
+        match from_str::<libc::pid_t>(old.as_slice().trim()) {
+          // Signal 0 checks only whether the process exists; EPERM means
+          // it does, but belongs to someone else
+          Some(old_pid) if unsafe { libc::kill(old_pid, 0) } == 0 ||
+                           os::errno() == libc::EPERM as int => {
+            return Err(IoError {
+              kind: PathAlreadyExists,
+              desc: "already running",
+              detail: Some(format!("process {} holds {}", old_pid, path.display()))
+            });
+          }
+          _ => { try!(fs::unlink(path)); }
+        }
+      }
+      Err(ref e) if e.kind == FileNotFound => {}
+      Err(e) => { return Err(e); }
+    }
+
+    let pid = unsafe { libc::getpid() };
+    let line = format!("{}\n", pid);
+    unsafe {
+      let fd = path.with_c_str(|p| libc::open(p, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+                                              libc::S_IRUSR | libc::S_IWUSR |
+                                              libc::S_IRGRP | libc::S_IROTH));
+      if fd == -1 {
+        return Err(IoError::last_error());
+      }
+      let written = libc::write(fd, line.as_ptr() as *const libc::c_void, line.len() as libc::size_t);
+      let err = if written as uint != line.len() { Some(IoError::last_error()) } else { None };
+      libc::close(fd);
+      match err {
+        Some(e) => {
+          let _ = fs::unlink(path);
+          return Err(e);
+        }
+        None => {}
+      }
+    }
+    Ok(PidFile { path: path.clone() })
+  }
+
+  /// Deletes the pid file
+  pub fn remove(self) -> IoResult<()> {
+    fs::unlink(&self.path)
+  }
+}
+
+extern "C" fn on_signal(signum: libc::c_int) {
+  if signum == libc::SIGHUP {
+    HANGUP.store(true, SeqCst);
+  } else {
+    TERMINATE.store(true, SeqCst);
+  }
+}
+
+/// Installs handlers for SIGINT, SIGTERM and SIGHUP, and returns a receiver
+/// on which caught signals arrive. Signal handlers can do next to nothing
+/// safely, so ours only set flags, which a task polls.
+pub fn catch_signals() -> Receiver<Signal> {
+  unsafe {
+    let handler = on_signal as libc::sighandler_t;
+    libc::signal(libc::SIGINT, handler);
+    libc::signal(libc::SIGTERM, handler);
+    libc::signal(libc::SIGHUP, handler);
+  }
+
+  let (tx, rx) = channel();
+  spawn(proc() {
+    loop {
+      timer::sleep(Duration::milliseconds(SIGNAL_POLL_FREQUENCY));
+      let mut caught = vec![];
+      if TERMINATE.swap(false, SeqCst) { caught.push(Terminate); }
+      if HANGUP.swap(false, SeqCst) { caught.push(Hangup); }
+      for signal in caught.move_iter() {
+        if tx.send_opt(signal).is_err() {
+          return;
+        }
+      }
+    }
+  });
+  rx
+}
+
@@ -0,0 +1,188 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Dashboard
+//!
+//! A live view of a running wallet for the terminal, for operators on
+//! machines without a desktop. It polls each network's RPC server and
+//! redraws sync progress, the peer, the mempool, wallet balances and
+//! coinjoin sessions until it is interrupted.
+//!
+
+use std::io::timer;
+use std::time::Duration;
+use serialize::json;
+
+use constants::DASHBOARD_REFRESH_FREQUENCY;
+use rpc_client::call;
+use user_data::NetworkConfig;
+
+/// ANSI escapes to move the cursor home and clear the screen
+static CLEAR_SCREEN: &'static str = "\x1b[H\x1b[2J";
+
+/// Looks up a field of a JSON object
+pub fn field<'a>(obj: &'a json::Json, name: &str) -> Option<&'a json::Json> {
+  obj.find(&name.to_string())
+}
+
+/// Formats a field of a JSON object for display, or `?` if it is missing
+pub fn show(obj: &json::Json, name: &str) -> String {
+  match field(obj, name) {
+    Some(&json::String(ref s)) => s.clone(),
+    Some(&json::Null) | None => "?".to_string(),
+    Some(value) => value.to_string()
+  }
+}
+
+/// Formats a number of seconds as e.g. `2h05m`
+pub fn format_duration(secs: i64) -> String {
+  if secs < 0 {
+    "0s".to_string()
+  } else if secs < 60 {
+    format!("{}s", secs)
+  } else if secs < 3600 {
+    format!("{}m{:02}s", secs / 60, secs % 60)
+  } else if secs < 86400 {
+    format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+  } else {
+    format!("{}d{:02}h", secs / 86400, (secs % 86400) / 3600)
+  }
+}
+
+/// Formats an amount in satoshi as BTC
+pub fn format_btc(obj: &json::Json, name: &str) -> String {
+  match field(obj, name).and_then(|v| v.as_u64()) {
+    Some(value) => format!("{}.{:08}", value / 100000000, value % 100000000),
+    None => "?".to_string()
+  }
+}
+
+/// Draws one network's section of the screen
+fn draw_network(config: &NetworkConfig, out: &mut String) {
+  let info = match call(config, "getinfo", vec![]) {
+    Ok(info) => info,
+    Err(e) => {
+      out.push_str(format!("{}: not running ({})\n\n", config.network, e).as_slice());
+      return;
+    }
+  };
+  let uptime = field(&info, "uptime").and_then(|v| v.as_i64()).unwrap_or(0);
+  out.push_str(format!("{}: version {}, up {}, debug level {}\n", config.network,
+                       show(&info, "version"), format_duration(uptime),
+                       show(&info, "debug_level")).as_slice());
+
+  match call(config, "getblockchaininfo", vec![]) {
+    Ok(chain) => {
+      match field(&chain, "sync") {
+        Some(sync) => {
+          let progress = field(sync, "progress").and_then(|v| v.as_f64()).unwrap_or(0.0);
+          let eta = match field(sync, "eta").and_then(|v| v.as_i64()) {
+            Some(eta) => format!(", eta {}", format_duration(eta)),
+            None => String::new()
+          };
+          out.push_str(format!("  sync      {} at {} of {} ({:.1}%){}\n",
+                               show(sync, "stage"), show(sync, "height"),
+                               show(sync, "target_height"), progress * 100.0, eta).as_slice());
+        }
+        None => {}
+      }
+      out.push_str(format!("  chain     {} blocks, tip {}\n", show(&chain, "blocks"),
+                           show(&chain, "bestblockhash")).as_slice());
+    }
+    Err(e) => { out.push_str(format!("  chain     unavailable ({})\n", e).as_slice()); }
+  }
+
+  match call(config, "getpeerinfo", vec![]) {
+    Ok(json::List(peers)) => {
+      for peer in peers.iter() {
+        let ping = match field(peer, "pingtime").and_then(|v| v.as_f64()) {
+          Some(secs) => format!("{}ms", (secs * 1000.0) as u64),
+          None => "-".to_string()
+        };
+        out.push_str(format!("  peer      {} {} height {} ping {} msgs {}\n",
+                             show(peer, "addr"), show(peer, "subver"),
+                             show(peer, "startingheight"), ping,
+                             show(peer, "msgrecv")).as_slice());
+      }
+    }
+    Ok(_) => {}
+    Err(e) => { out.push_str(format!("  peer      unavailable ({})\n", e).as_slice()); }
+  }
+
+  match call(config, "getmempoolinfo", vec![]) {
+    Ok(mempool) => {
+      let bytes = field(&mempool, "bytes").and_then(|v| v.as_u64()).unwrap_or(0);
+      let max = field(&mempool, "maxmempool").and_then(|v| v.as_u64()).unwrap_or(0);
+      out.push_str(format!("  mempool   {} transactions, {} of {} kB\n", show(&mempool, "size"),
+                           bytes / 1000, max / 1000).as_slice());
+    }
+    Err(e) => { out.push_str(format!("  mempool   unavailable ({})\n", e).as_slice()); }
+  }
+
+  // Only present if wallet RPC is enabled
+  match field(&info, "balances") {
+    Some(&json::Object(ref wallets)) => {
+      for (name, balances) in wallets.iter() {
+        out.push_str(format!("  wallet    {}: {} confirmed, {} safe, {} unconfirmed, {} timelocked\n",
+                             name, format_btc(balances, "confirmed"), format_btc(balances, "safe"),
+                             format_btc(balances, "unconfirmed"),
+                             format_btc(balances, "timelocked")).as_slice());
+      }
+    }
+    _ => {}
+  }
+
+  if config.coinjoin.enabled {
+    match call(config, "coinjoin_list", vec![]) {
+      Ok(list) => {
+        match field(&list, "sessions") {
+          Some(&json::List(ref sessions)) if sessions.len() > 0 => {
+            for session in sessions.iter() {
+              let remaining = field(session, "time_remaining").and_then(|v| v.as_i64()).unwrap_or(0);
+              out.push_str(format!("  coinjoin  {} {} {} BTC, {} participants, {} left\n",
+                                   show(session, "id"), show(session, "state"),
+                                   format_btc(session, "target_value"),
+                                   show(session, "participants"),
+                                   format_duration(remaining / 1000)).as_slice());
+            }
+          }
+          _ => { out.push_str("  coinjoin  no open sessions\n"); }
+        }
+      }
+      Err(e) => { out.push_str(format!("  coinjoin  unavailable ({})\n", e).as_slice()); }
+    }
+  }
+  out.push_str("\n");
+}
+
+/// Redraws the dashboard for the given networks until interrupted. Returns
+/// false at once if there are none.
+pub fn run(configs: &[NetworkConfig]) -> bool {
+  if configs.is_empty() {
+    println!("No network to show; choose one with --network.");
+    return false;
+  }
+  loop {
+    // Build the whole screen before clearing, so it does not flicker
+    // while we wait on the RPC servers
+    let mut out = String::new();
+    for config in configs.iter() {
+      draw_network(config, &mut out);
+    }
+    out.push_str("Press Ctrl-C to exit.");
+    print!("{}{}\n", CLEAR_SCREEN, out);
+    timer::sleep(Duration::milliseconds(DASHBOARD_REFRESH_FREQUENCY));
+  }
+}
+
@@ -0,0 +1,106 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Difficulty
+//!
+//! Conversions between the compact "nBits" encoding of block targets, full
+//! 256-bit targets, and the floating-point difficulty that humans and
+//! monitoring tools expect.
+//!
+
+use bitcoin::util::uint::Uint256;
+
+/// Decodes a compact target: the top byte is a base-256 exponent and the
+/// lower three bytes a mantissa. Negative or overflowing targets, which
+/// are invalid in block headers, decode as zero.
+pub fn target_from_compact(bits: u32) -> Uint256 {
+  let exponent = (bits >> 24) as uint;
+  let mantissa = (bits & 0x007fffff) as u64;
+  if bits & 0x00800000 != 0 || exponent > 34 {
+    return Uint256([0, 0, 0, 0]);
+  }
+  let mut words = [0u64, ..4];
+  if exponent <= 3 {
+    words[0] = mantissa >> (8 * (3 - exponent));
+  } else {
+    // Shift the mantissa left by 8 * (exponent - 3) bits across the words
+    let shift = 8 * (exponent - 3);
+    let (word, bit) = (shift / 64, shift % 64);
+    words[word] = mantissa << bit;
+    if bit > 0 && word + 1 < 4 {
+      words[word + 1] = mantissa >> (64 - bit);
+    }
+  }
+  Uint256(words)
+}
+
+/// Encodes a target in compact form, losing all but its top 23 bits
+pub fn compact_from_target(target: &Uint256) -> u32 {
+  let &Uint256(ref words) = target;
+  // Find the size of the target in bytes
+  let mut size = 32u;
+  while size > 0 && (words[(size - 1) / 8] >> (8 * ((size - 1) % 8))) & 0xff == 0 {
+    size -= 1;
+  }
+  // Read out the top three bytes as the mantissa
+  let byte = |n: uint| (words[n / 8] >> (8 * (n % 8))) & 0xff;
+  let mut mantissa = 0u64;
+  for i in range(0u, 3) {
+    if size > i {
+      mantissa |= byte(size - 1 - i) << (8 * (2 - i));
+    }
+  }
+  // The high bit of the mantissa is a sign bit, so avoid setting it
+  if mantissa & 0x00800000 != 0 {
+    mantissa >>= 8;
+    size += 1;
+  }
+  ((size as u32) << 24) | mantissa as u32
+}
+
+/// Computes the difficulty of a compact target, i.e. the ratio of the
+/// easiest possible target to it, as bitcoind does
+pub fn difficulty_from_compact(bits: u32) -> f64 {
+  let mantissa = bits & 0x00ffffff;
+  if mantissa == 0 {
+    return 0.0;
+  }
+  let mut shift = (bits >> 24) & 0xff;
+  let mut diff = 0x0000ffff as f64 / mantissa as f64;
+  while shift < 29 {
+    diff *= 256.0;
+    shift += 1;
+  }
+  while shift > 29 {
+    diff /= 256.0;
+    shift -= 1;
+  }
+  diff
+}
+
+/// Converts a 256-bit integer to a float, e.g. to report chain work
+pub fn uint256_to_f64(n: &Uint256) -> f64 {
+  let &Uint256(ref words) = n;
+  let mut ret = 0.0;
+  for word in words.iter().rev() {
+    ret = ret * 18446744073709551616.0 + *word as f64;
+  }
+  ret
+}
+
+/// Hex-encodes a 256-bit integer, most significant digit first
+pub fn uint256_hex(n: &Uint256) -> String {
+  let &Uint256(ref words) = n;
+  format!("{:016x}{:016x}{:016x}{:016x}", words[3], words[2], words[1], words[0])
+}
@@ -0,0 +1,350 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Disk Utilities
+//!
+//! Checking free space before big writes, and replacing files safely, so
+//! that a full disk or a failed write never leaves a half-written cache
+//! file for the next startup to choke on. Cache files are written with a
+//! SHA256 checksum alongside, which is checked when they are read back.
+//!
+
+use std::c_str::ToCStr;
+use std::io::{BufferedReader, BufferedWriter, File, IoError, IoResult, InvalidInput, OtherIoError};
+use std::io::{fs, MemReader};
+use std::path::posix::Path;
+use std::u64;
+use libc::{c_char, c_int, c_ulong};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use bitcoin::network::encodable::{ConsensusDecodable, ConsensusEncodable};
+use bitcoin::network::serialize::{RawDecoder, RawEncoder, deserialize};
+use bitcoin::util::hash::Sha256dHash;
+
+/// `struct statvfs`, as laid out by glibc on 64-bit Linux
+#[cfg(target_os = "linux", target_arch = "x86_64")]
+#[repr(C)]
+struct StatVfs {
+  f_bsize: c_ulong,
+  f_frsize: c_ulong,
+  f_blocks: u64,
+  f_bfree: u64,
+  f_bavail: u64,
+  f_files: u64,
+  f_ffree: u64,
+  f_favail: u64,
+  f_fsid: c_ulong,
+  f_flag: c_ulong,
+  f_namemax: c_ulong,
+  f_spare: [c_int, ..6]
+}
+
+#[cfg(target_os = "linux", target_arch = "x86_64")]
+extern {
+  fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
+}
+
+/// Returns the number of bytes available to us on the filesystem holding
+/// `path`, which must exist
+#[cfg(target_os = "linux", target_arch = "x86_64")]
+pub fn available_space(path: &Path) -> IoResult<u64> {
+  let mut buf = StatVfs {
+    f_bsize: 0, f_frsize: 0, f_blocks: 0, f_bfree: 0, f_bavail: 0, f_files: 0,
+    f_ffree: 0, f_favail: 0, f_fsid: 0, f_flag: 0, f_namemax: 0, f_spare: [0, ..6]
+  };
+  let ret = path.with_c_str(|p| unsafe { statvfs(p, &mut buf) });
+  if ret == 0 {
+    Ok(buf.f_bavail * buf.f_frsize as u64)
+  } else {
+    Err(IoError::last_error())
+  }
+}
+
+/// Elsewhere we do not know the layout of `struct statvfs`, so claim to
+/// have unlimited space and let the write find out
+#[cfg(not(target_os = "linux"))]
+pub fn available_space(_: &Path) -> IoResult<u64> {
+  Ok(u64::MAX)
+}
+
+#[cfg(target_os = "linux", not(target_arch = "x86_64"))]
+pub fn available_space(_: &Path) -> IoResult<u64> {
+  Ok(u64::MAX)
+}
+
+/// Checks that there is room to write a new version of the file at `path`
+/// alongside the old one, estimating its size as the old size plus a
+/// margin of `margin_pct` percent
+pub fn check_space(path: &Path, margin_pct: u64) -> IoResult<()> {
+  let old_size = match fs::stat(path) {
+    Ok(stat) => stat.size,
+    // Nothing to go by, so let the write find out
+    Err(_) => { return Ok(()); }
+  };
+  let needed = old_size + old_size * margin_pct / 100;
+  let available = try!(available_space(&path.dir_path()));
+  if available < needed {
+    return Err(IoError {
+      kind: OtherIoError,
+      desc: "not enough free disk space",
+      detail: Some(format!("{}: need about {} bytes, have {}", path.display(), needed, available))
+    });
+  }
+  Ok(())
+}
+
+/// Returns the path at which to write a new version of `path`, before
+/// moving it into place with `commit`
+pub fn temp_path(path: &Path) -> Path {
+  let mut name = path.filename().unwrap_or(b"file").to_vec();
+  name.push_all(b".tmp");
+  path.with_filename(name)
+}
+
+/// Moves a new version of `path`, written to `temp_path(path)`, into place
+pub fn commit(path: &Path) -> IoResult<()> {
+  fs::rename(&temp_path(path), path)
+}
+
+/// Returns the path of the marker written by `commit_group`, named after
+/// the first file of the group
+pub fn commit_marker_path(path: &Path) -> Path {
+  let mut name = path.filename().unwrap_or(b"file").to_vec();
+  name.push_all(b".commit");
+  path.with_filename(name)
+}
+
+/// Moves new versions of several files written by `write_checksummed` into
+/// place as one. A marker listing the files is written first, and only
+/// removed once every file is in place, so that if we stop partway
+/// through, `finish_commit` completes the group on the next startup rather
+/// than leaving old and new files side by side.
+pub fn commit_group(paths: &[&Path]) -> IoResult<()> {
+  let marker = commit_marker_path(paths[0]);
+  {
+    let mut file = try!(File::create(&temp_path(&marker)));
+    for path in paths.iter() {
+      try!(file.write(path.as_vec()));
+      try!(file.write_u8(b'\n'));
+    }
+    try!(file.fsync());
+  }
+  try!(commit(&marker));
+  for path in paths.iter() {
+    try!(commit_checksummed(*path));
+  }
+  fs::unlink(&marker)
+}
+
+/// Completes a `commit_group`, named by its first file, which was cut
+/// short. Returns whether there was one to complete.
+pub fn finish_commit(path: &Path) -> IoResult<bool> {
+  let marker = commit_marker_path(path);
+  if !marker.exists() {
+    return Ok(false);
+  }
+  let list = try!(File::open(&marker).read_to_end());
+  for name in list.as_slice().split(|&b| b == b'\n').filter(|name| !name.is_empty()) {
+    let path = Path::new(name);
+    // Files without a new version waiting were already moved into place
+    for path in [path.clone(), checksum_path(&path)].iter() {
+      if temp_path(path).exists() {
+        try!(commit(path));
+      }
+    }
+  }
+  try!(fs::unlink(&marker));
+  Ok(true)
+}
+
+/// Deletes a failed new version of `path`, leaving the old one as it was
+pub fn abandon(path: &Path) {
+  let _ = fs::unlink(&temp_path(path));
+}
+
+/// Returns the path of the checksum kept alongside `path`
+pub fn checksum_path(path: &Path) -> Path {
+  let mut name = path.filename().unwrap_or(b"file").to_vec();
+  name.push_all(b".sha256");
+  path.with_filename(name)
+}
+
+/// A writer which hashes everything written through it
+pub struct HashingWriter<W> {
+  inner: W,
+  hasher: Sha256
+}
+
+impl<W: Writer> HashingWriter<W> {
+  /// Wraps a writer
+  pub fn new(inner: W) -> HashingWriter<W> {
+    HashingWriter { inner: inner, hasher: Sha256::new() }
+  }
+
+  /// Returns the wrapped writer and the hex hash of what was written
+  pub fn finish(mut self) -> (W, String) {
+    let digest = self.hasher.result_str();
+    (self.inner, digest)
+  }
+}
+
+impl<W: Writer> Writer for HashingWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    self.hasher.input(buf);
+    self.inner.write(buf)
+  }
+
+  fn flush(&mut self) -> IoResult<()> {
+    self.inner.flush()
+  }
+}
+
+/// An incremental SHA256d hasher, for data too big to collect into one
+/// slice. As a `Writer`, structures can be serialized straight into it.
+pub struct Sha256dWriter {
+  sha: Sha256
+}
+
+impl Sha256dWriter {
+  /// Starts a new hash
+  pub fn new() -> Sha256dWriter {
+    Sha256dWriter { sha: Sha256::new() }
+  }
+
+  /// Adds data to the hash
+  pub fn input(&mut self, data: &[u8]) {
+    self.sha.input(data);
+  }
+
+  /// Finishes the hash
+  pub fn finish(mut self) -> Sha256dHash {
+    let mut out = [0u8, ..32];
+    self.sha.result(out.as_mut_slice());
+    self.sha.reset();
+    self.sha.input(out.as_slice());
+    self.sha.result(out.as_mut_slice());
+    deserialize(out.to_vec()).unwrap()
+  }
+}
+
+impl Writer for Sha256dWriter {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    self.input(buf);
+    Ok(())
+  }
+}
+
+/// Computes the SHA256d hash of a structure's serialization, without
+/// holding the serialization in memory
+pub fn hash_serialized<T: ConsensusEncodable<RawEncoder<Sha256dWriter>, IoError>>(data: &T)
+                                                                                 -> IoResult<Sha256dHash> {
+  let mut encoder = RawEncoder::new(Sha256dWriter::new());
+  try!(data.consensus_encode(&mut encoder));
+  Ok(encoder.unwrap().finish())
+}
+
+/// A reader which hashes everything read through it
+pub struct HashingReader<R> {
+  inner: R,
+  hasher: Sha256
+}
+
+impl<R: Reader> HashingReader<R> {
+  /// Wraps a reader
+  pub fn new(inner: R) -> HashingReader<R> {
+    HashingReader { inner: inner, hasher: Sha256::new() }
+  }
+
+  /// Returns the hex hash of what has been read
+  pub fn digest(&mut self) -> String {
+    self.hasher.result_str()
+  }
+}
+
+impl<R: Reader> Reader for HashingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    let n = try!(self.inner.read(buf));
+    self.hasher.input(buf.slice_to(n));
+    Ok(n)
+  }
+}
+
+/// The encoder used for checksummed files
+pub type ChecksumEncoder = RawEncoder<HashingWriter<BufferedWriter<File>>>;
+
+/// The decoder used for checksummed files, which are read whole into
+/// memory and decoded from there
+pub type ChecksumDecoder = RawDecoder<MemReader>;
+
+/// Writes `data`, and its checksum, to the temporary paths for `path`, to
+/// be moved into place with `commit_checksummed`
+pub fn write_checksummed<T: ConsensusEncodable<ChecksumEncoder, IoError>>(path: &Path, data: &T)
+                                                                         -> IoResult<()> {
+  let file = try!(File::create(&temp_path(path)));
+  let mut encoder = RawEncoder::new(HashingWriter::new(BufferedWriter::new(file)));
+  try!(data.consensus_encode(&mut encoder));
+  let (mut writer, digest) = encoder.unwrap().finish();
+  try!(writer.flush());
+  // Make sure the new version is really on disk before anything replaces
+  // the old one with it
+  try!(writer.get_mut_ref().fsync());
+  let mut sum_file = try!(File::create(&temp_path(&checksum_path(path))));
+  try!(sum_file.write_line(digest.as_slice()));
+  sum_file.fsync()
+}
+
+/// Moves a file written by `write_checksummed`, and its checksum, into place
+pub fn commit_checksummed(path: &Path) -> IoResult<()> {
+  try!(commit(path));
+  commit(&checksum_path(path))
+}
+
+/// Deletes a failed file written by `write_checksummed`, and its checksum
+pub fn abandon_checksummed(path: &Path) {
+  abandon(path);
+  abandon(&checksum_path(path));
+}
+
+/// Reads a file written by `write_checksummed`, failing if it does not
+/// match its checksum. Returns the data, and whether there was a checksum
+/// to check, which files written by older versions lack.
+pub fn read_checksummed<T: ConsensusDecodable<ChecksumDecoder, IoError>>(path: &Path)
+                                                                        -> IoResult<(T, bool)> {
+  // Reading the file in one go is much faster than pulling it through the
+  // decoder a few bytes at a time, and lets us reject a corrupt file
+  // before spending any time decoding it
+  let raw = try!(BufferedReader::new(try!(File::open(path))).read_to_end());
+  let checked = match File::open(&checksum_path(path)).read_to_string() {
+    Ok(expected) => {
+      let mut hasher = Sha256::new();
+      hasher.input(raw.as_slice());
+      let digest = hasher.result_str();
+      if expected.as_slice().trim() != digest.as_slice() {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "checksum mismatch",
+          detail: Some(format!("{}: expected {}, got {}", path.display(), expected.as_slice().trim(), digest))
+        });
+      }
+      true
+    }
+    Err(_) => false
+  };
+  let mut decoder = RawDecoder::new(MemReader::new(raw));
+  let data: T = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+  Ok((data, checked))
+}
+
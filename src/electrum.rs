@@ -0,0 +1,458 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Electrum Server
+//!
+//! Enough of the Electrum protocol, served over plain TCP on `electrum_port`,
+//! for light clients to use us as their backend. Requests and responses are
+//! JSON-RPC 2.0 objects, one per line. Scripts are named as Electrum names
+//! them, by the reversed hex of their SHA256 hash, and looked up in the
+//! address index. The methods served are
+//!
+//!   * `server.version`, `server.banner` and `server.ping`
+//!   * `blockchain.headers.subscribe`
+//!   * `blockchain.scripthash.get_balance`, `get_history`, `listunspent`,
+//!     `subscribe` and `unsubscribe`
+//!   * `blockchain.transaction.broadcast`
+//!
+//! Mempool transactions are reported alongside the address index's
+//! confirmed ones, at height 0. Subscribed clients are told of new tips, and
+//! of changes to their scripts' histories as blocks come in and, checked
+//! every `ELECTRUM_POLL_FREQUENCY` seconds, as the mempool changes. A
+//! broadcast waits to hear whether the mempool accepted the transaction,
+//! and reports it as an error if not.
+//!
+
+use std::collections::{HashMap, TreeMap};
+use std::io::{BufferedReader, IoResult, IoError, OtherIoError};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::timer::Timer;
+use std::io::{Acceptor, Listener};
+use std::mem;
+use std::sync::RWLockReadGuard;
+use std::time::Duration;
+use serialize::hex::FromHex;
+use serialize::json;
+use serialize::json::ToJson;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize_hex};
+use bitcoin::util::hash::Sha256dHash;
+use jsonrpc::error::{standard_error, Error, InvalidParams, InvalidRequest, MethodNotFound};
+
+use address_index::{AddressIndex, script_hash};
+use bitcoind::{Debug, SharedState, Warning};
+use constants::{ELECTRUM_MAX_LINE, ELECTRUM_POLL_FREQUENCY, ELECTRUM_PROTOCOL_VERSION};
+use constants::{ELECTRUM_BROADCAST_TIMEOUT, ELECTRUM_MAX_SUBSCRIPTIONS};
+use events::{Blocks, Chain, EventBus, NewTip, TxAccepted, TxRejected};
+use version;
+
+/// Starts listening for Electrum clients on the configured port, serving
+/// each from a task of its own
+pub fn start(shared: SharedState, events: EventBus) -> IoResult<()> {
+  let port = match shared.config.electrum_port {
+    Some(port) => port,
+    None => { return Ok(()); }
+  };
+  if shared.address_index.is_none() {
+    return Err(IoError {
+      kind: OtherIoError,
+      desc: "the Electrum server needs the address index",
+      detail: None
+    });
+  }
+  let listener = try!(TcpListener::bind(shared.config.rpc_server_addr.as_slice(), port));
+  let mut acceptor = try!(listener.listen());
+  spawn(proc() {
+    let (network, debug_level) = (shared.config.network, shared.config.debug_level);
+    for stream in acceptor.incoming() {
+      match stream {
+        Ok(stream) => {
+          let client = Client::new(stream, shared.clone(), events.clone());
+          spawn(proc() { client.serve() });
+        }
+        Err(e) => {
+          debug!((network, debug_level), Warning, "Electrum server: failed to accept: {}", e);
+        }
+      }
+    }
+  });
+  Ok(())
+}
+
+/// A connected Electrum client
+struct Client {
+  stream: TcpStream,
+  shared: SharedState,
+  events: EventBus,
+  /// Whether the client is subscribed to new tips
+  headers: bool,
+  /// The scripts the client is subscribed to, by their Electrum script
+  /// hash, and the status last sent for each
+  scripts: HashMap<String, json::Json>
+}
+
+impl Client {
+  fn new(stream: TcpStream, shared: SharedState, events: EventBus) -> Client {
+    Client { stream: stream, shared: shared, events: events, headers: false,
+             scripts: HashMap::new() }
+  }
+
+  /// Answers the client's requests, and sends it notifications, until it
+  /// hangs up
+  fn serve(mut self) {
+    let (network, debug_level) = (self.shared.config.network, self.shared.config.debug_level);
+    let peer = self.stream.peer_name().map(|a| a.to_string()).unwrap_or("unknown".to_string());
+    debug!((network, debug_level), Debug, "Electrum client {} connected", peer);
+
+    let (line_tx, line_rx) = channel();
+    let reader = self.stream.clone();
+    spawn(proc() { read_lines(reader, line_tx) });
+    let event_rx = self.events.subscribe(vec![Blocks]);
+    let mut timer = Timer::new().unwrap();
+    let poll = timer.periodic(Duration::seconds(ELECTRUM_POLL_FREQUENCY));
+
+    loop {
+      let mut line = None;
+      let mut new_tip = false;
+      let mut check = false;
+      nu_select!(
+        l from line_rx => { line = Some(l); },
+        event from event_rx => {
+          match event {
+            NewTip(_, _) => { new_tip = true; }
+            _ => {}
+          }
+        },
+        () from poll => { check = true; }
+      );
+      let ok = match line {
+        Some(Some(line)) => self.handle_line(line.as_slice()),
+        Some(None) => { break; }
+        None => {
+          let header = if new_tip && self.headers { Some(self.tip_header()) } else { None };
+          let notified = match header {
+            Some(header) => self.notify("blockchain.headers.subscribe", vec![header]).is_ok(),
+            None => true
+          };
+          notified && (!(new_tip || check) || self.check_scripts().is_ok())
+        }
+      };
+      if !ok {
+        break;
+      }
+    }
+    // Wake the reader, if it is still waiting on the client
+    let _ = self.stream.close_read();
+    debug!((network, debug_level), Debug, "Electrum client {} disconnected", peer);
+  }
+
+  /// Answers one line from the client. Returns false if the client could
+  /// not be written to.
+  fn handle_line(&mut self, line: &str) -> bool {
+    let request = match json::from_str(line) {
+      Ok(json::Object(obj)) => obj,
+      _ => {
+        return self.respond(json::Null, Err(standard_error(InvalidRequest, None))).is_ok();
+      }
+    };
+    let id = request.find(&"id".to_string()).map(|id| id.clone()).unwrap_or(json::Null);
+    let method = match request.find(&"method".to_string()) {
+      Some(&json::String(ref method)) => method.clone(),
+      _ => {
+        return self.respond(id, Err(standard_error(InvalidRequest, None))).is_ok();
+      }
+    };
+    let params = match request.find(&"params".to_string()) {
+      Some(&json::List(ref params)) => params.clone(),
+      None | Some(&json::Null) => vec![],
+      _ => {
+        return self.respond(id, Err(standard_error(InvalidParams, None))).is_ok();
+      }
+    };
+    let result = self.call(method.as_slice(), params.as_slice());
+    self.respond(id, result).is_ok()
+  }
+
+  /// Runs a request
+  fn call(&mut self, method: &str, params: &[json::Json]) -> Result<json::Json, Error> {
+    match method {
+      "server.version" => {
+        Ok(json::List(vec![json::String(version::version_string()),
+                           json::String(ELECTRUM_PROTOCOL_VERSION.to_string())]))
+      }
+      "server.banner" => Ok(json::String(version::version_string())),
+      "server.ping" => Ok(json::Null),
+      "blockchain.headers.subscribe" => {
+        self.headers = true;
+        Ok(self.tip_header())
+      }
+      "blockchain.scripthash.get_balance" => {
+        let (hash, script) = try!(self.script_param(params));
+        let unconfirmed = self.mempool_changes(hash.as_slice()).iter()
+                              .fold(0i64, |sum, &(_, change)| sum + change);
+        let mut obj = TreeMap::new();
+        obj.insert("confirmed".to_string(), self.index().balance(script.as_slice()).to_json());
+        obj.insert("unconfirmed".to_string(), unconfirmed.to_json());
+        Ok(json::Object(obj))
+      }
+      "blockchain.scripthash.get_history" => {
+        let (hash, script) = try!(self.script_param(params));
+        let mempool = self.mempool_changes(hash.as_slice());
+        let mut history = self.index().history(script.as_slice());
+        history.extend(mempool.move_iter().map(|(txid, _)| (txid, 0)));
+        Ok(json::List(history.move_iter().map(|(txid, height)| {
+          let mut obj = TreeMap::new();
+          obj.insert("tx_hash".to_string(), txid.to_json());
+          obj.insert("height".to_string(), height.to_json());
+          json::Object(obj)
+        }).collect()))
+      }
+      "blockchain.scripthash.listunspent" => {
+        let (_, script) = try!(self.script_param(params));
+        let utxos = self.index().utxos(script.as_slice());
+        Ok(json::List(utxos.move_iter().map(|out| {
+          let mut obj = TreeMap::new();
+          obj.insert("tx_hash".to_string(), out.txid.to_json());
+          obj.insert("tx_pos".to_string(), out.vout.to_json());
+          obj.insert("height".to_string(), out.height.to_json());
+          obj.insert("value".to_string(), out.value.to_json());
+          json::Object(obj)
+        }).collect()))
+      }
+      "blockchain.scripthash.subscribe" => {
+        let name = try!(string_param(params, 0));
+        if !self.scripts.contains_key(&name) && self.scripts.len() >= ELECTRUM_MAX_SUBSCRIPTIONS {
+          return Err(electrum_error(format!("at most {} scripts may be subscribed to",
+                                            ELECTRUM_MAX_SUBSCRIPTIONS)));
+        }
+        let (hash, script) = try!(self.script_param(params));
+        let mempool = self.mempool_changes(hash.as_slice());
+        let status = status(&*self.index(), script.as_slice(), mempool.as_slice());
+        self.scripts.insert(name, status.clone());
+        Ok(status)
+      }
+      "blockchain.scripthash.unsubscribe" => {
+        let hash = try!(string_param(params, 0));
+        Ok(json::Boolean(self.scripts.remove(&hash)))
+      }
+      "blockchain.transaction.broadcast" => {
+        let hex = try!(string_param(params, 0));
+        let raw = hex.as_slice().from_hex().ok();
+        let tx: Transaction = match raw.and_then(|raw| deserialize(raw).ok()) {
+          Some(tx) => tx,
+          None => { return Err(standard_error(InvalidParams, Some(json::String(hex)))); }
+        };
+        self.broadcast(tx)
+      }
+      _ => Err(standard_error(MethodNotFound, Some(json::String(method.to_string()))))
+    }
+  }
+
+  fn index<'a>(&'a self) -> RWLockReadGuard<'a, AddressIndex> {
+    self.shared.address_index.as_ref().unwrap().read()
+  }
+
+  /// The script hash parameter, and the script it names, which need not
+  /// have any confirmed history
+  fn script_param(&self, params: &[json::Json]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let hex = try!(string_param(params, 0));
+    let mut hash = match hex.as_slice().from_hex() {
+      Ok(ref hash) if hash.len() == 32 => hash.clone(),
+      _ => { return Err(standard_error(InvalidParams, Some(json::String(hex)))); }
+    };
+    // Electrum gives the hash reversed, like a txid
+    hash.reverse();
+    let script = self.index().script_by_hash(hash.as_slice()).unwrap_or(vec![]);
+    Ok((hash, script))
+  }
+
+  /// The mempool transactions paying to or spending from the script with
+  /// the given hash, by txid, with the net change each makes to its
+  /// balance. The address index must not be locked, since it comes
+  /// between the UTXO set and the mempool in the lock order.
+  fn mempool_changes(&self, hash: &[u8]) -> Vec<(Sha256dHash, i64)> {
+    let utxo_set = self.shared.utxo_set.read();
+    let mempool = self.shared.mempool.read();
+    let mut changes = mempool.script_changes(&*utxo_set,
+                                             |script| script_hash(script).as_slice() == hash);
+    changes.sort_by(|&(a, _), &(b, _)| format!("{:x}", a).cmp(&format!("{:x}", b)));
+    changes
+  }
+
+  /// Hands a transaction to the idle loop, the only one which can reach
+  /// the peer, and waits to hear whether the mempool accepted it
+  fn broadcast(&self, tx: Transaction) -> Result<json::Json, Error> {
+    let txid = tx.bitcoin_hash();
+    // Subscribe before looking in the mempool, so that the transaction
+    // cannot arrive from the peer unnoticed in between
+    let event_rx = self.events.subscribe(vec![Chain]);
+    if self.shared.mempool.read().get(&txid).is_some() {
+      return Ok(txid.to_json());
+    }
+    if self.shared.relay_tx.send_opt((tx, "electrum")).is_err() {
+      return Err(electrum_error("the node is shutting down".to_string()));
+    }
+    let mut timer = Timer::new().unwrap();
+    let timeout = timer.oneshot(Duration::seconds(ELECTRUM_BROADCAST_TIMEOUT));
+    let mut result = None;
+    while result.is_none() {
+      nu_select!(
+        event from event_rx => {
+          match event {
+            TxAccepted(ref tx) if tx.bitcoin_hash() == txid => {
+              result = Some(Ok(txid.to_json()));
+            }
+            TxRejected(ref tx, ref reason) if tx.bitcoin_hash() == txid => {
+              result = Some(Err(electrum_error(format!("transaction rejected: {}", reason))));
+            }
+            _ => {}
+          }
+        },
+        () from timeout => {
+          result = Some(Err(electrum_error("timed out waiting for the mempool".to_string())));
+        }
+      );
+    }
+    result.unwrap()
+  }
+
+  /// The current tip, as given to `blockchain.headers.subscribe`
+  fn tip_header(&self) -> json::Json {
+    let blockchain = self.shared.blockchain.read();
+    let tip = blockchain.get_block(blockchain.best_tip_hash()).unwrap();
+    let mut obj = TreeMap::new();
+    obj.insert("height".to_string(), tip.height.to_json());
+    obj.insert("hex".to_string(), json::String(serialize_hex(&tip.block.header).unwrap()));
+    json::Object(obj)
+  }
+
+  /// Sends a notification for each subscribed script whose status changed
+  fn check_scripts(&mut self) -> IoResult<()> {
+    // Look in the mempool before locking the address index
+    let mut mempool = HashMap::new();
+    for hash in self.scripts.keys() {
+      let mut raw = hash.as_slice().from_hex().unwrap();
+      raw.reverse();
+      mempool.insert(hash.clone(), (self.mempool_changes(raw.as_slice()), raw));
+    }
+    let mut changed = vec![];
+    {
+      let index = self.index();
+      for (hash, old) in self.scripts.iter() {
+        let &(ref changes, ref raw) = mempool.find(hash).unwrap();
+        let script = index.script_by_hash(raw.as_slice()).unwrap_or(vec![]);
+        let new = status(&*index, script.as_slice(), changes.as_slice());
+        if new != *old {
+          changed.push((hash.clone(), new));
+        }
+      }
+    }
+    for (hash, new) in changed.move_iter() {
+      self.scripts.insert(hash.clone(), new.clone());
+      try!(self.notify("blockchain.scripthash.subscribe", vec![json::String(hash), new]));
+    }
+    Ok(())
+  }
+
+  fn respond(&mut self, id: json::Json, result: Result<json::Json, Error>) -> IoResult<()> {
+    let mut obj = TreeMap::new();
+    obj.insert("jsonrpc".to_string(), json::String("2.0".to_string()));
+    obj.insert("id".to_string(), id);
+    match result {
+      Ok(result) => { obj.insert("result".to_string(), result); }
+      Err(e) => {
+        let mut err = TreeMap::new();
+        err.insert("code".to_string(), e.code.to_json());
+        err.insert("message".to_string(), json::String(e.message));
+        obj.insert("error".to_string(), json::Object(err));
+      }
+    }
+    self.send(json::Object(obj))
+  }
+
+  fn notify(&mut self, method: &str, params: Vec<json::Json>) -> IoResult<()> {
+    let mut obj = TreeMap::new();
+    obj.insert("jsonrpc".to_string(), json::String("2.0".to_string()));
+    obj.insert("method".to_string(), json::String(method.to_string()));
+    obj.insert("params".to_string(), json::List(params));
+    self.send(json::Object(obj))
+  }
+
+  fn send(&mut self, message: json::Json) -> IoResult<()> {
+    try!(self.stream.write_str(message.to_string().as_slice()));
+    try!(self.stream.write_u8(b'\n'));
+    self.stream.flush()
+  }
+}
+
+/// The status of a script, as Electrum defines it: the SHA256 of each
+/// transaction in its history, with its height, mempool transactions last
+/// at height 0, or null if it has none
+fn status(index: &AddressIndex, script: &[u8], mempool: &[(Sha256dHash, i64)]) -> json::Json {
+  let history = index.history(script);
+  if history.is_empty() && mempool.is_empty() {
+    return json::Null;
+  }
+  let mut hasher = Sha256::new();
+  for &(txid, height) in history.iter() {
+    hasher.input_str(format!("{:x}:{}:", txid, height).as_slice());
+  }
+  for &(txid, _) in mempool.iter() {
+    hasher.input_str(format!("{:x}:0:", txid).as_slice());
+  }
+  json::String(hasher.result_str())
+}
+
+/// An error for the client, with the code Electrum servers use for
+/// requests they refuse
+fn electrum_error(message: String) -> Error {
+  Error { code: 1, message: message, data: None }
+}
+
+/// A string parameter
+fn string_param(params: &[json::Json], n: uint) -> Result<String, Error> {
+  match params.get(n) {
+    Some(&json::String(ref s)) => Ok(s.clone()),
+    _ => Err(standard_error(InvalidParams, None))
+  }
+}
+
+/// Reads lines from a client, handing each on to `tx`, then None once the
+/// client hangs up or sends a line longer than `ELECTRUM_MAX_LINE`
+fn read_lines(stream: TcpStream, tx: Sender<Option<String>>) {
+  let mut reader = BufferedReader::new(stream);
+  let mut line = vec![];
+  loop {
+    match reader.read_byte() {
+      Ok(b'\n') => {
+        match String::from_utf8(mem::replace(&mut line, vec![])) {
+          Ok(s) => {
+            if tx.send_opt(Some(s)).is_err() {
+              return;
+            }
+          }
+          Err(_) => { break; }
+        }
+      }
+      Ok(b) if line.len() < ELECTRUM_MAX_LINE => { line.push(b); }
+      _ => { break; }
+    }
+  }
+  let _ = tx.send_opt(None);
+}
+
@@ -0,0 +1,222 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Events
+//!
+//! A small publish/subscribe bus. The idle loop publishes events as blocks
+//! are connected and disconnected, transactions reach the mempool, the
+//! chain tip moves and coinjoin sessions change state; the wallets publish
+//! events as they receive transactions. Subscribers include the wallets,
+//! the coinjoin server, the notification hooks and RPC clients, through
+//! the HTTP server's streaming endpoint.
+//!
+
+use std::collections::TreeMap;
+use std::sync::{Arc, Mutex};
+use serialize::json;
+use serialize::json::ToJson;
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use coinjoin::server::{SessionId, SessionState};
+
+/// The kinds of event which may be subscribed to
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Topic {
+  /// The chain tip changed
+  Blocks,
+  /// A wallet received a new transaction, or one of its transactions
+  /// was confirmed
+  WalletTransactions,
+  /// A coinjoin session was created or changed state
+  CoinjoinSessions,
+  /// A block was connected or disconnected, a transaction reached the
+  /// mempool, or the sync state changed
+  Chain
+}
+
+impl Topic {
+  /// Parses a topic name, as used in subscription requests
+  pub fn from_name(name: &str) -> Option<Topic> {
+    match name {
+      "blocks" => Some(Blocks),
+      "wallettx" => Some(WalletTransactions),
+      "coinjoin" => Some(CoinjoinSessions),
+      "chain" => Some(Chain),
+      _ => None
+    }
+  }
+
+  /// The name of a topic, as used in subscription requests
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Blocks => "blocks",
+      WalletTransactions => "wallettx",
+      CoinjoinSessions => "coinjoin",
+      Chain => "chain"
+    }
+  }
+
+  /// All topics
+  pub fn all() -> Vec<Topic> {
+    vec![Blocks, WalletTransactions, CoinjoinSessions, Chain]
+  }
+}
+
+/// How far the idle loop is through catching up with the peer
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum SyncState {
+  /// Downloading block headers
+  SyncingHeaders,
+  /// Connecting blocks to the UTXO set
+  SyncingUtxoSet,
+  /// Caught up
+  Synced
+}
+
+impl SyncState {
+  /// The name of a sync state, as used in event JSON
+  pub fn name(&self) -> &'static str {
+    match *self {
+      SyncingHeaders => "headers",
+      SyncingUtxoSet => "utxoset",
+      Synced => "synced"
+    }
+  }
+}
+
+/// Something which happened that subscribers may want to know about
+#[deriving(Clone, Show)]
+pub enum Event {
+  /// New chain tip (hash, height)
+  NewTip(Sha256dHash, uint),
+  /// New wallet transaction (wallet name, txid)
+  WalletTransaction(String, Sha256dHash),
+  /// Wallet transaction confirmed (wallet name, txid, height)
+  WalletConfirmation(String, Sha256dHash, uint),
+  /// Coinjoin session state change (session, new state)
+  CoinjoinSession(SessionId, SessionState),
+  /// Block connected to the UTXO set (block, height)
+  BlockConnected(Arc<Block>, uint),
+  /// Block disconnected from the UTXO set by a reorg
+  BlockDisconnected(Arc<Block>),
+  /// Transaction from the peer or RPC accepted to the mempool
+  TxAccepted(Arc<Transaction>),
+  /// Transaction from the peer refused by the mempool (transaction, reason)
+  TxRejected(Arc<Transaction>, String),
+  /// The sync state changed
+  SyncStateChanged(SyncState)
+}
+
+impl Event {
+  /// The topic under which an event is published
+  pub fn topic(&self) -> Topic {
+    match *self {
+      NewTip(_, _) => Blocks,
+      WalletTransaction(_, _) => WalletTransactions,
+      WalletConfirmation(_, _, _) => WalletTransactions,
+      CoinjoinSession(_, _) => CoinjoinSessions,
+      BlockConnected(_, _) | BlockDisconnected(_) | TxAccepted(_) |
+      TxRejected(_, _) | SyncStateChanged(_) => Chain
+    }
+  }
+}
+
+impl ToJson for Event {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("topic".to_string(), json::String(self.topic().name().to_string()));
+    match *self {
+      NewTip(ref hash, height) => {
+        obj.insert("hash".to_string(), hash.to_json());
+        obj.insert("height".to_string(), height.to_json());
+      }
+      WalletTransaction(ref wallet, ref txid) => {
+        obj.insert("wallet".to_string(), json::String(wallet.clone()));
+        obj.insert("txid".to_string(), txid.to_json());
+      }
+      WalletConfirmation(ref wallet, ref txid, height) => {
+        obj.insert("wallet".to_string(), json::String(wallet.clone()));
+        obj.insert("txid".to_string(), txid.to_json());
+        obj.insert("height".to_string(), height.to_json());
+      }
+      CoinjoinSession(ref id, state) => {
+        obj.insert("session_id".to_string(), id.to_json());
+        obj.insert("state".to_string(), state.to_json());
+      }
+      BlockConnected(ref block, height) => {
+        obj.insert("event".to_string(), json::String("blockconnected".to_string()));
+        obj.insert("hash".to_string(), block.bitcoin_hash().to_json());
+        obj.insert("height".to_string(), height.to_json());
+      }
+      BlockDisconnected(ref block) => {
+        obj.insert("event".to_string(), json::String("blockdisconnected".to_string()));
+        obj.insert("hash".to_string(), block.bitcoin_hash().to_json());
+      }
+      TxAccepted(ref tx) => {
+        obj.insert("event".to_string(), json::String("txaccepted".to_string()));
+        obj.insert("txid".to_string(), tx.bitcoin_hash().to_json());
+      }
+      TxRejected(ref tx, ref reason) => {
+        obj.insert("event".to_string(), json::String("txrejected".to_string()));
+        obj.insert("txid".to_string(), tx.bitcoin_hash().to_json());
+        obj.insert("reason".to_string(), json::String(reason.clone()));
+      }
+      SyncStateChanged(ref state) => {
+        obj.insert("event".to_string(), json::String("syncstate".to_string()));
+        obj.insert("state".to_string(), json::String(state.name().to_string()));
+      }
+    }
+    json::Object(obj)
+  }
+}
+
+/// A subscriber: the topics it wants and a channel to send them on
+struct Subscriber {
+  topics: Vec<Topic>,
+  sender: Sender<Event>
+}
+
+/// The event bus, shared between the idle loop and the RPC server
+#[deriving(Clone)]
+pub struct EventBus {
+  subscribers: Arc<Mutex<Vec<Subscriber>>>
+}
+
+impl EventBus {
+  /// Creates a new event bus with no subscribers
+  pub fn new() -> EventBus {
+    EventBus { subscribers: Arc::new(Mutex::new(vec![])) }
+  }
+
+  /// Subscribes to a set of topics, returning a channel of events
+  pub fn subscribe(&self, topics: Vec<Topic>) -> Receiver<Event> {
+    let (tx, rx) = channel();
+    self.subscribers.lock().push(Subscriber { topics: topics, sender: tx });
+    rx
+  }
+
+  /// Sends an event to every interested subscriber, forgetting about any
+  /// which have hung up
+  pub fn publish(&self, event: Event) {
+    let topic = event.topic();
+    let mut subscribers = self.subscribers.lock();
+    subscribers.retain(|sub| {
+      !sub.topics.contains(&topic) || sub.sender.send_opt(event.clone()).is_ok()
+    });
+  }
+}
@@ -0,0 +1,226 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Fee Estimation
+//!
+//! Estimates the fee rate a transaction needs to confirm within a given
+//! number of blocks, by watching how long mempool transactions take to
+//! confirm. Transactions are sorted into buckets by fee rate, and for each
+//! bucket we keep counts of how many confirmed within each number of
+//! blocks. The counts decay with every block so that old behaviour is
+//! gradually forgotten.
+//!
+
+use std::collections::HashMap;
+use std::io::{FileNotFound, IoResult};
+use std::num::Float;
+
+use bitcoin::util::hash::Sha256dHash;
+
+use constants::FEE_ESTIMATE_MAX_TARGET;
+use wallet::{read_toml, write_toml};
+
+/// Fee rate, in satoshi per 1000 bytes, at the bottom of the lowest bucket
+static MIN_BUCKET_RATE: f64 = 1000.0;
+/// Ratio between the fee rates of adjacent buckets
+static BUCKET_SPACING: f64 = 1.2;
+/// Number of buckets; the highest starts at around 1.2M satoshi per 1000 bytes
+pub static N_BUCKETS: uint = 40;
+/// Factor by which all counts are multiplied every block
+static DECAY: f64 = 0.998;
+/// Fraction of a bucket's transactions which must have confirmed within the
+/// target for its fee rate to be considered sufficient
+static SUCCESS_THRESHOLD: f64 = 0.85;
+/// (Decayed) number of transactions a bucket must have seen to be used
+static MIN_DATA_POINTS: f64 = 10.0;
+
+/// The part of the estimator's state which is saved to disk
+#[deriving(Clone, Encodable, Decodable)]
+pub struct FeeStats {
+  /// `confirmed[t][b]` counts transactions in bucket `b` which confirmed
+  /// within `t + 1` blocks
+  confirmed: Vec<Vec<f64>>,
+  /// `total[b]` counts transactions in bucket `b` which either confirmed or
+  /// were still unconfirmed after the maximum target
+  total: Vec<f64>,
+  /// Height of the last block counted
+  best_height: uint
+}
+
+impl FeeStats {
+  /// Creates empty statistics
+  fn new() -> FeeStats {
+    FeeStats {
+      confirmed: Vec::from_elem(FEE_ESTIMATE_MAX_TARGET, Vec::from_elem(N_BUCKETS, 0.0)),
+      total: Vec::from_elem(N_BUCKETS, 0.0),
+      best_height: 0
+    }
+  }
+
+  /// Whether the statistics have the shape we expect, e.g. after loading
+  /// them from a file written with different parameters
+  fn is_valid(&self) -> bool {
+    self.total.len() == N_BUCKETS &&
+      self.confirmed.len() == FEE_ESTIMATE_MAX_TARGET &&
+      self.confirmed.iter().all(|row| row.len() == N_BUCKETS)
+  }
+}
+
+/// The bucket containing a fee rate
+pub fn bucket_index(fee_rate: u64) -> uint {
+  let rate = fee_rate as f64;
+  if rate < MIN_BUCKET_RATE {
+    return 0;
+  }
+  let idx = ((rate / MIN_BUCKET_RATE).ln() / BUCKET_SPACING.ln()).floor() as uint;
+  if idx < N_BUCKETS { idx } else { N_BUCKETS - 1 }
+}
+
+/// The fee rate at the top of a bucket
+fn bucket_rate(idx: uint) -> u64 {
+  (MIN_BUCKET_RATE * BUCKET_SPACING.powi(idx as i32 + 1)).ceil() as u64
+}
+
+/// The range of fee rates in a bucket, from its bottom up to its top, which
+/// for the highest bucket is unbounded
+pub fn bucket_range(idx: uint) -> (u64, Option<u64>) {
+  let bottom = if idx == 0 { 0 } else { bucket_rate(idx - 1) };
+  let top = if idx + 1 < N_BUCKETS { Some(bucket_rate(idx)) } else { None };
+  (bottom, top)
+}
+
+/// The fee estimator
+pub struct FeeEstimator {
+  /// Confirmation statistics
+  stats: FeeStats,
+  /// Unconfirmed transactions being watched: height at which each entered
+  /// the mempool, and its bucket
+  tracked: HashMap<Sha256dHash, (uint, uint)>
+}
+
+impl FeeEstimator {
+  /// Creates an estimator with no data
+  pub fn new() -> FeeEstimator {
+    FeeEstimator::from_stats(FeeStats::new())
+  }
+
+  /// Creates an estimator from saved statistics, discarding them if they
+  /// do not fit our parameters
+  pub fn from_stats(stats: FeeStats) -> FeeEstimator {
+    FeeEstimator {
+      stats: if stats.is_valid() { stats } else { FeeStats::new() },
+      tracked: HashMap::new()
+    }
+  }
+
+  /// Loads saved statistics from disk, starting afresh if there are none
+  pub fn load(path: &Path) -> IoResult<FeeEstimator> {
+    match read_toml(path) {
+      Ok(stats) => Ok(FeeEstimator::from_stats(stats)),
+      Err(ref e) if e.kind == FileNotFound => Ok(FeeEstimator::new()),
+      Err(e) => Err(e)
+    }
+  }
+
+  /// Saves the statistics to disk
+  pub fn save(&self, path: &Path) -> IoResult<()> {
+    write_toml(path, &self.stats)
+  }
+
+  /// The number of unconfirmed transactions being watched
+  pub fn n_tracked(&self) -> uint {
+    self.tracked.len()
+  }
+
+  /// Starts watching a transaction which entered the mempool when the
+  /// chain tip was at `height`
+  pub fn tx_entered(&mut self, txid: Sha256dHash, fee_rate: u64, height: uint) {
+    self.tracked.insert(txid, (height, bucket_index(fee_rate)));
+  }
+
+  /// Counts the transactions confirmed by a newly connected block. Watched
+  /// transactions for which `in_mempool` is false were evicted or
+  /// conflicted, and are forgotten without being counted.
+  pub fn block_connected(&mut self, height: uint, txids: &[Sha256dHash],
+                         in_mempool: |&Sha256dHash| -> bool) {
+    // Blocks we have already counted (e.g. reconnected after a reorg) are
+    // not counted again
+    if height <= self.stats.best_height {
+      for txid in txids.iter() {
+        self.tracked.remove(txid);
+      }
+      return;
+    }
+    self.stats.best_height = height;
+
+    for row in self.stats.confirmed.mut_iter() {
+      for count in row.mut_iter() {
+        *count *= DECAY;
+      }
+    }
+    for count in self.stats.total.mut_iter() {
+      *count *= DECAY;
+    }
+
+    for txid in txids.iter() {
+      match self.tracked.pop(txid) {
+        Some((entered, bucket)) => {
+          let blocks = if height > entered { height - entered } else { 1 };
+          for t in range(blocks - 1, FEE_ESTIMATE_MAX_TARGET) {
+            *self.stats.confirmed.get_mut(t).get_mut(bucket) += 1.0;
+          }
+          *self.stats.total.get_mut(bucket) += 1.0;
+        }
+        None => {}
+      }
+    }
+
+    // Give up on transactions which have waited longer than any target
+    let mut done = vec![];
+    for (txid, &(entered, bucket)) in self.tracked.iter() {
+      if !in_mempool(txid) {
+        done.push(*txid);
+      } else if height >= entered + FEE_ESTIMATE_MAX_TARGET {
+        *self.stats.total.get_mut(bucket) += 1.0;
+        done.push(*txid);
+      }
+    }
+    for txid in done.iter() {
+      self.tracked.remove(txid);
+    }
+  }
+
+  /// Estimates the fee rate, in satoshi per 1000 bytes, needed to confirm
+  /// within `target` blocks; None if we do not have enough data
+  pub fn estimate(&self, target: uint) -> Option<u64> {
+    if target == 0 {
+      return None;
+    }
+    let t = if target > FEE_ESTIMATE_MAX_TARGET { FEE_ESTIMATE_MAX_TARGET } else { target } - 1;
+    // Walk down from the highest fee rate until a bucket fails
+    let mut ret = None;
+    for b in range(0, N_BUCKETS).rev() {
+      let total = self.stats.total[b];
+      if total < MIN_DATA_POINTS {
+        continue;
+      }
+      if self.stats.confirmed[t][b] / total < SUCCESS_THRESHOLD {
+        break;
+      }
+      ret = Some(bucket_rate(b));
+    }
+    ret
+  }
+}
+
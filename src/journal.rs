@@ -0,0 +1,229 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Save Journal
+//!
+//! Changes to the blockchain and UTXO set since they were last written to
+//! disk in full. The periodic save appends only the changes made since the
+//! previous save to the journal file, and only occasionally rewrites the
+//! whole state and empties the journal. On startup the journal is replayed
+//! on top of the full copy.
+//!
+//! The journal file starts with the blockchain tip and UTXO set tip of the
+//! full copy it applies to, so that a journal left over from before a full
+//! save (e.g. if we crashed partway through one) is not replayed twice.
+//!
+
+use std::io::{Append, BufferedReader, BufferedWriter, EndOfFile, File, FileNotFound};
+use std::io::{InvalidInput, IoError, IoResult, Open, Truncate, Write};
+use std::mem;
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::utxoset::{UtxoSet, TxoValidation};
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable};
+use bitcoin::network::serialize::{RawEncoder, RawDecoder};
+use bitcoin::util::hash::Sha256dHash;
+
+use constants::{FULL_SAVE_FREQUENCY, JOURNAL_MAX_ENTRIES};
+
+/// A change to the blockchain or UTXO set
+#[deriving(Clone)]
+pub enum JournalEntry {
+  /// A header was added to the blockchain
+  HeaderAdded(BlockHeader),
+  /// A block was connected to the UTXO set at the given height
+  BlockConnected(Block, uint),
+  /// A block was rewound from the UTXO set
+  BlockRewound(Block)
+}
+
+/// Changes not yet written to the journal file
+pub struct Journal {
+  /// Changes since the last save, oldest first
+  pending: Vec<JournalEntry>,
+  /// Set when too many changes built up to be worth journalling, so that
+  /// the next save must write everything
+  overflowed: bool,
+  /// Number of journal-only saves since the last full save
+  saves_since_full: uint
+}
+
+impl Journal {
+  /// Creates an empty journal. If `in_sync` is false, the journal file does
+  /// not match the full copy on disk, and the next save will be a full one.
+  pub fn new(in_sync: bool) -> Journal {
+    Journal { pending: vec![], overflowed: !in_sync, saves_since_full: 0 }
+  }
+
+  /// Records a change
+  pub fn record(&mut self, entry: JournalEntry) {
+    if self.overflowed {
+      return;
+    }
+    if self.pending.len() >= JOURNAL_MAX_ENTRIES {
+      self.pending.clear();
+      self.overflowed = true;
+    } else {
+      self.pending.push(entry);
+    }
+  }
+
+  /// Whether the next save should rewrite everything
+  pub fn needs_full_save(&self) -> bool {
+    self.overflowed || self.saves_since_full >= FULL_SAVE_FREQUENCY
+  }
+
+  /// Takes the changes to append to the journal file
+  pub fn take_pending(&mut self) -> Vec<JournalEntry> {
+    self.saves_since_full += 1;
+    mem::replace(&mut self.pending, vec![])
+  }
+
+  /// Makes the next save a full one, e.g. after a failed save
+  pub fn force_full_save(&mut self) {
+    self.pending.clear();
+    self.overflowed = true;
+  }
+
+  /// Forgets all changes, for when everything has been written in full
+  pub fn reset(&mut self) {
+    self.pending.clear();
+    self.overflowed = false;
+    self.saves_since_full = 0;
+  }
+}
+
+fn write_entry<W: Writer>(encoder: &mut RawEncoder<W>, entry: &JournalEntry) -> IoResult<()> {
+  match *entry {
+    HeaderAdded(ref header) => {
+      try!(0u8.consensus_encode(encoder));
+      header.consensus_encode(encoder)
+    }
+    BlockConnected(ref block, height) => {
+      try!(1u8.consensus_encode(encoder));
+      try!(block.consensus_encode(encoder));
+      (height as u64).consensus_encode(encoder)
+    }
+    BlockRewound(ref block) => {
+      try!(2u8.consensus_encode(encoder));
+      block.consensus_encode(encoder)
+    }
+  }
+}
+
+fn read_entry<R: Reader>(decoder: &mut RawDecoder<R>) -> IoResult<JournalEntry> {
+  let tag: u8 = try!(ConsensusDecodable::consensus_decode(decoder));
+  match tag {
+    0 => Ok(HeaderAdded(try!(ConsensusDecodable::consensus_decode(decoder)))),
+    1 => {
+      let block = try!(ConsensusDecodable::consensus_decode(decoder));
+      let height: u64 = try!(ConsensusDecodable::consensus_decode(decoder));
+      Ok(BlockConnected(block, height as uint))
+    }
+    2 => Ok(BlockRewound(try!(ConsensusDecodable::consensus_decode(decoder)))),
+    _ => Err(IoError { kind: InvalidInput,
+                       desc: "unknown journal entry",
+                       detail: Some(format!("tag {}", tag)) })
+  }
+}
+
+/// Empties the journal file, marking it as applying to a full copy with
+/// the given blockchain and UTXO set tips
+pub fn truncate(path: &Path, chain_tip: Sha256dHash, utxo_tip: Sha256dHash) -> IoResult<()> {
+  let mut encoder = RawEncoder::new(BufferedWriter::new(try!(File::open_mode(path, Truncate, Write))));
+  try!(chain_tip.consensus_encode(&mut encoder));
+  utxo_tip.consensus_encode(&mut encoder)
+}
+
+/// Appends changes to the journal file
+pub fn append(path: &Path, entries: &[JournalEntry]) -> IoResult<()> {
+  let mut encoder = RawEncoder::new(BufferedWriter::new(try!(File::open_mode(path, Append, Write))));
+  for entry in entries.iter() {
+    try!(write_entry(&mut encoder, entry));
+  }
+  Ok(())
+}
+
+/// A reader which counts the bytes read through it, so that we know where
+/// the last whole journal entry ended
+struct CountingReader<R> {
+  inner: R,
+  count: u64
+}
+
+impl<R: Reader> Reader for CountingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    let n = try!(self.inner.read(buf));
+    self.count += n as u64;
+    Ok(n)
+  }
+}
+
+/// Replays the journal file onto the blockchain and UTXO set loaded from
+/// the full copy. Returns whether the journal file applies to them (if
+/// not, it is ignored) and the number of entries replayed. A partially
+/// written entry at the end of the file is cut off, so that the next
+/// append does not land after it.
+pub fn replay(path: &Path, blockchain: &mut Blockchain, utxo_set: &mut UtxoSet)
+              -> IoResult<(bool, uint)> {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(ref e) if e.kind == FileNotFound => { return Ok((false, 0)); }
+    Err(e) => { return Err(e); }
+  };
+  let mut decoder = RawDecoder::new(CountingReader { inner: BufferedReader::new(file), count: 0 });
+  let chain_tip: Sha256dHash = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+  let utxo_tip: Sha256dHash = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+  if chain_tip != blockchain.best_tip_hash() || utxo_tip != utxo_set.last_hash() {
+    return Ok((false, 0));
+  }
+
+  let mut count = 0;
+  let mut reader = decoder.unwrap();
+  loop {
+    // Where the last whole entry ended
+    let good_len = reader.count;
+    let mut decoder = RawDecoder::new(reader);
+    let entry = read_entry(&mut decoder);
+    reader = decoder.unwrap();
+    let entry = match entry {
+      Ok(entry) => entry,
+      Err(ref e) if e.kind == EndOfFile => {
+        if reader.count > good_len {
+          let mut file = try!(File::open_mode(path, Open, Write));
+          try!(file.truncate(good_len as i64));
+        }
+        break;
+      }
+      Err(e) => { return Err(e); }
+    };
+    match entry {
+      HeaderAdded(header) => { let _ = blockchain.add_header(header); }
+      BlockConnected(block, height) => {
+        if utxo_set.update(&block, height, TxoValidation).is_err() {
+          return Ok((false, count));
+        }
+      }
+      BlockRewound(block) => {
+        if !utxo_set.rewind(&block) {
+          return Ok((false, count));
+        }
+      }
+    }
+    count += 1;
+  }
+  Ok((true, count))
+}
+
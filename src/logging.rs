@@ -0,0 +1,200 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Logging
+//!
+//! Where the `debug!` and `fatal!` macros send their records. Each network
+//! logs to its own file, which is rotated once it grows past a configured
+//! size, and optionally to stdout, as text or as one JSON object per line.
+//! Records name the module which logged them as their component.
+//!
+//! Logging happens from every task, including background saves, so the
+//! log files live in a registry shared by all of them rather than in any
+//! network's state.
+//!
+
+use std::collections::{HashMap, TreeMap};
+use std::io::{File, IoResult, Append, Write};
+use std::io::fs;
+use std::mem;
+use std::path::posix::Path;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use serialize::json;
+use time;
+
+use bitcoin::network::constants::Network;
+
+use bitcoind::DebugLevel;
+
+user_enum!(
+  #[doc="How log records are written"]
+  #[deriving(Clone, PartialEq, Eq)]
+  pub enum LogFormat {
+    #[doc="One line of text per record"]
+    Text <-> "text",
+    #[doc="One JSON object per line"]
+    Json <-> "json"
+  }
+)
+
+/// Where and how a network logs
+#[deriving(Clone, PartialEq)]
+pub struct LogConfig {
+  /// File to log to, if any
+  pub path: Option<Path>,
+  /// Size, in bytes, past which the log file is rotated; 0 never rotates
+  pub max_size: u64,
+  /// Number of rotated log files to keep
+  pub keep: uint,
+  /// Whether to also write records to stdout
+  pub stdout: bool,
+  /// Format of the records
+  pub format: LogFormat
+}
+
+/// A network's open log file
+struct Sink {
+  config: LogConfig,
+  file: Option<File>,
+  size: u64
+}
+
+impl Sink {
+  /// Opens the log file, if any, for appending
+  fn open(config: &LogConfig) -> Sink {
+    let (file, size) = match config.path {
+      Some(ref path) => {
+        let size = fs::stat(path).map(|s| s.size).unwrap_or(0);
+        match File::open_mode(path, Append, Write) {
+          Ok(file) => (Some(file), size),
+          Err(e) => {
+            println!("Failed to open log file {}: {}, logging to stdout only.", path.display(), e);
+            (None, 0)
+          }
+        }
+      }
+      None => (None, 0)
+    };
+    Sink { config: config.clone(), file: file, size: size }
+  }
+
+  /// Moves `log` to `log.1`, `log.1` to `log.2`, and so on, dropping the
+  /// oldest, then starts a fresh `log`
+  fn rotate(&mut self) -> IoResult<()> {
+    let path = match self.config.path {
+      Some(ref path) => path.clone(),
+      None => { return Ok(()); }
+    };
+    self.file = None;
+    let numbered = |n: uint| {
+      let mut name = path.filename().unwrap_or(b"log").to_vec();
+      name.push_all(format!(".{}", n).as_bytes());
+      path.with_filename(name)
+    };
+    if self.config.keep == 0 {
+      let _ = fs::unlink(&path);
+    } else {
+      let _ = fs::unlink(&numbered(self.config.keep));
+      for n in range(1, self.config.keep).rev() {
+        let _ = fs::rename(&numbered(n), &numbered(n + 1));
+      }
+      try!(fs::rename(&path, &numbered(1)));
+    }
+    self.file = Some(try!(File::open_mode(&path, Append, Write)));
+    self.size = 0;
+    Ok(())
+  }
+
+  /// Writes a formatted record
+  fn write(&mut self, line: &str) {
+    if self.config.stdout {
+      println!("{}", line);
+    }
+    if self.config.max_size > 0 && self.size + line.len() as u64 + 1 > self.config.max_size {
+      match self.rotate() {
+        Ok(()) => {}
+        Err(e) => { println!("Failed to rotate log file: {}", e); }
+      }
+    }
+    let failed = match self.file {
+      Some(ref mut file) => file.write_line(line).is_err(),
+      None => false
+    };
+    if failed {
+      // Don't lose the record, or keep failing on every one
+      if !self.config.stdout { println!("{}", line); }
+      println!("Failed to write to log file, logging to stdout only.");
+      self.file = None;
+      self.config.stdout = true;
+    } else {
+      self.size += line.len() as u64 + 1;
+    }
+  }
+}
+
+static mut SINKS: *const Mutex<HashMap<Network, Sink>> = 0 as *const Mutex<HashMap<Network, Sink>>;
+static INIT: Once = ONCE_INIT;
+
+/// Returns the registry of log files, creating it the first time
+fn sinks() -> &'static Mutex<HashMap<Network, Sink>> {
+  unsafe {
+    INIT.doit(|| {
+      let sinks: Box<Mutex<HashMap<Network, Sink>>> = box Mutex::new(HashMap::new());
+      SINKS = mem::transmute(sinks);
+    });
+    &*SINKS
+  }
+}
+
+/// Sets where a network logs, reopening its log file. Until this is called,
+/// a network logs text to stdout.
+pub fn configure(network: Network, config: &LogConfig) {
+  sinks().lock().insert(network, Sink::open(config));
+}
+
+/// Formats a record as a line of text or JSON
+fn format_record(format: LogFormat, network: Network, level: DebugLevel,
+                 component: &str, message: &str) -> String {
+  let now = time::now().rfc3339();
+  match format {
+    Text => format!("{} [{:6}] {}:{}: {}", now, level, network, component, message),
+    Json => {
+      let mut obj = TreeMap::new();
+      obj.insert("time".to_string(), json::String(now.to_string()));
+      obj.insert("level".to_string(), json::String(level.to_string()));
+      obj.insert("network".to_string(), json::String(network.to_string()));
+      obj.insert("component".to_string(), json::String(component.to_string()));
+      obj.insert("message".to_string(), json::String(message.to_string()));
+      json::Object(obj).to_string()
+    }
+  }
+}
+
+/// Logs a record. `module` is the logging module's path, whose last part
+/// names the component.
+pub fn log(network: Network, level: DebugLevel, module: &str, message: String) {
+  let component = module.split_str("::").last().unwrap_or(module);
+  let mut sinks = sinks().lock();
+  match sinks.find_mut(&network) {
+    Some(sink) => {
+      let line = format_record(sink.config.format, network, level, component,
+                               message.as_slice());
+      sink.write(line.as_slice());
+    }
+    None => {
+      println!("{}", format_record(Text, network, level, component, message.as_slice()));
+    }
+  }
+}
+
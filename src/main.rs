@@ -41,6 +41,8 @@
 #![deny(unused_mut)]
 #![warn(missing_doc)]
 
+extern crate getopts;
+extern crate libc;
 extern crate num;
 extern crate rand;
 extern crate rustrt;
@@ -54,42 +56,340 @@ extern crate http;
 extern crate jsonrpc;
 #[phase(plugin)] extern crate phf_mac;
 extern crate phf;
+extern crate secp256k1;
 extern crate toml;
 extern crate xdg;
 
 #[cfg(not(test))]
-use bitcoind::Bitcoind;
+use std::io;
 #[cfg(not(test))]
-use jsonrpc::server::JsonRpcServer;
+use std::io::fs;
+#[cfg(not(test))]
+use std::os;
+#[cfg(not(test))]
+use serialize::json;
+
+#[cfg(not(test))]
+use jsonrpc::error::{standard_error, InternalError};
+
+#[cfg(not(test))]
+use bitcoind::{Bitcoind, ConfigReload, ReloadRequest};
+#[cfg(not(test))]
+use cli::Options;
+#[cfg(not(test))]
+use daemon::{Hangup, PidFile, Terminate};
+#[cfg(not(test))]
+use events::EventBus;
 #[cfg(not(test))]
 use http::server::Server;
 #[cfg(not(test))]
-use user_data::{config_path, load_configuration};
+use rpc_auth::credentials;
+#[cfg(not(test))]
+use rpc_http::RpcHttpServer;
+#[cfg(not(test))]
+use user_data::{NetworkConfig, load_configuration};
+#[cfg(not(test))]
+use user_data::{default_configuration_text, write_default_configuration};
+#[cfg(not(test))]
+use user_data::read_configuration;
+#[cfg(not(test))]
+use wallet::check_wallet;
 // Public exports to get documentation
+#[macro_escape]
 pub mod bitcoind;
+pub mod address_index;
+pub mod checkpoints;
+pub mod cli;
 pub mod coinjoin;
+pub mod config_schema;
 pub mod constants;
+pub mod daemon;
+pub mod dashboard;
+pub mod difficulty;
+pub mod disk;
+pub mod electrum;
+pub mod events;
+pub mod fee_estimator;
+pub mod journal;
+pub mod logging;
+pub mod mempool;
+pub mod merkle;
+pub mod message_router;
+pub mod metrics;
+pub mod notify;
+pub mod payment_request;
+pub mod progress;
+pub mod rpc_auth;
+pub mod rpc_client;
+pub mod rpc_http;
 pub mod rpc_server;
+pub mod scheduler;
+pub mod script_info;
+pub mod timelock;
+pub mod tls;
 pub mod user_data;
+pub mod util;
+pub mod version;
 pub mod wallet;
+pub mod web_dashboard;
+pub mod worker_pool;
+
+/// A running network, as needed to reload its configuration
+#[cfg(not(test))]
+struct Running {
+  config: NetworkConfig,
+  rpc: RpcHttpServer,
+  config_tx: Sender<ConfigReload>
+}
+
+/// Rereads the configuration file and passes each running network its new
+/// configuration, with the command-line overrides applied again. RPC
+/// credentials are replaced here, since the RPC servers belong to us; the
+/// networks apply everything else themselves.
+#[cfg(not(test))]
+fn reload_configuration(opts: &Options, running: &mut Vec<Running>, request: ReloadRequest) {
+  let path = opts.config_path();
+  let (requester, mut reply) = match request {
+    Some((network, reply)) => (Some(network), Some(reply)),
+    None => (None, None)
+  };
+  println!("main: reloading configuration from {}", path.display());
+  let config = match read_configuration(&path) {
+    Ok(config) => config,
+    Err(e) => {
+      println!("main: failed to reload configuration: {}", e);
+      match reply {
+        Some(reply) => {
+          let err = standard_error(InternalError, Some(json::String(e.to_string())));
+          let _ = reply.send_opt(Err(err));
+        }
+        None => {}
+      }
+      return;
+    }
+  };
+
+  for new in config.move_iter() {
+    let mut new = new;
+    opts.apply(&mut new);
+    match running.mut_iter().find(|r| r.config.network == new.network) {
+      Some(r) => {
+        if new.log != r.config.log {
+          logging::configure(new.network, &new.log);
+        }
+        if new.rpc_user != r.config.rpc_user || new.rpc_password != r.config.rpc_password {
+          match credentials(&new) {
+            Ok(creds) => r.rpc.set_credentials(creds),
+            Err(e) => {
+              println!("{}: RPC server: {}, keeping old credentials.", new.network, e);
+              new.rpc_user = r.config.rpc_user.clone();
+              new.rpc_password = r.config.rpc_password.clone();
+            }
+          }
+        }
+        r.config = new.clone();
+        let reply = if requester == Some(new.network) { reply.take() } else { None };
+        let _ = r.config_tx.send_opt((new, reply));
+      }
+      None => {
+        if opts.wants(&new) {
+          println!("main: {} is newly configured; restart to start it.", new.network);
+        }
+      }
+    }
+  }
+  // The network which asked is no longer in the file
+  match reply {
+    Some(reply) => {
+      let err = standard_error(InternalError,
+                               Some(json::String("network no longer configured".to_string())));
+      let _ = reply.send_opt(Err(err));
+    }
+    None => {}
+  }
+}
+
+/// Checks the configuration and each network's wallets without starting
+/// anything, printing the settings each network would run with. Returns
+/// whether everything checked out.
+#[cfg(not(test))]
+fn check_configuration(opts: &Options) -> bool {
+  let path = opts.config_path();
+  println!("Checking {}", path.display());
+  // Diagnostics from the schema check are printed as the file is read
+  let config = match load_configuration(&path) {
+    Some(config) => config,
+    None => { return false; }
+  };
+  let mut ok = true;
+  let mut n_wanted = 0u;
+  for config in config.move_iter() {
+    let mut config = config;
+    opts.apply(&mut config);
+    if !opts.wants(&config) {
+      println!("\n{} is disabled and will not run.", config.network);
+      continue;
+    }
+    n_wanted += 1;
+    println!("\n{}", config.describe());
+    for wconfig in config.wallets.iter() {
+      match check_wallet(wconfig) {
+        Ok(true) => { println!("  wallet {}: ok", wconfig.name); }
+        Ok(false) => { println!("  wallet {}: not found, will be created", wconfig.name); }
+        Err(e) => {
+          println!("  wallet {}: unreadable: {}", wconfig.name, e);
+          ok = false;
+        }
+      }
+    }
+  }
+  if n_wanted == 0 {
+    println!("No networks are enabled.");
+    ok = false;
+  }
+  println!("\nConfiguration {}.", if ok { "OK" } else { "has errors" });
+  ok
+}
 
 /// Entry point
 #[cfg(not(test))]
 fn main()
 {
+  let args = os::args();
+  let opts = match cli::parse(args.tail()) {
+    Ok(opts) => opts,
+    Err(e) => {
+      println!("{}\n\n{}", e, cli::help(args[0].as_slice()));
+      unsafe { libc::exit(1); }
+    }
+  };
+  if opts.help {
+    println!("{}", cli::help(args[0].as_slice()));
+    return;
+  }
+  if opts.version {
+    println!("{}", version::version_string());
+    return;
+  }
+  // Talk to a running wallet, using the same configuration to find it
+  if opts.command != cli::Run && opts.command != cli::Start {
+    let config = match load_configuration(&opts.config_path()) {
+      Some(config) => config,
+      None => { println!("Failed to load configuration."); unsafe { libc::exit(1); } }
+    };
+    let mut configs = vec![];
+    for config in config.move_iter().filter(|c| opts.wants(c)) {
+      let mut config = config;
+      opts.apply(&mut config);
+      configs.push(config);
+    }
+    let ok = match opts.command {
+      cli::Stop => rpc_client::stop(configs.as_slice()),
+      cli::Status => rpc_client::status(configs.as_slice()),
+      cli::Rpc(ref method, ref params) => {
+        rpc_client::run(configs.as_slice(), method.as_slice(), params.as_slice())
+      }
+      cli::Dashboard => dashboard::run(configs.as_slice()),
+      cli::Run | cli::Start => unreachable!()
+    };
+    unsafe { libc::exit(if ok { 0 } else { 1 }); }
+  }
+
+  if opts.check_config {
+    let ok = check_configuration(&opts);
+    unsafe { libc::exit(if ok { 0 } else { 1 }); }
+  }
+
+  // Never overwrite an existing configuration file
+  if opts.dump_config {
+    let path = opts.config_path();
+    if path.exists() {
+      print!("{}", default_configuration_text());
+    } else {
+      match write_default_configuration(&path) {
+        Ok(()) => { println!("Wrote a default configuration to {}", path.display()); }
+        Err(e) => { println!("Failed to write {}: {}", path.display(), e); }
+      }
+    }
+    return;
+  }
+
+  match opts.datadir {
+    Some(ref dir) => match fs::mkdir_recursive(dir, io::UserDir) {
+      Ok(()) => {}
+      Err(e) => { println!("Failed to create {}: {}. Shutting down.", dir.display(), e); return; }
+    },
+    None => {}
+  }
+
+  // Fork before anything else, since only this thread survives it
+  if opts.daemon {
+    println!("Starting the Wizards' Wallet in the background, logging to {}",
+             opts.log_path().display());
+    match daemon::daemonize(&opts.log_path()) {
+      Ok(()) => {}
+      Err(e) => { println!("Failed to daemonize: {}. Shutting down.", e); return; }
+    }
+  }
   println!("Starting the Wizards' Wallet");
 
-  let config = match load_configuration(&config_path()) {
+  let config = match load_configuration(&opts.config_path()) {
       Some(config) => config,
       None => { println!("Failed to load configuration. Shutting down."); return; }
     };
+  let configs: Vec<NetworkConfig> = config.move_iter().filter(|c| opts.wants(c)).collect();
+  if configs.is_empty() {
+    println!("No networks are enabled. Shutting down.");
+    return;
+  }
 
-  for config in config.move_iter() {
+  let pid_file = if opts.daemon {
+    match PidFile::create(&opts.pid_path()) {
+      Ok(pid_file) => Some(pid_file),
+      Err(e) => { println!("Failed to write pid file: {}. Shutting down.", e); return; }
+    }
+  } else {
+    None
+  };
+
+  // A `stop` RPC on any network, or SIGINT or SIGTERM, asks us on
+  // `shutdown_rx` to stop them all. A `reloadconfig` RPC, or SIGHUP, asks
+  // us on `reload_rx` to reread the configuration file.
+  let (shutdown_tx, shutdown_rx) = channel();
+  let (reload_tx, reload_rx) = channel::<ReloadRequest>();
+  let signal_rx = daemon::catch_signals();
+  let signal_shutdown_tx = shutdown_tx.clone();
+  let signal_reload_tx = reload_tx.clone();
+  spawn(proc() {
+    for signal in signal_rx.iter() {
+      println!("main: caught {}", signal);
+      match signal {
+        Terminate => { let _ = signal_shutdown_tx.send_opt(()); }
+        Hangup => { let _ = signal_reload_tx.send_opt(None); }
+      }
+    }
+  });
+  let (done_tx, done_rx) = channel();
+  let mut stop_txs = vec![];
+  let mut running = vec![];
+
+  for config in configs.move_iter() {
+    let mut config = config;
+    opts.apply(&mut config);
     let network = config.network;
+    logging::configure(network, &config.log);
     println!("main: Starting a listener for {}", network);
+    let creds = match credentials(&config) {
+      Err(e) => {
+        println!("{}: RPC server: {}, failed to set up authentication.", network, e);
+        break;
+      }
+      Ok(creds) => creds
+    };
     // Connect to bitcoind
-    let (jsonrpc, rpc_rx) = match JsonRpcServer::new(config.rpc_server_addr.as_slice(),
-                                                     config.rpc_server_port) {
+    let events = EventBus::new();
+    notify::start(&config, &events);
+    let (jsonrpc, rpc_rx) = match RpcHttpServer::new(&config, creds, events.clone()) {
       Err(e) => {
         println!("{}: RPC server: {}, failed to start.", network, e);
         break;
@@ -97,7 +397,13 @@ fn main()
       Ok(tup) => tup
     };
     // Start bitcoind
-    let bitcoind = Bitcoind::new(config, rpc_rx);
+    let (stop_tx, stop_rx) = channel();
+    stop_txs.push(stop_tx);
+    let (config_tx, config_rx) = channel();
+    running.push(Running { config: config.clone(), rpc: jsonrpc.clone(), config_tx: config_tx });
+    let bitcoind = Bitcoind::new(config, rpc_rx, events, stop_rx, shutdown_tx.clone(),
+                                 config_rx, reload_tx.clone());
+    let done_tx = done_tx.clone();
     spawn(proc() {
       let mut bitcoind = bitcoind;
       match bitcoind.listen() {
@@ -106,6 +412,7 @@ fn main()
         }
         _ => {}
       }
+      done_tx.send(());
     });
     // Start the RPC server
     spawn (proc() {
@@ -115,5 +422,32 @@ fn main()
     });
   }
   println!("main: started all networks");
+
+  spawn(proc() {
+    let mut running = running;
+    for request in reload_rx.iter() {
+      reload_configuration(&opts, &mut running, request);
+    }
+  });
+
+  // Wait for a `stop` RPC, then have every network save its state. Once
+  // they are done we exit, since the RPC servers would otherwise run forever.
+  shutdown_rx.recv();
+  println!("main: stopping all networks");
+  for stop_tx in stop_txs.iter() {
+    let _ = stop_tx.send_opt(());
+  }
+  for _ in stop_txs.iter() {
+    done_rx.recv();
+  }
+  match pid_file {
+    Some(pid_file) => match pid_file.remove() {
+      Ok(()) => {}
+      Err(e) => { println!("main: failed to remove pid file: {}", e); }
+    },
+    None => {}
+  }
+  println!("main: all networks stopped, exiting");
+  unsafe { libc::exit(0); }
 }
 
@@ -0,0 +1,375 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Mempool
+//!
+//! Unconfirmed transactions which we have received from our peer or over
+//! RPC. Transactions are checked against the UTXO set and the rest of the
+//! pool: every input must exist and be unspent, inputs spending the UTXO
+//! set must have valid scripts, and outputs may not be worth more than
+//! inputs. A transaction which conflicts with one already
+//! in the pool replaces it only if everything it replaces signals
+//! replaceability and it pays more fee than all of them together.
+//!
+//! When the pool grows past its size limit, the transactions with the
+//! lowest fee rate are evicted, along with anything which spends them.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::blockdata::utxoset::UtxoSet;
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use fee_estimator::{N_BUCKETS, bucket_index};
+
+/// Reasons a transaction may be refused entry to the mempool
+#[deriving(Clone, PartialEq, Eq)]
+pub enum MempoolError {
+  /// The transaction is already in the pool
+  AlreadyHave,
+  /// The transaction has no inputs or no outputs
+  Empty,
+  /// An input is neither in the UTXO set nor the pool (txid, vout)
+  MissingInput(Sha256dHash, u32),
+  /// An input's script does not validate against the UTXO set (txid, vout)
+  InvalidInput(Sha256dHash, u32),
+  /// An input is spent by a pool transaction which cannot be replaced (txid)
+  Conflict(Sha256dHash),
+  /// The transaction's outputs are worth more than its inputs
+  NegativeFee,
+  /// The fee rate is too low to stay in the pool
+  FeeTooLow
+}
+
+impl fmt::Show for MempoolError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      AlreadyHave => write!(f, "transaction already in mempool"),
+      Empty => write!(f, "transaction has no inputs or no outputs"),
+      MissingInput(ref txid, vout) => write!(f, "input {:x}:{} not found", txid, vout),
+      InvalidInput(ref txid, vout) => write!(f, "input {:x}:{} failed script validation", txid, vout),
+      Conflict(ref txid) => write!(f, "conflicts with mempool transaction {:x}", txid),
+      NegativeFee => write!(f, "outputs exceed inputs"),
+      FeeTooLow => write!(f, "fee rate too low for mempool")
+    }
+  }
+}
+
+/// A transaction in the mempool
+#[deriving(Clone)]
+pub struct MempoolEntry {
+  /// The transaction itself
+  pub tx: Transaction,
+  /// Fee paid, in satoshi
+  pub fee: u64,
+  /// Serialized size, in bytes
+  pub size: uint,
+  /// Time (seconds since the epoch) at which it entered the pool
+  pub time: i64
+}
+
+impl MempoolEntry {
+  /// Fee rate in satoshi per 1000 bytes
+  pub fn fee_rate(&self) -> u64 {
+    self.fee * 1000 / self.size as u64
+  }
+
+  /// Whether the transaction signals that it may be replaced
+  pub fn signals_rbf(&self) -> bool {
+    self.tx.input.iter().any(|i| i.sequence < 0xfffffffe)
+  }
+}
+
+/// The mempool
+pub struct Mempool {
+  /// Transactions by txid
+  entries: HashMap<Sha256dHash, MempoolEntry>,
+  /// Which pool transaction spends each outpoint spent in the pool
+  spends: HashMap<(Sha256dHash, u32), Sha256dHash>,
+  /// Total serialized size of the pool
+  total_size: uint,
+  /// Size, in bytes, above which transactions are evicted
+  max_size: uint
+}
+
+impl Mempool {
+  /// Creates an empty mempool with the given size limit
+  pub fn new(max_size: uint) -> Mempool {
+    Mempool {
+      entries: HashMap::new(),
+      spends: HashMap::new(),
+      total_size: 0,
+      max_size: max_size
+    }
+  }
+
+  /// The number of transactions in the pool
+  pub fn len(&self) -> uint {
+    self.entries.len()
+  }
+
+  /// The total serialized size of the pool, in bytes
+  pub fn total_size(&self) -> uint {
+    self.total_size
+  }
+
+  /// The number and total size of the pool's transactions in each of the
+  /// fee estimator's fee rate buckets, lowest first
+  pub fn fee_histogram(&self) -> Vec<(uint, uint)> {
+    let mut ret = Vec::from_elem(N_BUCKETS, (0u, 0u));
+    for entry in self.entries.values() {
+      let idx = bucket_index(entry.fee_rate());
+      let (count, size) = ret[idx];
+      *ret.get_mut(idx) = (count + 1, size + entry.size);
+    }
+    ret
+  }
+
+  /// Looks up a transaction by txid
+  pub fn get<'a>(&'a self, txid: &Sha256dHash) -> Option<&'a MempoolEntry> {
+    self.entries.find(txid)
+  }
+
+  /// The net change, in satoshi, which each pool transaction paying to or
+  /// spending from a script makes to the script's balance. The script is
+  /// recognised by `is_script`, since callers may know only its hash.
+  pub fn script_changes(&self, utxo_set: &UtxoSet, is_script: |&[u8]| -> bool)
+                        -> Vec<(Sha256dHash, i64)> {
+    let mut ret = vec![];
+    for (txid, entry) in self.entries.iter() {
+      let mut touched = false;
+      let mut change = 0i64;
+      for input in entry.tx.input.iter() {
+        match self.find_output(utxo_set, input.prev_hash, input.prev_index) {
+          Some(ref out) if is_script(out.script_pubkey.as_slice()) => {
+            touched = true;
+            change -= out.value as i64;
+          }
+          _ => {}
+        }
+      }
+      for out in entry.tx.output.iter() {
+        if is_script(out.script_pubkey.as_slice()) {
+          touched = true;
+          change += out.value as i64;
+        }
+      }
+      if touched {
+        ret.push((*txid, change));
+      }
+    }
+    ret
+  }
+
+  /// Looks up an output, first in the pool and then in the UTXO set
+  fn find_output(&self, utxo_set: &UtxoSet, txid: Sha256dHash, vout: u32) -> Option<TxOut> {
+    match self.entries.find(&txid) {
+      Some(entry) => entry.tx.output.as_slice().get(vout as uint).map(|out| out.clone()),
+      None => utxo_set.get_utxo(txid, vout).map(|(_, out)| out.clone())
+    }
+  }
+
+  /// Validates a transaction and adds it to the pool, evicting whatever
+  /// it replaces and, if the pool is then too large, whatever has the
+  /// lowest fee rate. Returns the txid on success.
+  pub fn accept(&mut self, tx: Transaction, utxo_set: &UtxoSet, now: i64)
+                -> Result<Sha256dHash, MempoolError> {
+    let txid = tx.bitcoin_hash();
+    if self.entries.contains_key(&txid) {
+      return Err(AlreadyHave);
+    }
+    if tx.input.is_empty() || tx.output.is_empty() {
+      return Err(Empty);
+    }
+
+    // Check inputs, noting any pool transactions we conflict with
+    let mut total_in = 0u64;
+    let mut conflicts = vec![];
+    for (n, input) in tx.input.iter().enumerate() {
+      match self.find_output(utxo_set, input.prev_hash, input.prev_index) {
+        Some(out) => { total_in += out.value; }
+        None => { return Err(MissingInput(input.prev_hash, input.prev_index)); }
+      }
+      // Inputs spending pool transactions cannot be checked against the
+      // UTXO set; they are only as good as their parents
+      if !self.entries.contains_key(&input.prev_hash) &&
+         input.validate(utxo_set, &tx, n).is_err() {
+        return Err(InvalidInput(input.prev_hash, input.prev_index));
+      }
+      match self.spends.find(&(input.prev_hash, input.prev_index)) {
+        Some(spender) => {
+          if !conflicts.contains(spender) {
+            conflicts.push(*spender);
+          }
+        }
+        None => {}
+      }
+    }
+    let total_out = tx.output.iter().fold(0, |sum, out| sum + out.value);
+    if total_out > total_in {
+      return Err(NegativeFee);
+    }
+    let fee = total_in - total_out;
+
+    // Everything we would replace must signal it, and we must outbid it
+    if !conflicts.is_empty() {
+      let mut replaced = vec![];
+      for conflict in conflicts.iter() {
+        if !self.entries.find(conflict).unwrap().signals_rbf() {
+          return Err(Conflict(*conflict));
+        }
+        self.collect_descendants(*conflict, &mut replaced);
+      }
+      // We cannot spend a transaction we are replacing
+      for input in tx.input.iter() {
+        if replaced.contains(&input.prev_hash) {
+          return Err(Conflict(input.prev_hash));
+        }
+      }
+      let replaced_fee = replaced.iter().fold(0, |sum, txid| {
+        sum + self.entries.find(txid).unwrap().fee
+      });
+      if fee <= replaced_fee {
+        return Err(Conflict(conflicts[0]));
+      }
+      for txid in replaced.iter() {
+        self.remove_one(txid);
+      }
+    }
+
+    let size = serialize(&tx).unwrap().len();
+    for input in tx.input.iter() {
+      self.spends.insert((input.prev_hash, input.prev_index), txid);
+    }
+    self.entries.insert(txid, MempoolEntry { tx: tx, fee: fee, size: size, time: now });
+    self.total_size += size;
+
+    self.trim();
+    if self.entries.contains_key(&txid) { Ok(txid) } else { Err(FeeTooLow) }
+  }
+
+  /// Collects a transaction and every pool transaction which spends it,
+  /// directly or indirectly
+  fn collect_descendants(&self, txid: Sha256dHash, ret: &mut Vec<Sha256dHash>) {
+    if ret.contains(&txid) {
+      return;
+    }
+    ret.push(txid);
+    let n_outputs = match self.entries.find(&txid) {
+      Some(entry) => entry.tx.output.len(),
+      None => { return; }
+    };
+    for vout in range(0, n_outputs) {
+      match self.spends.find(&(txid, vout as u32)) {
+        Some(&child) => self.collect_descendants(child, ret),
+        None => {}
+      }
+    }
+  }
+
+  /// Removes a single transaction, leaving any descendants in place
+  fn remove_one(&mut self, txid: &Sha256dHash) -> Option<MempoolEntry> {
+    match self.entries.pop(txid) {
+      Some(entry) => {
+        for input in entry.tx.input.iter() {
+          let key = (input.prev_hash, input.prev_index);
+          if self.spends.find(&key) == Some(txid) {
+            self.spends.remove(&key);
+          }
+        }
+        self.total_size -= entry.size;
+        Some(entry)
+      }
+      None => None
+    }
+  }
+
+  /// Removes a transaction and everything which spends it. Returns the
+  /// number of transactions removed.
+  pub fn remove(&mut self, txid: Sha256dHash) -> uint {
+    let mut doomed = vec![];
+    self.collect_descendants(txid, &mut doomed);
+    let mut count = 0;
+    for txid in doomed.iter() {
+      if self.remove_one(txid).is_some() {
+        count += 1;
+      }
+    }
+    count
+  }
+
+  /// Removes transactions which entered the pool more than `max_age`
+  /// seconds before `now`, and everything which spends them. Returns the
+  /// number of transactions removed.
+  pub fn expire(&mut self, now: i64, max_age: i64) -> uint {
+    let expired: Vec<Sha256dHash> = self.entries.iter()
+                                        .filter(|&(_, entry)| entry.time + max_age < now)
+                                        .map(|(txid, _)| *txid)
+                                        .collect();
+    let mut count = 0;
+    for txid in expired.move_iter() {
+      count += self.remove(txid);
+    }
+    count
+  }
+
+  /// Evicts the lowest fee rate transactions (and their descendants)
+  /// until the pool fits within its size limit
+  fn trim(&mut self) {
+    while self.total_size > self.max_size {
+      let worst = match self.entries.iter().min_by(|&(_, entry)| entry.fee_rate()) {
+        Some((txid, _)) => *txid,
+        None => { break; }
+      };
+      self.remove(worst);
+    }
+  }
+
+  /// Removes transactions confirmed by a newly connected block, and any
+  /// which conflict with them
+  pub fn block_connected(&mut self, block: &Block) {
+    for tx in block.txdata.iter() {
+      self.remove_one(&tx.bitcoin_hash());
+      for input in tx.input.iter() {
+        let conflict = self.spends.find(&(input.prev_hash, input.prev_index)).map(|txid| *txid);
+        match conflict {
+          Some(txid) => { self.remove(txid); }
+          None => {}
+        }
+      }
+    }
+  }
+
+  /// Re-adds transactions from blocks removed by a reorg. The blocks should
+  /// be given in chain order, and the UTXO set should reflect the new chain,
+  /// so that transactions confirmed or double-spent by it are dropped.
+  /// Returns the number of transactions reinstated.
+  pub fn reinstate(&mut self, blocks: &[Vec<Transaction>], utxo_set: &UtxoSet, now: i64)
+                   -> uint {
+    let mut count = 0;
+    for txdata in blocks.iter() {
+      for tx in txdata.iter() {
+        if self.accept(tx.clone(), utxo_set, now).is_ok() {
+          count += 1;
+        }
+      }
+    }
+    count
+  }
+}
+
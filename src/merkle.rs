@@ -0,0 +1,289 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Partial Merkle Trees
+//!
+//! Proofs that some transactions are in a block, as carried by BIP 37
+//! `merkleblock` messages and `gettxoutproof` results: the merkle tree of
+//! the block's txids pruned down to the branches leading to the matched
+//! ones. A tree is serialized as the number of transactions in the block,
+//! the hashes left in the pruned tree, and one flag bit per node visited,
+//! depth first, saying whether a match lies beneath it.
+//!
+
+use std::cmp;
+
+use bitcoin::network::encodable::{ConsensusDecodable, ConsensusEncodable};
+use bitcoin::network::serialize::{SimpleDecoder, SimpleEncoder, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+/// A block's merkle tree, pruned down to the branches leading to some of
+/// its transactions
+#[deriving(Clone, PartialEq, Show)]
+pub struct PartialMerkleTree {
+  /// Number of transactions in the block
+  n_transactions: u32,
+  /// Hashes of the pruned tree, depth first
+  hashes: Vec<Sha256dHash>,
+  /// Whether each node visited, depth first, is an ancestor of a match
+  bits: Vec<bool>
+}
+
+/// Hashes two child nodes into their parent
+fn parent_hash(left: &Sha256dHash, right: &Sha256dHash) -> Sha256dHash {
+  let mut data = serialize(left).unwrap();
+  data.push_all(serialize(right).unwrap().as_slice());
+  Sha256dHash::from_data(data.as_slice())
+}
+
+impl PartialMerkleTree {
+  /// Builds the tree proving that the txids with a true entry in `matches`
+  /// are among `txids`, the txids of a block in order
+  pub fn from_txids(txids: &[Sha256dHash], matches: &[bool]) -> PartialMerkleTree {
+    assert_eq!(txids.len(), matches.len());
+    let mut ret = PartialMerkleTree {
+      n_transactions: txids.len() as u32,
+      hashes: vec![],
+      bits: vec![]
+    };
+    let height = ret.height();
+    ret.build(height, 0, txids, matches);
+    ret
+  }
+
+  /// Number of transactions in the block
+  pub fn n_transactions(&self) -> u32 { self.n_transactions }
+
+  /// Number of nodes at a given height, with the leaves at height 0
+  fn width(&self, height: uint) -> uint {
+    (self.n_transactions as uint + (1 << height) - 1) >> height
+  }
+
+  /// Height of the root
+  fn height(&self) -> uint {
+    let mut height = 0;
+    while self.width(height) > 1 {
+      height += 1;
+    }
+    height
+  }
+
+  /// Computes the hash of the node at `pos`, `height` levels above the leaves
+  fn hash_at(&self, height: uint, pos: uint, txids: &[Sha256dHash]) -> Sha256dHash {
+    if height == 0 {
+      return txids[pos];
+    }
+    let left = self.hash_at(height - 1, pos * 2, txids);
+    // A missing right child is taken to be a copy of the left one
+    let right = if pos * 2 + 1 < self.width(height - 1) {
+      self.hash_at(height - 1, pos * 2 + 1, txids)
+    } else {
+      left
+    };
+    parent_hash(&left, &right)
+  }
+
+  /// Records the node at `pos`, and if a match lies beneath it, its children
+  fn build(&mut self, height: uint, pos: uint, txids: &[Sha256dHash], matches: &[bool]) {
+    let start = pos << height;
+    let end = cmp::min((pos + 1) << height, txids.len());
+    let parent_of_match = matches.slice(start, end).iter().any(|&m| m);
+    self.bits.push(parent_of_match);
+    if height == 0 || !parent_of_match {
+      let hash = self.hash_at(height, pos, txids);
+      self.hashes.push(hash);
+    } else {
+      self.build(height - 1, pos * 2, txids, matches);
+      if pos * 2 + 1 < self.width(height - 1) {
+        self.build(height - 1, pos * 2 + 1, txids, matches);
+      }
+    }
+  }
+
+  /// Walks the tree as `build` laid it out, computing the hash of the node
+  /// at `pos` and collecting the matched txids beneath it
+  fn extract(&self, height: uint, pos: uint, bits_used: &mut uint, hashes_used: &mut uint,
+             matches: &mut Vec<Sha256dHash>) -> Result<Sha256dHash, String> {
+    if *bits_used >= self.bits.len() {
+      return Err("ran out of flag bits".to_string());
+    }
+    let parent_of_match = self.bits[*bits_used];
+    *bits_used += 1;
+    if height == 0 || !parent_of_match {
+      if *hashes_used >= self.hashes.len() {
+        return Err("ran out of hashes".to_string());
+      }
+      let hash = self.hashes[*hashes_used];
+      *hashes_used += 1;
+      if height == 0 && parent_of_match {
+        matches.push(hash);
+      }
+      return Ok(hash);
+    }
+    let left = try!(self.extract(height - 1, pos * 2, bits_used, hashes_used, matches));
+    let right = if pos * 2 + 1 < self.width(height - 1) {
+      let right = try!(self.extract(height - 1, pos * 2 + 1, bits_used, hashes_used, matches));
+      // Otherwise two trees with different transactions would have the
+      // same root (CVE-2012-2459)
+      if right == left {
+        return Err("identical left and right branches".to_string());
+      }
+      right
+    } else {
+      left
+    };
+    Ok(parent_hash(&left, &right))
+  }
+
+  /// Checks that the tree is well-formed, returning its merkle root, to be
+  /// compared against a block header's, and the matched txids
+  pub fn extract_matches(&self) -> Result<(Sha256dHash, Vec<Sha256dHash>), String> {
+    if self.n_transactions == 0 {
+      return Err("no transactions".to_string());
+    }
+    if self.hashes.len() > self.n_transactions as uint {
+      return Err("more hashes than transactions".to_string());
+    }
+    if self.bits.len() < self.hashes.len() {
+      return Err("fewer flag bits than hashes".to_string());
+    }
+    let mut bits_used = 0;
+    let mut hashes_used = 0;
+    let mut matches = vec![];
+    let height = self.height();
+    let root = try!(self.extract(height, 0, &mut bits_used, &mut hashes_used, &mut matches));
+    // Everything must be used, except the padding of the last flag byte
+    if (bits_used + 7) / 8 != (self.bits.len() + 7) / 8 {
+      return Err("unused flag bits".to_string());
+    }
+    if hashes_used != self.hashes.len() {
+      return Err("unused hashes".to_string());
+    }
+    Ok((root, matches))
+  }
+}
+
+impl<S: SimpleEncoder<E>, E> ConsensusEncodable<S, E> for PartialMerkleTree {
+  fn consensus_encode(&self, s: &mut S) -> Result<(), E> {
+    // Flag bits are packed least significant first
+    let mut bytes = Vec::from_elem((self.bits.len() + 7) / 8, 0u8);
+    for (n, &bit) in self.bits.iter().enumerate() {
+      if bit {
+        *bytes.get_mut(n / 8) |= 1 << (n % 8);
+      }
+    }
+    try!(self.n_transactions.consensus_encode(s));
+    try!(self.hashes.consensus_encode(s));
+    bytes.consensus_encode(s)
+  }
+}
+
+impl<D: SimpleDecoder<E>, E> ConsensusDecodable<D, E> for PartialMerkleTree {
+  fn consensus_decode(d: &mut D) -> Result<PartialMerkleTree, E> {
+    let n_transactions: u32 = try!(ConsensusDecodable::consensus_decode(d));
+    let hashes: Vec<Sha256dHash> = try!(ConsensusDecodable::consensus_decode(d));
+    let bytes: Vec<u8> = try!(ConsensusDecodable::consensus_decode(d));
+    let bits = range(0, bytes.len() * 8).map(|n| bytes[n / 8] & (1 << (n % 8)) != 0).collect();
+    Ok(PartialMerkleTree { n_transactions: n_transactions, hashes: hashes, bits: bits })
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use serialize::hex::FromHex;
+
+  use bitcoin::network::serialize::{deserialize, serialize};
+  use bitcoin::util::hash::Sha256dHash;
+
+  use rpc_server::hash_from_hex;
+  use super::PartialMerkleTree;
+
+  // Block 170, the first with a transaction other than a coinbase
+  static BLOCK_170_TXIDS: [&'static str, ..2] = [
+    "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082",
+    "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16"
+  ];
+  static BLOCK_170_ROOT: &'static str = "7dac2c5666815c17a3b36427de37bb9d2e2c5ccec3f8633eb91a4205cb4c10ff";
+  // Its merkleblock tree matching the second transaction: both txids,
+  // then flag bits 1, 0, 1
+  static BLOCK_170_TREE: &'static str = "020000000282501c1178fa0b222c1f3d474ec726b832013f0a532b44bb620cce8624a5feb1169e1e83e930853391bc6f35f605c6754cfead57cf8387639d3b4096c54f18f40105";
+
+  // Txid of the genesis block coinbase, its only transaction
+  static GENESIS_TXID: &'static str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+  static GENESIS_TREE: &'static str = "01000000013ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a0101";
+
+  fn block_170_txids() -> Vec<Sha256dHash> {
+    BLOCK_170_TXIDS.iter().map(|s| hash_from_hex(*s).unwrap()).collect()
+  }
+
+  fn decode(hex: &str) -> PartialMerkleTree {
+    deserialize(hex.from_hex().unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_block_170() {
+    let txids = block_170_txids();
+    let tree = PartialMerkleTree::from_txids(txids.as_slice(), [false, true]);
+    assert_eq!(tree.n_transactions(), 2);
+    assert_eq!(serialize(&tree).unwrap(), BLOCK_170_TREE.from_hex().unwrap());
+    assert_eq!(decode(BLOCK_170_TREE), tree);
+    assert_eq!(tree.extract_matches(),
+               Ok((hash_from_hex(BLOCK_170_ROOT).unwrap(), vec![txids[1]])));
+  }
+
+  #[test]
+  fn test_genesis() {
+    let txid = hash_from_hex(GENESIS_TXID).unwrap();
+    let tree = PartialMerkleTree::from_txids([txid], [true]);
+    assert_eq!(serialize(&tree).unwrap(), GENESIS_TREE.from_hex().unwrap());
+    // A lone transaction is its own merkle root
+    assert_eq!(decode(GENESIS_TREE).extract_matches(), Ok((txid, vec![txid])));
+  }
+
+  #[test]
+  fn test_no_matches() {
+    let tree = PartialMerkleTree::from_txids(block_170_txids().as_slice(), [false, false]);
+    assert_eq!(tree.extract_matches(), Ok((hash_from_hex(BLOCK_170_ROOT).unwrap(), vec![])));
+  }
+
+  #[test]
+  fn test_odd_width() {
+    // The third transaction has no sibling, so is paired with itself
+    let txids: Vec<Sha256dHash> = range(0u8, 3).map(|n| Sha256dHash::from_data([n])).collect();
+    let tree = PartialMerkleTree::from_txids(txids.as_slice(), [false, false, true]);
+    assert_eq!(serialize(&tree).unwrap(),
+               "03000000024bbe83bc38ebe2bcc7520d234139df1c0eb9ffa51f83eab1c5129b5b906b76551cc3adea40ebfd94433ac004777d68150cce9db4c771bc7de1b297a7b795bbba010d"
+                 .from_hex().unwrap());
+    let root = hash_from_hex("d0c1e5f32d1d424371ac1018770af4446140436d5926d112c67f562fe0df29e1").unwrap();
+    assert_eq!(tree.extract_matches(), Ok((root, vec![txids[2]])));
+  }
+
+  #[test]
+  fn test_malformed() {
+    // Both leaves the same, which would collide with a one-transaction tree
+    let dup = "0200000002".to_string() + BLOCK_170_TREE.slice(10, 74) +
+              BLOCK_170_TREE.slice(10, 74) + "0107";
+    assert!(decode(dup.as_slice()).extract_matches().is_err());
+    // A hash left over
+    let extra = "0100000002".to_string() + GENESIS_TREE.slice(10, 74) +
+                GENESIS_TREE.slice(10, 74) + "0101";
+    assert!(decode(extra.as_slice()).extract_matches().is_err());
+    // Flag bits run out
+    let short = "0200000002".to_string() + BLOCK_170_TREE.slice(10, 138) + "00";
+    assert!(decode(short.as_slice()).extract_matches().is_err());
+    // No transactions
+    assert!(decode("000000000000").extract_matches().is_err());
+  }
+}
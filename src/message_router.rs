@@ -0,0 +1,307 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Message Router
+//!
+//! A task which reads everything the peer sends us and sorts it into a
+//! queue per subsystem, so that e.g. the headers sync waiting for a
+//! `headers` message does not throw away a transaction meant for the
+//! mempool. Pings are answered by the router itself.
+//!
+//! The mempool queue is bounded: while nothing drains it, e.g. during the
+//! initial sync, `tx` and `inv` messages beyond `MEMPOOL_QUEUE_SIZE` are
+//! dropped rather than piling up in memory. Missing a few only means
+//! learning of those transactions later, when they are mined.
+//!
+//! Messages are classified once, by `Command`, which names the message
+//! types by their 12-byte command field. The router sorts by it and the
+//! idle loop logs by it. A command field this tree does not know decodes
+//! to `Unknown` with the raw bytes, rather than failing with a string.
+//!
+//! Each connection gets its own router. When the connection fails, every
+//! queue receives `Disconnected`, and whoever sees it first reconnects and
+//! starts a new router; the old queues are then dropped.
+//!
+
+use std::comm::{Full, RecvDisconnected};
+use std::fmt;
+use std::str;
+
+use phf::PhfMap;
+
+use bitcoin::network::message::{mod, SocketResponse, NetworkMessage,
+                                MessageReceived, ConnectionFailed};
+use bitcoin::network::socket::Socket;
+use bitcoin::util::misc::consume_err;
+
+use constants::MEMPOOL_QUEUE_SIZE;
+
+/// Something taken from a subsystem's queue
+pub enum Routed {
+  /// A message from the peer
+  Message(NetworkMessage),
+  /// The connection failed, with the given error
+  Disconnected(String)
+}
+
+/// The subsystems which messages are sorted into
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Subsystem {
+  /// `headers` messages, for the headers sync
+  HeaderSync,
+  /// `block` and `notfound` messages, for the UTXO sync and block data fetch
+  BlockFetch,
+  /// `tx` and `inv` messages
+  MempoolMessages,
+  /// `pong` messages, for latency measurement
+  Pings,
+  /// Everything else, e.g. `version`, handled by the idle loop
+  Control
+}
+
+/// The type of a network message, as named by its command field
+#[deriving(Clone, PartialEq, Eq)]
+pub enum Command {
+  /// `version`
+  Version,
+  /// `verack`
+  Verack,
+  /// `addr`
+  Addr,
+  /// `inv`
+  Inv,
+  /// `getdata`
+  GetData,
+  /// `notfound`
+  NotFound,
+  /// `getblocks`
+  GetBlocks,
+  /// `getheaders`
+  GetHeaders,
+  /// `tx`
+  Tx,
+  /// `block`
+  Block,
+  /// `headers`
+  Headers,
+  /// `ping`
+  Ping,
+  /// `pong`
+  Pong,
+  /// A command we do not know, with the raw command field
+  Unknown([u8, ..12])
+}
+
+static COMMANDS: PhfMap<&'static str, Command> = phf_map! {
+  "version" => Version,
+  "verack" => Verack,
+  "addr" => Addr,
+  "inv" => Inv,
+  "getdata" => GetData,
+  "notfound" => NotFound,
+  "getblocks" => GetBlocks,
+  "getheaders" => GetHeaders,
+  "tx" => Tx,
+  "block" => Block,
+  "headers" => Headers,
+  "ping" => Ping,
+  "pong" => Pong
+};
+
+impl Command {
+  /// The command of a decoded message
+  pub fn of(msg: &NetworkMessage) -> Command {
+    match *msg {
+      message::Version(_) => Version,
+      message::Verack => Verack,
+      message::Addr(_) => Addr,
+      message::Inv(_) => Inv,
+      message::GetData(_) => GetData,
+      message::NotFound(_) => NotFound,
+      message::GetBlocks(_) => GetBlocks,
+      message::GetHeaders(_) => GetHeaders,
+      message::Tx(_) => Tx,
+      message::Block(_) => Block,
+      message::Headers(_) => Headers,
+      message::Ping(_) => Ping,
+      message::Pong(_) => Pong
+    }
+  }
+
+  /// Looks up the command named by a raw, NUL-padded command field
+  pub fn from_bytes(field: &[u8, ..12]) -> Command {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(12);
+    // The padding must be all NULs, or the field is malformed
+    if field.slice_from(len).iter().any(|&b| b != 0) {
+      return Unknown(*field);
+    }
+    match str::from_utf8(field.slice_to(len)) {
+      Some(name) => match COMMANDS.find_equiv(&name) {
+        Some(command) => command.clone(),
+        None => Unknown(*field)
+      },
+      None => Unknown(*field)
+    }
+  }
+
+  /// The subsystem messages with this command are sorted into
+  pub fn subsystem(&self) -> Subsystem {
+    match *self {
+      Headers => HeaderSync,
+      Block | NotFound => BlockFetch,
+      Tx | Inv => MempoolMessages,
+      Ping | Pong => Pings,
+      _ => Control
+    }
+  }
+}
+
+impl fmt::Show for Command {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Unknown(ref field) => {
+        try!(write!(f, "unknown ("));
+        for &b in field.iter().take_while(|&&b| b != 0) {
+          if b >= 0x20 && b < 0x7f {
+            try!(write!(f, "{}", b as char));
+          } else {
+            try!(write!(f, "\\x{:02x}", b));
+          }
+        }
+        write!(f, ")")
+      }
+      Version => write!(f, "version"),
+      Verack => write!(f, "verack"),
+      Addr => write!(f, "addr"),
+      Inv => write!(f, "inv"),
+      GetData => write!(f, "getdata"),
+      NotFound => write!(f, "notfound"),
+      GetBlocks => write!(f, "getblocks"),
+      GetHeaders => write!(f, "getheaders"),
+      Tx => write!(f, "tx"),
+      Block => write!(f, "block"),
+      Headers => write!(f, "headers"),
+      Ping => write!(f, "ping"),
+      Pong => write!(f, "pong")
+    }
+  }
+}
+
+/// The receiving ends of a router's queues
+pub struct MessageRouter {
+  /// `headers` messages
+  pub headers: Receiver<Routed>,
+  /// `block` and `notfound` messages
+  pub blocks: Receiver<Routed>,
+  /// `tx` and `inv` messages
+  pub mempool: Receiver<Routed>,
+  /// `pong` messages
+  pub pings: Receiver<Routed>,
+  /// Everything else
+  pub control: Receiver<Routed>
+}
+
+impl MessageRouter {
+  /// Spawns a router for a new connection. `sock` is used only to answer
+  /// pings.
+  pub fn start(net_chan: Receiver<SocketResponse>, sock: Socket) -> MessageRouter {
+    let (headers_tx, headers_rx) = channel();
+    let (blocks_tx, blocks_rx) = channel();
+    let (mempool_tx, mempool_rx) = sync_channel(MEMPOOL_QUEUE_SIZE);
+    let (pings_tx, pings_rx) = channel();
+    let (control_tx, control_rx) = channel();
+
+    spawn(proc() {
+      let mut sock = sock;
+      let senders: [&Sender<Routed>, ..4] = [&headers_tx, &blocks_tx, &pings_tx, &control_tx];
+      for response in net_chan.iter() {
+        match response {
+          MessageReceived(message::Ping(nonce)) => {
+            consume_err("Warning: failed to send pong in response to ping",
+              sock.send_message(message::Pong(nonce)));
+            // Still pass it on, so that it counts as traffic from the peer
+            let _ = pings_tx.send_opt(Message(message::Ping(nonce)));
+          }
+          MessageReceived(msg) => {
+            let queue = match Command::of(&msg).subsystem() {
+              HeaderSync => &headers_tx,
+              BlockFetch => &blocks_tx,
+              MempoolMessages => {
+                match mempool_tx.try_send(Message(msg)) {
+                  Ok(()) | Err(Full(_)) => { continue; }
+                  Err(RecvDisconnected(_)) => { break; }
+                }
+              }
+              Pings => &pings_tx,
+              Control => &control_tx
+            };
+            // If the receiver hung up, we have been replaced by a new router
+            if queue.send_opt(Message(msg)).is_err() {
+              break;
+            }
+          }
+          ConnectionFailed(e, tx) => {
+            tx.send(());
+            for sender in senders.iter() {
+              let _ = sender.send_opt(Disconnected(e.to_string()));
+            }
+            // The other queues will see this if the mempool queue is full
+            let _ = mempool_tx.try_send(Disconnected(e.to_string()));
+            break;
+          }
+        }
+      }
+    });
+
+    MessageRouter {
+      headers: headers_rx,
+      blocks: blocks_rx,
+      mempool: mempool_rx,
+      pings: pings_rx,
+      control: control_rx
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Command, Unknown, Version, GetHeaders, Pong};
+
+  fn field(name: &str) -> [u8, ..12] {
+    let mut ret = [0u8, ..12];
+    for (i, b) in name.bytes().enumerate() {
+      ret[i] = b;
+    }
+    ret
+  }
+
+  #[test]
+  fn test_from_bytes() {
+    assert_eq!(Command::from_bytes(&field("version")), Version);
+    assert_eq!(Command::from_bytes(&field("getheaders")), GetHeaders);
+    assert_eq!(Command::from_bytes(&field("pong")), Pong);
+    assert_eq!(Command::from_bytes(&field("sendheaders")), Unknown(field("sendheaders")));
+    // Garbage after the NUL padding
+    let mut bad = field("ping");
+    bad[11] = b'x';
+    assert_eq!(Command::from_bytes(&bad), Unknown(bad));
+  }
+
+  #[test]
+  fn test_show() {
+    assert_eq!(Version.to_string(), "version".to_string());
+    assert_eq!(Unknown(field("sendheaders")).to_string(),
+               "unknown (sendheaders)".to_string());
+  }
+}
@@ -0,0 +1,170 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Metrics
+//!
+//! Counters and gauges describing the wallet, as returned by the
+//! `getmetrics` RPC, as JSON or in the Prometheus text format. The HTTP
+//! server serves the latter at `/metrics`.
+//!
+
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::ToJson;
+
+/// The kind of a metric
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum MetricKind {
+  /// A value which only ever goes up
+  Counter,
+  /// A value which may go up or down
+  Gauge
+}
+
+impl MetricKind {
+  /// The name of the kind, as used in the text format
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Counter => "counter",
+      Gauge => "gauge"
+    }
+  }
+}
+
+/// A single metric, and its values for each set of labels
+struct Family {
+  name: String,
+  help: &'static str,
+  kind: MetricKind,
+  samples: Vec<(Vec<(&'static str, String)>, f64)>
+}
+
+/// Escapes a label value for the text format
+fn escape_label(value: &str) -> String {
+  value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+/// A set of metrics. Every name is prefixed with `wizards_wallet_`.
+pub struct Metrics {
+  families: Vec<Family>
+}
+
+impl Metrics {
+  /// Creates an empty set of metrics
+  pub fn new() -> Metrics {
+    Metrics { families: vec![] }
+  }
+
+  /// Adds a value of a metric, which is created the first time it is used
+  pub fn add(&mut self, kind: MetricKind, name: &str, help: &'static str,
+             labels: Vec<(&'static str, String)>, value: f64) {
+    let name = format!("wizards_wallet_{}", name);
+    match self.families.mut_iter().find(|f| f.name == name) {
+      Some(family) => {
+        family.samples.push((labels, value));
+        return;
+      }
+      None => {}
+    }
+    self.families.push(Family {
+      name: name,
+      help: help,
+      kind: kind,
+      samples: vec![(labels, value)]
+    });
+  }
+
+  /// Adds an unlabelled gauge
+  pub fn gauge(&mut self, name: &str, help: &'static str, value: f64) {
+    self.add(Gauge, name, help, vec![], value);
+  }
+
+  /// Adds an unlabelled counter
+  pub fn counter(&mut self, name: &str, help: &'static str, value: f64) {
+    self.add(Counter, name, help, vec![], value);
+  }
+
+  /// Renders the metrics in the Prometheus text format
+  pub fn to_text(&self) -> String {
+    let mut ret = String::new();
+    for family in self.families.iter() {
+      ret.push_str(format!("# HELP {} {}\n# TYPE {} {}\n",
+                           family.name, family.help, family.name, family.kind.name()).as_slice());
+      for &(ref labels, value) in family.samples.iter() {
+        ret.push_str(family.name.as_slice());
+        if !labels.is_empty() {
+          let labels: Vec<String> = labels.iter().map(|&(name, ref value)| {
+            format!("{}=\"{}\"", name, escape_label(value.as_slice()))
+          }).collect();
+          ret.push_str(format!("{{{}}}", labels.connect(",")).as_slice());
+        }
+        ret.push_str(format!(" {}\n", value).as_slice());
+      }
+    }
+    ret
+  }
+}
+
+impl ToJson for Metrics {
+  fn to_json(&self) -> json::Json {
+    let mut ret = vec![];
+    for family in self.families.iter() {
+      let mut samples = vec![];
+      for &(ref labels, value) in family.samples.iter() {
+        let mut label_obj = TreeMap::new();
+        for &(name, ref value) in labels.iter() {
+          label_obj.insert(name.to_string(), json::String(value.clone()));
+        }
+        let mut obj = TreeMap::new();
+        obj.insert("labels".to_string(), json::Object(label_obj));
+        obj.insert("value".to_string(), value.to_json());
+        samples.push(json::Object(obj));
+      }
+      let mut obj = TreeMap::new();
+      obj.insert("name".to_string(), json::String(family.name.clone()));
+      obj.insert("help".to_string(), json::String(family.help.to_string()));
+      obj.insert("type".to_string(), json::String(family.kind.name().to_string()));
+      obj.insert("samples".to_string(), json::List(samples));
+      ret.push(json::Object(obj));
+    }
+    json::List(ret)
+  }
+}
+
+/// Timings of saves of the blockchain and UTXO set to disk
+#[deriving(Clone, Default)]
+pub struct SaveStats {
+  /// Number of saves, journalled or full
+  pub saves: u64,
+  /// Number of saves which rewrote everything
+  pub full_saves: u64,
+  /// Number of saves which failed to write something
+  pub failures: u64,
+  /// Duration, in ns, of the last save
+  pub last_duration_ns: u64,
+  /// Total duration, in ns, of all saves
+  pub total_duration_ns: u64
+}
+
+impl SaveStats {
+  /// Records a finished save
+  pub fn record(&mut self, full: bool, ok: bool, duration_ns: u64) {
+    self.saves += 1;
+    if full { self.full_saves += 1; }
+    if !ok { self.failures += 1; }
+    self.last_duration_ns = duration_ns;
+    self.total_duration_ns += duration_ns;
+  }
+}
+
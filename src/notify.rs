@@ -0,0 +1,75 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Notification Hooks
+//!
+//! Runs user-configured shell commands when the chain tip changes or a
+//! wallet transaction is seen or confirmed, in the manner of bitcoind's
+//! `-blocknotify` and `-walletnotify`. Any `%s` in a command is replaced
+//! by the block hash or txid.
+//!
+
+use std::io::process::Command;
+
+use bitcoind::{Debug, Warning};
+use events::{Blocks, EventBus, NewTip, WalletConfirmation, WalletTransaction, WalletTransactions};
+use user_data::NetworkConfig;
+
+/// Runs a hook command through the shell, logging any failure
+fn run_hook(config: &NetworkConfig, template: &str, arg: String) {
+  let cmd = template.replace("%s", arg.as_slice());
+  debug!((config.network, config.debug_level), Debug, "Running notify hook `{}`", cmd);
+  match Command::new("sh").arg("-c").arg(cmd.as_slice()).status() {
+    Ok(status) => {
+      if !status.success() {
+        debug!((config.network, config.debug_level), Warning,
+               "Notify hook `{}` failed: {}", cmd, status);
+      }
+    }
+    Err(e) => {
+      debug!((config.network, config.debug_level), Warning,
+             "Could not run notify hook `{}`: {}", cmd, e);
+    }
+  }
+}
+
+/// Subscribes to the event bus and spawns a task to run the configured
+/// hooks. Does nothing if no hooks are configured. Hooks are run one at
+/// a time, in the order their events were published.
+pub fn start(config: &NetworkConfig, events: &EventBus) {
+  let mut topics = vec![];
+  if config.block_notify.is_some() { topics.push(Blocks); }
+  if config.wallet_notify.is_some() { topics.push(WalletTransactions); }
+  if topics.is_empty() {
+    return;
+  }
+
+  let config = config.clone();
+  let rx = events.subscribe(topics);
+  spawn(proc() {
+    for event in rx.iter() {
+      match (event, &config.block_notify, &config.wallet_notify) {
+        (NewTip(hash, _), &Some(ref cmd), _) => {
+          run_hook(&config, cmd.as_slice(), format!("{:x}", hash));
+        }
+        (WalletTransaction(_, txid), _, &Some(ref cmd)) |
+        (WalletConfirmation(_, txid, _), _, &Some(ref cmd)) => {
+          run_hook(&config, cmd.as_slice(), format!("{:x}", txid));
+        }
+        _ => {}
+      }
+    }
+  });
+}
+
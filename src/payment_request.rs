@@ -0,0 +1,611 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Payment Protocol
+//!
+//! BIP70 payment requests: fetching a `PaymentRequest` from a merchant,
+//! checking its signature, reading the outputs it asks to be paid,
+//! checking that it is for our network and has not expired, and sending
+//! the merchant a `Payment` once the paying transaction is broadcast. The
+//! messages are protocol buffers, of which only the two wire types BIP70
+//! uses are handled.
+//!
+//! Requests are only fetched over `https://`, from servers whose
+//! certificates the system trusts. A signed request must carry an X.509
+//! certificate chain to a trusted root, whose leaf signed the request;
+//! its subject is reported as the merchant. Unsigned requests are allowed,
+//! as BIP70 allows them, but name no merchant.
+//!
+
+use std::ascii::StrAsciiExt;
+use std::collections::TreeMap;
+use std::io::{BufferedReader, EndOfFile, IoError, IoResult, InvalidInput, OtherIoError};
+use std::num::from_str_radix;
+use std::str::from_utf8;
+use serialize::hex::ToHex;
+use serialize::json;
+use serialize::json::ToJson;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::network::constants::{Network, Bitcoin, BitcoinTestnet};
+use bitcoin::network::serialize::serialize;
+
+use constants::{PAYMENT_REQUEST_MAX_SIZE, PAYMENT_REQUEST_TIMEOUT};
+use script_info;
+use tls;
+use tls::TlsStream;
+
+/// Longest HTTP status or header line, in bytes, that we will read
+static MAX_HEADER_LINE: uint = 8192;
+/// Most HTTP header lines that we will read
+static MAX_HEADER_LINES: uint = 100;
+
+/// A request for payment, as sent by a merchant
+#[deriving(Clone)]
+pub struct PaymentRequest {
+  /// Version of the payment details format
+  pub details_version: u64,
+  /// How the request is signed: "none", "x509+sha256" or "x509+sha1"
+  pub pki_type: String,
+  /// Subject of the certificate which signed the request, once checked
+  pub merchant: Option<String>,
+  /// What is to be paid
+  pub details: PaymentDetails,
+  /// DER-encoded certificate chain, signer first
+  certificates: Vec<Vec<u8>>,
+  /// Signature over `unsigned`
+  signature: Vec<u8>,
+  /// The request as serialized, with an empty signature, which is what
+  /// the merchant signed
+  unsigned: Vec<u8>
+}
+
+/// The body of a payment request
+#[deriving(Clone)]
+pub struct PaymentDetails {
+  /// Network the payment is on, "main" or "test"
+  pub network: String,
+  /// Outputs the paying transaction(s) must have
+  pub outputs: Vec<TxOut>,
+  /// Time (seconds since the epoch) at which the request was made
+  pub time: u64,
+  /// Time after which the request should not be paid, if any
+  pub expires: Option<u64>,
+  /// Note to the payer
+  pub memo: Option<String>,
+  /// Where to send the `Payment` message, if anywhere
+  pub payment_url: Option<String>,
+  /// Data to return to the merchant in the `Payment` message
+  pub merchant_data: Option<Vec<u8>>
+}
+
+/// Creates an error for a malformed message
+fn malformed(detail: &str) -> IoError {
+  IoError {
+    kind: InvalidInput,
+    desc: "Malformed payment protocol message",
+    detail: Some(detail.to_string())
+  }
+}
+
+/// The value of a protocol buffer field
+enum Field<'a> {
+  /// A varint, for integer types
+  Varint(u64),
+  /// A length-delimited value, for strings, bytes and submessages
+  Bytes(&'a [u8])
+}
+
+/// Reads the fields of a protocol buffer message in order
+struct ProtoReader<'a> {
+  data: &'a [u8],
+  pos: uint
+}
+
+impl<'a> ProtoReader<'a> {
+  fn new(data: &'a [u8]) -> ProtoReader<'a> {
+    ProtoReader { data: data, pos: 0 }
+  }
+
+  fn read_varint(&mut self) -> IoResult<u64> {
+    let mut ret = 0u64;
+    let mut shift = 0u;
+    loop {
+      if self.pos >= self.data.len() || shift > 63 {
+        return Err(malformed("bad varint"));
+      }
+      let byte = self.data[self.pos];
+      self.pos += 1;
+      ret |= (byte & 0x7f) as u64 << shift;
+      if byte & 0x80 == 0 {
+        return Ok(ret);
+      }
+      shift += 7;
+    }
+  }
+
+  /// Reads the next field number and value, or `None` at the end of the
+  /// message
+  fn next_field(&mut self) -> IoResult<Option<(u64, Field<'a>)>> {
+    if self.pos == self.data.len() {
+      return Ok(None);
+    }
+    let key = try!(self.read_varint());
+    let value = match key & 7 {
+      0 => Varint(try!(self.read_varint())),
+      2 => {
+        let len = try!(self.read_varint());
+        if len > (self.data.len() - self.pos) as u64 {
+          return Err(malformed("truncated field"));
+        }
+        let data = self.data;
+        let start = self.pos;
+        self.pos += len as uint;
+        Bytes(data.slice(start, self.pos))
+      }
+      _ => { return Err(malformed("unsupported wire type")); }
+    };
+    Ok(Some((key >> 3, value)))
+  }
+}
+
+/// Reads a string field
+fn read_string(data: &[u8]) -> IoResult<String> {
+  match from_utf8(data) {
+    Some(s) => Ok(s.to_string()),
+    None => Err(malformed("string is not UTF-8"))
+  }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+  while n >= 0x80 {
+    out.push((n as u8 & 0x7f) | 0x80);
+    n >>= 7;
+  }
+  out.push(n as u8);
+}
+
+/// Writes a length-delimited field
+fn write_bytes(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+  write_varint(out, field << 3 | 2);
+  write_varint(out, data.len() as u64);
+  out.push_all(data);
+}
+
+/// Writes an integer field
+fn write_uint(out: &mut Vec<u8>, field: u64, n: u64) {
+  write_varint(out, field << 3);
+  write_varint(out, n);
+}
+
+/// Decodes an `Output` message
+fn decode_output(data: &[u8]) -> IoResult<TxOut> {
+  let mut reader = ProtoReader::new(data);
+  let mut ret = TxOut { value: 0, script_pubkey: Script::new() };
+  loop {
+    match try!(reader.next_field()) {
+      Some((1, Varint(amount))) => { ret.value = amount; }
+      Some((2, Bytes(script))) => { ret.script_pubkey = Script::from_vec(script.to_vec()); }
+      Some(_) => {}
+      None => { return Ok(ret); }
+    }
+  }
+}
+
+/// Encodes an `Output` message
+fn encode_output(out: &TxOut) -> Vec<u8> {
+  let mut ret = vec![];
+  write_uint(&mut ret, 1, out.value);
+  write_bytes(&mut ret, 2, out.script_pubkey.as_slice());
+  ret
+}
+
+impl PaymentDetails {
+  /// Decodes a serialized `PaymentDetails` message
+  pub fn from_slice(data: &[u8]) -> IoResult<PaymentDetails> {
+    let mut reader = ProtoReader::new(data);
+    let mut ret = PaymentDetails {
+      network: "main".to_string(),
+      outputs: vec![],
+      time: 0,
+      expires: None,
+      memo: None,
+      payment_url: None,
+      merchant_data: None
+    };
+    let mut have_time = false;
+    loop {
+      match try!(reader.next_field()) {
+        Some((1, Bytes(network))) => { ret.network = try!(read_string(network)); }
+        Some((2, Bytes(output))) => { ret.outputs.push(try!(decode_output(output))); }
+        Some((3, Varint(time))) => { ret.time = time; have_time = true; }
+        Some((4, Varint(expires))) => { ret.expires = Some(expires); }
+        Some((5, Bytes(memo))) => { ret.memo = Some(try!(read_string(memo))); }
+        Some((6, Bytes(url))) => { ret.payment_url = Some(try!(read_string(url))); }
+        Some((7, Bytes(data))) => { ret.merchant_data = Some(data.to_vec()); }
+        Some(_) => {}
+        None => { break; }
+      }
+    }
+    if !have_time {
+      return Err(malformed("payment details have no time"));
+    }
+    Ok(ret)
+  }
+}
+
+/// Decodes an `X509Certificates` message into its certificates
+fn decode_certificates(data: &[u8]) -> IoResult<Vec<Vec<u8>>> {
+  let mut reader = ProtoReader::new(data);
+  let mut ret = vec![];
+  loop {
+    match try!(reader.next_field()) {
+      Some((1, Bytes(cert))) => { ret.push(cert.to_vec()); }
+      Some(_) => {}
+      None => { return Ok(ret); }
+    }
+  }
+}
+
+impl PaymentRequest {
+  /// Decodes a serialized `PaymentRequest` message. Its signature is not
+  /// checked until `verify` is called.
+  pub fn from_slice(data: &[u8]) -> IoResult<PaymentRequest> {
+    let mut reader = ProtoReader::new(data);
+    let mut details_version = 1;
+    let mut pki_type = "none".to_string();
+    let mut details = None;
+    let mut certificates = vec![];
+    let mut signature = vec![];
+    let mut unsigned = vec![];
+    loop {
+      let start = reader.pos;
+      match try!(reader.next_field()) {
+        Some((1, Varint(version))) => { details_version = version; }
+        Some((2, Bytes(pki))) => { pki_type = try!(read_string(pki)); }
+        Some((3, Bytes(pki_data))) => { certificates = try!(decode_certificates(pki_data)); }
+        Some((4, Bytes(serialized))) => {
+          details = Some(try!(PaymentDetails::from_slice(serialized)));
+        }
+        Some((5, Bytes(sig))) => {
+          signature = sig.to_vec();
+          write_bytes(&mut unsigned, 5, &[]);
+          continue;
+        }
+        Some(_) => {}
+        None => { break; }
+      }
+      unsigned.push_all(data.slice(start, reader.pos));
+    }
+    match details {
+      Some(details) => Ok(PaymentRequest {
+        details_version: details_version,
+        pki_type: pki_type,
+        merchant: None,
+        details: details,
+        certificates: certificates,
+        signature: signature,
+        unsigned: unsigned
+      }),
+      None => Err(malformed("payment request has no details"))
+    }
+  }
+
+  /// Checks the request's signature, if it has one, and its certificate
+  /// chain, setting `merchant` to the signer
+  pub fn verify(&mut self) -> IoResult<()> {
+    let hash = match self.pki_type.as_slice() {
+      "none" => { return Ok(()); }
+      "x509+sha256" => tls::Sha256,
+      "x509+sha1" => tls::Sha1,
+      _ => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "Unsupported payment request signature type",
+          detail: Some(self.pki_type.clone())
+        });
+      }
+    };
+    let subject = try!(tls::verify_signature(self.certificates.as_slice(), hash,
+                                             self.unsigned.as_slice(),
+                                             self.signature.as_slice()));
+    self.merchant = Some(subject);
+    Ok(())
+  }
+
+  /// Checks that the request is for `network`, has not expired at `now`,
+  /// and asks for something to be paid
+  pub fn validate(&self, network: Network, now: i64) -> Result<(), String> {
+    let expected = match network { Bitcoin => "main", BitcoinTestnet => "test" };
+    if self.details.network.as_slice() != expected {
+      return Err(format!("request is for network `{}`, not `{}`", self.details.network, expected));
+    }
+    match self.details.expires {
+      Some(expires) if now >= 0 && now as u64 > expires => {
+        return Err(format!("request expired at {}", expires));
+      }
+      _ => {}
+    }
+    if self.details.outputs.is_empty() {
+      return Err("request has no outputs".to_string());
+    }
+    Ok(())
+  }
+
+  /// The total amount requested, in satoshi
+  pub fn amount(&self) -> u64 {
+    self.details.outputs.iter().fold(0, |sum, out| sum + out.value)
+  }
+
+  /// Whether a transaction has every output the request asks for, each
+  /// with at least the amount asked
+  pub fn paid_by(&self, tx: &Transaction) -> bool {
+    let mut used = Vec::from_elem(tx.output.len(), false);
+    for wanted in self.details.outputs.iter() {
+      let found = tx.output.iter().enumerate().position(|(n, out)| {
+        !used[n] && out.script_pubkey == wanted.script_pubkey && out.value >= wanted.value
+      });
+      match found {
+        Some(n) => { *used.get_mut(n) = true; }
+        None => { return false; }
+      }
+    }
+    true
+  }
+
+  /// Describes the request, with its outputs' addresses on `network`
+  pub fn summary_json(&self, network: Network) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("network".to_string(), self.details.network.to_json());
+    obj.insert("pki_type".to_string(), self.pki_type.to_json());
+    obj.insert("merchant".to_string(), self.merchant.to_json());
+    obj.insert("amount".to_string(), self.amount().to_json());
+    obj.insert("outputs".to_string(), json::List(self.details.outputs.iter().map(|out| {
+      let mut obj = TreeMap::new();
+      let script_type = script_info::classify(out.script_pubkey.as_slice());
+      obj.insert("amount".to_string(), out.value.to_json());
+      obj.insert("script_pubkey".to_string(), out.script_pubkey.as_slice().to_hex().to_json());
+      obj.insert("addresses".to_string(), script_type.addresses(network).to_json());
+      json::Object(obj)
+    }).collect()));
+    obj.insert("time".to_string(), self.details.time.to_json());
+    obj.insert("expires".to_string(), self.details.expires.to_json());
+    obj.insert("memo".to_string(), self.details.memo.to_json());
+    obj.insert("payment_url".to_string(), self.details.payment_url.to_json());
+    json::Object(obj)
+  }
+}
+
+/// Encodes a `Payment` message, which carries the paying transactions back
+/// to the merchant along with the request's merchant data
+pub fn encode_payment(request: &PaymentRequest, transactions: &[Transaction],
+                      refund_to: Option<&TxOut>, memo: Option<&str>) -> Vec<u8> {
+  let mut ret = vec![];
+  match request.details.merchant_data {
+    Some(ref data) => write_bytes(&mut ret, 1, data.as_slice()),
+    None => {}
+  }
+  for tx in transactions.iter() {
+    write_bytes(&mut ret, 2, serialize(tx).unwrap().as_slice());
+  }
+  match refund_to {
+    Some(out) => write_bytes(&mut ret, 3, encode_output(out).as_slice()),
+    None => {}
+  }
+  match memo {
+    Some(memo) => write_bytes(&mut ret, 4, memo.as_bytes()),
+    None => {}
+  }
+  ret
+}
+
+/// Decodes a `PaymentACK` message, returning its memo
+fn decode_payment_ack(data: &[u8]) -> IoResult<Option<String>> {
+  let mut reader = ProtoReader::new(data);
+  let mut memo = None;
+  loop {
+    match try!(reader.next_field()) {
+      Some((2, Bytes(s))) => { memo = Some(try!(read_string(s))); }
+      Some(_) => {}
+      None => { return Ok(memo); }
+    }
+  }
+}
+
+/// Reads a line of an HTTP response head, without its line ending
+fn read_header_line<R: Reader>(stream: &mut BufferedReader<R>) -> IoResult<String> {
+  let mut line = vec![];
+  loop {
+    let byte = try!(stream.read_byte());
+    if byte == b'\n' {
+      break;
+    }
+    if line.len() >= MAX_HEADER_LINE {
+      return Err(malformed("HTTP header line too long"));
+    }
+    line.push(byte);
+  }
+  if line.last() == Some(&b'\r') {
+    line.pop();
+  }
+  match from_utf8(line.as_slice()) {
+    Some(s) => Ok(s.to_string()),
+    None => Err(malformed("HTTP header is not UTF-8"))
+  }
+}
+
+/// Reads an HTTP response body of unknown length, up to `max_size` bytes
+fn read_to_end_bounded<R: Reader>(stream: &mut BufferedReader<R>, max_size: uint)
+                                 -> IoResult<Vec<u8>> {
+  let mut ret = vec![];
+  let mut buf = [0u8, ..4096];
+  loop {
+    match stream.read(buf.as_mut_slice()) {
+      Ok(n) => {
+        if ret.len() + n > max_size {
+          return Err(malformed("HTTP response too large"));
+        }
+        ret.push_all(buf.slice_to(n));
+      }
+      Err(ref e) if e.kind == EndOfFile => { return Ok(ret); }
+      Err(e) => { return Err(e); }
+    }
+  }
+}
+
+/// Reads a chunked HTTP response body, up to `max_size` bytes
+fn read_chunked<R: Reader>(stream: &mut BufferedReader<R>, max_size: uint)
+                          -> IoResult<Vec<u8>> {
+  let mut ret = vec![];
+  loop {
+    let line = try!(read_header_line(stream));
+    // Chunk extensions, after a semicolon, are ignored
+    let size_str = line.as_slice().split(';').next().unwrap_or("").trim();
+    let size: uint = match from_str_radix(size_str, 16) {
+      Some(size) => size,
+      None => { return Err(malformed("bad HTTP chunk size")); }
+    };
+    if size == 0 {
+      break;
+    }
+    if size > max_size - ret.len() {
+      return Err(malformed("HTTP response too large"));
+    }
+    ret.push_all(try!(stream.read_exact(size)).as_slice());
+    if !try!(read_header_line(stream)).is_empty() {
+      return Err(malformed("HTTP chunk longer than its size"));
+    }
+  }
+  // Skip any trailers
+  for _ in range(0, MAX_HEADER_LINES) {
+    if try!(read_header_line(stream)).is_empty() {
+      return Ok(ret);
+    }
+  }
+  Err(malformed("too many HTTP trailers"))
+}
+
+/// Reads an HTTP response, returning its body if the status is 200. The
+/// body may be sent with a length, chunked, or until the connection
+/// closes, and in any case may be no longer than `max_size` bytes.
+fn read_response<R: Reader>(stream: &mut BufferedReader<R>, max_size: uint) -> IoResult<Vec<u8>> {
+  let status_line = try!(read_header_line(stream));
+  let status = status_line.as_slice().split(' ').nth(1).and_then(|s| from_str::<uint>(s));
+  if status != Some(200) {
+    return Err(IoError {
+      kind: OtherIoError,
+      desc: "Merchant returned an HTTP error",
+      detail: Some(status_line)
+    });
+  }
+
+  let mut content_length = None;
+  let mut chunked = false;
+  let mut n_lines = 0u;
+  loop {
+    let line = try!(read_header_line(stream));
+    if line.is_empty() {
+      break;
+    }
+    n_lines += 1;
+    if n_lines > MAX_HEADER_LINES {
+      return Err(malformed("too many HTTP headers"));
+    }
+    let (name, value) = match line.as_slice().find(':') {
+      Some(n) => (line.as_slice().slice_to(n).trim().to_ascii_lower(),
+                  line.as_slice().slice_from(n + 1).trim()),
+      None => { return Err(malformed("bad HTTP header")); }
+    };
+    match name.as_slice() {
+      "content-length" => match from_str::<uint>(value) {
+        Some(len) => { content_length = Some(len); }
+        None => { return Err(malformed("bad HTTP content length")); }
+      },
+      "transfer-encoding" => { chunked = value.to_ascii_lower().as_slice().contains("chunked"); }
+      _ => {}
+    }
+  }
+
+  if chunked {
+    read_chunked(stream, max_size)
+  } else {
+    match content_length {
+      Some(len) if len > max_size => Err(malformed("HTTP response too large")),
+      Some(len) => stream.read_exact(len),
+      None => read_to_end_bounded(stream, max_size)
+    }
+  }
+}
+
+/// Makes an HTTP request to an `https://` URL, returning the response
+/// body, which may be no longer than `max_size` bytes
+fn https_request(url: &str, method: &str, accept: &str, content: Option<(&str, &[u8])>,
+                 max_size: uint) -> IoResult<Vec<u8>> {
+  let bad_url = IoError {
+    kind: InvalidInput,
+    desc: "Unsupported payment protocol URL, only https:// is allowed",
+    detail: Some(url.to_string())
+  };
+  if !url.starts_with("https://") {
+    return Err(bad_url);
+  }
+  let rest = url.slice_from(8);
+  let (authority, path) = match rest.find('/') {
+    Some(n) => (rest.slice_to(n), rest.slice_from(n)),
+    None => (rest, "/")
+  };
+  let (host, port) = match authority.find(':') {
+    Some(n) => match from_str::<u16>(authority.slice_from(n + 1)) {
+      Some(port) => (authority.slice_to(n), port),
+      None => { return Err(bad_url); }
+    },
+    None => (authority, 443)
+  };
+
+  let mut stream = try!(TlsStream::connect(host, port, PAYMENT_REQUEST_TIMEOUT));
+  try!(stream.write_str(format!("{} {} HTTP/1.1\r\n\
+                                 Host: {}\r\n\
+                                 Accept: {}\r\n\
+                                 Connection: close\r\n",
+                                method, path, authority, accept).as_slice()));
+  match content {
+    Some((content_type, body)) => {
+      try!(stream.write_str(format!("Content-Type: {}\r\n\
+                                     Content-Length: {}\r\n\r\n",
+                                    content_type, body.len()).as_slice()));
+      try!(stream.write(body));
+    }
+    None => { try!(stream.write_str("\r\n")); }
+  }
+  read_response(&mut BufferedReader::new(stream), max_size)
+}
+
+/// Fetches and decodes a payment request, checking its signature
+pub fn fetch(url: &str) -> IoResult<PaymentRequest> {
+  let body = try!(https_request(url, "GET", "application/bitcoin-paymentrequest", None,
+                                PAYMENT_REQUEST_MAX_SIZE));
+  let mut request = try!(PaymentRequest::from_slice(body.as_slice()));
+  try!(request.verify());
+  Ok(request)
+}
+
+/// Sends an encoded `Payment` message to the request's payment URL,
+/// returning the memo of the merchant's acknowledgement
+pub fn send_payment(url: &str, payment: &[u8]) -> IoResult<Option<String>> {
+  let body = try!(https_request(url, "POST", "application/bitcoin-paymentack",
+                                Some(("application/bitcoin-payment", payment)),
+                                PAYMENT_REQUEST_MAX_SIZE));
+  decode_payment_ack(body.as_slice())
+}
@@ -0,0 +1,135 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Sync Progress
+//!
+//! How far headers or UTXO sync has got, as a percentage and an estimated
+//! time to finish. The target height is whichever is higher of the height
+//! the peer advertised on connecting and a guess from how old the block
+//! we are at is, since the peer's height goes stale while we sync.
+//!
+
+use std::cmp;
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::ToJson;
+use time;
+
+use constants::{BLOCK_SPACING, SYNC_PROGRESS_LOG_FREQUENCY};
+use events::{SyncState, Synced};
+
+/// Progress of the current sync stage
+#[deriving(Clone)]
+pub struct SyncProgress {
+  /// The stage in progress
+  pub stage: SyncState,
+  /// Height reached
+  pub height: uint,
+  /// Timestamp of the block at `height`
+  pub block_time: i64,
+  /// Height the peer advertised on connecting, if any
+  pub peer_height: Option<uint>,
+  /// Height at which the stage started
+  start_height: uint,
+  /// Time (seconds since the epoch) at which the stage started
+  started_at: i64,
+  /// Time (seconds since the epoch) at which progress was last logged
+  last_logged: i64
+}
+
+impl SyncProgress {
+  /// Creates a record of no sync in progress
+  pub fn new() -> SyncProgress {
+    SyncProgress {
+      stage: Synced,
+      height: 0,
+      block_time: 0,
+      peer_height: None,
+      start_height: 0,
+      started_at: 0,
+      last_logged: 0
+    }
+  }
+
+  /// Starts a new stage from the given height
+  pub fn start(&mut self, stage: SyncState, height: uint, block_time: i64,
+               peer_height: Option<uint>) {
+    let now = time::get_time().sec;
+    self.stage = stage;
+    self.height = height;
+    self.block_time = block_time;
+    self.peer_height = peer_height;
+    self.start_height = height;
+    self.started_at = now;
+    self.last_logged = now;
+  }
+
+  /// Records that the stage has reached `height`. Returns true if it is
+  /// time to log progress again.
+  pub fn update(&mut self, height: uint, block_time: i64) -> bool {
+    self.height = height;
+    self.block_time = block_time;
+    let now = time::get_time().sec;
+    if now - self.last_logged >= SYNC_PROGRESS_LOG_FREQUENCY {
+      self.last_logged = now;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// The height we expect to sync to
+  pub fn target_height(&self) -> uint {
+    let age = cmp::max(0, time::get_time().sec - self.block_time);
+    let guess = self.height + (age / BLOCK_SPACING) as uint;
+    cmp::max(guess, self.peer_height.unwrap_or(0))
+  }
+
+  /// The fraction, from 0 to 1, of the way to the target height
+  pub fn fraction(&self) -> f64 {
+    if self.stage == Synced {
+      return 1.0;
+    }
+    let target = self.target_height();
+    if target == 0 { 1.0 } else { self.height as f64 / target as f64 }
+  }
+
+  /// Estimated time, in s, until the stage is done, from its rate so far
+  pub fn eta(&self) -> Option<i64> {
+    if self.stage == Synced || self.height <= self.start_height {
+      return None;
+    }
+    let elapsed = time::get_time().sec - self.started_at;
+    let done = (self.height - self.start_height) as f64;
+    let target = self.target_height();
+    let remaining = if target > self.height { (target - self.height) as f64 } else { 0.0 };
+    Some((elapsed as f64 * remaining / done) as i64)
+  }
+}
+
+impl ToJson for SyncProgress {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("stage".to_string(), json::String(self.stage.name().to_string()));
+    obj.insert("height".to_string(), self.height.to_json());
+    obj.insert("target_height".to_string(), self.target_height().to_json());
+    obj.insert("progress".to_string(), self.fraction().to_json());
+    obj.insert("eta".to_string(), match self.eta() {
+      Some(eta) => eta.to_json(),
+      None => json::Null
+    });
+    json::Object(obj)
+  }
+}
+
@@ -0,0 +1,153 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # RPC Authentication
+//!
+//! HTTP Basic authentication for the JSON-RPC server. Credentials come from
+//! the `rpc_user` and `rpc_password` config options if both are set; else a
+//! random password is generated on startup and written, with the username
+//! `__cookie__`, to a cookie file readable only by the user.
+//!
+//! The config options may instead be kept in a separate secrets file, named
+//! by `credentials_file`, which we refuse to read unless only the user can.
+//!
+
+use std::c_str::ToCStr;
+use std::io;
+use std::io::{File, FileNotFound, IoError, IoResult, InvalidInput, ShortWrite};
+use std::io::fs;
+use std::path::posix::Path;
+use std::rand::{mod, Rng};
+use serialize::base64::FromBase64;
+use serialize::hex::ToHex;
+use libc;
+
+use http::server::Request;
+
+use constants::{RPC_COOKIE_BYTES, RPC_COOKIE_USER};
+use user_data::NetworkConfig;
+use util::constant_time_eq;
+use wallet::read_toml;
+
+/// Checks the Authorization header of a request against `user:password`
+/// credentials, as returned by `credentials`
+pub fn authorized(request: &Request, credentials: &[u8]) -> bool {
+  let header = match request.headers.authorization {
+    Some(ref header) => header,
+    None => { return false; }
+  };
+  let header = header.as_slice().trim();
+  if !header.starts_with("Basic ") {
+    return false;
+  }
+  match header.slice_from(6).trim().from_base64() {
+    Ok(given) => constant_time_eq(given.as_slice(), credentials),
+    Err(_) => false
+  }
+}
+
+/// Secrets kept out of the main configuration file
+#[deriving(Decodable)]
+pub struct Secrets {
+  /// Username for HTTP Basic authentication of RPC requests
+  pub rpc_user: Option<String>,
+  /// Password for HTTP Basic authentication of RPC requests
+  pub rpc_password: Option<String>
+}
+
+/// Reads a secrets file, which must not be accessible to other users
+pub fn read_secrets(path: &Path) -> IoResult<Secrets> {
+  let stat = try!(fs::stat(path));
+  if stat.perm.intersects(io::GroupRWX | io::OtherRWX) {
+    return Err(IoError {
+      kind: InvalidInput,
+      desc: "Credentials file is accessible to other users; chmod it to 600",
+      detail: Some(path.display().to_string())
+    });
+  }
+  read_toml(path)
+}
+
+/// Determines the `user:password` credentials with which to call a running
+/// network's RPC server: the configured ones, or else the cookie it wrote
+pub fn client_credentials(config: &NetworkConfig) -> IoResult<String> {
+  match (&config.rpc_user, &config.rpc_password) {
+    (&Some(ref user), &Some(ref password)) => Ok(format!("{}:{}", user, password)),
+    _ => {
+      let mut file = try!(File::open(&config.rpc_cookie_path));
+      file.read_to_string().map(|s| s.as_slice().trim().to_string())
+    }
+  }
+}
+
+/// Writes a new cookie file. It is created readable only by the user from
+/// the start, rather than narrowed after another user may have opened it,
+/// and never through a file or link which is already there.
+fn write_cookie(path: &Path, credentials: &str) -> IoResult<()> {
+  match fs::unlink(path) {
+    Ok(()) => {}
+    Err(ref e) if e.kind == FileNotFound => {}
+    Err(e) => { return Err(e); }
+  }
+  unsafe {
+    let fd = path.with_c_str(|p| libc::open(p, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+                                            libc::S_IRUSR | libc::S_IWUSR));
+    if fd == -1 {
+      return Err(IoError::last_error());
+    }
+    let written = libc::write(fd, credentials.as_ptr() as *const libc::c_void,
+                              credentials.len() as libc::size_t);
+    let ret = if written == -1 {
+      Err(IoError::last_error())
+    } else if written as uint != credentials.len() {
+      Err(IoError { kind: ShortWrite(written as uint), desc: "short write to cookie file",
+                    detail: Some(path.display().to_string()) })
+    } else {
+      Ok(())
+    };
+    libc::close(fd);
+    ret
+  }
+}
+
+/// Determines the `user:password` credentials for a network's RPC server,
+/// generating and writing out a new cookie file if none are configured
+pub fn credentials(config: &NetworkConfig) -> IoResult<String> {
+  match (&config.rpc_user, &config.rpc_password) {
+    (&Some(ref user), &Some(ref password)) => {
+      if user.as_slice().contains_char(':') {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "RPC user may not contain a colon",
+          detail: Some(user.clone())
+        });
+      }
+      Ok(format!("{}:{}", user, password))
+    }
+    (&None, &None) => {
+      let mut rng = try!(rand::OsRng::new());
+      let mut password = Vec::from_elem(RPC_COOKIE_BYTES, 0u8);
+      rng.fill_bytes(password.as_mut_slice());
+      let credentials = format!("{}:{}", RPC_COOKIE_USER, password.as_slice().to_hex());
+
+      try!(write_cookie(&config.rpc_cookie_path, credentials.as_slice()));
+      Ok(credentials)
+    }
+    _ => Err(IoError {
+      kind: InvalidInput,
+      desc: "rpc_user and rpc_password must be set together",
+      detail: None
+    })
+  }
+}
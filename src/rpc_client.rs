@@ -0,0 +1,218 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # RPC Client
+//!
+//! A minimal JSON-RPC client, for the command-line tools which talk to a
+//! running wallet. It finds the RPC server and its credentials from the
+//! same configuration the wallet was started with.
+//!
+
+use std::collections::TreeMap;
+use std::io::{IoError, IoResult, InvalidInput, OtherIoError};
+use std::io::net::tcp::TcpStream;
+use std::str::from_utf8;
+use serialize::base64::{ToBase64, STANDARD};
+use serialize::json;
+
+use constants::RPC_CLIENT_TIMEOUT;
+use rpc_auth::client_credentials;
+use user_data::NetworkConfig;
+
+/// Calls a method on a network's RPC server, returning its result. An
+/// error returned by the server has kind `OtherIoError`, with the error
+/// object as its detail.
+pub fn call(config: &NetworkConfig, method: &str, params: Vec<json::Json>)
+           -> IoResult<json::Json> {
+  call_timeout(config, method, params, Some(RPC_CLIENT_TIMEOUT))
+}
+
+/// Calls a method as `call` does, waiting up to `timeout` ms for the reply,
+/// or forever if `None`, for methods which block until something happens
+pub fn call_timeout(config: &NetworkConfig, method: &str, params: Vec<json::Json>,
+                    timeout: Option<u64>) -> IoResult<json::Json> {
+  let credentials = try!(client_credentials(config));
+  let mut request = TreeMap::new();
+  request.insert("jsonrpc".to_string(), json::String("2.0".to_string()));
+  request.insert("method".to_string(), json::String(method.to_string()));
+  request.insert("params".to_string(), json::List(params));
+  request.insert("id".to_string(), json::U64(1));
+  let body = json::Object(request).to_string();
+
+  let mut stream = try!(TcpStream::connect(config.rpc_server_addr.as_slice(),
+                                           config.rpc_server_port));
+  stream.set_timeout(timeout);
+  try!(stream.write_str(format!("POST / HTTP/1.1\r\n\
+                                 Host: {}:{}\r\n\
+                                 Authorization: Basic {}\r\n\
+                                 Content-Type: application/json\r\n\
+                                 Content-Length: {}\r\n\
+                                 Connection: close\r\n\r\n",
+                                config.rpc_server_addr, config.rpc_server_port,
+                                credentials.as_bytes().to_base64(STANDARD),
+                                body.len()).as_slice()));
+  try!(stream.write_str(body.as_slice()));
+  let response = try!(stream.read_to_end());
+  parse_response(response.as_slice())
+}
+
+/// Splits an HTTP response into its status code and body, and decodes the
+/// JSON-RPC reply in the body
+fn parse_response(response: &[u8]) -> IoResult<json::Json> {
+  let bad_response = |detail: &str| IoError {
+    kind: InvalidInput,
+    desc: "Bad response from RPC server",
+    detail: Some(detail.to_string())
+  };
+
+  let response = match from_utf8(response) {
+    Some(s) => s,
+    None => { return Err(bad_response("not UTF-8")); }
+  };
+  let (head, body) = match response.find_str("\r\n\r\n") {
+    Some(n) => (response.slice_to(n), response.slice_from(n + 4)),
+    None => { return Err(bad_response("no end of headers")); }
+  };
+  let status = head.split(' ').nth(1).and_then(|s| from_str::<uint>(s));
+  match status {
+    Some(200) => {}
+    Some(401) => {
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "RPC server refused our credentials",
+        detail: None
+      });
+    }
+    Some(status) => {
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "RPC server returned an HTTP error",
+        detail: Some(format!("status {}: {}", status, body.trim()))
+      });
+    }
+    None => { return Err(bad_response(head.lines().next().unwrap_or(""))); }
+  }
+
+  let mut reply = match json::from_str(body) {
+    Ok(json::Object(obj)) => obj,
+    _ => { return Err(bad_response(body)); }
+  };
+  match reply.pop(&"error".to_string()) {
+    Some(json::Null) | None => {}
+    Some(error) => {
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "RPC call failed",
+        detail: Some(error.to_string())
+      });
+    }
+  }
+  Ok(reply.pop(&"result".to_string()).unwrap_or(json::Null))
+}
+
+/// Parses a command-line parameter as JSON, or failing that as a string,
+/// so that plain words need not be quoted
+pub fn parse_param(param: &str) -> json::Json {
+  match json::from_str(param) {
+    Ok(json) => json,
+    Err(_) => json::String(param.to_string())
+  }
+}
+
+/// Calls a method on the first network given, with parameters from the
+/// command line, and pretty-prints the result or error. Returns whether
+/// the call succeeded.
+pub fn run(configs: &[NetworkConfig], method: &str, params: &[String]) -> bool {
+  let config = match configs.head() {
+    Some(config) => config,
+    None => {
+      println!("No network to call; choose one with --network.");
+      return false;
+    }
+  };
+  let params = params.iter().map(|p| parse_param(p.as_slice())).collect();
+  match call_timeout(config, method, params, None) {
+    // Print strings bare, so that e.g. hex can be piped elsewhere
+    Ok(json::String(s)) => { println!("{}", s); true }
+    Ok(json::Null) => true,
+    Ok(result) => { println!("{}", result.to_pretty_str()); true }
+    Err(e) => {
+      match e.detail {
+        Some(ref detail) if e.kind == OtherIoError => {
+          match json::from_str(detail.as_slice()) {
+            Ok(error) => println!("{}: {}", e.desc, error.to_pretty_str()),
+            Err(_) => println!("{}", e)
+          }
+        }
+        _ => println!("{}", e)
+      }
+      false
+    }
+  }
+}
+
+/// Asks a running wallet to stop, trying each network's RPC server until
+/// one answers, since stopping one network stops the whole process.
+/// Returns whether any did.
+pub fn stop(configs: &[NetworkConfig]) -> bool {
+  for config in configs.iter() {
+    match call(config, "stop", vec![]) {
+      Ok(_) => {
+        println!("{}: asked the wallet to stop.", config.network);
+        return true;
+      }
+      Err(e) => { println!("{}: not running ({}).", config.network, e); }
+    }
+  }
+  false
+}
+
+/// Prints a summary of each network from its `getinfo` RPC. Returns
+/// whether all of them are running.
+pub fn status(configs: &[NetworkConfig]) -> bool {
+  let mut all_running = true;
+  for config in configs.iter() {
+    let info = match call(config, "getinfo", vec![]) {
+      Ok(json::Object(info)) => info,
+      Ok(other) => {
+        println!("{}: unexpected reply to getinfo: {}", config.network, other);
+        all_running = false;
+        continue;
+      }
+      Err(e) => {
+        println!("{}: not running ({}).", config.network, e);
+        all_running = false;
+        continue;
+      }
+    };
+    let field = |name: &str| match info.find(&name.to_string()) {
+      Some(&json::String(ref s)) => s.clone(),
+      Some(value) => value.to_string(),
+      None => "?".to_string()
+    };
+    println!("{}: running, version {}, {} blocks, up {}s, debug level {}, coinjoin {}",
+             config.network, field("version"), field("blocks"), field("uptime"),
+             field("debug_level"), if field("coinjoin").as_slice() == "true" { "on" } else { "off" });
+    match info.find(&"balances".to_string()) {
+      Some(&json::Object(ref wallets)) => {
+        for (name, balances) in wallets.iter() {
+          println!("  wallet {}: {}", name, balances);
+        }
+      }
+      _ => {}
+    }
+  }
+  all_running
+}
+
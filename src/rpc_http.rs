@@ -0,0 +1,566 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # JSON-RPC over HTTP
+//!
+//! The HTTP side of the RPC server. Requests are authenticated and parsed
+//! here, following JSON-RPC 2.0 (including batches and notifications) for
+//! requests which carry `"jsonrpc": "2.0"` and JSON-RPC 1.0 otherwise, and
+//! are then passed to the idle loop to be dispatched by `handle_rpc`.
+//!
+//! Clients which exceed the configured per-IP rate limit, or arrive while
+//! the maximum number of requests is already being handled, are turned
+//! away with a "server busy" error rather than queueing up in the idle loop.
+//!
+//! Requests to `/events` instead subscribe to the event bus. The response
+//! is never finished; each event is sent as a line of JSON as it happens.
+//! Topics may be selected with a query such as `/events?topics=blocks,chain`.
+//!
+//! Chain data is also available by plain GET requests, for clients without
+//! JSON-RPC support:
+//!
+//!   * `/rest/block/<hash>.<json|hex>`
+//!   * `/rest/headers/<count>/<hash>.<json|hex>`
+//!   * `/rest/tx/<txid>.<json|hex>`
+//!
+//! and metrics, in the Prometheus text format, at `/metrics`. A read-only
+//! HTML dashboard is served at `/dashboard`.
+//!
+
+use std::collections::{HashMap, TreeMap};
+use std::io::{IoError, IoResult, InvalidInput};
+use std::io::net::addrinfo::get_host_addresses;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use time;
+use std::str::from_utf8;
+use std::sync::{Arc, Mutex, RWLock};
+use serialize::json;
+use serialize::json::ToJson;
+
+use http::headers::content_type::MediaType;
+use http::server::{Config, Request, ResponseWriter, Server};
+use http::server::request::AbsolutePath;
+use http::status;
+use jsonrpc;
+use jsonrpc::error::{standard_error, Error, InternalError, InvalidParams, InvalidRequest, ParseError};
+
+use constants::RPC_RATE_LIMIT_PRUNE;
+use events::{EventBus, Topic};
+use rpc_auth::authorized;
+use user_data::NetworkConfig;
+use web_dashboard;
+
+/// A request as passed to the idle loop, with the address of the client
+/// which made it and a channel for the response
+pub type RpcMessage = (jsonrpc::Request, Option<SocketAddr>, Sender<jsonrpc::JsonResult<json::Json>>);
+
+/// The JSON-RPC version of a request, which determines the shape of the response
+#[deriving(Clone, PartialEq, Eq, Show)]
+enum Version {
+  V1,
+  V2
+}
+
+/// JSON-RPC error code for requests turned away by the limits
+static SERVER_BUSY: int = -32000;
+
+/// Limits on how hard clients may use the server, shared between the
+/// copies of the server handling each connection
+struct Limits {
+  /// Requests per minute allowed from each IP, if limited
+  rate: Option<uint>,
+  /// Maximum number of requests in progress at once
+  max_concurrent: uint,
+  state: Mutex<LimitState>
+}
+
+struct LimitState {
+  /// Number of requests in progress
+  active: uint,
+  /// Token bucket for each client: tokens left, and time in ns of the
+  /// last refill
+  buckets: HashMap<IpAddr, (f64, u64)>
+}
+
+impl Limits {
+  /// Takes a token from a client's bucket, returning false if it is empty
+  fn check_rate(&self, ip: IpAddr) -> bool {
+    let rate = match self.rate {
+      Some(rate) => rate as f64,
+      None => { return true; }
+    };
+    let now = time::precise_time_ns();
+    let mut state = self.state.lock();
+    if state.buckets.len() > RPC_RATE_LIMIT_PRUNE {
+      // Forget clients whose buckets would have refilled anyway
+      let stale: Vec<IpAddr> = state.buckets.iter()
+                                    .filter(|&(_, &(_, last))| now - last > 60000000000)
+                                    .map(|(ip, _)| *ip)
+                                    .collect();
+      for ip in stale.iter() {
+        state.buckets.remove(ip);
+      }
+    }
+    let bucket = state.buckets.find_or_insert(ip, (rate, now));
+    let (tokens, last) = *bucket;
+    // Refill at `rate` tokens per minute, up to a minute's worth
+    let tokens = (tokens + rate * (now - last) as f64 / 60e9).min(rate);
+    if tokens < 1.0 {
+      *bucket = (tokens, now);
+      false
+    } else {
+      *bucket = (tokens - 1.0, now);
+      true
+    }
+  }
+
+  /// Marks a request as in progress, returning false if too many are
+  fn begin(&self) -> bool {
+    let mut state = self.state.lock();
+    if state.active >= self.max_concurrent {
+      false
+    } else {
+      state.active += 1;
+      true
+    }
+  }
+
+  /// Marks a request as finished
+  fn end(&self) {
+    self.state.lock().active -= 1;
+  }
+}
+
+/// Ends a request, as counted by `Limits`, when dropped
+struct ActiveRequest<'a> {
+  limits: &'a Limits
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for ActiveRequest<'a> {
+  fn drop(&mut self) {
+    self.limits.end();
+  }
+}
+
+/// The HTTP server which accepts JSON-RPC requests
+#[deriving(Clone)]
+pub struct RpcHttpServer {
+  addr: SocketAddr,
+  credentials: Arc<RWLock<Vec<u8>>>,
+  sender: Sender<RpcMessage>,
+  events: EventBus,
+  limits: Arc<Limits>
+}
+
+impl RpcHttpServer {
+  /// Creates a new server listening on the configured address and port,
+  /// which requires `user:password` credentials. Returns the server along
+  /// with the receiving end of its request channel.
+  pub fn new(config: &NetworkConfig, credentials: String, events: EventBus)
+      -> IoResult<(RpcHttpServer, Receiver<RpcMessage>)> {
+    let ips = try!(get_host_addresses(config.rpc_server_addr.as_slice()));
+    let ip = match ips.as_slice().head() {
+      Some(ip) => *ip,
+      None => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "RPC server address did not resolve",
+          detail: Some(config.rpc_server_addr.clone())
+        });
+      }
+    };
+    // The cookie file is only readable locally, so anyone able to reach a
+    // remote bind must have been given a password explicitly
+    if !is_loopback(ip) && config.rpc_user.is_none() {
+      return Err(IoError {
+        kind: InvalidInput,
+        desc: "non-loopback RPC server address requires rpc_user and rpc_password",
+        detail: Some(config.rpc_server_addr.clone())
+      });
+    }
+    let (tx, rx) = channel();
+    Ok((RpcHttpServer {
+      addr: SocketAddr { ip: ip, port: config.rpc_server_port },
+      credentials: Arc::new(RWLock::new(credentials.into_bytes())),
+      sender: tx,
+      events: events,
+      limits: Arc::new(Limits {
+        rate: config.rpc_rate_limit,
+        max_concurrent: config.rpc_max_concurrent,
+        state: Mutex::new(LimitState { active: 0, buckets: HashMap::new() })
+      })
+    }, rx))
+  }
+
+  /// Replaces the `user:password` credentials which requests must carry,
+  /// for this server and all its clones
+  pub fn set_credentials(&self, credentials: String) {
+    *self.credentials.write() = credentials.into_bytes();
+  }
+
+  /// Runs a single parsed request, returning the response object, or None
+  /// if the request was a notification
+  fn run_request(&self, request: json::Json, caller: Option<SocketAddr>) -> Option<json::Json> {
+    let mut obj = match request {
+      json::Object(obj) => obj,
+      _ => { return Some(error_response(V2, json::Null, standard_error(InvalidRequest, None))); }
+    };
+
+    // Clients disagree about what to put here for 1.0 (e.g. "1.0", 1 or
+    // null), so anything which is not 2.0 is taken as 1.0
+    let version = match obj.pop(&"jsonrpc".to_string()) {
+      Some(json::String(ref s)) if s.as_slice() == "2.0" => V2,
+      _ => V1
+    };
+    // In 2.0 a notification omits the id; in 1.0 it has a null id
+    let (id, notification) = match (obj.pop(&"id".to_string()), version) {
+      (None, V2) => (json::Null, true),
+      (None, V1) | (Some(json::Null), V1) => (json::Null, true),
+      (Some(id), _) => (id, false)
+    };
+    let method = match obj.pop(&"method".to_string()) {
+      Some(json::String(method)) => method,
+      _ => {
+        return Some(error_response(version, id, standard_error(InvalidRequest, None)));
+      }
+    };
+    let params = match obj.pop(&"params".to_string()) {
+      None | Some(json::Null) => vec![],
+      Some(json::List(params)) => params,
+      Some(_) => {
+        let err = standard_error(InvalidParams,
+                                 Some(json::String("params must be an array".to_string())));
+        return if notification { None } else { Some(error_response(version, id, err)) };
+      }
+    };
+
+    let result = self.call(method, params, id.clone(), caller);
+    if notification {
+      return None;
+    }
+    Some(match result {
+      Ok(res) => result_response(version, id, res),
+      Err(err) => error_response(version, id, err)
+    })
+  }
+
+  /// Passes a request to the idle loop and waits for the result
+  fn call(&self, method: String, params: Vec<json::Json>, id: json::Json,
+          caller: Option<SocketAddr>) -> jsonrpc::JsonResult<json::Json> {
+    let (tx, rx) = channel();
+    self.sender.send((jsonrpc::Request { method: method, params: params, id: id }, caller, tx));
+    match rx.recv_opt() {
+      Ok(result) => result,
+      Err(_) => Err(standard_error(InternalError, None))
+    }
+  }
+
+  /// Answers a REST request by making the equivalent RPC call
+  fn rest_request(&self, path: &str, caller: Option<SocketAddr>, response: &mut ResponseWriter) {
+    let (path, hex) = if path.ends_with(".hex") {
+      (path.slice_to(path.len() - 4), true)
+    } else if path.ends_with(".json") {
+      (path.slice_to(path.len() - 5), false)
+    } else {
+      (path, false)
+    };
+    let parts: Vec<&str> = path.split('/').collect();
+    let verbose = json::Boolean(!hex);
+    let (method, params) = match parts.as_slice() {
+      ["block", hash] => ("getblock", vec![json::String(hash.to_string()), verbose]),
+      ["tx", txid] => ("getrawtransaction", vec![json::String(txid.to_string()), verbose]),
+      ["headers", count, hash] => {
+        match from_str::<u64>(count) {
+          Some(count) => ("getblockheaders",
+                          vec![json::String(hash.to_string()), json::U64(count), verbose]),
+          None => { return empty_response(response, status::BadRequest); }
+        }
+      }
+      _ => { return empty_response(response, status::NotFound); }
+    };
+
+    let result = match self.call(method.to_string(), params, json::Null, caller) {
+      Ok(result) => result,
+      Err(err) => {
+        let bad_request = standard_error(InvalidParams, None);
+        let status = if err.code == bad_request.code { status::BadRequest } else { status::NotFound };
+        return empty_response(response, status);
+      }
+    };
+    let body = match result {
+      // Consecutive headers are simply concatenated
+      json::List(ref headers) if hex => {
+        let mut s = String::new();
+        for header in headers.iter() {
+          match *header {
+            json::String(ref h) => s.push_str(h.as_slice()),
+            _ => {}
+          }
+        }
+        s.push_char('\n');
+        s
+      }
+      json::String(ref s) if hex => format!("{}\n", s),
+      result => result.to_string()
+    };
+    let body = body.into_bytes();
+    response.headers.content_type = Some(if hex { text_media_type() } else { json_media_type() });
+    response.headers.content_length = Some(body.len());
+    let _ = response.write(body.as_slice());
+  }
+
+  /// Answers a `/metrics` request with the output of `getmetrics`
+  fn metrics_request(&self, caller: Option<SocketAddr>, response: &mut ResponseWriter) {
+    let params = vec![json::String("prometheus".to_string())];
+    let body = match self.call("getmetrics".to_string(), params, json::Null, caller) {
+      Ok(json::String(text)) => text.into_bytes(),
+      _ => { return empty_response(response, status::InternalServerError); }
+    };
+    response.headers.content_type = Some(MediaType {
+      type_: "text".to_string(),
+      subtype: "plain".to_string(),
+      parameters: vec![("version".to_string(), "0.0.4".to_string())]
+    });
+    response.headers.content_length = Some(body.len());
+    let _ = response.write(body.as_slice());
+  }
+
+  /// Answers a `/dashboard` request with an HTML page built from the
+  /// results of RPC calls. Calls which fail, e.g. because wallet or
+  /// coinjoin RPC is disabled, just leave their section out.
+  fn dashboard_request(&self, caller: Option<SocketAddr>, response: &mut ResponseWriter) {
+    let call = |method: &str, params: Vec<json::Json>| {
+      self.call(method.to_string(), params, json::Null, caller).ok()
+    };
+    let body = web_dashboard::render(call("getblockchaininfo", vec![]),
+                                     call("getinfo", vec![]),
+                                     call("listtransactions", vec![json::U64(10)]),
+                                     call("coinjoin_list", vec![])).into_bytes();
+    response.headers.content_type = Some(MediaType {
+      type_: "text".to_string(),
+      subtype: "html".to_string(),
+      parameters: vec![("charset".to_string(), "utf-8".to_string())]
+    });
+    response.headers.content_length = Some(body.len());
+    let _ = response.write(body.as_slice());
+  }
+
+  /// Streams events on the given topics to the client until it hangs up
+  fn stream_events(&self, topics: Vec<Topic>, response: &mut ResponseWriter) {
+    let rx = self.events.subscribe(topics);
+    // With no content length set, the response is sent chunked
+    response.headers.content_type = Some(json_media_type());
+    for event in rx.iter() {
+      let mut line = event.to_json().to_string();
+      line.push_char('\n');
+      if response.write(line.as_bytes()).is_err() || response.flush().is_err() {
+        break;
+      }
+    }
+  }
+}
+
+impl Server for RpcHttpServer {
+  fn get_config(&self) -> Config {
+    Config { bind_address: self.addr }
+  }
+
+  fn handle_request(&self, request: Request, response: &mut ResponseWriter) {
+    // Check limits before authentication, so they also slow password guessing
+    let within_rate = match request.remote_addr {
+      Some(addr) => self.limits.check_rate(addr.ip),
+      None => true
+    };
+    if !within_rate {
+      return busy_response(response, "rate limit exceeded");
+    }
+
+    // Event streams are long-lived and never reach the idle loop, so they
+    // are not counted against the concurrency limit
+    let _active = match request.request_uri {
+      AbsolutePath(ref path) if path.as_slice() == "/events" ||
+                                path.as_slice().starts_with("/events?") => None,
+      _ => {
+        if !self.limits.begin() {
+          return busy_response(response, "too many requests in progress");
+        }
+        Some(ActiveRequest { limits: &*self.limits })
+      }
+    };
+
+    if !authorized(&request, self.credentials.read().as_slice()) {
+      response.status = status::Unauthorized;
+      response.headers.www_authenticate = Some("Basic realm=\"jsonrpc\"".to_string());
+      response.headers.content_length = Some(0);
+      return;
+    }
+
+    match request.request_uri {
+      AbsolutePath(ref path) if path.as_slice() == "/metrics" => {
+        self.metrics_request(request.remote_addr, response);
+        return;
+      }
+      AbsolutePath(ref path) if path.as_slice() == "/dashboard" => {
+        self.dashboard_request(request.remote_addr, response);
+        return;
+      }
+      AbsolutePath(ref path) if path.as_slice().starts_with("/rest/") => {
+        self.rest_request(path.as_slice().slice_from(6), request.remote_addr, response);
+        return;
+      }
+      AbsolutePath(ref path) if path.as_slice() == "/events" ||
+                                path.as_slice().starts_with("/events?") => {
+        match parse_topics(path.as_slice().slice_from(7)) {
+          Some(topics) => self.stream_events(topics, response),
+          None => empty_response(response, status::BadRequest)
+        }
+        return;
+      }
+      _ => {}
+    }
+
+    let caller = request.remote_addr;
+    let parsed = from_utf8(request.body.as_slice()).and_then(|s| json::from_str(s).ok());
+    let reply = match parsed {
+      None => Some(error_response(V2, json::Null, standard_error(ParseError, None))),
+      Some(json::List(batch)) => {
+        if batch.is_empty() {
+          Some(error_response(V2, json::Null, standard_error(InvalidRequest, None)))
+        } else {
+          let replies: Vec<json::Json> = batch.move_iter()
+                                              .filter_map(|req| self.run_request(req, caller))
+                                              .collect();
+          if replies.is_empty() { None } else { Some(json::List(replies)) }
+        }
+      }
+      Some(req) => self.run_request(req, caller)
+    };
+
+    match reply {
+      Some(reply) => {
+        let body = reply.to_string().into_bytes();
+        response.headers.content_type = Some(json_media_type());
+        response.headers.content_length = Some(body.len());
+        let _ = response.write(body.as_slice());
+      }
+      // Notifications get no response body at all
+      None => {
+        response.status = status::NoContent;
+        response.headers.content_length = Some(0);
+      }
+    }
+  }
+}
+
+/// Parses the query string of an `/events` request into a list of topics;
+/// with no `topics` parameter, all topics are subscribed to
+fn parse_topics(query: &str) -> Option<Vec<Topic>> {
+  let query = query.trim_left_chars('?');
+  for param in query.split('&') {
+    if param.starts_with("topics=") {
+      let mut ret = vec![];
+      for name in param.slice_from(7).split(',') {
+        match Topic::from_name(name) {
+          Some(topic) => ret.push(topic),
+          None => { return None; }
+        }
+      }
+      return Some(ret);
+    }
+  }
+  Some(Topic::all())
+}
+
+/// Turns a request away with a "server busy" error
+fn busy_response(response: &mut ResponseWriter, reason: &str) {
+  let err = Error {
+    code: SERVER_BUSY,
+    message: "Server busy".to_string(),
+    data: Some(json::String(reason.to_string()))
+  };
+  let body = error_response(V2, json::Null, err).to_string().into_bytes();
+  response.status = status::ServiceUnavailable;
+  response.headers.content_type = Some(json_media_type());
+  response.headers.content_length = Some(body.len());
+  let _ = response.write(body.as_slice());
+}
+
+/// Finishes a response with a status code and no body
+fn empty_response(response: &mut ResponseWriter, status: status::Status) {
+  response.status = status;
+  response.headers.content_length = Some(0);
+}
+
+/// The content type of JSON responses
+fn json_media_type() -> MediaType {
+  MediaType {
+    type_: "application".to_string(),
+    subtype: "json".to_string(),
+    parameters: vec![]
+  }
+}
+
+/// The content type of hex-encoded REST responses
+fn text_media_type() -> MediaType {
+  MediaType {
+    type_: "text".to_string(),
+    subtype: "plain".to_string(),
+    parameters: vec![]
+  }
+}
+
+/// Whether an IP address only accepts connections from the local machine
+fn is_loopback(ip: IpAddr) -> bool {
+  match ip {
+    Ipv4Addr(a, _, _, _) => a == 127,
+    Ipv6Addr(a, b, c, d, e, f, g, h) => (a, b, c, d, e, f, g, h) == (0, 0, 0, 0, 0, 0, 0, 1)
+  }
+}
+
+/// Converts an error to a JSON-RPC error object
+fn error_json(err: Error) -> json::Json {
+  let mut obj = TreeMap::new();
+  obj.insert("code".to_string(), err.code.to_json());
+  obj.insert("message".to_string(), json::String(err.message));
+  match err.data {
+    Some(data) => { obj.insert("data".to_string(), data); }
+    None => {}
+  }
+  json::Object(obj)
+}
+
+/// Builds a successful response, echoing the request's version
+fn result_response(version: Version, id: json::Json, result: json::Json) -> json::Json {
+  let mut obj = TreeMap::new();
+  obj.insert("result".to_string(), result);
+  match version {
+    V1 => { obj.insert("error".to_string(), json::Null); }
+    V2 => { obj.insert("jsonrpc".to_string(), json::String("2.0".to_string())); }
+  }
+  obj.insert("id".to_string(), id);
+  json::Object(obj)
+}
+
+/// Builds an error response, echoing the request's version
+fn error_response(version: Version, id: json::Json, err: Error) -> json::Json {
+  let mut obj = TreeMap::new();
+  obj.insert("error".to_string(), error_json(err));
+  match version {
+    V1 => { obj.insert("result".to_string(), json::Null); }
+    V2 => { obj.insert("jsonrpc".to_string(), json::String("2.0".to_string())); }
+  }
+  obj.insert("id".to_string(), id);
+  json::Object(obj)
+}
@@ -16,30 +16,60 @@
 //!
 //! Functions and data to handle RPC calls
 
+use std::cmp;
 use std::io::{IoError, MemReader};
-use std::collections::TreeMap;
+use std::io::net::ip::SocketAddr;
+use std::mem;
+use std::task;
+use std::collections::{DList, Deque, TreeMap};
+use std::sync::{Arc, Mutex, RWLock};
+use std::default::Default;
 use std::time::Duration;
 use serialize::Decodable;
 use serialize::hex::FromHex;
 use serialize::json;
 use serialize::json::ToJson;
+use time;
 
-use bitcoin::network::serialize::{RawDecoder, deserialize, serialize};
+use bitcoin::network::serialize::{BitcoinHash, RawDecoder, deserialize, serialize, serialize_hex};
 use bitcoin::network::encodable::{ConsensusDecodable, VarInt};
-use bitcoin::network::message;
 use bitcoin::util::hash::Sha256dHash;
-use bitcoin::util::misc::consume_err;
+use bitcoin::blockdata::blockchain::Blockchain;
 use bitcoin::blockdata::script::Script;
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::wallet::wallet::{AccountNotFound, External};
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+use bitcoin::network::constants::Network;
+use bitcoin::util::base58::ToBase58;
+use bitcoin::wallet::wallet::AccountNotFound;
 use jsonrpc;
-use jsonrpc::error::{standard_error, Error, InvalidParams, MethodNotFound};
+use jsonrpc::error::{standard_error, Error, InternalError, InvalidParams, MethodNotFound};
 use phf::PhfOrderedMap;
+use secp256k1::Secp256k1;
 
-use bitcoind::IdleState;
-use coinjoin::server::{Complete, Server, Session, SessionId};
-use coinjoin::CoinjoinError;
-use wallet::save_wallet;
+use address_index::AddressIndex;
+use bitcoind::{Debug, DebugLevel, IdleState, Notice, SharedState, Status, Warning};
+use bitcoind::{LeavePeer, TryPeer};
+use bitcoind::broadcast_transaction;
+use coinjoin::blind;
+use coinjoin::server::{Complete, Server, Session, SessionId, SessionOptions, SessionState};
+use coinjoin::{BlindKeyPending, CoinjoinError, DenominationInUse, NonStandardDenomination};
+use constants::{BLOCK_WAIT_TIMEOUT, COINJOIN_WAIT_TIMEOUT, DUST_THRESHOLD};
+use constants::{FEE_ESTIMATE_MAX_TARGET, MAX_DENOMINATION_SPLIT, MAX_HEADERS_RESULTS};
+use constants::{DEFAULT_PEER_PORT, NETWORK_HASHPS_BLOCKS, RPC_RECENT_CALLS};
+use constants::{EST_INPUT_SIZE, EST_OUTPUT_SIZE, MAX_NULL_DATA_SIZE};
+use difficulty::{difficulty_from_compact, uint256_hex, uint256_to_f64};
+use disk;
+use events::{Synced, SyncingHeaders, SyncingUtxoSet};
+use fee_estimator;
+use metrics::{Counter, Gauge, Metrics};
+use payment_request;
+use script_info;
+use script_info::P2shAddress;
+use timelock::{Timelock, median_time_past};
+use user_data::{NetworkConfig, PeerAddress};
+use version;
+use wallet::{FixedRate, OutPoint, WalletTx, backup_wallet, balances, save_wallet};
+use wallet::{PrivateKey, sign_input_with_keys, sign_transaction, spendable_outputs};
+use wallet::{owned_outpoints, split_denominations};
 
 pub type JsonResult = jsonrpc::JsonResult<json::Json>;
 
@@ -48,22 +78,100 @@ enum RawDecodeMode {
     PrependLength
 }
 
+/// The type of an RPC parameter, for documentation
+pub enum ParamType {
+  /// A hex-encoded 256-bit hash, e.g. a txid
+  HashParam,
+  /// Other hex-encoded data
+  HexParam,
+  /// An integer
+  IntParam,
+  /// An amount in satoshi
+  AmountParam,
+  /// A boolean
+  BoolParam,
+  /// A string
+  StringParam,
+  /// A JSON object
+  ObjectParam,
+  /// A JSON list
+  ListParam,
+  /// Depends on the command; see its description
+  AnyParam
+}
+
+impl ParamType {
+  /// The name of the type, as reported by `help`
+  pub fn name(&self) -> &'static str {
+    match *self {
+      HashParam => "hash",
+      HexParam => "hex",
+      IntParam => "integer",
+      AmountParam => "amount",
+      BoolParam => "boolean",
+      StringParam => "string",
+      ObjectParam => "object",
+      ListParam => "list",
+      AnyParam => "any"
+    }
+  }
+}
+
+/// An RPC parameter: its name, type, whether it is required, and a description
+pub type RpcParam = (&'static str, ParamType, bool, &'static str);
+
 /// A single RPC command
 pub struct RpcCall {
   name: &'static str,
   desc: &'static str,
   usage: &'static str,
+  params: &'static [RpcParam],
+  result: &'static str,
   coinjoin: bool,
   wallet: bool,
-  call: fn(&RpcCall, &mut IdleState, Vec<json::Json>) -> JsonResult
+  call: RpcFn
+}
+
+/// An RPC command's implementation, and where it runs
+enum RpcFn {
+  /// On the idle loop, with access to all of its state. Calls which use
+  /// the peer socket, the coinjoin server or long-polling go here.
+  IdleLoop(fn(&RpcCall, &mut IdleState, Vec<json::Json>) -> JsonResult),
+  /// On a worker task, with access only to the state shared behind locks
+  Worker(fn(&RpcCall, &SharedState, Vec<json::Json>) -> JsonResult)
+}
+
+impl ToJson for RpcCall {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("name".to_string(), json::String(self.name.to_string()));
+    obj.insert("description".to_string(), json::String(self.desc.to_string()));
+    obj.insert("usage".to_string(), json::String(self.usage.to_string()));
+    let params = self.params.iter().map(|&(name, ref ty, required, desc)| {
+      let mut param = TreeMap::new();
+      param.insert("name".to_string(), json::String(name.to_string()));
+      param.insert("type".to_string(), json::String(ty.name().to_string()));
+      param.insert("required".to_string(), json::Boolean(required));
+      param.insert("description".to_string(), json::String(desc.to_string()));
+      json::Object(param)
+    }).collect();
+    obj.insert("params".to_string(), json::List(params));
+    obj.insert("result".to_string(), json::String(self.result.to_string()));
+    obj.insert("coinjoin".to_string(), json::Boolean(self.coinjoin));
+    obj.insert("wallet".to_string(), json::Boolean(self.wallet));
+    json::Object(obj)
+  }
 }
 
 // Forget you saw this macro...just forget it.
 macro_rules! rpc_calls(
   ( $( #[doc=$doc:tt]
        #[usage=$usage:tt]
+       #[params=$params:tt]
+       #[result=$result:tt]
        #[coinjoin=$coinjoin:tt]
        #[wallet=$wallet:tt]
+       #[runs_on=$runs_on:ident]
        pub fn $name:ident($($param:tt: $paramty:ty),+) $code:expr),+ ) => (
     $(
       // `tt` token trees can only be passed to a macro. On the other hand,
@@ -88,9 +196,11 @@ macro_rules! rpc_calls(
             name: stringify!($name),
             desc: $doc,
             usage: $usage,
+            params: &$params,
+            result: $result,
             coinjoin: $coinjoin,
             wallet: $wallet,
-            call: $name
+            call: $runs_on($name)
           }
         ),+
       };
@@ -106,316 +216,2505 @@ macro_rules! rpc_calls(
 
 // Main RPC call list
 rpc_calls!{
-  #[doc="Fetches a list of commands"]
+  #[doc="Fetches a list of commands, or the full description of one command, including its parameters and result"]
+  #[usage="[command]"]
+  #[params=[("command", StringParam, false, "Command to describe in full")]]
+  #[result="object mapping each command to its description and usage; or, given a command, its full description"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn help(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      0 => {
+        let mut ret = TreeMap::new();
+        for call in RPC_CALLS.values() {
+          if rpc_enabled(call, &shared.config) {
+            let mut obj = TreeMap::new();
+            obj.insert("description".to_string(), json::String(call.desc.to_string()));
+            obj.insert("usage".to_string(), json::String(call.usage.to_string()));
+            ret.insert(call.name.to_string(), json::Object(obj));
+          }
+        }
+        Ok(json::Object(ret))
+      }
+      1 => {
+        let name: String = try!(decode_param(params[0].clone()));
+        match RPC_CALLS.find_equiv(&name.as_slice()) {
+          Some(call) if rpc_enabled(call, &shared.config) => Ok(call.to_json()),
+          _ => Err(standard_error(MethodNotFound, Some(json::String(name))))
+        }
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Summarizes the state of the wallet, for dashboards and health checks. Balances are only included if wallet RPC is enabled."]
   #[usage=""]
+  #[params=[]]
+  #[result="object {version, network, blocks, connections, coinjoin, debug_level, uptime, balances}"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn help(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
+  #[runs_on=Worker]
+  pub fn getinfo(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let (tip_height, median_time) = best_height_and_time(&*shared.blockchain.read());
     let mut ret = TreeMap::new();
-    for call in RPC_CALLS.values() {
-      if !call.coinjoin || idle_state.config.coinjoin_on {
-        let mut obj = TreeMap::new();
-        obj.insert("description".to_string(), json::String(call.desc.to_string()));
-        obj.insert("usage".to_string(), json::String(call.usage.to_string()));
-        ret.insert(call.name.to_string(), json::Object(obj));
+    ret.insert("version".to_string(), json::String(version::VERSION.to_string()));
+    ret.insert("network".to_string(), json::String(shared.config.network.to_string()));
+    ret.insert("blocks".to_string(), tip_height.to_json());
+    // We only ever have the one peer
+    ret.insert("connections".to_string(), json::U64(1));
+    ret.insert("coinjoin".to_string(), json::Boolean(shared.config.coinjoin.enabled));
+    ret.insert("debug_level".to_string(), json::String(shared.config.debug_level.to_string()));
+    ret.insert("uptime".to_string(), (time::get_time().sec - shared.started_at).to_json());
+    if shared.config.wallet_rpc {
+      let mut wallets = TreeMap::new();
+      for w in shared.wallets.iter() {
+        let w = w.lock();
+        wallets.insert(w.config.name.clone(), balances(&w.wallet, &w.meta, tip_height, median_time).to_json());
       }
+      ret.insert("balances".to_string(), json::Object(wallets));
     }
     Ok(json::Object(ret))
   },
 
-  #[doc="Gets a specific block from the blockchain"]
-  #[usage="<hash>"]
+  #[doc="Describes the build: its version, git commit and build date, the P2P protocol version it speaks and the user agent it gives peers"]
+  #[usage=""]
+  #[params=[]]
+  #[result="object {version, git_commit, build_date, protocol_version, user_agent}"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn getblock(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+  #[runs_on=Worker]
+  pub fn getversion(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
     match params.len() {
-      1 => {
-        let blockchain = idle_state.blockchain.read();
-        let hash: Sha256dHash = try!(decode_param(params[0].clone()));
-
-        match blockchain.get_block(hash) {
-          Some(node) => {
-            let mut ret = TreeMap::new();
-            ret.insert("header".to_string(), node.block.header.to_json());
-            ret.insert("has_txdata".to_string(), json::Boolean(node.has_txdata));
-            if node.has_txdata {
-              ret.insert("transactions".to_string(), node.block.txdata.to_json());
-            }
-            Ok(json::Object(ret))
-          }
-          None => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
-        }
-      }
+      0 => Ok(version::to_json()),
       _ => Err(usage_error(rpc))
     }
   },
 
-  #[doc="Gets the current number of unspent outputs on the blockchain."]
+  #[doc="Gets per-method RPC call counts and latencies, and a list of the most recent calls"]
   #[usage=""]
+  #[params=[]]
+  #[result="object {since, methods, recent}"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn getutxocount(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+  #[runs_on=Worker]
+  pub fn getrpcinfo(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
     match params.len() {
-      0 => Ok(json::U64(idle_state.utxo_set.read().n_utxos() as u64)),
+      0 => Ok(shared.rpc_stats.lock().to_json()),
       _ => Err(usage_error(rpc))
     }
   },
 
-  #[doc="Gets the length of the longest chain, starting from the given hash or genesis."]
-  #[usage="[start hash]"]
+  #[doc="Gets counters and gauges describing the wallet: chain and sync progress, peer, mempool, RPC calls, coinjoin sessions and saves"]
+  #[usage="[format]"]
+  #[params=[("format", StringParam, false, "\"json\" (default) or \"prometheus\", for the Prometheus text format")]]
+  #[result="list of objects {name, help, type, samples}; or, for the Prometheus format, a string"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn getblockcount(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      0 => {
-        let blockchain = idle_state.blockchain.read();
-        // Subtract 1 from the hash since the genesis counts as block 0
-        Ok(json::U64(blockchain.iter(blockchain.genesis_hash()).count() as u64 - 1))
-      }
+  #[runs_on=IdleLoop]
+  pub fn getmetrics(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let text = match params.len() {
+      0 => false,
       1 => {
-        let blockchain = idle_state.blockchain.read();
-        let hash: Sha256dHash = try!(decode_param(params[0].clone()));
+        let format: String = try!(decode_param(params[0].clone()));
+        match format.as_slice() {
+          "json" => false,
+          "prometheus" => true,
+          _ => { return Err(usage_error(rpc)); }
+        }
+      }
+      _ => { return Err(usage_error(rpc)); }
+    };
 
-        // Subtract 1 from the hash since the genesis counts as block 0
-        match blockchain.iter(hash).count() {
-          0 => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
-          n => Ok(json::U64(n as u64 - 1)),
+    let mut metrics = Metrics::new();
+    {
+      let blockchain = idle_state.blockchain.read();
+      let utxo_set = idle_state.utxo_set.read();
+      metrics.gauge("header_height", "Height of the best known header", best_height(&*blockchain) as f64);
+      let utxo_height = blockchain.get_block(utxo_set.last_hash()).map_or(0, |node| node.height);
+      metrics.gauge("utxo_height", "Height of the last block connected to the UTXO set", utxo_height as f64);
+      metrics.gauge("utxo_count", "Number of unspent outputs", utxo_set.n_utxos() as f64);
+    }
+    metrics.gauge("peer_start_height", "Chain height the peer reported on connecting",
+                  idle_state.peer.start_height.unwrap_or(0) as f64);
+    for state in [SyncingHeaders, SyncingUtxoSet, Synced].iter() {
+      metrics.add(Gauge, "sync_state", "Whether the wallet is in each sync state",
+                  vec![("state", state.name().to_string())],
+                  if *state == idle_state.sync_state { 1.0 } else { 0.0 });
+    }
+    // We only ever have the one peer
+    metrics.gauge("peers", "Number of connected peers", 1.0);
+    metrics.counter("peer_messages_received", "Messages received from the peer",
+                    idle_state.peer.messages_received as f64);
+    metrics.counter("peer_bytes_received", "Bytes received from the peer",
+                    idle_state.peer.bytes_received as f64);
+    metrics.counter("peer_bytes_sent", "Bytes sent to the peer",
+                    idle_state.peer.bytes_sent as f64);
+    metrics.counter("sync_stalls", "Times syncing gave up waiting on the peer and reconnected",
+                    idle_state.stalls as f64);
+    {
+      let mempool = idle_state.mempool.read();
+      metrics.gauge("mempool_transactions", "Number of transactions in the mempool", mempool.len() as f64);
+      metrics.gauge("mempool_bytes", "Total size of transactions in the mempool", mempool.total_size() as f64);
+    }
+    {
+      let stats = idle_state.rpc_stats.lock();
+      for (name, method) in stats.methods.iter() {
+        metrics.add(Counter, "rpc_calls", "RPC calls handled, by method",
+                    vec![("method", name.clone())], method.calls as f64);
+        metrics.add(Counter, "rpc_errors", "RPC calls which failed, by method",
+                    vec![("method", name.clone())], method.errors as f64);
+      }
+    }
+    match idle_state.coinjoin {
+      Some(ref mut server) => {
+        server.update_all();
+        let mut counts: TreeMap<String, uint> = TreeMap::new();
+        for session in server.sessions().iter() {
+          let state = session.state().to_json().as_string().unwrap_or("").to_string();
+          *counts.find_or_insert(state, 0) += 1;
+        }
+        for (state, count) in counts.iter() {
+          metrics.add(Gauge, "coinjoin_sessions", "Coinjoin sessions, by state",
+                      vec![("state", state.clone())], *count as f64);
         }
       }
-      _ => Err(usage_error(rpc))
+      None => {}
+    }
+    {
+      let saves = idle_state.save_stats.lock();
+      metrics.counter("saves", "Saves of the blockchain and UTXO set", saves.saves as f64);
+      metrics.counter("full_saves", "Saves which rewrote the blockchain and UTXO set in full",
+                      saves.full_saves as f64);
+      metrics.counter("save_failures", "Saves which failed", saves.failures as f64);
+      metrics.gauge("last_save_seconds", "Duration of the last save",
+                    saves.last_duration_ns as f64 / 1e9);
+      metrics.counter("save_seconds", "Total duration of all saves",
+                      saves.total_duration_ns as f64 / 1e9);
+    }
+
+    if text {
+      Ok(json::String(metrics.to_text()))
+    } else {
+      Ok(metrics.to_json())
     }
   },
 
-  #[doc="Decodes a raw transaction"]
-  #[usage="<hex-encoded tx data>"]
+  #[doc="Gets the time, in seconds, since the wallet started"]
+  #[usage=""]
+  #[params=[]]
+  #[result="integer (seconds)"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn raw_decode(rpc: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
+  #[runs_on=Worker]
+  pub fn uptime(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
     match params.len() {
-      1 => {
-        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-        Ok(tx.to_json())
-      }
+      0 => Ok((time::get_time().sec - shared.started_at).to_json()),
       _ => Err(usage_error(rpc))
     }
   },
 
-  #[doc="Validates a raw transaction"]
-  #[usage="<hex-encoded tx data>"]
+  #[doc="Saves all state to disk and shuts down every network, then exits. Unfinished coinjoin sessions are abandoned."]
+  #[usage=""]
+  #[params=[]]
+  #[result="string"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn raw_validate(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-        let utxo_set = idle_state.utxo_set.read();
-        match tx.validate(&*utxo_set) {
-          Ok(_) => Ok(json::Boolean(true)),
-          Err(e) => Err(bitcoin_json_error(InvalidTx, Some(json::String(e.to_string()))))
-        }
-      }
-      _ => Err(usage_error(rpc))
+  #[runs_on=IdleLoop]
+  pub fn stop(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
     }
+    // The main task tells every network, including this one, to stop once
+    // this reply has been sent
+    let _ = idle_state.shutdown_tx.send_opt(());
+    Ok(json::String("Wizards' Wallet stopping".to_string()))
   },
 
-  #[doc="Traces execution of a raw transaction's scripts"]
-  #[usage="<hex-encoded tx data>"]
+  #[doc="Rereads the configuration file, as on SIGHUP. Debug level, peer, RPC credentials, coinjoin and wallet policy settings take effect at once; changes to other settings are reported as needing a restart."]
+  #[usage=""]
+  #[params=[]]
+  #[result="object {applied, restart_required}, each a list of setting names"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn raw_trace(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-        let utxo_set = idle_state.utxo_set.read();
-        Ok(tx.trace(&*utxo_set).to_json())
-      }
-      _ => Err(usage_error(rpc))
+  #[runs_on=IdleLoop]
+  pub fn reloadconfig(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
     }
+    // The main task rereads the file and hands it to every network; this
+    // one answers once it has applied its part
+    let reply = idle_state.rpc_reply.take().unwrap();
+    let _ = idle_state.reload_tx.send_opt(Some((idle_state.config.network, reply)));
+    Ok(json::Null)
   },
 
-  #[doc="Traces execution of an individual script"]
-  #[usage="<hex-encoded script>"]
+  #[doc="Sets this network's debug level until the next restart or configuration reload. No components have their own level yet, so naming one is an error."]
+  #[usage="<level> [component]"]
+  #[params=[("level", StringParam, true, "DEBUG, NOTE, STATUS, WARN, ERROR or FATAL"),
+            ("component", StringParam, false, "Component to set the level of, rather than the whole network")]]
+  #[result="string (the previous level)"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn script_trace(rpc: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
-        Ok(script.trace(&mut vec![], None).to_json())
-      }
-      _ => Err(usage_error(rpc))
+  #[runs_on=IdleLoop]
+  pub fn setdebuglevel(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() < 1 || params.len() > 2 {
+      return Err(usage_error(rpc));
+    }
+    let name: String = try!(decode_param(params[0].clone()));
+    let level = match DebugLevel::from_name(name.as_slice()) {
+      Some(level) => level,
+      None => { return Err(standard_error(InvalidParams, Some(json::String(name)))); }
+    };
+    if params.len() == 2 {
+      let component: String = try!(decode_param(params[1].clone()));
+      return Err(standard_error(InvalidParams,
+                                Some(json::String(format!("unknown component `{}`", component)))));
     }
+    let previous = idle_state.config.debug_level;
+    idle_state.config.debug_level = level;
+    debug!(idle_state, Status, "Debug level changed from {} to {} over RPC.", previous, level);
+    Ok(json::String(previous.to_string()))
   },
 
-  #[doc="Checks whether a script pubkey can be proven to have no satisfying input. Returns 'spendable' or 'unspendable'."]
-  #[usage="<hex-encoded script>"]
+  #[doc="Gets a specific block from the blockchain; if verbose is false, as hex-encoded block data"]
+  #[usage="<hash> [verbose]"]
+  #[params=[("hash", HashParam, true, "Hash of the block"),
+            ("verbose", BoolParam, false, "Whether to decode the block (default true)")]]
+  #[result="object (decoded block), or hex string if not verbose"]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn script_unspendable(rpc: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
+  #[runs_on=Worker]
+  pub fn getblock(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
     match params.len() {
-      1 => {
-        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
-        Ok(json::String(if script.is_provably_unspendable() { "unspendable" } else { "spendable" }.to_string()))
+      1 | 2 => {
+        let blockchain = shared.blockchain.read();
+        let hash: Sha256dHash = try!(decode_hash_param(params[0].clone()));
+        let verbose = if params.len() == 2 { try!(decode_param(params[1].clone())) } else { true };
+
+        match blockchain.get_block(hash) {
+          Some(node) if !verbose => {
+            // Without transaction data we cannot give the full block
+            if !node.has_txdata {
+              return Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json())));
+            }
+            Ok(json::String(serialize_hex(&node.block).unwrap()))
+          }
+          Some(node) => {
+            let mut ret = TreeMap::new();
+            ret.insert("header".to_string(), node.block.header.to_json());
+            ret.insert("has_txdata".to_string(), json::Boolean(node.has_txdata));
+            if node.has_txdata {
+              ret.insert("transactions".to_string(), node.block.txdata.to_json());
+            }
+            Ok(json::Object(ret))
+          }
+          None => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
+        }
       }
       _ => Err(usage_error(rpc))
     }
   },
 
-  #[doc="Starts a new coinjoin session"]
-  #[usage="<target amount (satoshi)> <join duration (seconds)> <merge duration (seconds)>"]
-  #[coinjoin=true]
+  #[doc="Gets the hash of the best chain tip"]
+  #[usage=""]
+  #[params=[]]
+  #[result="hash"]
+  #[coinjoin=false]
   #[wallet=false]
-  pub fn coinjoin_start(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) { 
+  #[runs_on=Worker]
+  pub fn getbestblockhash(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
     match params.len() {
-      3 => {
-        let target: u64 = try!(decode_param(params[0].clone()));
-        let join_duration = Duration::seconds(try!(decode_param(params[1].clone())));
-        let expiry_duration = Duration::seconds(try!(decode_param(params[2].clone())));
+      0 => Ok(shared.blockchain.read().best_tip_hash().to_json()),
+      _ => Err(usage_error(rpc))
+    }
+  },
 
-        // Start session manager if we haven't
-        if idle_state.coinjoin.is_none() {
-          idle_state.coinjoin = Some(Server::new());
-        }
-        // Update the server state
-        let server = idle_state.coinjoin.get_mut_ref();
-        server.update_all();
-        // Obtain a donation address
-        let mut address = idle_state.wallet.new_address("coinjoin", External);
-        if address == Err(AccountNotFound) {
-          try!(idle_state.wallet.account_insert("coinjoin".to_string())
-                 .map_err(|e| bitcoin_json_error(WalletError,
-                                                 Some(json::String(e.to_string())))));
-          address = idle_state.wallet.new_address("coinjoin", External);
-        }
-        let address = try!(address.map_err(|e| bitcoin_json_error(WalletError,
-                                               Some(json::String(e.to_string())))));
+  #[doc="Gets the difficulty of the best chain tip"]
+  #[usage=""]
+  #[params=[]]
+  #[result="number"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getdifficulty(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let blockchain = shared.blockchain.read();
+    let tip = blockchain.get_block(blockchain.best_tip_hash()).unwrap();
+    Ok(difficulty_from_compact(tip.block.header.bits).to_json())
+  },
 
-        // Saveout the wallet before using the address
-        try!(save_wallet(&idle_state.config, &idle_state.wallet)
-                 .map_err(|e| bitcoin_json_error(WalletError,
-                                                 Some(json::String(e.to_string())))));
+  #[doc="Describes the best chain: its tip, height, difficulty and cumulative work, and how far sync has got"]
+  #[usage=""]
+  #[params=[]]
+  #[result="object {chain, bestblockhash, blocks, bits, difficulty, chainwork, chainwork_float, verificationprogress, sync {stage, height, target_height, progress, eta}}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getblockchaininfo(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let blockchain = shared.blockchain.read();
+    let tip_hash = blockchain.best_tip_hash();
+    let tip = blockchain.get_block(tip_hash).unwrap();
+    let mut ret = TreeMap::new();
+    ret.insert("chain".to_string(), json::String(shared.config.network.to_string()));
+    ret.insert("bestblockhash".to_string(), tip_hash.to_json());
+    ret.insert("blocks".to_string(), tip.height.to_json());
+    ret.insert("bits".to_string(), json::String(format!("{:08x}", tip.block.header.bits)));
+    ret.insert("difficulty".to_string(), difficulty_from_compact(tip.block.header.bits).to_json());
+    ret.insert("chainwork".to_string(), json::String(uint256_hex(&tip.total_work)));
+    // Approximate, but convenient for comparing against other nodes at a glance
+    ret.insert("chainwork_float".to_string(), uint256_to_f64(&tip.total_work).to_json());
+    let progress = shared.sync_progress.lock();
+    ret.insert("verificationprogress".to_string(), progress.fraction().to_json());
+    ret.insert("sync".to_string(), progress.to_json());
+    Ok(json::Object(ret))
+  },
 
-        // Add the new sesion
-        let session = try!(Session::new(target, join_duration, expiry_duration, address)
-                             .map_err(|e| bitcoin_json_error(BadRng,
-                                                             Some(json::String(e.to_string())))));
-        let id = session.id();
-        server.set_current_session(session);
-        Ok(id.to_json())
-      }
-      _ => Err(usage_error(rpc))
+  #[doc="Describes mining on the network: the current difficulty, the network's hash rate estimated from the work and timestamps of the last nblocks blocks, and the number of transactions in our mempool"]
+  #[usage="[nblocks]"]
+  #[params=[("nblocks", IntParam, false, "Number of blocks to estimate the hash rate over (default 120)")]]
+  #[result="object {blocks, difficulty, networkhashps, pooledtx}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getmininginfo(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let nblocks: uint = match params.len() {
+      0 => NETWORK_HASHPS_BLOCKS,
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    if nblocks == 0 {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String("nblocks must be positive".to_string()))));
     }
+    let blockchain = shared.blockchain.read();
+    let tip_hash = blockchain.best_tip_hash();
+    let tip = blockchain.get_block(tip_hash).unwrap();
+    // Only the blocks back to genesis can be spanned, which also keeps
+    // `nblocks + 1` from overflowing
+    let nblocks = cmp::min(nblocks, tip.height);
+    // Timestamps need not increase, so take the span of all of them
+    let mut start = tip;
+    let mut min_time = tip.block.header.time;
+    let mut max_time = min_time;
+    for node in blockchain.rev_iter(tip_hash).take(nblocks + 1) {
+      min_time = cmp::min(min_time, node.block.header.time);
+      max_time = cmp::max(max_time, node.block.header.time);
+      start = node;
+    }
+    let hashps = if max_time > min_time {
+      uint256_to_f64(&(tip.total_work - start.total_work)) / (max_time - min_time) as f64
+    } else {
+      0.0
+    };
+
+    let mut ret = TreeMap::new();
+    ret.insert("blocks".to_string(), tip.height.to_json());
+    ret.insert("difficulty".to_string(), difficulty_from_compact(tip.block.header.bits).to_json());
+    ret.insert("networkhashps".to_string(), hashps.to_json());
+    ret.insert("pooledtx".to_string(), shared.mempool.read().len().to_json());
+    Ok(json::Object(ret))
   },
 
-  #[doc="Gets the status of the current coinjoin session"]
-  #[usage="[session id]"]
-  #[coinjoin=true]
+  #[doc="Gets a block header along with its position in the chain; if verbose is false, just the hex-encoded header"]
+  #[usage="<hash> [verbose]"]
+  #[params=[("hash", HashParam, true, "Hash of the block"),
+            ("verbose", BoolParam, false, "Whether to decode the header (default true)")]]
+  #[result="object {hash, height, confirmations, difficulty, chainwork, previousblockhash, nextblockhash, ...}, or hex string if not verbose"]
+  #[coinjoin=false]
   #[wallet=false]
-  pub fn coinjoin_status(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    if idle_state.coinjoin.is_none() {
-      return Err(bitcoin_json_error(SessionNotFound, None));
+  #[runs_on=Worker]
+  pub fn getblockheader(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 1 && params.len() != 2 {
+      return Err(usage_error(rpc));
     }
-    // Update the server state
-    let server = idle_state.coinjoin.get_mut_ref();
-    server.update_all();
+    let hash: Sha256dHash = try!(decode_hash_param(params[0].clone()));
+    let verbose = if params.len() == 2 { try!(decode_param(params[1].clone())) } else { true };
 
-    match params.len() {
-      0 => server.current_session().map_or(Err(bitcoin_json_error(SessionNotFound, None)), |s| Ok(s.to_json())),
-      1 => {
-        let id: SessionId = try!(decode_param(params[0].clone()));
-        server.session(&id).map_or(Err(bitcoin_json_error(SessionNotFound, None)), |s| Ok(s.to_json()))
+    let blockchain = shared.blockchain.read();
+    let node = match blockchain.get_block(hash) {
+      Some(node) => node,
+      None => { return Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))); }
+    };
+    if !verbose {
+      return Ok(json::String(serialize_hex(&node.block.header).unwrap()));
+    }
+
+    let tip_hash = blockchain.best_tip_hash();
+    let tip_height = blockchain.get_block(tip_hash).unwrap().height;
+    // A block is on the best chain if the best chain has it at its height
+    let on_best_chain = node.height <= tip_height &&
+                        blockchain.rev_iter(tip_hash)
+                                  .nth(tip_height - node.height)
+                                  .map_or(false, |n| n.block.bitcoin_hash() == hash);
+
+    let mut ret = match node.block.header.to_json() {
+      json::Object(obj) => obj,
+      _ => unreachable!()
+    };
+    ret.insert("hash".to_string(), hash.to_json());
+    ret.insert("height".to_string(), node.height.to_json());
+    ret.insert("difficulty".to_string(), difficulty_from_compact(node.block.header.bits).to_json());
+    ret.insert("chainwork".to_string(), json::String(uint256_hex(&node.total_work)));
+    ret.insert("previousblockhash".to_string(), node.block.header.prev_blockhash.to_json());
+    if on_best_chain {
+      ret.insert("confirmations".to_string(), json::I64((tip_height + 1 - node.height) as i64));
+      match blockchain.iter(hash).nth(1) {
+        Some(next) => { ret.insert("nextblockhash".to_string(), next.block.bitcoin_hash().to_json()); }
+        None => {}
       }
-      _ => Err(usage_error(rpc))
+    } else {
+      // As bitcoind does, report stale blocks as having -1 confirmations
+      ret.insert("confirmations".to_string(), json::I64(-1));
     }
+    Ok(json::Object(ret))
   },
 
-  #[doc="Adds a unsigned transaction to the current coinjoin session"]
-  #[usage="<rawtx> [session id]"]
-  #[coinjoin=true]
+  #[doc="Gets the headers of up to `count` blocks, starting from the given hash and following the best chain; if verbose is false, hex-encoded"]
+  #[usage="<hash> [count] [verbose]"]
+  #[params=[("hash", HashParam, true, "Hash of the first block"),
+            ("count", IntParam, false, "Maximum number of headers (default and at most 2000)"),
+            ("verbose", BoolParam, false, "Whether to decode the headers (default true)")]]
+  #[result="list of header objects, or of hex strings if not verbose"]
+  #[coinjoin=false]
   #[wallet=false]
-  pub fn coinjoin_add_raw_unsigned(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    if idle_state.coinjoin.is_none() {
-      return Err(bitcoin_json_error(SessionNotFound, None));
+  #[runs_on=Worker]
+  pub fn getblockheaders(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() < 1 || params.len() > 3 {
+      return Err(usage_error(rpc));
     }
-    // Update the server state
-    let server = idle_state.coinjoin.get_mut_ref();
-    server.update_all();
-
-    let session = match params.len() {
-      1 => {
-        match server.current_session_mut() {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
-        }
-      }
-      2 => {
-        let id: SessionId = try!(decode_param(params[1].clone()));
-        match server.session_mut(&id) {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
-        }
+    let blockchain = shared.blockchain.read();
+    let hash: Sha256dHash = try!(decode_hash_param(params[0].clone()));
+    let count: uint = if params.len() >= 2 { try!(decode_param(params[1].clone())) }
+                      else { MAX_HEADERS_RESULTS };
+    let verbose = if params.len() == 3 { try!(decode_param(params[2].clone())) } else { true };
+    if blockchain.get_block(hash).is_none() {
+      return Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json())));
+    }
+    let mut headers = vec![];
+    for node in blockchain.iter(hash).take(cmp::min(count, MAX_HEADERS_RESULTS)) {
+      if verbose {
+        headers.push(node.block.header.to_json());
+      } else {
+        headers.push(json::String(serialize_hex(&node.block.header).unwrap()));
       }
-      _ => { return Err(usage_error(rpc)); }
-    };
-    let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-    match session.add_unsigned(&tx, &*idle_state.utxo_set.read()) {
-      Ok(()) => Ok(json::Boolean(true)),
-      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
     }
+    Ok(json::List(headers))
   },
 
-  #[doc="Submits a (partially-)signed transaction to the current coinjoin session"]
-  #[usage="<rawtx> [session id]"]
-  #[coinjoin=true]
+  #[doc="Finds a transaction among the wallets' transactions, the mempool and the blocks whose data we keep, or in the given block, returning it hex-encoded, or decoded if verbose is true. There is no transaction index, so older transactions cannot be found without a block hash whose data is kept."]
+  #[usage="<txid> [verbose] [block hash]"]
+  #[params=[("txid", HashParam, true, "Transaction id"),
+            ("verbose", BoolParam, false, "Whether to decode the transaction (default false)"),
+            ("block hash", HashParam, false, "Block to look for the transaction in")]]
+  #[result="hex string, or if verbose object {txid, hex, source, blockhash, confirmations, ...}"]
+  #[coinjoin=false]
   #[wallet=false]
-  pub fn coinjoin_add_raw_signed(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    if idle_state.coinjoin.is_none() {
-      return Err(bitcoin_json_error(SessionNotFound, None));
+  #[runs_on=Worker]
+  pub fn getrawtransaction(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() < 1 || params.len() > 3 {
+      return Err(usage_error(rpc));
     }
-    // Update the server state
-    let server = idle_state.coinjoin.get_mut_ref();
-    server.update_all();
+    let txid: Sha256dHash = try!(decode_hash_param(params[0].clone()));
+    let verbose = if params.len() >= 2 { try!(decode_param(params[1].clone())) } else { false };
+    let block_hash: Option<Sha256dHash> = if params.len() == 3 {
+      Some(try!(decode_hash_param(params[2].clone())))
+    } else {
+      None
+    };
 
-    let session = match params.len() {
-      1 => {
-        match server.current_session_mut() {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+    let blockchain = shared.blockchain.read();
+    let (tip_hash, tip_height) = {
+      let hash = blockchain.best_tip_hash();
+      (hash, blockchain.get_block(hash).unwrap().height)
+    };
+    // (tx, containing block and its height, where we found it)
+    let mut found = None;
+    // Whether every block was searched, so that the tx surely does not exist
+    let mut searched_all = false;
+    match block_hash {
+      Some(hash) => {
+        match blockchain.get_block(hash) {
+          Some(node) if node.has_txdata => {
+            match node.block.txdata.iter().find(|tx| tx.bitcoin_hash() == txid) {
+              Some(tx) => { found = Some((tx.clone(), Some((hash, node.height)), "block")); }
+              None => { return Err(bitcoin_json_error(TxNotFound, Some(txid.to_json()))); }
+            }
+          }
+          Some(_) => { return Err(bitcoin_json_error(TxNotIndexed, Some(txid.to_json()))); }
+          None => { return Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))); }
         }
       }
-      2 => {
-        let id: SessionId = try!(decode_param(params[1].clone()));
-        match server.session_mut(&id) {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+      None => {
+        // Wallet transactions first, since these include unconfirmed ones
+        for w in shared.wallets.iter() {
+          match w.lock().meta.find_transaction(txid).map(|wtx| (wtx.transaction(), wtx.height)) {
+            Some((Ok(tx), height)) => {
+              let block = height.and_then(|h| blockchain.rev_iter(tip_hash)
+                                                        .find(|node| node.height == h)
+                                                        .map(|node| (node.block.bitcoin_hash(), h)));
+              found = Some((tx, block, "wallet"));
+              break;
+            }
+            _ => {}
+          }
+        }
+        if found.is_none() {
+          found = shared.mempool.read().get(&txid).map(|entry| (entry.tx.clone(), None, "mempool"));
+        }
+        if found.is_none() {
+          searched_all = true;
+          for node in blockchain.rev_iter(tip_hash) {
+            if !node.has_txdata {
+              searched_all = false;
+              break;
+            }
+            match node.block.txdata.iter().find(|tx| tx.bitcoin_hash() == txid) {
+              Some(tx) => {
+                found = Some((tx.clone(), Some((node.block.bitcoin_hash(), node.height)), "block"));
+                break;
+              }
+              None => {}
+            }
+          }
         }
       }
-      _ => { return Err(usage_error(rpc)); }
-    };
-    let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    }
 
-    // Add the signed transaction
-    let ret = match session.add_signed(&tx, &*idle_state.utxo_set.read()) {
-      Ok(()) => Ok(json::Boolean(true)),
-      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+    let (tx, block, source) = match found {
+      Some(found) => found,
+      None if searched_all => { return Err(bitcoin_json_error(TxNotFound, Some(txid.to_json()))); }
+      None => { return Err(bitcoin_json_error(TxNotIndexed, Some(txid.to_json()))); }
     };
-    // If that was the last one, submit it
-    if session.state() == Complete {
-      let complete_tx = session.signed_transaction().unwrap().clone();
-      consume_err("Coinjoin: failed to send `tx` message",
-        idle_state.sock.send_message(message::Tx(complete_tx)));
+    let hex = serialize_hex(&tx).unwrap();
+    if !verbose {
+      return Ok(json::String(hex));
     }
-    ret
-  }
-}
+    let mut ret = match tx.to_json() {
+      json::Object(obj) => obj,
+      _ => unreachable!()
+    };
+    ret.insert("txid".to_string(), txid.to_json());
+    ret.insert("hex".to_string(), json::String(hex));
+    ret.insert("source".to_string(), json::String(source.to_string()));
+    match block {
+      Some((hash, height)) => {
+        ret.insert("blockhash".to_string(), hash.to_json());
+        ret.insert("confirmations".to_string(), (tip_height + 1 - height).to_json());
+      }
+      None => { ret.insert("confirmations".to_string(), 0u.to_json()); }
+    }
+    Ok(json::Object(ret))
+  },
 
-enum BitcoinJsonError {
-  BadRng,
+  #[doc="Gets the current number of unspent outputs on the blockchain."]
+  #[usage=""]
+  #[params=[]]
+  #[result="integer"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getutxocount(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      0 => Ok(json::U64(shared.utxo_set.read().n_utxos() as u64)),
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Describes the UTXO set: the block it is up to date with, its number of unspent outputs, and the SHA256d hash of its serialization, for comparing against another copy. Hashing reads the whole set, so may take a while."]
+  #[usage=""]
+  #[params=[]]
+  #[result="object {height, bestblock, txouts, hash_serialized}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn gettxoutsetinfo(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let blockchain = shared.blockchain.read();
+    let utxo_set = shared.utxo_set.read();
+    let hash = try!(disk::hash_serialized(&*utxo_set)
+                      .map_err(|e| standard_error(InternalError, Some(json::String(e.to_string())))));
+    let mut ret = TreeMap::new();
+    let height = blockchain.get_block(utxo_set.last_hash()).map_or(0, |node| node.height);
+    ret.insert("height".to_string(), height.to_json());
+    ret.insert("bestblock".to_string(), utxo_set.last_hash().to_json());
+    ret.insert("txouts".to_string(), utxo_set.n_utxos().to_json());
+    ret.insert("hash_serialized".to_string(), hash.to_json());
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Gets the total value of the unspent outputs to any address. Needs the address index."]
+  #[usage="<address>"]
+  #[params=[("address", StringParam, true, "Address to look up")]]
+  #[result="amount in satoshi"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getaddressbalance(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 1 {
+      return Err(usage_error(rpc));
+    }
+    let (index, script) = try!(indexed_address(shared, params[0].clone()));
+    let balance = index.read().balance(script.as_slice());
+    Ok(balance.to_json())
+  },
+
+  #[doc="Lists the unspent outputs to any address, oldest first. Needs the address index."]
+  #[usage="<address>"]
+  #[params=[("address", StringParam, true, "Address to look up")]]
+  #[result="list of objects {txid, vout, amount, height}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getaddressutxos(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 1 {
+      return Err(usage_error(rpc));
+    }
+    let (index, script) = try!(indexed_address(shared, params[0].clone()));
+    let utxos = index.read().utxos(script.as_slice());
+    Ok(json::List(utxos.iter().map(|out| {
+      let mut obj = TreeMap::new();
+      obj.insert("txid".to_string(), out.txid.to_json());
+      obj.insert("vout".to_string(), out.vout.to_json());
+      obj.insert("amount".to_string(), out.value.to_json());
+      obj.insert("height".to_string(), out.height.to_json());
+      json::Object(obj)
+    }).collect()))
+  },
+
+  #[doc="Lists the transactions paying to or spending from any address, oldest first. Needs the address index, and only knows of spends since the index was built at startup."]
+  #[usage="<address>"]
+  #[params=[("address", StringParam, true, "Address to look up")]]
+  #[result="list of txids"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getaddresstxids(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 1 {
+      return Err(usage_error(rpc));
+    }
+    let (index, script) = try!(indexed_address(shared, params[0].clone()));
+    let txids = index.read().txids(script.as_slice());
+    Ok(txids.to_json())
+  },
+
+  #[doc="Gets the number and total size of transactions in the mempool, and the size past which it evicts the lowest-fee ones"]
+  #[usage=""]
+  #[params=[]]
+  #[result="object {size, bytes, maxmempool}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getmempoolinfo(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let mempool = shared.mempool.read();
+    let mut ret = TreeMap::new();
+    ret.insert("size".to_string(), mempool.len().to_json());
+    ret.insert("bytes".to_string(), mempool.total_size().to_json());
+    ret.insert("maxmempool".to_string(), shared.config.mempool_max_size.to_json());
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Gets a histogram of the mempool by fee rate, in the fee estimator's buckets: for each nonempty bucket, its range of fee rates in satoshi per 1000 bytes (the highest having no upper bound), and the number and total size of its transactions. Buckets are listed from the lowest fee rate."]
+  #[usage=""]
+  #[params=[]]
+  #[result="list of objects {min_fee_rate, max_fee_rate, count, bytes}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getmempoolfeehistogram(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let histogram = shared.mempool.read().fee_histogram();
+    let mut ret = vec![];
+    for (idx, &(count, bytes)) in histogram.iter().enumerate() {
+      if count == 0 {
+        continue;
+      }
+      let (bottom, top) = fee_estimator::bucket_range(idx);
+      let mut obj = TreeMap::new();
+      obj.insert("min_fee_rate".to_string(), bottom.to_json());
+      obj.insert("max_fee_rate".to_string(), top.to_json());
+      obj.insert("count".to_string(), count.to_json());
+      obj.insert("bytes".to_string(), bytes.to_json());
+      ret.push(json::Object(obj));
+    }
+    Ok(json::List(ret))
+  },
+
+  #[doc="Estimates the fee rate, in satoshi per 1000 bytes, needed for a transaction to confirm within the given number of blocks. Returns -1 if there is not yet enough data."]
+  #[usage="<nblocks>"]
+  #[params=[("nblocks", IntParam, true, "Confirmation target in blocks")]]
+  #[result="fee rate, or -1"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn estimatefee(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let target: uint = match params.len() {
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    if target == 0 || target > FEE_ESTIMATE_MAX_TARGET {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String(format!("nblocks must be between 1 and {}",
+                                                          FEE_ESTIMATE_MAX_TARGET)))));
+    }
+    match shared.fee_estimator.read().estimate(target) {
+      Some(rate) => Ok(json::I64(rate as i64)),
+      None => Ok(json::I64(-1))
+    }
+  },
+
+  #[doc="Describes the connected peers. Traffic is counted in whole messages, headers included. Peers are never banned, so the misbehavior score is always 0."]
+  #[usage=""]
+  #[params=[]]
+  #[result="list of peer objects"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn getpeerinfo(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    let peer = &idle_state.peer;
+    let mut obj = TreeMap::new();
+    obj.insert("addr".to_string(), json::String(peer.addr.to_string()));
+    obj.insert("inbound".to_string(), json::Boolean(false));
+    obj.insert("conntime".to_string(), peer.connected_at.to_json());
+    obj.insert("version".to_string(), peer.version.to_json());
+    obj.insert("subver".to_string(), peer.user_agent.to_json());
+    obj.insert("startingheight".to_string(), peer.start_height.to_json());
+    obj.insert("lastrecv".to_string(), peer.last_recv.to_json());
+    obj.insert("msgrecv".to_string(), peer.messages_received.to_json());
+    obj.insert("bytessent".to_string(), peer.bytes_sent.to_json());
+    obj.insert("bytesrecv".to_string(), peer.bytes_received.to_json());
+    obj.insert("banscore".to_string(), 0u.to_json());
+    // In seconds, as bitcoind reports it
+    obj.insert("pingtime".to_string(), peer.ping_time.map(|ns| ns as f64 / 1e9).to_json());
+    Ok(json::List(vec![json::Object(obj)]))
+  },
+
+  #[doc="Adds a peer to the list we connect from, removes one (moving on to the next if it is connected), or connects once to a peer outside the list. Changes last until the next restart or configuration reload."]
+  #[usage="<host[:port]> <add|remove|onetry>"]
+  #[params=[("peer", StringParam, true, "Peer address, as host or host:port"),
+            ("command", StringParam, true, "add, remove or onetry")]]
+  #[result="list of strings (the configured peers)"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn addnode(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 2 {
+      return Err(usage_error(rpc));
+    }
+    let peer: String = try!(decode_param(params[0].clone()));
+    let command: String = try!(decode_param(params[1].clone()));
+    let addr = match PeerAddress::parse(peer.as_slice(), DEFAULT_PEER_PORT) {
+      Some(addr) => addr,
+      None => {
+        return Err(standard_error(InvalidParams,
+                                  Some(json::String(format!("invalid peer `{}`", peer)))));
+      }
+    };
+    let position = idle_state.config.peers.iter().position(|p| *p == addr);
+    match (command.as_slice(), position) {
+      ("add", None) => {
+        debug!(idle_state, Status, "Adding peer {} over RPC.", addr);
+        idle_state.config.peers.push(addr);
+      }
+      ("add", Some(_)) => {
+        return Err(standard_error(InvalidParams,
+                                  Some(json::String(format!("peer {} already added", addr)))));
+      }
+      ("remove", Some(_)) if idle_state.config.peers.len() == 1 => {
+        return Err(standard_error(InvalidParams,
+                                  Some(json::String("cannot remove the only peer".to_string()))));
+      }
+      ("remove", Some(n)) => {
+        debug!(idle_state, Status, "Removing peer {} over RPC.", addr);
+        idle_state.config.peers.remove(n);
+        if idle_state.peer.addr == addr {
+          idle_state.peer_change = Some(LeavePeer);
+        }
+      }
+      ("remove", None) => {
+        return Err(standard_error(InvalidParams,
+                                  Some(json::String(format!("peer {} was not added", addr)))));
+      }
+      ("onetry", _) => {
+        debug!(idle_state, Status, "Trying peer {} over RPC.", addr);
+        idle_state.peer_change = Some(TryPeer(addr));
+      }
+      _ => { return Err(usage_error(rpc)); }
+    }
+    let peers: Vec<String> = idle_state.config.peers.iter().map(|p| p.to_string()).collect();
+    Ok(peers.to_json())
+  },
+
+  #[doc="Gets the length of the longest chain, starting from the given hash or genesis."]
+  #[usage="[start hash]"]
+  #[params=[("start hash", HashParam, false, "Block to count from (default genesis)")]]
+  #[result="integer"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn getblockcount(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      0 => {
+        let blockchain = shared.blockchain.read();
+        // Subtract 1 from the hash since the genesis counts as block 0
+        Ok(json::U64(blockchain.iter(blockchain.genesis_hash()).count() as u64 - 1))
+      }
+      1 => {
+        let blockchain = shared.blockchain.read();
+        let hash: Sha256dHash = try!(decode_hash_param(params[0].clone()));
+
+        // Subtract 1 from the hash since the genesis counts as block 0
+        match blockchain.iter(hash).count() {
+          0 => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
+          n => Ok(json::U64(n as u64 - 1)),
+        }
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Waits for the chain tip to change, returning the new tip, or the current tip on timeout."]
+  #[usage="[timeout (s)]"]
+  #[params=[("timeout", IntParam, false, "Seconds to wait (default 600)")]]
+  #[result="object {hash, height}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn waitfornewblock(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let timeout = match params.len() {
+      0 => BLOCK_WAIT_TIMEOUT,
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let (hash, _) = chain_tip(idle_state);
+    // Hold on to the reply channel and answer from `notify_block_waiters`
+    let reply = idle_state.rpc_reply.take().unwrap();
+    idle_state.block_waiters.push(BlockWaiter {
+      start_tip: hash,
+      height: None,
+      deadline: time::precise_time_ns() + timeout as u64 * 1000000000,
+      reply: reply
+    });
+    Ok(json::Null)
+  },
+
+  #[doc="Waits for the chain to reach the given height, returning the tip then, or the current tip on timeout."]
+  #[usage="<height> [timeout (s)]"]
+  #[params=[("height", IntParam, true, "Height to wait for"),
+            ("timeout", IntParam, false, "Seconds to wait (default 600)")]]
+  #[result="object {hash, height}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn waitforblockheight(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 1 && params.len() != 2 {
+      return Err(usage_error(rpc));
+    }
+    let height: uint = try!(decode_param(params[0].clone()));
+    let timeout = if params.len() == 2 { try!(decode_param(params[1].clone())) }
+                  else { BLOCK_WAIT_TIMEOUT };
+    let (hash, tip_height) = chain_tip(idle_state);
+    if tip_height >= height {
+      return Ok(tip_json(hash, tip_height));
+    }
+    let reply = idle_state.rpc_reply.take().unwrap();
+    idle_state.block_waiters.push(BlockWaiter {
+      start_tip: hash,
+      height: Some(height),
+      deadline: time::precise_time_ns() + timeout as u64 * 1000000000,
+      reply: reply
+    });
+    Ok(json::Null)
+  },
+
+  #[doc="Decodes a raw transaction"]
+  #[usage="<hex-encoded tx data>"]
+  #[params=[("tx", HexParam, true, "Hex-encoded transaction")]]
+  #[result="object (decoded transaction)"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn raw_decode(rpc: &RpcCall, _: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      1 => {
+        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+        Ok(tx.to_json())
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Validates a raw transaction"]
+  #[usage="<hex-encoded tx data>"]
+  #[params=[("tx", HexParam, true, "Hex-encoded transaction")]]
+  #[result="true"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn raw_validate(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      1 => {
+        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+        let utxo_set = shared.utxo_set.read();
+        match tx.validate(&*utxo_set) {
+          Ok(_) => Ok(json::Boolean(true)),
+          Err(e) => Err(bitcoin_json_error(InvalidTx, Some(json::String(e.to_string()))))
+        }
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Traces execution of a raw transaction's scripts"]
+  #[usage="<hex-encoded tx data>"]
+  #[params=[("tx", HexParam, true, "Hex-encoded transaction")]]
+  #[result="object (trace of each input's scripts)"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn raw_trace(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      1 => {
+        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+        let utxo_set = shared.utxo_set.read();
+        Ok(tx.trace(&*utxo_set).to_json())
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Creates an unsigned raw transaction spending the given outputs to the given addresses. Inputs are a list of {\"txid\", \"vout\"} objects; outputs are an object mapping addresses to amounts in satoshi."]
+  #[usage="<inputs> <outputs> [locktime]"]
+  #[params=[("inputs", ListParam, true, "List of {\"txid\", \"vout\"} objects to spend"),
+            ("outputs", ObjectParam, true, "Object mapping addresses to amounts in satoshi"),
+            ("locktime", IntParam, false, "Lock time (default 0)")]]
+  #[result="hex string (unsigned transaction)"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn createrawtransaction(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 2 && params.len() != 3 {
+      return Err(usage_error(rpc));
+    }
+    let inputs = try!(decode_outpoints_param(params[0].clone()));
+    let outputs: TreeMap<String, u64> = try!(decode_param(params[1].clone()));
+    let lock_time: u32 = if params.len() == 3 { try!(decode_param(params[2].clone())) } else { 0 };
+
+    let mut tx = Transaction {
+      version: 1,
+      lock_time: lock_time,
+      // A nonzero locktime only has effect if some input is not final
+      input: inputs.iter().map(|o| TxIn {
+        prev_hash: o.txid,
+        prev_index: o.vout,
+        script_sig: Script::new(),
+        sequence: if lock_time > 0 { 0xfffffffe } else { 0xffffffff }
+      }).collect(),
+      output: Vec::with_capacity(outputs.len())
+    };
+    for (address, &value) in outputs.iter() {
+      match script_info::address_script(shared.config.network, address.as_slice()) {
+        Some(script) => tx.output.push(TxOut { value: value, script_pubkey: script }),
+        None => { return Err(bitcoin_json_error(InvalidAddressOrKey,
+                                                Some(json::String(address.clone())))); }
+      }
+    }
+    Ok(json::String(serialize_hex(&tx).unwrap()))
+  },
+
+  #[doc="Signs the inputs of a raw transaction with the active wallet's keys, or, if given, only with the supplied WIF private keys (which must pay to pubkey or pubkey hash). Outputs not in the UTXO set need their scripts given as a list of {\"txid\", \"vout\", \"script_pubkey\"} objects. Inputs which cannot be signed keep any existing signature."]
+  #[usage="<hex-encoded tx data> [prevouts] [private keys]"]
+  #[params=[("tx", HexParam, true, "Hex-encoded transaction"),
+            ("prevouts", ListParam, false, "List of {\"txid\", \"vout\", \"script_pubkey\"} objects, or null"),
+            ("private keys", ListParam, false, "List of WIF private keys to sign with instead of the wallet's")]]
+  #[result="object {hex, complete, errors}"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn signrawtransaction(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() < 1 || params.len() > 3 {
+      return Err(usage_error(rpc));
+    }
+    let mut tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    let raw_prevouts: Vec<RawPrevout> = match params.get(1) {
+      Some(&json::Null) | None => vec![],
+      Some(p) => try!(decode_param(p.clone()))
+    };
+    let mut prevouts = Vec::with_capacity(raw_prevouts.len());
+    for p in raw_prevouts.move_iter() {
+      let txid = try!(decode_hash_param(json::String(p.txid)));
+      let script: Script = try!(decode_hex_param(json::String(p.script_pubkey), PrependLength));
+      prevouts.push((OutPoint { txid: txid, vout: p.vout }, script));
+    }
+    let keys = match params.get(2) {
+      Some(p) => {
+        let wifs: Vec<String> = try!(decode_param(p.clone()));
+        let mut keys = Vec::with_capacity(wifs.len());
+        for wif in wifs.iter() {
+          match PrivateKey::from_wif(shared.config.network, wif.as_slice()) {
+            Some(key) => keys.push(key),
+            None => { return Err(bitcoin_json_error(InvalidAddressOrKey, None)); }
+          }
+        }
+        Some(keys)
+      }
+      None => None
+    };
+    let secp = try!(Secp256k1::new().map_err(|e| bitcoin_json_error(BadRng,
+                                                  Some(json::String(e.to_string())))));
+
+    let utxo_set = shared.utxo_set.read();
+    let w = shared.wallets[shared.active_wallet].lock();
+    let mut errors = vec![];
+    for n in range(0, tx.input.len()) {
+      let outpoint = OutPoint { txid: tx.input[n].prev_hash, vout: tx.input[n].prev_index };
+      let script_pubkey = match prevouts.iter().find(|&&(ref o, _)| *o == outpoint) {
+        Some(&(_, ref script)) => script.clone(),
+        None => match utxo_set.get_utxo(outpoint.txid, outpoint.vout) {
+          Some((_, out)) => out.script_pubkey.clone(),
+          None => {
+            errors.push((outpoint, "prevout not found".to_string()));
+            continue;
+          }
+        }
+      };
+      let old_script_sig = tx.input[n].script_sig.clone();
+      let signed = match keys {
+        Some(ref keys) => sign_input_with_keys(&secp, &mut tx, n, &script_pubkey, keys.as_slice()),
+        None => w.wallet.sign_input(&mut tx, n, &script_pubkey).is_ok()
+      };
+      if !signed {
+        tx.input.get_mut(n).script_sig = old_script_sig;
+        if tx.input[n].script_sig.as_slice().is_empty() {
+          errors.push((outpoint, "unable to sign".to_string()));
+        }
+      }
+    }
+
+    let mut ret = TreeMap::new();
+    ret.insert("hex".to_string(), json::String(serialize_hex(&tx).unwrap()));
+    ret.insert("complete".to_string(), json::Boolean(errors.is_empty()));
+    if !errors.is_empty() {
+      ret.insert("errors".to_string(), json::List(errors.move_iter().map(|(outpoint, err)| {
+        let mut obj = TreeMap::new();
+        obj.insert("outpoint".to_string(), outpoint.to_json());
+        obj.insert("error".to_string(), json::String(err));
+        json::Object(obj)
+      }).collect()));
+    }
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Traces execution of an individual script"]
+  #[usage="<hex-encoded script>"]
+  #[params=[("script", HexParam, true, "Hex-encoded script")]]
+  #[result="object (execution trace)"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn script_trace(rpc: &RpcCall, _: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      1 => {
+        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+        Ok(script.trace(&mut vec![], None).to_json())
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Checks whether a script pubkey can be proven to have no satisfying input. Returns 'spendable' or 'unspendable'."]
+  #[usage="<hex-encoded script>"]
+  #[params=[("script", HexParam, true, "Hex-encoded script")]]
+  #[result="\"spendable\" or \"unspendable\""]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn script_unspendable(rpc: &RpcCall, _: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      1 => {
+        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+        Ok(json::String(if script.is_provably_unspendable() { "unspendable" } else { "spendable" }.to_string()))
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Decodes a script, giving its disassembly, type and the addresses it pays to. Unless the script is itself P2SH, also gives the P2SH address which would use it as a redeem script."]
+  #[usage="<hex-encoded script>"]
+  #[params=[("script", HexParam, true, "Hex-encoded script")]]
+  #[result="object {asm, type, reqSigs, addresses, p2sh}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn decodescript(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    match params.len() {
+      1 => {
+        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+        let network = shared.config.network;
+        let script_type = script_info::classify(script.as_slice());
+
+        let mut obj = TreeMap::new();
+        obj.insert("asm".to_string(), json::String(script_info::disassemble(script.as_slice())));
+        obj.insert("type".to_string(), json::String(script_type.name().to_string()));
+        match script_type {
+          script_info::Multisig(m, _) => { obj.insert("reqSigs".to_string(), m.to_json()); }
+          script_info::NullData | script_info::NonStandard => {}
+          _ => { obj.insert("reqSigs".to_string(), 1u.to_json()); }
+        }
+        let addresses = script_type.addresses(network);
+        if addresses.len() > 0 {
+          obj.insert("addresses".to_string(), addresses.to_json());
+        }
+        match script_type {
+          script_info::PayToScriptHash(_) => {}
+          _ => {
+            let p2sh = P2shAddress::from_script(network, script.as_slice());
+            obj.insert("p2sh".to_string(), json::String(p2sh.to_base58check()));
+          }
+        }
+        Ok(json::Object(obj))
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Starts a new coinjoin session. Only one session per target amount may accept joiners at once. Durations and options not given are taken from the coinjoin configuration."]
+  #[usage="<target amount (satoshi)> [join duration (seconds)] [merge duration (seconds)] [options]"]
+  #[params=[("target", AmountParam, true, "Target output amount"),
+            ("join duration", IntParam, false, "Seconds to accept joiners for"),
+            ("merge duration", IntParam, false, "Seconds to collect signatures for"),
+            ("options", ObjectParam, false, "Session options")]]
+  #[result="session id"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_start(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) { 
+    match params.len() {
+      1 | 2 | 3 | 4 => {
+        let target: u64 = try!(decode_param(params[0].clone()));
+        let join_duration = Duration::seconds(
+          if params.len() > 1 { try!(decode_param(params[1].clone())) }
+          else { idle_state.config.coinjoin.join_duration });
+        let expiry_duration = Duration::seconds(
+          if params.len() > 2 { try!(decode_param(params[2].clone())) }
+          else { idle_state.config.coinjoin.expiry_duration });
+        let options: SessionOptions = if params.len() == 4 { try!(decode_param(params[3].clone())) }
+                                      else { Default::default() };
+
+        start_coinjoin_session(idle_state, target, join_duration, expiry_duration, options)
+          .map(|id| id.to_json())
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Gets the status of the current coinjoin session"]
+  #[usage="[session id]"]
+  #[params=[("session id", StringParam, false, "Session to describe (default the current one)")]]
+  #[result="object (session status)"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_status(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+
+    match params.len() {
+      0 => server.current_session().map_or(Err(bitcoin_json_error(SessionNotFound, None)), |s| Ok(s.to_json())),
+      1 => {
+        let id: SessionId = try!(decode_param(params[0].clone()));
+        server.session(&id).map_or(Err(bitcoin_json_error(SessionNotFound, None)), |s| Ok(s.to_json()))
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Lists open coinjoin sessions (or, if `all` is true, all which have not yet been deleted) with their states, denominations, participant counts, and the length of and time remaining in the current phase"]
+  #[usage="[all]"]
+  #[params=[("all", BoolParam, false, "Whether to include finished sessions (default false)")]]
+  #[result="list of session status objects"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_list(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let all: bool = match params.len() {
+      0 => false,
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let mut ret = TreeMap::new();
+    match idle_state.coinjoin {
+      Some(ref mut server) => {
+        server.update_all();
+        let mut sessions: Vec<&Session> = server.sessions().move_iter()
+                                                .filter(|s| all || !s.state().is_final())
+                                                .collect();
+        sessions.sort_by(|a, b| a.target_value().cmp(&b.target_value()));
+        ret.insert("current".to_string(), server.current_session_id().to_json());
+        ret.insert("sessions".to_string(),
+                   json::List(sessions.iter().map(|s| s.summary_json()).collect()));
+      }
+      None => {
+        ret.insert("current".to_string(), json::Null);
+        ret.insert("sessions".to_string(), json::List(vec![]));
+      }
+    }
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Waits until a coinjoin session reaches (or passes) a given state, or finishes, then returns its status"]
+  #[usage="<session id> <state> [timeout (seconds)]"]
+  #[params=[("session id", StringParam, true, "Session to wait on"),
+            ("state", StringParam, true, "State to wait for"),
+            ("timeout", IntParam, false, "Seconds to wait (default 300)")]]
+  #[result="object (session status)"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_wait(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 2 && params.len() != 3 {
+      return Err(usage_error(rpc));
+    }
+    let id: SessionId = try!(decode_param(params[0].clone()));
+    let state: SessionState = try!(decode_param(params[1].clone()));
+    let timeout = if params.len() == 3 { try!(decode_param(params[2].clone())) }
+                  else { COINJOIN_WAIT_TIMEOUT };
+
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    {
+      let server = idle_state.coinjoin.get_mut_ref();
+      server.update_all();
+      match server.session(&id) {
+        Some(s) => {
+          if s.state() >= state || s.state().is_final() {
+            return Ok(s.to_json());
+          }
+        }
+        None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+      }
+    }
+    // Hold on to the reply channel and answer from `notify_coinjoin_waiters`
+    let reply = idle_state.rpc_reply.take().unwrap();
+    idle_state.coinjoin_waiters.push(CoinjoinWaiter {
+      session: id,
+      state: state,
+      deadline: time::precise_time_ns() + timeout as u64 * 1000000000,
+      reply: reply
+    });
+    Ok(json::Null)
+  },
+
+  #[doc="Adds a unsigned transaction to a coinjoin session, along with a proof of ownership of its inputs; by default, to the session whose target amount matches an output"]
+  #[usage="<rawtx> <ownership proof rawtx> [session id or null] [proof of work nonce]"]
+  #[params=[("rawtx", HexParam, true, "Unsigned transaction"),
+            ("ownership proof", HexParam, true, "Transaction proving ownership of the inputs"),
+            ("session id", StringParam, false, "Session to join, or null to choose by target amount"),
+            ("nonce", IntParam, false, "Proof of work nonce")]]
+  #[result="true"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_submit(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    let source = idle_state.rpc_caller.map(|c| c.ip);
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+
+    if params.len() < 2 || params.len() > 4 {
+      return Err(usage_error(rpc));
+    }
+    let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    let proof = try!(decode_hex_param(params[1].clone(), DecodeAsIs));
+    let pow_nonce: u64 = if params.len() == 4 { try!(decode_param(params[3].clone())) } else { 0 };
+    try!(server.check_banned(&tx, source).map_err(|e| bitcoin_json_error(CoinjoinError(e), None)));
+    let session = match params.get(2) {
+      None | Some(&json::Null) => {
+        match server.route_unsigned(&tx) {
+          Some(s) => s,
+          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+        }
+      }
+      Some(param) => {
+        let id: SessionId = try!(decode_param(param.clone()));
+        match server.session_mut(&id) {
+          Some(s) => s,
+          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+        }
+      }
+    };
+    match session.add_unsigned(&tx, &proof, pow_nonce, source, &*idle_state.utxo_set.read()) {
+      Ok(()) => Ok(json::Boolean(true)),
+      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+    }
+  },
+
+  #[doc="Adds a unsigned transaction to a blinded coinjoin session, along with a proof of ownership of its inputs and a blinded target output; returns a blind signature on the output"]
+  #[usage="<rawtx> <ownership proof rawtx> <blinded output (hex)> <session id> [proof of work nonce]"]
+  #[params=[("rawtx", HexParam, true, "Unsigned transaction"),
+            ("ownership proof", HexParam, true, "Transaction proving ownership of the inputs"),
+            ("blinded output", HexParam, true, "Blinded target output"),
+            ("session id", StringParam, true, "Session to join"),
+            ("nonce", IntParam, false, "Proof of work nonce")]]
+  #[result="hex string (blind signature)"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_add_blinded(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    let source = idle_state.rpc_caller.map(|c| c.ip);
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+
+    if params.len() != 4 && params.len() != 5 {
+      return Err(usage_error(rpc));
+    }
+    let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    let proof = try!(decode_hex_param(params[1].clone(), DecodeAsIs));
+    let pow_nonce: u64 = if params.len() == 5 { try!(decode_param(params[4].clone())) } else { 0 };
+    let blinded_hex: String = try!(decode_param(params[2].clone()));
+    let blinded = match blind::from_hex(blinded_hex.as_slice()) {
+      Some(n) => n,
+      None => { return Err(standard_error(InvalidParams, Some(json::String(blinded_hex)))); }
+    };
+    let id: SessionId = try!(decode_param(params[3].clone()));
+    try!(server.check_banned(&tx, source).map_err(|e| bitcoin_json_error(CoinjoinError(e), None)));
+    let session = match server.session_mut(&id) {
+      Some(s) => s,
+      None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+    };
+    match session.add_unsigned_blinded(&tx, &proof, pow_nonce, &blinded, source,
+                                       &*idle_state.utxo_set.read()) {
+      Ok(sig) => Ok(json::String(blind::to_hex(&sig))),
+      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+    }
+  },
+
+  #[doc="Registers a target output in a blinded coinjoin session, using an unblinded signature from coinjoin_add_blinded. Should be sent over a different connection than the inputs."]
+  #[usage="<scriptpubkey (hex)> <signature (hex)> <session id>"]
+  #[params=[("scriptpubkey", HexParam, true, "Target output script"),
+            ("signature", HexParam, true, "Unblinded signature from coinjoin_add_blinded"),
+            ("session id", StringParam, true, "Session to register with")]]
+  #[result="true"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_register_output(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+
+    if params.len() != 3 {
+      return Err(usage_error(rpc));
+    }
+    let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+    let sig_hex: String = try!(decode_param(params[1].clone()));
+    let sig = match blind::from_hex(sig_hex.as_slice()) {
+      Some(n) => n,
+      None => { return Err(standard_error(InvalidParams, Some(json::String(sig_hex)))); }
+    };
+    let id: SessionId = try!(decode_param(params[2].clone()));
+    let session = match server.session_mut(&id) {
+      Some(s) => s,
+      None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+    };
+    match session.register_output(script, &sig) {
+      Ok(()) => Ok(json::Boolean(true)),
+      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+    }
+  },
+
+  #[doc="Submits a (partially-)signed transaction to a coinjoin session; by default, the one whose merged transaction it signs"]
+  #[usage="<rawtx> [session id]"]
+  #[params=[("rawtx", HexParam, true, "(Partially) signed transaction"),
+            ("session id", StringParam, false, "Session to sign for (default the one whose transaction it signs)")]]
+  #[result="true"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_sign(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    if params.len() != 1 && params.len() != 2 {
+      return Err(usage_error(rpc));
+    }
+    let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+
+    // Scope here so that we are done with the session before submitting
+    let (ret, complete_tx) = {
+      // Update the server state
+      let server = idle_state.coinjoin.get_mut_ref();
+      server.update_all();
+
+      let session = match params.len() {
+        1 => {
+          match server.route_signed(&tx) {
+            Some(s) => s,
+            None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+          }
+        }
+        _ => {
+          let id: SessionId = try!(decode_param(params[1].clone()));
+          match server.session_mut(&id) {
+            Some(s) => s,
+            None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
+          }
+        }
+      };
+
+      // Add the signed transaction
+      let ret = match session.add_signed(&tx, &*idle_state.utxo_set.read()) {
+        Ok(()) => Ok(json::Boolean(true)),
+        Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+      };
+      if session.state() == Complete {
+        (ret, Some(session.signed_transaction().unwrap().clone()))
+      } else {
+        (ret, None)
+      }
+    };
+    // If that was the last one, submit it
+    match complete_tx {
+      Some(complete_tx) => broadcast_transaction(idle_state, complete_tx, "Coinjoin"),
+      None => {}
+    }
+    ret
+  },
+
+  #[doc="Deprecated alias of coinjoin_submit"]
+  #[usage="<rawtx> <ownership proof rawtx> [session id or null] [proof of work nonce]"]
+  #[params=[("rawtx", HexParam, true, "Unsigned transaction"),
+            ("ownership proof", HexParam, true, "Transaction proving ownership of the inputs"),
+            ("session id", StringParam, false, "Session to join, or null to choose by target amount"),
+            ("nonce", IntParam, false, "Proof of work nonce")]]
+  #[result="true"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_add_raw_unsigned(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    coinjoin_submit(rpc, idle_state, params)
+  },
+
+  #[doc="Deprecated alias of coinjoin_sign"]
+  #[usage="<rawtx> [session id]"]
+  #[params=[("rawtx", HexParam, true, "(Partially) signed transaction"),
+            ("session id", StringParam, false, "Session to sign for (default the one whose transaction it signs)")]]
+  #[result="true"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_add_raw_signed(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    coinjoin_sign(rpc, idle_state, params)
+  },
+
+  #[doc="Lists inputs, and the addresses which submitted them, banned from coinjoin sessions for failing to sign"]
+  #[usage=""]
+  #[params=[]]
+  #[result="list of banned inputs {txid, vout, time_until_unban} and addresses {address, time_until_unban}"]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[runs_on=IdleLoop]
+  pub fn coinjoin_listbans(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if params.len() != 0 {
+      return Err(usage_error(rpc));
+    }
+    match idle_state.coinjoin {
+      Some(ref mut server) => {
+        server.update_all();
+        Ok(server.bans())
+      }
+      None => Ok(json::List(vec![]))
+    }
+  },
+
+  #[doc="Splits wallet funds into coinjoin denominations (by default, the configured ones), for joining one output at a time: sends the given amount, split into denominations, to new wallet addresses. Whatever is left over below the smallest denomination stays in the change output."]
+  #[usage="<amount (satoshi)> [denominations]"]
+  #[params=[("amount", AmountParam, true, "Amount to split"),
+            ("denominations", ListParam, false, "Denominations in satoshi (default the configured ones)")]]
+  #[result="object {txid, outputs, change}"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=IdleLoop]
+  pub fn splitdenominations(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let (amount, denominations): (u64, Vec<u64>) = match params.len() {
+      1 => (try!(decode_param(params[0].clone())), idle_state.config.coinjoin.denominations.clone()),
+      2 => (try!(decode_param(params[0].clone())), try!(decode_param(params[1].clone()))),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    if denominations.iter().all(|&d| d == 0) {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String("no denominations given or configured".to_string()))));
+    }
+    // Refuse to produce absurd numbers of outputs, e.g. for tiny denominations
+    let (values, _) = match split_denominations(amount, denominations.as_slice(),
+                                                MAX_DENOMINATION_SPLIT) {
+      Some(split) => split,
+      None => {
+        return Err(standard_error(InvalidParams,
+                                  Some(json::String(format!("amount would split into more than {} outputs",
+                                                            MAX_DENOMINATION_SPLIT)))));
+      }
+    };
+    if values.is_empty() {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String("amount is less than every denomination".to_string()))));
+    }
+    let split_total = values.iter().fold(0, |sum, &v| sum + v);
+
+    let (tx, txid, change) = {
+      let fee_estimator = idle_state.fee_estimator.read();
+      let wallet = idle_state.wallets[idle_state.active_wallet].clone();
+      let mut w = wallet.lock();
+      let fee_policy = w.fee_policy(&idle_state.config);
+      // Take inputs, largest first, until they cover the split and the fee
+      // for a transaction with them, the split outputs and a change output
+      let base_size = 10 + (values.len() as u64 + 1) * EST_OUTPUT_SIZE;
+      let mut inputs = vec![];
+      let mut total_in = 0;
+      let mut fee = 0;
+      for (outpoint, txo) in spendable_outputs(&w.wallet, &w.meta).move_iter() {
+        total_in += txo.value;
+        inputs.push((outpoint, txo));
+        fee = fee_policy.fee_for_size(&*fee_estimator,
+                                      base_size + inputs.len() as u64 * EST_INPUT_SIZE);
+        if total_in >= split_total + fee {
+          break;
+        }
+      }
+      if inputs.is_empty() || total_in < split_total + fee {
+        return Err(bitcoin_json_error(InsufficientFunds, Some(total_in.to_json())));
+      }
+
+      let mut tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: inputs.iter().map(|&(ref outpoint, _)| TxIn {
+          prev_hash: outpoint.txid,
+          prev_index: outpoint.vout,
+          script_sig: Script::new(),
+          sequence: 0xffffffff
+        }).collect(),
+        output: vec![]
+      };
+      // Each denomination goes to its own address, so that the outputs
+      // cannot be linked to each other once joined
+      for &value in values.iter() {
+        let (address, _) = try!(w.new_address("default", true)
+                                  .map_err(|e| bitcoin_json_error(WalletError,
+                                                                  Some(json::String(e.to_string())))));
+        tx.output.push(TxOut { value: value, script_pubkey: address.script_pubkey() });
+      }
+      // Change too small to be worth an output goes to the fee
+      let mut change = total_in - split_total - fee;
+      let change_vout = if change >= DUST_THRESHOLD {
+        let (address, _) = try!(w.new_address("default", true)
+                                  .map_err(|e| bitcoin_json_error(WalletError,
+                                                                  Some(json::String(e.to_string())))));
+        tx.output.push(TxOut { value: change, script_pubkey: address.script_pubkey() });
+        Some(values.len() as u32)
+      } else {
+        fee += change;
+        change = 0;
+        None
+      };
+      try!(save_wallet(&idle_state.config, &w.config, &w.wallet)
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+
+      let prevouts: Vec<TxOut> = inputs.move_iter().map(|(_, txo)| txo).collect();
+      try!(sign_transaction(&w.wallet, &mut tx, prevouts.as_slice())
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+      let txid = tx.bitcoin_hash();
+      let our_vouts = range(0, tx.output.len() as u32).collect();
+      w.meta.add_transaction(WalletTx::new(&tx, Some(fee), change_vout, our_vouts));
+      try!(w.save_metadata()
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+      (tx, txid, change)
+    };
+    broadcast_transaction(idle_state, tx, "splitdenominations");
+
+    let mut ret = TreeMap::new();
+    ret.insert("txid".to_string(), txid.to_json());
+    ret.insert("outputs".to_string(), values.to_json());
+    ret.insert("change".to_string(), change.to_json());
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Gets the wallet balance, broken down into unconfirmed, confirmed and safely-confirmed amounts"]
+  #[usage=""]
+  #[params=[]]
+  #[result="object {unconfirmed, confirmed, safe}"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn getbalances(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let (tip_height, median_time) = best_height_and_time(&*shared.blockchain.read());
+    let w = shared.wallets[shared.active_wallet].lock();
+    match params.len() {
+      0 => {
+        Ok(balances(&w.wallet, &w.meta, tip_height, median_time).to_json())
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Gets a new receiving address from the given account (default \"default\"). Warns if the address has already received funds; if `refuse_address_reuse` is configured, such addresses are skipped."]
+  #[usage="[account]"]
+  #[params=[("account", StringParam, false, "Account to take the address from (default \"default\")")]]
+  #[result="object {address, reused, warning}"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn getnewaddress(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let mut w = shared.wallets[shared.active_wallet].lock();
+    let account: String = match params.len() {
+      0 => "default".to_string(),
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let (address, reused) = try!(w.new_address(account.as_slice(),
+                                               shared.config.refuse_address_reuse)
+                                   .map_err(|e| bitcoin_json_error(WalletError,
+                                                                   Some(json::String(e.to_string())))));
+    // Save before handing out the address
+    try!(save_wallet(&shared.config, &w.config, &w.wallet)
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+
+    let mut ret = TreeMap::new();
+    ret.insert("address".to_string(), json::String(address.to_base58check()));
+    ret.insert("reused".to_string(), json::Boolean(reused));
+    if reused {
+      debug!(shared, Warning, "Wallet `{}`: handing out address {} which has already received funds.",
+             w.config.name, address.to_base58check());
+      ret.insert("warning".to_string(),
+                 json::String("This address has already received funds; reusing it harms privacy.".to_string()));
+    }
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Creates a time-locked savings address whose funds cannot be spent until the given block height (or Unix time, if at least 500000000)"]
+  #[usage="<locktime> [account]"]
+  #[params=[("locktime", IntParam, true, "Block height, or Unix time if at least 500000000"),
+            ("account", StringParam, false, "Account to pay to (default \"default\")")]]
+  #[result="object (timelock)"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn createtimelock(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let (tip_height, median_time) = best_height_and_time(&*shared.blockchain.read());
+    let mut w = shared.wallets[shared.active_wallet].lock();
+    let (locktime, account): (u32, String) = match params.len() {
+      1 => (try!(decode_param(params[0].clone())), "default".to_string()),
+      2 => (try!(decode_param(params[0].clone())), try!(decode_param(params[1].clone()))),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let (address, _) = try!(w.new_address(account.as_slice(), true)
+                              .map_err(|e| bitcoin_json_error(WalletError,
+                                                              Some(json::String(e.to_string())))));
+    try!(save_wallet(&shared.config, &w.config, &w.wallet)
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+
+    let timelock = Timelock::new(locktime, account, &address);
+    let ret = timelock.to_json(shared.config.network, tip_height, median_time);
+    w.meta.add_timelock(timelock);
+    try!(w.save_metadata()
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+    Ok(ret)
+  },
+
+  #[doc="Lists the wallet's time-locked savings addresses"]
+  #[usage=""]
+  #[params=[]]
+  #[result="list of timelock objects"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn listtimelocks(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let (tip_height, median_time) = best_height_and_time(&*shared.blockchain.read());
+    let w = shared.wallets[shared.active_wallet].lock();
+    match params.len() {
+      0 => {
+        Ok(json::List(w.meta.timelocks().iter()
+                        .map(|t| t.to_json(shared.config.network, tip_height, median_time))
+                        .collect()))
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Spends all the funds of an expired time-locked savings address (identified by its index in listtimelocks) to a new address in its account"]
+  #[usage="<index>"]
+  #[params=[("index", IntParam, true, "Index of the timelock in listtimelocks")]]
+  #[result="txid"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=IdleLoop]
+  pub fn spendtimelock(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let (tip_height, median_time) = best_height_and_time(&*idle_state.blockchain.read());
+    let wallet = idle_state.wallets[idle_state.active_wallet].clone();
+    let mut w = wallet.lock();
+    let idx: uint = match params.len() {
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let timelock = match w.meta.timelocks().get(idx) {
+      Some(t) => t.clone(),
+      None => { return Err(standard_error(InvalidParams, Some(idx.to_json()))); }
+    };
+    if !timelock.is_expired(tip_height, median_time) {
+      return Err(bitcoin_json_error(TimelockNotExpired, Some(timelock.locktime.to_json())));
+    }
+    // The outputs stay in the timelock until the spend confirms
+    let pending_spend = w.meta.transactions().iter().filter(|wtx| wtx.is_pending())
+                         .any(|wtx| match wtx.transaction() {
+                           Ok(tx) => timelock.is_spent_by(&tx),
+                           Err(_) => false
+                         });
+    if pending_spend {
+      return Err(bitcoin_json_error(InvalidTx, Some(json::String(
+        "timelock is already being spent by a pending transaction".to_string()))));
+    }
+    let fee = w.fee_policy(&idle_state.config).fee_for_size(&*idle_state.fee_estimator.read(),
+                                                            timelock.spend_size());
+    if timelock.balance() < fee + DUST_THRESHOLD {
+      return Err(bitcoin_json_error(InsufficientFunds, Some(timelock.balance().to_json())));
+    }
+
+    let (address, _) = try!(w.new_address(timelock.account.as_slice(), true)
+                              .map_err(|e| bitcoin_json_error(WalletError,
+                                                              Some(json::String(e.to_string())))));
+    try!(save_wallet(&idle_state.config, &w.config, &w.wallet)
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+    let tx = try!(timelock.spending_transaction(&w.wallet, address.script_pubkey(), fee)
+                    .map_err(|e| bitcoin_json_error(WalletError,
+                                                    Some(json::String(e.to_string())))));
+    let txid = tx.bitcoin_hash();
+
+    w.meta.add_transaction(WalletTx::new(&tx, Some(fee), Some(0), vec![0]));
+    try!(w.save_metadata()
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+    // Broadcasting takes the UTXO set lock, which must not be taken after a
+    // wallet's lock, so release the wallet first
+    drop(w);
+    broadcast_transaction(idle_state, tx, "spendtimelock");
+    Ok(txid.to_json())
+  },
+
+  #[doc="Sets the wallet's fee policy: a rate in satoshi per 1000 bytes, \"economic\" or \"fast\". With no argument, reverts to the network default. Returns the policy in effect."]
+  #[usage="[rate | \"economic\" | \"fast\"]"]
+  #[params=[("policy", AnyParam, false, "Rate in satoshi per 1000 bytes, \"economic\" or \"fast\" (default the network default)")]]
+  #[result="the fee policy in effect"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn settxfee(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let mut w = shared.wallets[shared.active_wallet].lock();
+    let policy = match params.len() {
+      0 => None,
+      1 => match params[0] {
+        json::U64(rate) => Some(FixedRate(rate)),
+        _ => Some(try!(decode_param(params[0].clone())))
+      },
+      _ => { return Err(usage_error(rpc)); }
+    };
+    w.meta.set_fee_policy(policy);
+    try!(w.save_metadata()
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+    Ok(w.fee_policy(&shared.config).to_json())
+  },
+
+  #[doc="Lists wallet transactions, newest first. Unconfirmed transactions have 0 confirmations."]
+  #[usage="[count]"]
+  #[params=[("count", IntParam, false, "Number of transactions (default 10)")]]
+  #[result="list of transaction objects"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn listtransactions(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let count: uint = match params.len() {
+      0 => 10,
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let tip_height = best_height(&*shared.blockchain.read());
+    let w = shared.wallets[shared.active_wallet].lock();
+    let ret = w.meta.transactions().iter().rev().take(count).map(|wtx| {
+      let mut obj = match wtx.to_json() {
+        json::Object(obj) => obj,
+        _ => unreachable!()
+      };
+      obj.insert("confirmations".to_string(), wtx.confirmations(tip_height).to_json());
+      json::Object(obj)
+    }).collect();
+    Ok(json::List(ret))
+  },
+
+  #[doc="Lists alerts about double-spends of wallet transactions and outputs, oldest first. If <clear> is true, forgets them afterward."]
+  #[usage="[clear]"]
+  #[params=[("clear", BoolParam, false, "Whether to forget the alerts afterward (default false)")]]
+  #[result="list of alerts"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn listalerts(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let mut w = shared.wallets[shared.active_wallet].lock();
+    let clear: bool = match params.len() {
+      0 => false,
+      1 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let ret = json::List(w.meta.alerts().iter().map(|a| a.to_json()).collect());
+    if clear {
+      w.meta.clear_alerts();
+      try!(w.save_metadata()
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+    }
+    Ok(ret)
+  },
+
+  #[doc="Locks (or with <unlock> true, unlocks) wallet outputs, excluding them from automatic coin selection. Outputs to lock must be unspent outputs of the wallet; if any is not, none are locked. Unlocking with no outputs given unlocks everything."]
+  #[usage="<unlock> [[{\"txid\": <txid>, \"vout\": <n>}, ...]]"]
+  #[params=[("unlock", BoolParam, true, "Whether to unlock rather than lock"),
+            ("outputs", ListParam, false, "List of {\"txid\", \"vout\"} objects")]]
+  #[result="true"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn lockunspent(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let mut w = shared.wallets[shared.active_wallet].lock();
+    let unlock: bool = match params.len() {
+      1 | 2 => try!(decode_param(params[0].clone())),
+      _ => { return Err(usage_error(rpc)); }
+    };
+
+    if params.len() == 1 {
+      if !unlock {
+        return Err(usage_error(rpc));
+      }
+      w.meta.unlock_all();
+    } else {
+      let outputs = try!(decode_outpoints_param(params[1].clone()));
+      // Check every output before locking any, so a bad one changes nothing
+      if !unlock {
+        let owned = owned_outpoints(&w.wallet);
+        match outputs.iter().find(|out| !owned.contains(*out)) {
+          Some(out) => { return Err(bitcoin_json_error(OutputNotFound, Some(out.to_json()))); }
+          None => {}
+        }
+      }
+      for out in outputs.move_iter() {
+        if unlock {
+          w.meta.unlock_output(&out);
+        } else {
+          w.meta.lock_output(out);
+        }
+      }
+    }
+
+    try!(w.save_metadata()
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+    Ok(json::Boolean(true))
+  },
+
+  #[doc="Lists all wallet outputs which are locked against automatic coin selection"]
+  #[usage=""]
+  #[params=[]]
+  #[result="list of {txid, vout} objects"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn listlockunspent(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let w = shared.wallets[shared.active_wallet].lock();
+    match params.len() {
+      0 => Ok(json::List(w.meta.locked_outputs().iter()
+                                                 .map(|o| o.to_json()).collect())),
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Rebroadcasts an unconfirmed wallet transaction with a higher fee, by replacement if it signals RBF and otherwise by spending its change output. The fee defaults to the original plus the wallet's fee rate applied to the transaction's size."]
+  #[usage="<txid> [new total fee (satoshi)]"]
+  #[params=[("txid", HashParam, true, "Unconfirmed wallet transaction"),
+            ("fee", AmountParam, false, "New total fee")]]
+  #[result="txid of the replacement or child transaction"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=IdleLoop]
+  pub fn bumpfee(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let wallet = idle_state.wallets[idle_state.active_wallet].clone();
+    let mut w = wallet.lock();
+    if params.len() != 1 && params.len() != 2 {
+      return Err(usage_error(rpc));
+    }
+    let txid: Sha256dHash = try!(decode_hash_param(params[0].clone()));
+    let pending = match w.meta.find_pending(txid) {
+      Some(p) => p.clone(),
+      None => { return Err(bitcoin_json_error(TxNotFound, Some(txid.to_json()))); }
+    };
+    let old_fee = match pending.fee {
+      Some(fee) => fee,
+      None => { return Err(bitcoin_json_error(CannotBumpFee,
+                                              Some(json::String("unknown fee".to_string())))); }
+    };
+    let parent = try!(pending.transaction()
+                        .map_err(|e| bitcoin_json_error(WalletError,
+                                                        Some(json::String(e.to_string())))));
+    let new_fee: u64 = if params.len() == 2 {
+      try!(decode_param(params[1].clone()))
+    } else {
+      let size = serialize(&parent).unwrap().len() as u64;
+      let fee_estimator = idle_state.fee_estimator.read();
+      old_fee + w.fee_policy(&idle_state.config).fee_for_size(&*fee_estimator, size)
+    };
+    if new_fee <= old_fee {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String(format!("new fee must exceed old fee {}",
+                                                          old_fee)))));
+    }
+    let delta = new_fee - old_fee;
+    let change_vout = match pending.change_vout {
+      Some(n) => n as uint,
+      None => { return Err(bitcoin_json_error(CannotBumpFee,
+                                              Some(json::String("no change output".to_string())))); }
+    };
+    let change_value = parent.output[change_vout].value;
+    if change_value < delta + DUST_THRESHOLD {
+      return Err(bitcoin_json_error(CannotBumpFee,
+                                    Some(json::String("change output too small".to_string()))));
+    }
+
+    let signals_rbf = parent.input.iter().any(|i| i.sequence < 0xfffffffe);
+    let (mut tx, prevouts, new_change_vout) = if signals_rbf {
+      // Replace the transaction outright, taking the extra fee from change
+      let mut prevouts = Vec::with_capacity(parent.input.len());
+      {
+        let utxo_set = idle_state.utxo_set.read();
+        for input in parent.input.iter() {
+          match utxo_set.get_utxo(input.prev_hash, input.prev_index) {
+            Some((_, out)) => prevouts.push(out.clone()),
+            None => {
+              return Err(bitcoin_json_error(OutputNotFound,
+                                            Some(OutPoint { txid: input.prev_hash,
+                                                            vout: input.prev_index }.to_json())));
+            }
+          }
+        }
+      }
+      let mut tx = parent.clone();
+      for input in tx.input.mut_iter() {
+        input.script_sig = Default::default();
+      }
+      tx.output.get_mut(change_vout).value -= delta;
+      (tx, prevouts, Some(change_vout as u32))
+    } else {
+      // Child-pays-for-parent: spend our change back to ourselves, paying
+      // the extra fee. We reuse the change script so that we need not know
+      // which account the change belongs to.
+      let prevout = parent.output[change_vout].clone();
+      let tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+          prev_hash: txid,
+          prev_index: change_vout as u32,
+          script_sig: Default::default(),
+          sequence: 0xffffffff
+        }],
+        output: vec![TxOut {
+          value: change_value - delta,
+          script_pubkey: prevout.script_pubkey.clone()
+        }]
+      };
+      (tx, vec![prevout], Some(0))
+    };
+
+    try!(sign_transaction(&w.wallet, &mut tx, prevouts.as_slice())
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+    let new_txid = tx.bitcoin_hash();
+
+    if signals_rbf {
+      let our_vouts = pending.our_vouts.clone();
+      w.meta.remove_transaction(txid);
+      w.meta.add_transaction(WalletTx::new(&tx, Some(new_fee), new_change_vout,
+                                                           our_vouts));
+    } else {
+      w.meta.add_transaction(WalletTx::new(&tx, Some(delta), new_change_vout,
+                                                           vec![0]));
+    }
+    try!(w.save_metadata()
+           .map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+
+    // Broadcasting takes the UTXO set lock, which must not be taken after a
+    // wallet's lock, so release the wallet first
+    drop(w);
+    broadcast_transaction(idle_state, tx, "bumpfee");
+    Ok(new_txid.to_json())
+  },
+
+  #[doc="Sends a transaction with an OP_RETURN output carrying the given data, of at most 40 bytes, funded and signed by the wallet. Any amount given to the output is burned."]
+  #[usage="<hex-encoded data> [amount]"]
+  #[params=[("data", HexParam, true, "Hex-encoded data to embed"),
+            ("amount", AmountParam, false, "Satoshi to burn in the output (default 0)")]]
+  #[result="txid"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=IdleLoop]
+  pub fn senddata(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let (hex, amount): (String, u64) = match params.len() {
+      1 => (try!(decode_param(params[0].clone())), 0),
+      2 => (try!(decode_param(params[0].clone())), try!(decode_param(params[1].clone()))),
+      _ => { return Err(usage_error(rpc)); }
+    };
+    let data = try!(hex.as_slice().from_hex()
+                      .map_err(|e| standard_error(InvalidParams,
+                                                  Some(json::String(e.to_string())))));
+    if data.len() > MAX_NULL_DATA_SIZE {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String(format!("data must be at most {} bytes",
+                                                          MAX_NULL_DATA_SIZE)))));
+    }
+    let data_output = TxOut {
+      value: amount,
+      script_pubkey: script_info::null_data_script(data.as_slice())
+    };
+
+    let (tx, txid) = {
+      let fee_estimator = idle_state.fee_estimator.read();
+      let wallet = idle_state.wallets[idle_state.active_wallet].clone();
+      let mut w = wallet.lock();
+      let fee_policy = w.fee_policy(&idle_state.config);
+      // Take inputs, largest first, until they cover the amount and the fee
+      // for a transaction with them, the data and a change output
+      let base_size = 10 + EST_OUTPUT_SIZE + serialize(&data_output).unwrap().len() as u64;
+      let mut inputs = vec![];
+      let mut total_in = 0;
+      let mut fee = 0;
+      for (outpoint, txo) in spendable_outputs(&w.wallet, &w.meta).move_iter() {
+        total_in += txo.value;
+        inputs.push((outpoint, txo));
+        fee = fee_policy.fee_for_size(&*fee_estimator,
+                                      base_size + inputs.len() as u64 * EST_INPUT_SIZE);
+        if total_in >= amount + fee {
+          break;
+        }
+      }
+      if inputs.is_empty() || total_in < amount + fee {
+        return Err(bitcoin_json_error(InsufficientFunds, Some(total_in.to_json())));
+      }
+
+      let mut tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: inputs.iter().map(|&(ref outpoint, _)| TxIn {
+          prev_hash: outpoint.txid,
+          prev_index: outpoint.vout,
+          script_sig: Script::new(),
+          sequence: 0xffffffff
+        }).collect(),
+        output: vec![data_output]
+      };
+      // Change too small to be worth an output goes to the fee
+      let change = total_in - amount - fee;
+      let change_vout = if change >= DUST_THRESHOLD {
+        let (address, _) = try!(w.new_address("default", true)
+                                  .map_err(|e| bitcoin_json_error(WalletError,
+                                                                  Some(json::String(e.to_string())))));
+        try!(save_wallet(&idle_state.config, &w.config, &w.wallet)
+               .map_err(|e| bitcoin_json_error(WalletError,
+                                               Some(json::String(e.to_string())))));
+        tx.output.push(TxOut { value: change, script_pubkey: address.script_pubkey() });
+        Some(1u32)
+      } else {
+        fee += change;
+        None
+      };
+
+      let prevouts: Vec<TxOut> = inputs.move_iter().map(|(_, txo)| txo).collect();
+      try!(sign_transaction(&w.wallet, &mut tx, prevouts.as_slice())
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+      let txid = tx.bitcoin_hash();
+      let our_vouts = match change_vout { Some(n) => vec![n], None => vec![] };
+      w.meta.add_transaction(WalletTx::new(&tx, Some(fee), change_vout, our_vouts));
+      try!(w.save_metadata()
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+      (tx, txid)
+    };
+    broadcast_transaction(idle_state, tx, "senddata");
+    Ok(txid.to_json())
+  },
+
+  #[doc="Writes a copy of the wallet to the given path, and its metadata to the same path with `.meta` appended"]
+  #[usage="<path>"]
+  #[params=[("path", StringParam, true, "Where to write the backup")]]
+  #[result="true"]
+  #[coinjoin=false]
+  #[wallet=true]
+  #[runs_on=Worker]
+  pub fn backupwallet(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    let w = shared.wallets[shared.active_wallet].lock();
+    match params.len() {
+      1 => {
+        let path: String = try!(decode_param(params[0].clone()));
+        let path = match Path::new_opt(path.as_slice()) {
+          Some(p) => p,
+          None => { return Err(standard_error(InvalidParams, Some(json::String(path)))); }
+        };
+        try!(backup_wallet(&w.wallet, &w.meta, &path)
+               .map_err(|e| bitcoin_json_error(WalletError,
+                                               Some(json::String(e.to_string())))));
+        Ok(json::Boolean(true))
+      }
+      _ => Err(usage_error(rpc))
+    }
+  },
+
+  #[doc="Fetches a BIP70 payment request from an https:// URL and describes it, after checking its signature, that it is for this network and that it has not expired. A signed request's certificate chain must lead to a root the system trusts; its signer is given as `merchant`, which is null for unsigned requests."]
+  #[usage="<url>"]
+  #[params=[("url", StringParam, true, "URL of the payment request")]]
+  #[result="object {network, pki_type, merchant, amount, outputs, time, expires, memo, payment_url}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn fetchpaymentrequest(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 1 {
+      return Err(usage_error(rpc));
+    }
+    let url: String = try!(decode_param(params[0].clone()));
+    let request = try!(fetch_payment_request(url.as_slice(), shared.config.network));
+    Ok(request.summary_json(shared.config.network))
+  },
+
+  #[doc="Pays a BIP70 payment request: fetches it again, checks that the given signed transaction pays every output it asks for, broadcasts the transaction, and sends the merchant a Payment message if the request has a payment URL. A failure to reach the merchant after broadcasting is reported in `ack_error` rather than as an error."]
+  #[usage="<url> <hex-encoded tx data> [memo]"]
+  #[params=[("url", StringParam, true, "URL of the payment request"),
+            ("tx", HexParam, true, "Hex-encoded signed transaction paying the request"),
+            ("memo", StringParam, false, "Note to the merchant")]]
+  #[result="object {txid, ack_memo, ack_error}"]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[runs_on=Worker]
+  pub fn sendpayment(rpc: &RpcCall, shared: &SharedState, params: Vec<json::Json>) {
+    if params.len() != 2 && params.len() != 3 {
+      return Err(usage_error(rpc));
+    }
+    let url: String = try!(decode_param(params[0].clone()));
+    let tx: Transaction = try!(decode_hex_param(params[1].clone(), DecodeAsIs));
+    let memo: Option<String> = match params.get(2) {
+      Some(p) => Some(try!(decode_param(p.clone()))),
+      None => None
+    };
+    let request = try!(fetch_payment_request(url.as_slice(), shared.config.network));
+    if !request.paid_by(&tx) {
+      return Err(bitcoin_json_error(InvalidTx, Some(json::String(
+        "transaction does not pay every output of the request".to_string()))));
+    }
+
+    let txid = tx.bitcoin_hash();
+    let payment = payment_request::encode_payment(&request, &[tx.clone()], None,
+                                                  memo.as_ref().map(|m| m.as_slice()));
+    shared.relay_tx.send((tx, "sendpayment"));
+
+    let mut ret = TreeMap::new();
+    ret.insert("txid".to_string(), txid.to_json());
+    match request.details.payment_url {
+      Some(ref payment_url) => {
+        match payment_request::send_payment(payment_url.as_slice(), payment.as_slice()) {
+          Ok(ack_memo) => { ret.insert("ack_memo".to_string(), ack_memo.to_json()); }
+          Err(e) => {
+            debug!((shared.config.network, shared.config.debug_level), Warning,
+                   "sendpayment: broadcast {:x}, but could not send payment to {}: {}",
+                   txid, payment_url, e);
+            ret.insert("ack_error".to_string(), json::String(e.to_string()));
+          }
+        }
+      }
+      None => {}
+    }
+    Ok(json::Object(ret))
+  }
+}
+
+/// Looks up the script of an address parameter, along with the address
+/// index to find it in
+fn indexed_address(shared: &SharedState, param: json::Json)
+                  -> jsonrpc::JsonResult<(Arc<RWLock<AddressIndex>>, Script)> {
+  let index = match shared.address_index {
+    Some(ref index) => index.clone(),
+    None => { return Err(bitcoin_json_error(AddressIndexDisabled, None)); }
+  };
+  let address: String = try!(decode_param(param));
+  match script_info::address_script(shared.config.network, address.as_slice()) {
+    Some(script) => Ok((index, script)),
+    None => Err(bitcoin_json_error(InvalidAddressOrKey, Some(json::String(address))))
+  }
+}
+
+/// Fetches a payment request and checks that it can be paid on `network`
+fn fetch_payment_request(url: &str, network: Network)
+                        -> jsonrpc::JsonResult<payment_request::PaymentRequest> {
+  let request = try!(payment_request::fetch(url)
+                       .map_err(|e| bitcoin_json_error(PaymentRequestError,
+                                                       Some(json::String(e.to_string())))));
+  try!(request.validate(network, time::get_time().sec)
+         .map_err(|e| bitcoin_json_error(PaymentRequestError, Some(json::String(e)))));
+  Ok(request)
+}
+
+enum BitcoinJsonError {
+  BadRng,
   BlockNotFound,
   CoinjoinError(CoinjoinError),
   InvalidTx,
   SessionNotFound,
-  WalletError
+  WalletError,
+  OutputNotFound,
+  TxNotFound,
+  CannotBumpFee,
+  WalletNotFound,
+  TimelockNotExpired,
+  InsufficientFunds,
+  TxNotIndexed,
+  InvalidAddressOrKey,
+  PaymentRequestError,
+  AddressIndexDisabled
+}
+
+/// An output given as an RPC parameter, before its txid is parsed
+#[deriving(Decodable)]
+struct RawOutPoint {
+  txid: String,
+  vout: u32
+}
+
+/// A previous output given to `signrawtransaction`
+#[deriving(Decodable)]
+struct RawPrevout {
+  txid: String,
+  vout: u32,
+  script_pubkey: String
+}
+
+/// A `coinjoin_wait` call awaiting a session state change
+pub struct CoinjoinWaiter {
+  session: SessionId,
+  state: SessionState,
+  // Time, in ns, at which to give up and return the current status
+  deadline: u64,
+  reply: Sender<JsonResult>
+}
+
+/// A `waitfornewblock` or `waitforblockheight` call awaiting a new tip
+pub struct BlockWaiter {
+  // Tip when the call was made
+  start_tip: Sha256dHash,
+  // Height to wait for, or None to wait for any change of tip
+  height: Option<uint>,
+  // Time, in ns, at which to give up and return the current tip
+  deadline: u64,
+  reply: Sender<JsonResult>
+}
+
+/// Returns the hash and height of the best chain tip
+fn chain_tip(idle_state: &IdleState) -> (Sha256dHash, uint) {
+  let blockchain = idle_state.blockchain.read();
+  let hash = blockchain.best_tip_hash();
+  (hash, blockchain.get_block(hash).unwrap().height)
+}
+
+/// Describes a chain tip for the block waiting calls
+fn tip_json(hash: Sha256dHash, height: uint) -> json::Json {
+  let mut ret = TreeMap::new();
+  ret.insert("hash".to_string(), hash.to_json());
+  ret.insert("height".to_string(), height.to_json());
+  json::Object(ret)
+}
+
+/// Answers any block waiting calls whose conditions are met, or which
+/// have timed out
+pub fn notify_block_waiters(idle_state: &mut IdleState) {
+  if idle_state.block_waiters.is_empty() {
+    return;
+  }
+  let (hash, height) = chain_tip(idle_state);
+  let now = time::precise_time_ns();
+
+  let waiters = mem::replace(&mut idle_state.block_waiters, vec![]);
+  for waiter in waiters.move_iter() {
+    let done = match waiter.height {
+      Some(target) => height >= target,
+      None => hash != waiter.start_tip
+    };
+    if done || now > waiter.deadline {
+      // The client may have hung up, which is fine
+      let _ = waiter.reply.send_opt(Ok(tip_json(hash, height)));
+    } else {
+      idle_state.block_waiters.push(waiter);
+    }
+  }
+}
+
+/// Answers any `coinjoin_wait` calls whose sessions have reached the
+/// awaited state, or which have timed out
+pub fn notify_coinjoin_waiters(idle_state: &mut IdleState) {
+  if idle_state.coinjoin_waiters.is_empty() {
+    return;
+  }
+  let server = match idle_state.coinjoin {
+    Some(ref mut server) => server,
+    None => { return; }
+  };
+  server.update_all();
+  let now = time::precise_time_ns();
+
+  let waiters = mem::replace(&mut idle_state.coinjoin_waiters, vec![]);
+  for waiter in waiters.move_iter() {
+    let response = match server.session(&waiter.session) {
+      Some(s) => {
+        if s.state() >= waiter.state || s.state().is_final() || now > waiter.deadline {
+          Some(Ok(s.to_json()))
+        } else {
+          None
+        }
+      }
+      None => Some(Err(bitcoin_json_error(SessionNotFound, None)))
+    };
+    match response {
+      // The client may have hung up, which is fine
+      Some(response) => { let _ = waiter.reply.send_opt(response); }
+      None => { idle_state.coinjoin_waiters.push(waiter); }
+    }
+  }
+}
+
+/// Starts a new coinjoin session, paying donations to the active wallet
+pub fn start_coinjoin_session(idle_state: &mut IdleState, target: u64,
+                              join_duration: Duration, expiry_duration: Duration,
+                              options: SessionOptions) -> jsonrpc::JsonResult<SessionId> {
+  let denominations = &idle_state.config.coinjoin.denominations;
+  if !denominations.is_empty() && !denominations.contains(&target) {
+    return Err(bitcoin_json_error(CoinjoinError(NonStandardDenomination(target)), None));
+  }
+  // Start session manager if we haven't
+  if idle_state.coinjoin.is_none() {
+    idle_state.coinjoin = Some(Server::new());
+  }
+  // Update the server state
+  let server = idle_state.coinjoin.get_mut_ref();
+  server.update_all();
+  if server.joining_session(target).is_some() {
+    return Err(bitcoin_json_error(CoinjoinError(DenominationInUse(target)), None));
+  }
+  let options = options.or(&idle_state.config.coinjoin.options);
+  try!(options.validate().map_err(|e| standard_error(InvalidParams, Some(json::String(e)))));
+  let blind_key = if options.blinded() {
+    match server.take_blind_key() {
+      Some(key) => Some(key),
+      None => { return Err(bitcoin_json_error(CoinjoinError(BlindKeyPending), None)); }
+    }
+  } else {
+    None
+  };
+  let mut w = idle_state.wallets[idle_state.active_wallet].lock();
+  let fee_policy = match idle_state.config.coinjoin.fee_policy {
+    Some(ref policy) => policy.clone(),
+    None => w.fee_policy(&idle_state.config)
+  };
+  let fee_rate = fee_policy.estimated_rate(&*idle_state.fee_estimator.read());
+
+  // Obtain a donation address, unless one is configured
+  let address = match idle_state.config.coinjoin.donation_address {
+    Some(ref address) => address.clone(),
+    None => {
+      let refuse_reuse = idle_state.config.refuse_address_reuse;
+      let mut address = w.new_address("coinjoin", refuse_reuse);
+      if address.as_ref().err() == Some(&AccountNotFound) {
+        try!(w.wallet.account_insert("coinjoin".to_string())
+               .map_err(|e| bitcoin_json_error(WalletError,
+                                               Some(json::String(e.to_string())))));
+        address = w.new_address("coinjoin", refuse_reuse);
+      }
+      let (address, reused) = try!(address.map_err(|e| bitcoin_json_error(WalletError,
+                                                       Some(json::String(e.to_string())))));
+      if reused {
+        debug!(idle_state, Warning, "Coinjoin donation address {} has already received funds.",
+               address.to_base58check());
+      }
+
+      // Saveout the wallet before using the address
+      try!(save_wallet(&idle_state.config, &w.config, &w.wallet)
+               .map_err(|e| bitcoin_json_error(WalletError,
+                                               Some(json::String(e.to_string())))));
+      address
+    }
+  };
+
+  // Add the new sesion
+  let session = try!(Session::new(target, fee_rate, join_duration, expiry_duration, address, options,
+                                  blind_key)
+                       .map_err(|e| bitcoin_json_error(BadRng,
+                                                       Some(json::String(e.to_string())))));
+  let id = session.id();
+  try!(server.set_current_session(session)
+         .map_err(|e| bitcoin_json_error(CoinjoinError(e), None)));
+  Ok(id)
+}
+
+/// Returns the height of the best chain tip
+fn best_height(blockchain: &Blockchain) -> uint {
+  blockchain.get_block(blockchain.best_tip_hash()).unwrap().height
+}
+
+/// The height and median time past of the chain tip, against which
+/// timelocks expire
+fn best_height_and_time(blockchain: &Blockchain) -> (uint, i64) {
+  (best_height(blockchain), median_time_past(blockchain))
 }
 
 /// Decode a Json parameter
@@ -426,6 +2725,42 @@ fn decode_param<T:Decodable<json::Decoder, json::DecoderError>>(param: json::Jso
                                 Some(json::String(e.to_string()))))
 }
 
+/// Parses a hash from hex, in the usual display form, which is the
+/// reverse of the byte order hashes are serialized in
+pub fn hash_from_hex(s: &str) -> Option<Sha256dHash> {
+  match s.from_hex() {
+    Ok(mut data) => {
+      if data.len() != 32 {
+        return None;
+      }
+      data.reverse();
+      deserialize(data).ok()
+    }
+    Err(_) => None
+  }
+}
+
+/// Decode a hash parameter, given as a hex string in display form
+fn decode_hash_param(param: json::Json) -> jsonrpc::JsonResult<Sha256dHash> {
+  let hex: String = try!(decode_param(param));
+  match hash_from_hex(hex.as_slice()) {
+    Some(hash) => Ok(hash),
+    None => Err(standard_error(InvalidParams,
+                               Some(json::String(format!("`{}` is not a 64-digit hex hash", hex)))))
+  }
+}
+
+/// Decode a list of {"txid", "vout"} objects, with txids in display form
+fn decode_outpoints_param(param: json::Json) -> jsonrpc::JsonResult<Vec<OutPoint>> {
+  let raw: Vec<RawOutPoint> = try!(decode_param(param));
+  let mut ret = Vec::with_capacity(raw.len());
+  for out in raw.move_iter() {
+    let txid = try!(decode_hash_param(json::String(out.txid)));
+    ret.push(OutPoint { txid: txid, vout: out.vout });
+  }
+  Ok(ret)
+}
+
 /// Decode a hex-encoded parameter
 fn decode_hex_param<T:ConsensusDecodable<RawDecoder<MemReader>, IoError>>(param: json::Json, mode: RawDecodeMode)
                                                                           -> jsonrpc::JsonResult<T> {
@@ -465,7 +2800,7 @@ fn bitcoin_json_error(code: BitcoinJsonError, data: Option<json::Json>) -> Error
     CoinjoinError(e) => Error {
       code: -3,
       message: format!("Coinjoin error: {}", e),
-      data: data
+      data: data.or(Some(e.to_json()))
     },
     InvalidTx => Error {
       code: -4,
@@ -481,6 +2816,56 @@ fn bitcoin_json_error(code: BitcoinJsonError, data: Option<json::Json>) -> Error
       code: -6,
       message: "Wallet error".to_string(),
       data: data
+    },
+    OutputNotFound => Error {
+      code: -7,
+      message: "Output not found".to_string(),
+      data: data
+    },
+    TxNotFound => Error {
+      code: -8,
+      message: "Transaction not found".to_string(),
+      data: data
+    },
+    CannotBumpFee => Error {
+      code: -9,
+      message: "Cannot bump fee".to_string(),
+      data: data
+    },
+    WalletNotFound => Error {
+      code: -10,
+      message: "Wallet not found".to_string(),
+      data: data
+    },
+    TimelockNotExpired => Error {
+      code: -11,
+      message: "Timelock has not expired".to_string(),
+      data: data
+    },
+    InsufficientFunds => Error {
+      code: -12,
+      message: "Insufficient funds".to_string(),
+      data: data
+    },
+    TxNotIndexed => Error {
+      code: -13,
+      message: "Transaction not in wallets or kept blocks, and there is no transaction index".to_string(),
+      data: data
+    },
+    InvalidAddressOrKey => Error {
+      code: -14,
+      message: "Invalid address or key".to_string(),
+      data: data
+    },
+    PaymentRequestError => Error {
+      code: -15,
+      message: "Payment request error".to_string(),
+      data: data
+    },
+    AddressIndexDisabled => Error {
+      code: -16,
+      message: "Address index not enabled; set `address_index` in the configuration".to_string(),
+      data: data
     }
   }
 }
@@ -491,14 +2876,235 @@ fn usage_error(rpc: &RpcCall) -> Error {
                  Some(json::String(format!("Usage: {} {}", rpc.name, rpc.usage))))
 }
 
-/// Handles a JSON-RPC request, returning a result to be given back to the peer
-pub fn handle_rpc(request: jsonrpc::Request, idle_state: &mut IdleState) -> JsonResult {
-  let method = request.method.as_slice();
+/// Whether an RPC call is available under the given configuration
+fn rpc_enabled(rpc: &RpcCall, config: &NetworkConfig) -> bool {
+  (!rpc.coinjoin || config.coinjoin.enabled) && (!rpc.wallet || config.wallet_rpc)
+}
+
+/// Handles an RPC request, sending the response on `reply`. Calls which
+/// need only the shared state are handed to a worker task and answered
+/// from there; the rest run on the idle loop, where long-polling calls may
+/// take `reply` from the idle state to answer later. Wallet commands may be
+/// directed at a specific wallet by prefixing the method with the wallet
+/// name and a dot, e.g. `savings.getbalances`; otherwise they act on the
+/// default wallet.
+pub fn handle_rpc(request: jsonrpc::Request, caller: Option<SocketAddr>,
+                  reply: Sender<JsonResult>, idle_state: &mut IdleState) {
+  let method = request.method.clone();
+  let start = time::precise_time_ns();
+  match resolve_rpc(method.as_slice(), &idle_state.config) {
+    Ok((rpc, wallet_idx)) => match rpc.call {
+      IdleLoop(call) => {
+        idle_state.active_wallet = wallet_idx;
+        idle_state.rpc_reply = Some(reply);
+        idle_state.rpc_caller = caller;
+        let ret = call(rpc, idle_state, request.params);
+        idle_state.active_wallet = 0;
+        idle_state.rpc_caller = None;
+        let reply = idle_state.rpc_reply.take();
+        finish_rpc(&idle_state.config, &*idle_state.rpc_stats, method, caller, start, ret, reply);
+      }
+      Worker(call) => {
+        let mut shared = idle_state.shared();
+        shared.active_wallet = wallet_idx;
+        let config = shared.config.clone();
+        let rpc_stats = shared.rpc_stats.clone();
+        let params = request.params;
+        idle_state.rpc_pool.execute(proc() {
+          // Run the call in its own task, so that a failure is reported to
+          // the caller rather than leaving them waiting for a reply
+          let ret = match task::try(proc() { call(rpc, &shared, params) }) {
+            Ok(ret) => ret,
+            Err(_) => Err(standard_error(InternalError, Some(json::String("RPC call failed".to_string()))))
+          };
+          finish_rpc(&config, &*rpc_stats, method, caller, start, ret, Some(reply));
+        });
+      }
+    },
+    Err(e) => {
+      finish_rpc(&idle_state.config, &*idle_state.rpc_stats, method, caller, start, Err(e), Some(reply));
+    }
+  }
+}
+
+/// Logs a finished RPC call, records it in the statistics and sends the
+/// result on `reply`, if there still is one
+fn finish_rpc(config: &NetworkConfig, stats: &Mutex<RpcStats>, method: String,
+              caller: Option<SocketAddr>, start: u64, ret: JsonResult,
+              reply: Option<Sender<JsonResult>>) {
+  let duration = time::precise_time_ns() - start;
+  let error = ret.as_ref().err().map(|e| e.code);
+  let caller_str = caller.map_or("unknown".to_string(), |c| c.to_string());
+  let (network, debug_level) = (config.network, config.debug_level);
+  match error {
+    Some(code) => debug!((network, debug_level), Notice,
+                         "RPC `{}` from {} failed with code {} after {}us",
+                         method, caller_str, code, duration / 1000),
+    None => debug!((network, debug_level), Debug, "RPC `{}` from {} took {}us",
+                   method, caller_str, duration / 1000)
+  }
+  stats.lock().record(method, caller, duration, error);
+
+  match reply {
+    // The client may have hung up, which is fine
+    Some(reply) => { let _ = reply.send_opt(ret); }
+    None => {}
+  }
+}
+
+/// Counters for a single RPC method
+#[deriving(Clone, Default)]
+struct MethodStats {
+  calls: u64,
+  errors: u64,
+  total_ns: u64,
+  max_ns: u64
+}
+
+/// A record of a recent RPC call
+struct RecentCall {
+  method: String,
+  caller: Option<SocketAddr>,
+  // Time (seconds since the epoch) at which the call finished
+  time: i64,
+  duration_ns: u64,
+  error: Option<int>
+}
+
+/// Statistics on the RPC calls we have handled, for `getrpcinfo`
+pub struct RpcStats {
+  started_at: i64,
+  methods: TreeMap<String, MethodStats>,
+  recent: DList<RecentCall>
+}
+
+impl RpcStats {
+  /// Creates a new empty set of statistics
+  pub fn new() -> RpcStats {
+    RpcStats {
+      started_at: time::get_time().sec,
+      methods: TreeMap::new(),
+      recent: DList::new()
+    }
+  }
+
+  /// Records a call. Only time spent in the idle loop is counted; long-polling
+  /// calls do not count the time spent waiting.
+  fn record(&mut self, method: String, caller: Option<SocketAddr>, duration_ns: u64, error: Option<int>) {
+    {
+      let stats = self.methods.find_or_insert(method.clone(), Default::default());
+      stats.calls += 1;
+      if error.is_some() {
+        stats.errors += 1;
+      }
+      stats.total_ns += duration_ns;
+      stats.max_ns = cmp::max(stats.max_ns, duration_ns);
+    }
+    if self.recent.len() >= RPC_RECENT_CALLS {
+      self.recent.pop_front();
+    }
+    self.recent.push(RecentCall {
+      method: method,
+      caller: caller,
+      time: time::get_time().sec,
+      duration_ns: duration_ns,
+      error: error
+    });
+  }
+}
+
+impl ToJson for RpcStats {
+  fn to_json(&self) -> json::Json {
+    let mut methods = TreeMap::new();
+    for (name, stats) in self.methods.iter() {
+      let mut obj = TreeMap::new();
+      obj.insert("calls".to_string(), stats.calls.to_json());
+      obj.insert("errors".to_string(), stats.errors.to_json());
+      obj.insert("total_us".to_string(), (stats.total_ns / 1000).to_json());
+      obj.insert("mean_us".to_string(), (stats.total_ns / stats.calls / 1000).to_json());
+      obj.insert("max_us".to_string(), (stats.max_ns / 1000).to_json());
+      methods.insert(name.clone(), json::Object(obj));
+    }
+    let mut recent = vec![];
+    for call in self.recent.iter().rev() {
+      let mut obj = TreeMap::new();
+      obj.insert("method".to_string(), json::String(call.method.clone()));
+      obj.insert("caller".to_string(), call.caller.map(|c| c.to_string()).to_json());
+      obj.insert("time".to_string(), call.time.to_json());
+      obj.insert("duration_us".to_string(), (call.duration_ns / 1000).to_json());
+      obj.insert("error".to_string(), call.error.to_json());
+      recent.push(json::Object(obj));
+    }
+    let mut ret = TreeMap::new();
+    ret.insert("since".to_string(), self.started_at.to_json());
+    ret.insert("methods".to_string(), json::Object(methods));
+    ret.insert("recent".to_string(), json::List(recent));
+    json::Object(ret)
+  }
+}
+
+/// Looks up the RPC command for a method name, and the index of the
+/// wallet it acts on
+fn resolve_rpc(method: &str, config: &NetworkConfig)
+               -> jsonrpc::JsonResult<(&'static RpcCall, uint)> {
+  let not_found = Err(standard_error(MethodNotFound, Some(json::String(method.to_string()))));
+  let (wallet_name, method) = match method.find('.') {
+    Some(n) => (Some(method.slice_to(n)), method.slice_from(n + 1)),
+    None => (None, method)
+  };
+
   match RPC_CALLS.find_equiv(&method) {
-    Some(rpc) if !rpc.coinjoin || idle_state.config.coinjoin_on =>
-      (rpc.call)(rpc, idle_state, request.params),
-    _ => Err(standard_error(MethodNotFound,
-                            Some(json::String(request.method.clone()))))
+    Some(rpc) if rpc_enabled(rpc, config) => {
+      match wallet_name {
+        Some(name) => {
+          if !rpc.wallet {
+            return not_found;
+          }
+          // Wallets are loaded in the order they are configured
+          match config.wallets.iter().position(|w| w.name.as_slice() == name) {
+            Some(idx) => Ok((rpc, idx)),
+            None => Err(bitcoin_json_error(WalletNotFound, Some(json::String(name.to_string()))))
+          }
+        }
+        None => Ok((rpc, 0))
+      }
+    }
+    _ => not_found
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use std::collections::TreeMap;
+  use serialize::json;
+  use serialize::json::ToJson;
+
+  use wallet::OutPoint;
+  use super::{decode_hash_param, decode_outpoints_param, hash_from_hex};
+
+  // Txid of the genesis block coinbase, in display form
+  static GENESIS_TXID: &'static str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+  #[test]
+  fn test_hash_round_trip() {
+    let hash = hash_from_hex(GENESIS_TXID).unwrap();
+    assert_eq!(hash.to_json(), json::String(GENESIS_TXID.to_string()));
+    assert_eq!(decode_hash_param(hash.to_json()).ok(), Some(hash));
+
+    assert!(hash_from_hex("4a5e1e4b").is_none());
+    assert!(hash_from_hex("not hex at all").is_none());
+    assert!(decode_hash_param(json::U64(5)).is_err());
+  }
+
+  #[test]
+  fn test_outpoints_round_trip() {
+    let out = OutPoint { txid: hash_from_hex(GENESIS_TXID).unwrap(), vout: 0 };
+    let param = json::List(vec![out.to_json()]);
+    assert_eq!(decode_outpoints_param(param).ok(), Some(vec![out]));
+
+    let mut bad = TreeMap::new();
+    bad.insert("txid".to_string(), json::String("00".to_string()));
+    bad.insert("vout".to_string(), json::U64(0));
+    assert!(decode_outpoints_param(json::List(vec![json::Object(bad)])).is_err());
+  }
+}
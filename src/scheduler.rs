@@ -0,0 +1,123 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Scheduler
+//!
+//! Periodic and one-shot jobs for the idle loop. The scheduler does not run
+//! anything itself: the idle loop ticks it, and queues the states of any
+//! tasks which have come due.
+//!
+
+use user_data::TaskPeriods;
+
+/// A job which the idle loop runs on a schedule
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Task {
+  /// Sync with the peer, then save everything to disk
+  SyncAndSave,
+  /// Save everything to disk, e.g. to retry a failed save
+  Save,
+  /// Drop the peer and connect afresh
+  RotatePeer,
+  /// Start any scheduled coinjoin sessions which are due
+  CoinjoinSchedule,
+  /// Forget mempool transactions which have been waiting too long
+  ExpireMempool,
+  /// Write the fee estimator's statistics to disk
+  FlushFeeEstimates
+}
+
+/// A scheduled task
+struct Job {
+  task: Task,
+  /// Time, in s, between runs; `None` for a job which runs only once
+  period: Option<i64>,
+  /// Time (seconds since the epoch) at which the job next runs
+  due: i64
+}
+
+/// A set of scheduled jobs
+pub struct Scheduler {
+  jobs: Vec<Job>
+}
+
+impl Scheduler {
+  /// Creates a scheduler with no jobs
+  pub fn new() -> Scheduler {
+    Scheduler { jobs: vec![] }
+  }
+
+  /// Creates a scheduler running the periodic tasks at the given periods
+  pub fn with_periods(periods: &TaskPeriods, now: i64) -> Scheduler {
+    let mut ret = Scheduler::new();
+    ret.set_periods(periods, now);
+    ret
+  }
+
+  /// Runs `task` every `period` seconds, starting `period` seconds from
+  /// `now`, replacing any earlier schedule for it. A period of zero or
+  /// less removes it from the schedule instead.
+  pub fn every(&mut self, task: Task, period: i64, now: i64) {
+    self.cancel(task);
+    if period > 0 {
+      self.jobs.push(Job { task: task, period: Some(period), due: now + period });
+    }
+  }
+
+  /// Runs `task` once, `delay` seconds from `now`. Any periodic schedule
+  /// for the same task is left alone.
+  pub fn once(&mut self, task: Task, delay: i64, now: i64) {
+    self.jobs.push(Job { task: task, period: None, due: now + delay });
+  }
+
+  /// Removes all jobs for `task`
+  pub fn cancel(&mut self, task: Task) {
+    self.jobs.retain(|job| job.task != task);
+  }
+
+  /// Schedules the periodic tasks at the given periods. Tasks whose period
+  /// is unchanged keep their place in the schedule.
+  pub fn set_periods(&mut self, periods: &TaskPeriods, now: i64) {
+    for &(task, period) in [(SyncAndSave, periods.save),
+                            (RotatePeer, periods.peer_rotation),
+                            (CoinjoinSchedule, periods.coinjoin_schedule),
+                            (ExpireMempool, periods.mempool_expiry),
+                            (FlushFeeEstimates, periods.fee_estimates)].iter() {
+      let unchanged = self.jobs.iter().any(|job| job.task == task && job.period == Some(period));
+      if !unchanged {
+        self.every(task, period, now);
+      }
+    }
+  }
+
+  /// Returns the tasks which are due as of `now`, in the order they came
+  /// due. Periodic jobs are rescheduled and one-shot jobs are removed.
+  pub fn due(&mut self, now: i64) -> Vec<Task> {
+    let mut due: Vec<(i64, Task)> = vec![];
+    for job in self.jobs.mut_iter() {
+      if job.due <= now {
+        due.push((job.due, job.task));
+        match job.period {
+          // Skip any runs we missed rather than running them all at once
+          Some(period) => { while job.due <= now { job.due += period; } }
+          None => {}
+        }
+      }
+    }
+    self.jobs.retain(|job| job.period.is_some() || job.due > now);
+    due.sort_by(|a, b| a.ref0().cmp(b.ref0()));
+    due.move_iter().map(|(_, task)| task).collect()
+  }
+}
+
@@ -0,0 +1,372 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Script Information
+//!
+//! Disassembly and classification of scripts, and the addresses which
+//! standard scripts pay to. This works on raw script bytes, so it will
+//! happily describe scripts which do not even parse.
+//!
+
+use std::fmt;
+use std::from_str::FromStr;
+use serialize::{Decodable, Decoder, Encodable, Encoder};
+use serialize::hex::{FromHex, ToHex};
+use serialize::json;
+use serialize::json::ToJson;
+
+use crypto::digest::Digest;
+use crypto::ripemd160::Ripemd160;
+use crypto::sha2::Sha256;
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Script;
+use bitcoin::network::constants::{Network, Bitcoin, BitcoinTestnet};
+use bitcoin::util::base58::{FromBase58, ToBase58};
+
+/// A RIPEMD160(SHA256(data)) hash, as addresses and scripts use to refer
+/// to public keys and redeem scripts
+pub struct Hash160([u8, ..20]);
+
+impl Hash160 {
+  /// Hashes some data
+  pub fn from_data(data: &[u8]) -> Hash160 {
+    let mut sha = Sha256::new();
+    let mut sha_out = [0u8, ..32];
+    sha.input(data);
+    sha.result(sha_out.as_mut_slice());
+
+    let mut rmd = Ripemd160::new();
+    let mut ret = [0u8, ..20];
+    rmd.input(sha_out.as_slice());
+    rmd.result(ret.as_mut_slice());
+    Hash160(ret)
+  }
+
+  /// Takes a hash which has already been computed, e.g. from a script,
+  /// if it is the right length
+  pub fn from_slice(data: &[u8]) -> Option<Hash160> {
+    if data.len() != 20 {
+      return None;
+    }
+    let mut ret = [0u8, ..20];
+    for (r, d) in ret.mut_iter().zip(data.iter()) {
+      *r = *d;
+    }
+    Some(Hash160(ret))
+  }
+
+  /// The bytes of the hash
+  pub fn as_slice<'a>(&'a self) -> &'a [u8] {
+    let &Hash160(ref data) = self;
+    data.as_slice()
+  }
+}
+
+impl Clone for Hash160 {
+  fn clone(&self) -> Hash160 { *self }
+}
+
+impl PartialEq for Hash160 {
+  fn eq(&self, other: &Hash160) -> bool { self.as_slice() == other.as_slice() }
+}
+
+impl Eq for Hash160 {}
+
+impl fmt::Show for Hash160 {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.as_slice().to_hex())
+  }
+}
+
+impl FromStr for Hash160 {
+  fn from_str(s: &str) -> Option<Hash160> {
+    s.from_hex().ok().and_then(|data| Hash160::from_slice(data.as_slice()))
+  }
+}
+
+impl ToJson for Hash160 {
+  fn to_json(&self) -> json::Json {
+    json::String(self.as_slice().to_hex())
+  }
+}
+
+impl<E: Encoder<S>, S> Encodable<E, S> for Hash160 {
+  fn encode(&self, e: &mut E) -> Result<(), S> {
+    e.emit_str(self.as_slice().to_hex().as_slice())
+  }
+}
+
+impl<D: Decoder<E>, E> Decodable<D, E> for Hash160 {
+  fn decode(d: &mut D) -> Result<Hash160, E> {
+    let st = try!(d.read_str());
+    match from_str(st.as_slice()) {
+      Some(hash) => Ok(hash),
+      None => Err(d.error(format!("`{}` is not a 20-byte hex string", st).as_slice()))
+    }
+  }
+}
+
+/// A P2SH address
+pub struct P2shAddress {
+  network: Network,
+  hash: Hash160
+}
+
+impl P2shAddress {
+  /// The P2SH address of a redeem script
+  pub fn from_script(network: Network, script: &[u8]) -> P2shAddress {
+    P2shAddress { network: network, hash: hash160(script) }
+  }
+}
+
+impl ToBase58 for P2shAddress {
+  fn base58_layout(&self) -> Vec<u8> {
+    let mut ret = vec![match self.network { Bitcoin => 5, BitcoinTestnet => 196 }];
+    ret.push_all(self.hash.as_slice());
+    ret
+  }
+}
+
+/// A pay-to-pubkey-hash address
+pub struct P2pkhAddress {
+  network: Network,
+  hash: Hash160
+}
+
+impl P2pkhAddress {
+  /// The address of a public key
+  pub fn from_pubkey(network: Network, pubkey: &[u8]) -> P2pkhAddress {
+    P2pkhAddress { network: network, hash: hash160(pubkey) }
+  }
+}
+
+impl ToBase58 for P2pkhAddress {
+  fn base58_layout(&self) -> Vec<u8> {
+    let mut ret = vec![match self.network { Bitcoin => 0, BitcoinTestnet => 111 }];
+    ret.push_all(self.hash.as_slice());
+    ret
+  }
+}
+
+/// Decodes a P2PKH or P2SH address for `network` into the script which
+/// pays to it
+pub fn address_script(network: Network, address: &str) -> Option<Script> {
+  let data: Vec<u8> = match FromBase58::from_base58check(address) {
+    Ok(data) => data,
+    Err(_) => { return None; }
+  };
+  if data.len() != 21 {
+    return None;
+  }
+  let mut script = Script::new();
+  match (network, data[0]) {
+    (Bitcoin, 0) | (BitcoinTestnet, 111) => {
+      script.push_opcode(opcodes::all::OP_DUP);
+      script.push_opcode(opcodes::all::OP_HASH160);
+      script.push_slice(data.slice_from(1));
+      script.push_opcode(opcodes::all::OP_EQUALVERIFY);
+      script.push_opcode(opcodes::all::OP_CHECKSIG);
+    }
+    (Bitcoin, 5) | (BitcoinTestnet, 196) => {
+      script.push_opcode(opcodes::all::OP_HASH160);
+      script.push_slice(data.slice_from(1));
+      script.push_opcode(opcodes::all::OP_EQUAL);
+    }
+    _ => { return None; }
+  }
+  Some(script)
+}
+
+/// Builds an unspendable script carrying some data, i.e. OP_RETURN followed
+/// by a single push
+pub fn null_data_script(data: &[u8]) -> Script {
+  let mut script = Script::new();
+  script.push_opcode(opcodes::all::OP_RETURN);
+  script.push_slice(data);
+  script
+}
+
+/// Computes RIPEMD160(SHA256(data))
+pub fn hash160(data: &[u8]) -> Hash160 {
+  Hash160::from_data(data)
+}
+
+/// A single parsed script instruction
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Instruction {
+  /// Push some data (including the empty push, OP_0)
+  PushData(Vec<u8>),
+  /// Any other opcode, including small-integer pushes
+  Opcode(u8),
+  /// A push which runs off the end of the script
+  Truncated
+}
+
+/// Splits a script into instructions. Parsing stops at the first
+/// truncated push, which is recorded as the final instruction.
+pub fn instructions(script: &[u8]) -> Vec<Instruction> {
+  let mut ret = vec![];
+  let mut i = 0;
+  while i < script.len() {
+    let op = script[i];
+    i += 1;
+    // Read the length of any push
+    let (len, len_size) = match op {
+      0x00...0x4b => (op as uint, 0),
+      0x4c if i + 1 <= script.len() => (script[i] as uint, 1),
+      0x4d if i + 2 <= script.len() => (script[i] as uint | script[i + 1] as uint << 8, 2),
+      0x4e if i + 4 <= script.len() => (script[i] as uint | script[i + 1] as uint << 8 |
+                                        script[i + 2] as uint << 16 | script[i + 3] as uint << 24, 4),
+      0x4c...0x4e => { ret.push(Truncated); break; }
+      _ => { ret.push(Opcode(op)); continue; }
+    };
+    i += len_size;
+    if i + len > script.len() {
+      ret.push(Truncated);
+      break;
+    }
+    ret.push(PushData(script.slice(i, i + len).to_vec()));
+    i += len;
+  }
+  ret
+}
+
+/// The name of a non-push opcode, in the form bitcoind's disassembler uses
+fn opcode_name(op: u8) -> String {
+  let name = match op {
+    0x4f => "-1",
+    0x50 => "OP_RESERVED",
+    0x51...0x60 => { return format!("{}", op - 0x50); }
+    0x61 => "OP_NOP", 0x62 => "OP_VER", 0x63 => "OP_IF", 0x64 => "OP_NOTIF",
+    0x65 => "OP_VERIF", 0x66 => "OP_VERNOTIF", 0x67 => "OP_ELSE", 0x68 => "OP_ENDIF",
+    0x69 => "OP_VERIFY", 0x6a => "OP_RETURN",
+    0x6b => "OP_TOALTSTACK", 0x6c => "OP_FROMALTSTACK", 0x6d => "OP_2DROP", 0x6e => "OP_2DUP",
+    0x6f => "OP_3DUP", 0x70 => "OP_2OVER", 0x71 => "OP_2ROT", 0x72 => "OP_2SWAP",
+    0x73 => "OP_IFDUP", 0x74 => "OP_DEPTH", 0x75 => "OP_DROP", 0x76 => "OP_DUP",
+    0x77 => "OP_NIP", 0x78 => "OP_OVER", 0x79 => "OP_PICK", 0x7a => "OP_ROLL",
+    0x7b => "OP_ROT", 0x7c => "OP_SWAP", 0x7d => "OP_TUCK",
+    0x7e => "OP_CAT", 0x7f => "OP_SUBSTR", 0x80 => "OP_LEFT", 0x81 => "OP_RIGHT",
+    0x82 => "OP_SIZE",
+    0x83 => "OP_INVERT", 0x84 => "OP_AND", 0x85 => "OP_OR", 0x86 => "OP_XOR",
+    0x87 => "OP_EQUAL", 0x88 => "OP_EQUALVERIFY", 0x89 => "OP_RESERVED1", 0x8a => "OP_RESERVED2",
+    0x8b => "OP_1ADD", 0x8c => "OP_1SUB", 0x8d => "OP_2MUL", 0x8e => "OP_2DIV",
+    0x8f => "OP_NEGATE", 0x90 => "OP_ABS", 0x91 => "OP_NOT", 0x92 => "OP_0NOTEQUAL",
+    0x93 => "OP_ADD", 0x94 => "OP_SUB", 0x95 => "OP_MUL", 0x96 => "OP_DIV",
+    0x97 => "OP_MOD", 0x98 => "OP_LSHIFT", 0x99 => "OP_RSHIFT",
+    0x9a => "OP_BOOLAND", 0x9b => "OP_BOOLOR", 0x9c => "OP_NUMEQUAL", 0x9d => "OP_NUMEQUALVERIFY",
+    0x9e => "OP_NUMNOTEQUAL", 0x9f => "OP_LESSTHAN", 0xa0 => "OP_GREATERTHAN",
+    0xa1 => "OP_LESSTHANOREQUAL", 0xa2 => "OP_GREATERTHANOREQUAL",
+    0xa3 => "OP_MIN", 0xa4 => "OP_MAX", 0xa5 => "OP_WITHIN",
+    0xa6 => "OP_RIPEMD160", 0xa7 => "OP_SHA1", 0xa8 => "OP_SHA256", 0xa9 => "OP_HASH160",
+    0xaa => "OP_HASH256", 0xab => "OP_CODESEPARATOR", 0xac => "OP_CHECKSIG",
+    0xad => "OP_CHECKSIGVERIFY", 0xae => "OP_CHECKMULTISIG", 0xaf => "OP_CHECKMULTISIGVERIFY",
+    0xb0 => "OP_NOP1", 0xb1 => "OP_NOP2", 0xb2 => "OP_NOP3", 0xb3 => "OP_NOP4",
+    0xb4 => "OP_NOP5", 0xb5 => "OP_NOP6", 0xb6 => "OP_NOP7", 0xb7 => "OP_NOP8",
+    0xb8 => "OP_NOP9", 0xb9 => "OP_NOP10",
+    _ => "OP_UNKNOWN"
+  };
+  name.to_string()
+}
+
+/// Disassembles a script into space-separated opcode names and hex pushes
+pub fn disassemble(script: &[u8]) -> String {
+  let words: Vec<String> = instructions(script).move_iter().map(|ins| match ins {
+    PushData(ref data) if data.len() == 0 => "0".to_string(),
+    PushData(data) => data.as_slice().to_hex(),
+    Opcode(op) => opcode_name(op),
+    Truncated => "[error]".to_string()
+  }).collect();
+  words.connect(" ")
+}
+
+/// The standard script forms
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ScriptType {
+  /// <pubkey> OP_CHECKSIG
+  PayToPubkey(Vec<u8>),
+  /// OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+  PayToPubkeyHash(Hash160),
+  /// OP_HASH160 <hash> OP_EQUAL
+  PayToScriptHash(Hash160),
+  /// m <pubkeys...> n OP_CHECKMULTISIG (required signatures, keys)
+  Multisig(uint, Vec<Vec<u8>>),
+  /// OP_RETURN followed only by pushes
+  NullData,
+  /// Anything else
+  NonStandard
+}
+
+impl ScriptType {
+  /// The name of the type, as bitcoind reports it
+  pub fn name(&self) -> &'static str {
+    match *self {
+      PayToPubkey(_) => "pubkey",
+      PayToPubkeyHash(_) => "pubkeyhash",
+      PayToScriptHash(_) => "scripthash",
+      Multisig(_, _) => "multisig",
+      NullData => "nulldata",
+      NonStandard => "nonstandard"
+    }
+  }
+
+  /// The addresses a script of this type pays to
+  pub fn addresses(&self, network: Network) -> Vec<String> {
+    match *self {
+      PayToPubkey(ref key) => vec![P2pkhAddress::from_pubkey(network, key.as_slice()).to_base58check()],
+      PayToPubkeyHash(ref hash) => vec![P2pkhAddress { network: network, hash: hash.clone() }.to_base58check()],
+      PayToScriptHash(ref hash) => vec![P2shAddress { network: network, hash: hash.clone() }.to_base58check()],
+      Multisig(_, ref keys) => keys.iter().map(|key| P2pkhAddress::from_pubkey(network, key.as_slice())
+                                                       .to_base58check()).collect(),
+      NullData | NonStandard => vec![]
+    }
+  }
+}
+
+/// Whether some data has the length of a compressed or uncompressed public key
+fn is_pubkey(data: &[u8]) -> bool {
+  match data.len() {
+    33 => data[0] == 0x02 || data[0] == 0x03,
+    65 => data[0] == 0x04,
+    _ => false
+  }
+}
+
+/// Classifies a script as one of the standard forms
+pub fn classify(script: &[u8]) -> ScriptType {
+  let ins = instructions(script);
+  match ins.as_slice() {
+    [PushData(ref key), Opcode(0xac)] if is_pubkey(key.as_slice()) => PayToPubkey(key.clone()),
+    [Opcode(0x76), Opcode(0xa9), PushData(ref hash), Opcode(0x88), Opcode(0xac)] if hash.len() == 20
+      => PayToPubkeyHash(Hash160::from_slice(hash.as_slice()).unwrap()),
+    [Opcode(0xa9), PushData(ref hash), Opcode(0x87)] if hash.len() == 20 && script.len() == 23
+      => PayToScriptHash(Hash160::from_slice(hash.as_slice()).unwrap()),
+    [Opcode(0x6a), ..rest] if rest.iter().all(|i| match *i { PushData(_) => true, _ => false })
+      => NullData,
+    [Opcode(m @ 0x51...0x60), ..rest] if rest.len() >= 3 => {
+      let n_keys = rest.len() - 2;
+      let keys: Vec<Vec<u8>> = rest.slice_to(n_keys).iter().filter_map(|i| match *i {
+        PushData(ref key) if is_pubkey(key.as_slice()) => Some(key.clone()),
+        _ => None
+      }).collect();
+      let m = (m - 0x50) as uint;
+      match rest.slice_from(n_keys) {
+        [Opcode(n), Opcode(0xae)] if n > 0x50 && n <= 0x60 && (n - 0x50) as uint == n_keys &&
+                                     keys.len() == n_keys && m <= n_keys => Multisig(m, keys),
+        _ => NonStandard
+      }
+    }
+    _ => NonStandard
+  }
+}
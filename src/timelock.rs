@@ -0,0 +1,257 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Time-locked Savings
+//!
+//! Experimental support for "savings" outputs which cannot be spent until
+//! a chosen block height or time, enforced by OP_CHECKLOCKTIMEVERIFY. Each
+//! timelock is a P2SH script paying to one of our keys once the lock expires.
+//!
+//! Timelocks follow the blockchain only: outputs are recorded and spent as
+//! blocks are connected, and restored if those blocks are disconnected. A
+//! time lock expires once the median time past of the tip exceeds it, as
+//! for any other lock time.
+//!
+//! Note that CLTV is not (yet) a consensus rule, so until it is, these
+//! outputs are protected only by the goodwill of miners.
+//!
+
+use std::collections::TreeMap;
+use serialize::hex::{FromHex, ToHex};
+use serialize::json;
+use serialize::json::ToJson;
+
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+use bitcoin::network::constants::Network;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::base58::ToBase58;
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::wallet::address::Address;
+use bitcoin::wallet::wallet::{mod, Wallet};
+
+use constants::{EST_INPUT_SIZE, EST_OUTPUT_SIZE, MEDIAN_TIME_SPAN};
+use script_info::{hash160, P2shAddress};
+use wallet::OutPoint;
+
+/// Lock times below this are block heights; at or above, Unix timestamps
+pub static LOCKTIME_THRESHOLD: u32 = 500000000;
+
+/// An output which pays to a timelock script
+#[deriving(Clone, PartialEq, Eq, Show, Encodable, Decodable)]
+pub struct FundedOutput {
+  /// The output
+  pub outpoint: OutPoint,
+  /// Its value, in satoshi
+  pub value: u64
+}
+
+/// An output which paid to a timelock, spent by a confirmed transaction
+#[deriving(Clone, PartialEq, Eq, Show, Encodable, Decodable)]
+pub struct SpentOutput {
+  /// The output
+  pub output: FundedOutput,
+  /// The transaction which spent it
+  pub spent_by: Sha256dHash
+}
+
+/// A time-locked savings script
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Timelock {
+  /// Block height or Unix time before which the funds cannot be spent
+  pub locktime: u32,
+  /// The wallet account whose key can spend the funds
+  pub account: String,
+  /// Hex-encoded redeem script
+  redeem_script: String,
+  /// Unspent outputs paying to the script
+  pub outputs: Vec<FundedOutput>,
+  /// Spent outputs, kept to restore if the block spending them is
+  /// disconnected
+  spent: Vec<SpentOutput>
+}
+
+impl Timelock {
+  /// Creates a new timelock paying to `address` after `locktime`
+  pub fn new(locktime: u32, account: String, address: &Address) -> Timelock {
+    let mut script = Script::new();
+    script.push_int(locktime as i64);
+    script.push_opcode(opcodes::all::OP_NOP2);  // OP_CHECKLOCKTIMEVERIFY
+    script.push_opcode(opcodes::all::OP_DROP);
+    script.push_opcode(opcodes::all::OP_DUP);
+    script.push_opcode(opcodes::all::OP_HASH160);
+    script.push_slice(address.hash.as_slice());
+    script.push_opcode(opcodes::all::OP_EQUALVERIFY);
+    script.push_opcode(opcodes::all::OP_CHECKSIG);
+    Timelock {
+      locktime: locktime,
+      account: account,
+      redeem_script: script.as_slice().to_hex(),
+      outputs: vec![],
+      spent: vec![]
+    }
+  }
+
+  /// The redeem script
+  pub fn redeem_script(&self) -> Script {
+    Script::from_vec(self.redeem_script.as_slice().from_hex().unwrap())
+  }
+
+  /// The P2SH scriptpubkey which pays to this timelock
+  pub fn script_pubkey(&self) -> Script {
+    let mut script = Script::new();
+    script.push_opcode(opcodes::all::OP_HASH160);
+    script.push_slice(hash160(self.redeem_script().as_slice()).as_slice());
+    script.push_opcode(opcodes::all::OP_EQUAL);
+    script
+  }
+
+  /// The P2SH address which pays to this timelock
+  pub fn address(&self, network: Network) -> P2shAddress {
+    P2shAddress::from_script(network, self.redeem_script().as_slice())
+  }
+
+  /// Whether the lock has expired, i.e. whether a transaction spending the
+  /// outputs could go in the next block, given the current tip height and
+  /// its median time past
+  pub fn is_expired(&self, tip_height: uint, median_time_past: i64) -> bool {
+    if self.locktime < LOCKTIME_THRESHOLD {
+      tip_height as u32 >= self.locktime
+    } else {
+      median_time_past > self.locktime as i64
+    }
+  }
+
+  /// Total value of the outputs paying to this timelock
+  pub fn balance(&self) -> u64 {
+    self.outputs.iter().fold(0, |acc, o| acc + o.value)
+  }
+
+  /// Whether a transaction spends any output of this timelock
+  pub fn is_spent_by(&self, tx: &Transaction) -> bool {
+    self.outputs.iter().any(|o| tx.input.iter().any(|i| i.prev_hash == o.outpoint.txid &&
+                                                        i.prev_index == o.outpoint.vout))
+  }
+
+  /// Records any outputs of a confirmed transaction which pay to this
+  /// timelock, and any which it spends. Returns true if anything changed.
+  pub fn tx_confirmed(&mut self, tx: &Transaction) -> bool {
+    let mut changed = false;
+    let txid = tx.bitcoin_hash();
+    let mut n = 0;
+    while n < self.outputs.len() {
+      let spent = tx.input.iter().any(|i| i.prev_hash == self.outputs[n].outpoint.txid &&
+                                          i.prev_index == self.outputs[n].outpoint.vout);
+      if spent {
+        let output = self.outputs.remove(n).unwrap();
+        self.spent.push(SpentOutput { output: output, spent_by: txid });
+        changed = true;
+      } else {
+        n += 1;
+      }
+    }
+
+    let script_pubkey = self.script_pubkey();
+    for (n, out) in tx.output.iter().enumerate() {
+      if out.script_pubkey == script_pubkey {
+        let funded = FundedOutput { outpoint: OutPoint { txid: txid, vout: n as u32 },
+                                    value: out.value };
+        if !self.outputs.contains(&funded) {
+          self.outputs.push(funded);
+          changed = true;
+        }
+      }
+    }
+    changed
+  }
+
+  /// Undoes `tx_confirmed` for a transaction whose block was disconnected:
+  /// forgets its outputs to this timelock and restores those it spent.
+  /// Returns true if anything changed.
+  pub fn tx_unconfirmed(&mut self, tx: &Transaction) -> bool {
+    let txid = tx.bitcoin_hash();
+    let old_len = self.outputs.len();
+    self.outputs.retain(|o| o.outpoint.txid != txid);
+    let mut changed = self.outputs.len() != old_len;
+    let mut n = 0;
+    while n < self.spent.len() {
+      if self.spent[n].spent_by == txid {
+        let spent = self.spent.remove(n).unwrap();
+        self.outputs.push(spent.output);
+        changed = true;
+      } else {
+        n += 1;
+      }
+    }
+    changed
+  }
+
+  /// Builds and signs a transaction spending every output of this timelock
+  /// to `dest`, paying `fee`. The caller must check that the lock has
+  /// expired and that the outputs are worth more than the fee.
+  pub fn spending_transaction(&self, wallet: &Wallet, dest: Script, fee: u64)
+                              -> Result<Transaction, wallet::Error> {
+    let redeem_script = self.redeem_script();
+    let mut tx = Transaction {
+      version: 1,
+      lock_time: self.locktime,
+      // CLTV requires that the input not be final
+      input: self.outputs.iter().map(|o| TxIn {
+        prev_hash: o.outpoint.txid,
+        prev_index: o.outpoint.vout,
+        script_sig: Script::new(),
+        sequence: 0
+      }).collect(),
+      output: vec![TxOut { value: self.balance() - fee, script_pubkey: dest }]
+    };
+    for n in range(0, tx.input.len()) {
+      try!(wallet.sign_input(&mut tx, n, &redeem_script));
+      tx.input.get_mut(n).script_sig.push_slice(redeem_script.as_slice());
+    }
+    Ok(tx)
+  }
+
+  /// Estimated serialized size, in bytes, of `spending_transaction`
+  pub fn spend_size(&self) -> u64 {
+    10 + self.outputs.len() as u64 * (EST_INPUT_SIZE + self.redeem_script.len() as u64 / 2) +
+      EST_OUTPUT_SIZE
+  }
+
+  /// Serializes the timelock for RPC output
+  pub fn to_json(&self, network: Network, tip_height: uint, median_time_past: i64) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("address".to_string(), json::String(self.address(network).to_base58check()));
+    obj.insert("locktime".to_string(), self.locktime.to_json());
+    obj.insert("account".to_string(), json::String(self.account.clone()));
+    obj.insert("redeem_script".to_string(), json::String(self.redeem_script.clone()));
+    obj.insert("balance".to_string(), self.balance().to_json());
+    obj.insert("expired".to_string(), self.is_expired(tip_height, median_time_past).to_json());
+    obj.insert("outputs".to_string(),
+               json::List(self.outputs.iter().map(|o| o.outpoint.to_json()).collect()));
+    json::Object(obj)
+  }
+}
+
+/// The median time of the last `MEDIAN_TIME_SPAN` blocks up to the tip,
+/// which time locks are compared against
+pub fn median_time_past(blockchain: &Blockchain) -> i64 {
+  let mut times: Vec<i64> = blockchain.rev_iter(blockchain.best_tip_hash())
+                                      .take(MEDIAN_TIME_SPAN)
+                                      .map(|node| node.block.header.time as i64)
+                                      .collect();
+  times.sort();
+  times[times.len() / 2]
+}
@@ -0,0 +1,294 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # TLS
+//!
+//! Just enough of OpenSSL for the payment protocol: client connections
+//! which check the server's certificate chain and hostname against the
+//! system's trusted roots, and checking a signature made with the leaf of
+//! an X.509 certificate chain, likewise checked against the trusted roots.
+//!
+
+use std::c_str::ToCStr;
+use std::io::{EndOfFile, IoError, IoResult, OtherIoError};
+use std::io::standard_error;
+use std::mem;
+use std::ptr;
+use std::str::from_utf8;
+use std::sync::{Once, ONCE_INIT};
+use libc::{c_char, c_int, c_long, c_uchar, c_uint, c_void, size_t};
+use libc::{setsockopt, socklen_t, suseconds_t, time_t, timeval};
+use libc::{SOL_SOCKET, SO_RCVTIMEO, SO_SNDTIMEO};
+
+// `BIO_ctrl` commands, for the macros OpenSSL wraps them in
+static BIO_C_SET_CONNECT: c_int = 100;
+static BIO_C_DO_STATE_MACHINE: c_int = 101;
+static BIO_C_GET_FD: c_int = 105;
+static BIO_C_GET_SSL: c_int = 110;
+// `SSL_ctrl` command and name type for `SSL_set_tlsext_host_name`
+static SSL_CTRL_SET_TLSEXT_HOSTNAME: c_int = 55;
+static TLSEXT_NAMETYPE_HOST_NAME: c_long = 0;
+static SSL_VERIFY_PEER: c_int = 1;
+static X509_V_OK: c_long = 0;
+
+#[link(name = "ssl")]
+#[link(name = "crypto")]
+extern {
+  fn SSL_library_init() -> c_int;
+  fn SSLv23_client_method() -> *const c_void;
+  fn SSL_CTX_new(method: *const c_void) -> *mut c_void;
+  fn SSL_CTX_free(ctx: *mut c_void);
+  fn SSL_CTX_set_default_verify_paths(ctx: *mut c_void) -> c_int;
+  fn SSL_CTX_set_verify(ctx: *mut c_void, mode: c_int, callback: *const c_void);
+  fn SSL_ctrl(ssl: *mut c_void, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+  fn SSL_get_verify_result(ssl: *const c_void) -> c_long;
+  fn SSL_get_peer_certificate(ssl: *const c_void) -> *mut c_void;
+
+  fn BIO_new_ssl_connect(ctx: *mut c_void) -> *mut c_void;
+  fn BIO_ctrl(bio: *mut c_void, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+  fn BIO_read(bio: *mut c_void, buf: *mut c_void, len: c_int) -> c_int;
+  fn BIO_write(bio: *mut c_void, buf: *const c_void, len: c_int) -> c_int;
+  fn BIO_free_all(bio: *mut c_void);
+
+  fn d2i_X509(out: *mut *mut c_void, data: *mut *const c_uchar, len: c_long) -> *mut c_void;
+  fn X509_free(x509: *mut c_void);
+  fn X509_check_host(x509: *mut c_void, name: *const c_char, namelen: size_t,
+                     flags: c_uint, peername: *mut *mut c_char) -> c_int;
+  fn X509_get_pubkey(x509: *mut c_void) -> *mut c_void;
+  fn X509_get_subject_name(x509: *mut c_void) -> *mut c_void;
+  fn X509_NAME_oneline(name: *mut c_void, buf: *mut c_char, size: c_int) -> *mut c_char;
+  fn X509_STORE_new() -> *mut c_void;
+  fn X509_STORE_free(store: *mut c_void);
+  fn X509_STORE_set_default_paths(store: *mut c_void) -> c_int;
+  fn X509_STORE_CTX_new() -> *mut c_void;
+  fn X509_STORE_CTX_free(ctx: *mut c_void);
+  fn X509_STORE_CTX_init(ctx: *mut c_void, store: *mut c_void, x509: *mut c_void,
+                         chain: *mut c_void) -> c_int;
+  fn X509_verify_cert(ctx: *mut c_void) -> c_int;
+  fn sk_new_null() -> *mut c_void;
+  fn sk_push(stack: *mut c_void, data: *mut c_void) -> c_int;
+  fn sk_free(stack: *mut c_void);
+
+  fn EVP_PKEY_free(pkey: *mut c_void);
+  fn EVP_sha1() -> *const c_void;
+  fn EVP_sha256() -> *const c_void;
+  fn EVP_MD_CTX_create() -> *mut c_void;
+  fn EVP_MD_CTX_destroy(ctx: *mut c_void);
+  fn EVP_DigestVerifyInit(ctx: *mut c_void, pctx: *mut *mut c_void, md: *const c_void,
+                          engine: *mut c_void, pkey: *mut c_void) -> c_int;
+  fn EVP_DigestUpdate(ctx: *mut c_void, data: *const c_void, len: size_t) -> c_int;
+  fn EVP_DigestVerifyFinal(ctx: *mut c_void, sig: *const c_uchar, siglen: size_t) -> c_int;
+}
+
+static INIT: Once = ONCE_INIT;
+
+/// Initializes OpenSSL, the first time it is called
+fn init() {
+  INIT.doit(|| unsafe { SSL_library_init(); });
+}
+
+/// Creates an error for a failed TLS or X.509 operation
+fn tls_error(desc: &'static str, detail: Option<String>) -> IoError {
+  IoError { kind: OtherIoError, desc: desc, detail: detail }
+}
+
+/// Frees an OpenSSL object when it goes out of scope
+struct Owned {
+  ptr: *mut c_void,
+  free: unsafe extern "C" fn(*mut c_void)
+}
+
+impl Owned {
+  fn new(ptr: *mut c_void, free: unsafe extern "C" fn(*mut c_void)) -> Option<Owned> {
+    if ptr.is_null() { None } else { Some(Owned { ptr: ptr, free: free }) }
+  }
+}
+
+impl Drop for Owned {
+  fn drop(&mut self) {
+    unsafe { (self.free)(self.ptr); }
+  }
+}
+
+/// A TLS connection to a server whose certificate has been checked
+pub struct TlsStream {
+  // Dropped in order: the connection before the context it was made with
+  bio: Owned,
+  _ctx: Owned
+}
+
+impl TlsStream {
+  /// Connects to `host`, checking that its certificate chains to a root
+  /// the system trusts and is for `host`. Reads and writes give up after
+  /// `timeout` ms.
+  pub fn connect(host: &str, port: u16, timeout: u64) -> IoResult<TlsStream> {
+    init();
+    let host_c = host.to_c_str();
+    let addr_c = format!("{}:{}", host, port).to_c_str();
+    unsafe {
+      let ctx = match Owned::new(SSL_CTX_new(SSLv23_client_method()), SSL_CTX_free) {
+        Some(ctx) => ctx,
+        None => { return Err(tls_error("Could not create TLS context", None)); }
+      };
+      if SSL_CTX_set_default_verify_paths(ctx.ptr) != 1 {
+        return Err(tls_error("Could not load the system's trusted certificates", None));
+      }
+      SSL_CTX_set_verify(ctx.ptr, SSL_VERIFY_PEER, ptr::null());
+      let bio = match Owned::new(BIO_new_ssl_connect(ctx.ptr), BIO_free_all) {
+        Some(bio) => bio,
+        None => { return Err(tls_error("Could not create TLS connection", None)); }
+      };
+      let mut ssl: *mut c_void = ptr::null_mut();
+      BIO_ctrl(bio.ptr, BIO_C_GET_SSL, 0, &mut ssl as *mut *mut c_void as *mut c_void);
+      // Send the hostname, for servers hosting several
+      SSL_ctrl(ssl, SSL_CTRL_SET_TLSEXT_HOSTNAME, TLSEXT_NAMETYPE_HOST_NAME,
+               host_c.as_ptr() as *mut c_void);
+      BIO_ctrl(bio.ptr, BIO_C_SET_CONNECT, 0, addr_c.as_ptr() as *mut c_void);
+      if BIO_ctrl(bio.ptr, BIO_C_DO_STATE_MACHINE, 0, ptr::null_mut()) != 1 {
+        return Err(tls_error("TLS connection failed", Some(format!("{}:{}", host, port))));
+      }
+
+      if SSL_get_verify_result(ssl as *const c_void) != X509_V_OK {
+        return Err(tls_error("Server certificate is not trusted", Some(host.to_string())));
+      }
+      let cert = match Owned::new(SSL_get_peer_certificate(ssl as *const c_void), X509_free) {
+        Some(cert) => cert,
+        None => { return Err(tls_error("Server sent no certificate", Some(host.to_string()))); }
+      };
+      if X509_check_host(cert.ptr, host_c.as_ptr(), host.len() as size_t, 0,
+                         ptr::null_mut()) != 1 {
+        return Err(tls_error("Server certificate is not for this host", Some(host.to_string())));
+      }
+
+      let mut fd: c_int = -1;
+      BIO_ctrl(bio.ptr, BIO_C_GET_FD, 0, &mut fd as *mut c_int as *mut c_void);
+      let tv = timeval {
+        tv_sec: (timeout / 1000) as time_t,
+        tv_usec: ((timeout % 1000) * 1000) as suseconds_t
+      };
+      for &opt in [SO_RCVTIMEO, SO_SNDTIMEO].iter() {
+        setsockopt(fd, SOL_SOCKET, opt, &tv as *const timeval as *const c_void,
+                   mem::size_of::<timeval>() as socklen_t);
+      }
+      Ok(TlsStream { bio: bio, _ctx: ctx })
+    }
+  }
+}
+
+impl Reader for TlsStream {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    let n = unsafe { BIO_read(self.bio.ptr, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int) };
+    if n > 0 {
+      Ok(n as uint)
+    } else if n == 0 {
+      Err(standard_error(EndOfFile))
+    } else {
+      Err(tls_error("TLS read failed", None))
+    }
+  }
+}
+
+impl Writer for TlsStream {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    let mut written = 0;
+    while written < buf.len() {
+      let rest = buf.slice_from(written);
+      let n = unsafe {
+        BIO_write(self.bio.ptr, rest.as_ptr() as *const c_void, rest.len() as c_int)
+      };
+      if n <= 0 {
+        return Err(tls_error("TLS write failed", None));
+      }
+      written += n as uint;
+    }
+    Ok(())
+  }
+}
+
+/// Hash functions which a certificate signature may use
+pub enum SignatureHash {
+  /// SHA1
+  Sha1,
+  /// SHA256
+  Sha256
+}
+
+/// Checks that `certs`, DER-encoded with the signer's first, chain to a
+/// root the system trusts, and that `signature` is the signer's signature
+/// of `data`. Returns the signer's subject name.
+pub fn verify_signature(certs: &[Vec<u8>], hash: SignatureHash, data: &[u8], signature: &[u8])
+                       -> IoResult<String> {
+  init();
+  if certs.is_empty() {
+    return Err(tls_error("No certificates given", None));
+  }
+  unsafe {
+    let mut parsed = Vec::with_capacity(certs.len());
+    for cert in certs.iter() {
+      let mut p = cert.as_ptr();
+      match Owned::new(d2i_X509(ptr::null_mut(), &mut p, cert.len() as c_long), X509_free) {
+        Some(x509) => parsed.push(x509),
+        None => { return Err(tls_error("Malformed certificate", None)); }
+      }
+    }
+    let leaf = parsed[0].ptr;
+
+    // Check the chain
+    let store = match Owned::new(X509_STORE_new(), X509_STORE_free) {
+      Some(store) => store,
+      None => { return Err(tls_error("Could not create certificate store", None)); }
+    };
+    if X509_STORE_set_default_paths(store.ptr) != 1 {
+      return Err(tls_error("Could not load the system's trusted certificates", None));
+    }
+    // The stack does not own the certificates, so is freed with `sk_free`
+    let chain = match Owned::new(sk_new_null(), sk_free) {
+      Some(chain) => chain,
+      None => { return Err(tls_error("Could not create certificate chain", None)); }
+    };
+    for cert in parsed.iter().skip(1) {
+      sk_push(chain.ptr, cert.ptr);
+    }
+    let store_ctx = match Owned::new(X509_STORE_CTX_new(), X509_STORE_CTX_free) {
+      Some(ctx) => ctx,
+      None => { return Err(tls_error("Could not create certificate store", None)); }
+    };
+    if X509_STORE_CTX_init(store_ctx.ptr, store.ptr, leaf, chain.ptr) != 1 ||
+       X509_verify_cert(store_ctx.ptr) != 1 {
+      return Err(tls_error("Certificate chain is not trusted", None));
+    }
+
+    // Check the signature
+    let pkey = match Owned::new(X509_get_pubkey(leaf), EVP_PKEY_free) {
+      Some(pkey) => pkey,
+      None => { return Err(tls_error("Certificate has no usable public key", None)); }
+    };
+    let md = match hash { Sha1 => EVP_sha1(), Sha256 => EVP_sha256() };
+    let md_ctx = match Owned::new(EVP_MD_CTX_create(), EVP_MD_CTX_destroy) {
+      Some(ctx) => ctx,
+      None => { return Err(tls_error("Could not create digest context", None)); }
+    };
+    if EVP_DigestVerifyInit(md_ctx.ptr, ptr::null_mut(), md, ptr::null_mut(), pkey.ptr) != 1 ||
+       EVP_DigestUpdate(md_ctx.ptr, data.as_ptr() as *const c_void, data.len() as size_t) != 1 ||
+       EVP_DigestVerifyFinal(md_ctx.ptr, signature.as_ptr(), signature.len() as size_t) != 1 {
+      return Err(tls_error("Signature does not verify", None));
+    }
+
+    let mut name = Vec::from_elem(256, 0u8);
+    X509_NAME_oneline(X509_get_subject_name(leaf), name.as_mut_ptr() as *mut c_char,
+                      name.len() as c_int);
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(from_utf8(name.slice_to(len)).unwrap_or("").to_string())
+  }
+}
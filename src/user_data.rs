@@ -17,18 +17,30 @@
 //! Functions for storing and reading data from disk are here
 //!
 
-use std::collections::HashMap;
-use std::io::{File, IoResult, IoError, InvalidInput, FileNotFound};
+use std::collections::{HashMap, TreeMap};
+use std::default::Default;
+use std::fmt;
+use std::io::{File, IoResult, IoError, InvalidInput, FileNotFound, UserDir};
+use std::io::fs;
 use std::path::posix::Path;
 use std::str::from_utf8;
 use std::vec::MoveItems;
 use serialize::Decoder;
+use serialize::json;
+use serialize::json::ToJson;
 
 use xdg;
 
 use bitcoin::network::constants::{Network, Bitcoin, BitcoinTestnet};
+use bitcoin::util::base58::FromBase58;
+use bitcoin::wallet::address::Address;
 
 use bitcoind::{DebugLevel, Status};
+use coinjoin::server::SessionOptions;
+use config_schema;
+use logging::{LogConfig, LogFormat, Text};
+use rpc_auth::read_secrets;
+use wallet::{FeePolicy, Economic};
 
 /// Returns the path to the user's configuration file on disk
 pub fn config_path() -> Path {
@@ -36,6 +48,18 @@ pub fn config_path() -> Path {
   dirs.want_write_config("wizards-wallet/wizards-wallet.conf")
 }
 
+/// Returns the path to the pid file written when running as a daemon
+pub fn pid_path() -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_cache("wizards-wallet/wizards-wallet.pid")
+}
+
+/// Returns the path to the log file written when running as a daemon
+pub fn log_path() -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_cache("wizards-wallet/wizards-wallet.log")
+}
+
 /// Returns the default path to the blockchain file on disk
 fn blockchain_path(network: Network) -> Path {
   let dirs = xdg::XdgDirs::new();
@@ -54,6 +78,21 @@ fn utxo_set_path(network: Network) -> Path {
   }
 }
 
+/// Returns the default path to the journal of changes to the blockchain
+/// and UTXO set since they were last saved in full
+fn chain_journal_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_cache(format!("wizards-wallet/journal.{}.dat",
+                                network_name(network)).as_slice())
+}
+
+/// Returns the default path to the saved fee estimator state
+fn fee_estimates_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_cache(format!("wizards-wallet/fee-estimates.{}.toml",
+                                network_name(network)).as_slice())
+}
+
 /// Returns the default path to the user's wallet file on disk
 fn wallet_path(network: Network) -> Path {
   let dirs = xdg::XdgDirs::new();
@@ -63,47 +102,419 @@ fn wallet_path(network: Network) -> Path {
   }
 }
 
+/// Returns the default path to the user's wallet metadata file on disk
+fn wallet_meta_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  match network {
+    Bitcoin => dirs.want_write_config("wizards-wallet/wallet-meta.bitcoin.toml"),
+    BitcoinTestnet => dirs.want_write_config("wizards-wallet/wallet-meta.testnet.toml")
+  }
+}
+
+/// Returns the short name of a network, as used in filenames
+fn network_name(network: Network) -> &'static str {
+  match network {
+    Bitcoin => "bitcoin",
+    BitcoinTestnet => "testnet"
+  }
+}
+
+/// Parses the short name of a network, as returned by `network_name`
+pub fn network_from_name(name: &str) -> Option<Network> {
+  match name {
+    "bitcoin" => Some(Bitcoin),
+    "testnet" => Some(BitcoinTestnet),
+    _ => None
+  }
+}
+
+/// Returns the default port for a network's RPC server
+fn rpc_server_port(network: Network) -> u16 {
+  use constants::{DEFAULT_RPC_SERVER_PORT, DEFAULT_TESTNET_RPC_SERVER_PORT};
+  match network {
+    Bitcoin => DEFAULT_RPC_SERVER_PORT,
+    BitcoinTestnet => DEFAULT_TESTNET_RPC_SERVER_PORT
+  }
+}
+
+/// Returns the default path to a named (non-default) wallet file on disk
+fn named_wallet_path(network: Network, name: &str) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_config(format!("wizards-wallet/wallet.{}.{}.toml",
+                                 network_name(network), name).as_slice())
+}
+
+/// Returns the default path to a named (non-default) wallet's metadata file on disk
+fn named_wallet_meta_path(network: Network, name: &str) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_config(format!("wizards-wallet/wallet-meta.{}.{}.toml",
+                                 network_name(network), name).as_slice())
+}
+
+/// Returns the default path to the RPC cookie file, used for authentication
+/// when no RPC user and password are configured
+fn rpc_cookie_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_data(format!("wizards-wallet/rpc.{}.cookie", network_name(network)).as_slice())
+}
+
+/// Returns the default path to a network's log file
+fn log_file_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_data(format!("wizards-wallet/debug.{}.log", network_name(network)).as_slice())
+}
+
+/// Returns the default directory for rotating wallet backups
+fn wallet_backup_dir() -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_data("wizards-wallet/backups")
+}
+
+/// Moves a default path into the data directory, if there is one
+fn in_datadir(datadir: &Option<Path>, default: Path) -> Path {
+  match (datadir, default.filename()) {
+    (&Some(ref dir), Some(name)) => dir.join(name),
+    _ => default.clone()
+  }
+}
+
+/// A coinjoin session which the server reopens whenever the previous
+/// session for the same target value has finished
+#[deriving(Clone, PartialEq, Decodable)]
+pub struct ScheduledSession {
+  /// Target output value, in satoshi
+  pub target: u64,
+  /// Time, in s, to collect unsigned transactions, if not the coinjoin
+  /// section's default
+  pub join_duration: Option<i64>,
+  /// Time, in s, to wait for signatures, if not the coinjoin section's
+  /// default
+  pub expiry_duration: Option<i64>,
+  /// Minimum time, in s, between the starts of successive sessions
+  pub interval: Option<i64>,
+  /// Options for each session
+  pub options: Option<SessionOptions>
+}
+
+/// Settings for the coinjoin server, which `coinjoin_start` and scheduled
+/// sessions fall back on
+#[deriving(Clone, PartialEq)]
+pub struct CoinjoinConfig {
+  /// Whether to operate a coinjoin server as part of RPC
+  pub enabled: bool,
+  /// Target values, in satoshi, which coinjoin sessions may use; if empty,
+  /// any value is allowed
+  pub denominations: Vec<u64>,
+  /// Time, in s, for sessions to collect unsigned transactions
+  pub join_duration: i64,
+  /// Time, in s, for sessions to wait for signatures
+  pub expiry_duration: i64,
+  /// Fee policy for the donation sessions ask of joiners; the active
+  /// wallet's if not set
+  pub fee_policy: Option<FeePolicy>,
+  /// Address to take donations at; a fresh wallet address per session if
+  /// not set
+  pub donation_address: Option<Address>,
+  /// Options for every session, where the session's own do not say
+  pub options: SessionOptions,
+  /// Sessions to keep running without manual `coinjoin_start` calls
+  pub schedule: Vec<ScheduledSession>
+}
+
+impl CoinjoinConfig {
+  /// The settings of a network with no coinjoin configuration
+  fn default() -> CoinjoinConfig {
+    use constants::{DEFAULT_COINJOIN_JOIN_DURATION, DEFAULT_COINJOIN_EXPIRY_DURATION};
+    CoinjoinConfig {
+      enabled: false,
+      denominations: vec![],
+      join_duration: DEFAULT_COINJOIN_JOIN_DURATION,
+      expiry_duration: DEFAULT_COINJOIN_EXPIRY_DURATION,
+      fee_policy: None,
+      donation_address: None,
+      options: Default::default(),
+      schedule: vec![]
+    }
+  }
+
+  /// Reads the `coinjoin` table, falling back on the older `coinjoin_on`,
+  /// `coinjoin_denominations` and `coinjoin_schedule` keys
+  fn from_toml(network: Network, toml: Option<TomlCoinjoinConfig>, old_on: Option<bool>,
+               old_denominations: Option<Vec<u64>>, old_schedule: Option<Vec<ScheduledSession>>)
+              -> IoResult<CoinjoinConfig> {
+    let default = CoinjoinConfig::default();
+    let toml = toml.unwrap_or(TomlCoinjoinConfig {
+      enabled: None, denominations: None, join_duration: None, expiry_duration: None,
+      fee_policy: None, donation_address: None, options: None, schedule: None
+    });
+    let donation_address = match toml.donation_address {
+      Some(s) => match FromBase58::from_base58check(s.as_slice()) {
+        Ok(addr) => {
+          let addr: Address = addr;
+          if addr.network != network {
+            return Err(IoError {
+              kind: InvalidInput,
+              desc: "Coinjoin donation address is for the wrong network",
+              detail: Some(format!("{}: {}", network, s))
+            });
+          }
+          Some(addr)
+        }
+        Err(_) => {
+          return Err(IoError {
+            kind: InvalidInput,
+            desc: "Invalid coinjoin donation address",
+            detail: Some(format!("{}: {}", network, s))
+          });
+        }
+      },
+      None => None
+    };
+    Ok(CoinjoinConfig {
+      enabled: toml.enabled.or(old_on).unwrap_or(default.enabled),
+      denominations: toml.denominations.or(old_denominations).unwrap_or(default.denominations),
+      join_duration: toml.join_duration.unwrap_or(default.join_duration),
+      expiry_duration: toml.expiry_duration.unwrap_or(default.expiry_duration),
+      fee_policy: toml.fee_policy,
+      donation_address: donation_address,
+      options: toml.options.unwrap_or(default.options),
+      schedule: toml.schedule.or(old_schedule).unwrap_or(default.schedule)
+    })
+  }
+}
+
+/// Time, in s, between runs of each periodic task. A period of 0 disables
+/// the task.
+#[deriving(Clone, PartialEq)]
+pub struct TaskPeriods {
+  /// Syncing with the peer and saving to disk
+  pub save: i64,
+  /// Dropping the peer for a fresh connection
+  pub peer_rotation: i64,
+  /// Starting scheduled coinjoin sessions
+  pub coinjoin_schedule: i64,
+  /// Forgetting expired mempool transactions
+  pub mempool_expiry: i64,
+  /// Writing fee estimator statistics to disk
+  pub fee_estimates: i64
+}
+
+impl TaskPeriods {
+  /// Returns the default periods, with any given ones substituted
+  fn from_toml(toml: Option<TomlTaskPeriods>) -> TaskPeriods {
+    use constants::{COINJOIN_SCHEDULE_FREQUENCY, FEE_ESTIMATES_FLUSH_FREQUENCY};
+    use constants::{DEFAULT_SAVE_FREQUENCY, MEMPOOL_EXPIRY_FREQUENCY, PEER_ROTATION_FREQUENCY};
+
+    let toml = toml.unwrap_or(TomlTaskPeriods {
+      save: None, peer_rotation: None, coinjoin_schedule: None,
+      mempool_expiry: None, fee_estimates: None
+    });
+    TaskPeriods {
+      save: toml.save.unwrap_or(DEFAULT_SAVE_FREQUENCY),
+      peer_rotation: toml.peer_rotation.unwrap_or(PEER_ROTATION_FREQUENCY),
+      coinjoin_schedule: toml.coinjoin_schedule.unwrap_or(COINJOIN_SCHEDULE_FREQUENCY),
+      mempool_expiry: toml.mempool_expiry.unwrap_or(MEMPOOL_EXPIRY_FREQUENCY),
+      fee_estimates: toml.fee_estimates.unwrap_or(FEE_ESTIMATES_FLUSH_FREQUENCY)
+    }
+  }
+}
+
+/// The address of a peer to connect to
+#[deriving(Clone, PartialEq)]
+pub struct PeerAddress {
+  /// Host name or IP address
+  pub host: String,
+  /// Port
+  pub port: u16
+}
+
+impl PeerAddress {
+  /// Parses `host` or `host:port`, using `default_port` for the former
+  pub fn parse(s: &str, default_port: u16) -> Option<PeerAddress> {
+    match s.rfind(':') {
+      Some(n) => from_str::<u16>(s.slice_from(n + 1)).map(|port| {
+        PeerAddress { host: s.slice_to(n).to_string(), port: port }
+      }),
+      None => Some(PeerAddress { host: s.to_string(), port: default_port })
+    }
+  }
+}
+
+impl fmt::Show for PeerAddress {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.host, self.port)
+  }
+}
+
+/// Configuration for a single wallet
+#[deriving(Clone, PartialEq)]
+pub struct WalletConfig {
+  /// Name by which RPC calls select the wallet
+  pub name: String,
+  /// Path to the wallet
+  pub path: Path,
+  /// Path to the wallet metadata (output locks, transactions, etc.)
+  pub meta_path: Path
+}
+
 /// User's global program configuration for a specific network
-#[deriving(Clone)]
+#[deriving(Clone, PartialEq)]
 pub struct NetworkConfig {
   /// The network this configuration is for
   pub network: Network,
-  /// Address to connect to the network peer on
-  pub peer_addr: String,
-  /// Port to connect to the network peer on
-  pub peer_port: u16,
+  /// Whether to run this network, unless it is chosen on the command line
+  pub enabled: bool,
+  /// Peers to connect to, in order of preference. We use one at a time,
+  /// moving on to the next when it fails or stalls.
+  pub peers: Vec<PeerAddress>,
   /// Address to listen for RPC requests on
   pub rpc_server_addr: String,
   /// Port to listen for RPC requests on
   pub rpc_server_port: u16,
-  /// Whether to operate a coinjoin server as part of RPC
-  pub coinjoin_on: bool,
+  /// Username for HTTP Basic authentication of RPC requests
+  pub rpc_user: Option<String>,
+  /// Password for HTTP Basic authentication of RPC requests
+  pub rpc_password: Option<String>,
+  /// File, readable only by the user, which the RPC user and password were
+  /// read from, if they were not in the configuration file itself
+  pub credentials_file: Option<Path>,
+  /// File to write randomly generated RPC credentials to, if no user and
+  /// password are configured
+  pub rpc_cookie_path: Path,
+  /// Maximum number of RPC requests per minute from a single IP, if any
+  pub rpc_rate_limit: Option<uint>,
+  /// Maximum number of RPC requests to handle at once
+  pub rpc_max_concurrent: uint,
+  /// Number of worker tasks which handle RPC calls off the idle loop
+  pub rpc_workers: uint,
+  /// Maximum total size, in bytes, of transactions in the mempool
+  pub mempool_max_size: uint,
+  /// Memory, in bytes, which UTXO sync may fill with downloaded blocks
+  pub utxo_sync_memory: uint,
+  /// The most blocks UTXO sync may have requested at once
+  pub utxo_sync_n_blocks: uint,
+  /// Number of recent blocks whose full data is kept, to follow reorgs;
+  /// only used when starting a new UTXO set, which records its own
+  pub blockchain_n_full_blocks: uint,
+  /// Coinjoin server settings
+  pub coinjoin: CoinjoinConfig,
   /// Whether to allow wallet commands over RPC
   pub wallet_rpc: bool,
+  /// Whether to index the UTXO set by address, for the address RPCs
+  pub address_index: bool,
+  /// Port to serve the Electrum protocol on, from the address index, if any
+  pub electrum_port: Option<u16>,
   /// Path to the on-disk blockchain cache
   pub blockchain_path: Path,
   /// Path to the on-disk UTXO set cache
   pub utxo_set_path: Path,
-  /// Path to the user's wallet
-  pub wallet_path: Path,
+  /// Path to the journal of changes since the blockchain and UTXO set
+  /// caches were last written in full
+  pub chain_journal_path: Path,
+  /// Path to the on-disk fee estimator state
+  pub fee_estimates_path: Path,
+  /// The user's wallets; the first is the default wallet
+  pub wallets: Vec<WalletConfig>,
+  /// Directory in which to keep rotating backups of wallets and their metadata
+  pub wallet_backup_dir: Path,
+  /// Number of rotating wallet backups to keep; 0 to disable
+  pub wallet_backup_count: uint,
+  /// Fee policy for wallets which do not set their own
+  pub fee_policy: FeePolicy,
+  /// Whether to skip over addresses which have already received funds,
+  /// rather than just warning about them
+  pub refuse_address_reuse: bool,
+  /// Shell command to run when the chain tip changes; `%s` is replaced
+  /// by the new tip's hash
+  pub block_notify: Option<String>,
+  /// Shell command to run when a wallet transaction is seen or confirmed;
+  /// `%s` is replaced by the txid
+  pub wallet_notify: Option<String>,
+  /// Time between runs of each periodic task
+  pub task_periods: TaskPeriods,
+  /// Where and how to log
+  pub log: LogConfig,
   /// Path to the on-disk UTXO set cache
   pub debug_level: DebugLevel
 }
 
 #[deriving(Decodable)]
 struct TomlNetworkConfig {
+  enabled: Option<bool>,
+  datadir: Option<Path>,
   peer_addr: Option<String>,
   peer_port: Option<u16>,
+  peers: Option<Vec<String>>,
   rpc_server_addr: Option<String>,
   rpc_server_port: Option<u16>,
+  rpc_user: Option<String>,
+  rpc_password: Option<String>,
+  credentials_file: Option<Path>,
+  rpc_cookie_path: Option<Path>,
+  rpc_rate_limit: Option<uint>,
+  rpc_max_concurrent: Option<uint>,
+  rpc_workers: Option<uint>,
+  mempool_max_size: Option<uint>,
+  utxo_sync_memory: Option<uint>,
+  utxo_sync_n_blocks: Option<uint>,
+  blockchain_n_full_blocks: Option<uint>,
   coinjoin_on: Option<bool>,
+  coinjoin_schedule: Option<Vec<ScheduledSession>>,
+  coinjoin_denominations: Option<Vec<u64>>,
+  coinjoin: Option<TomlCoinjoinConfig>,
   wallet_rpc: Option<bool>,
+  address_index: Option<bool>,
+  electrum_port: Option<u16>,
   blockchain_path: Option<Path>,
   utxo_set_path: Option<Path>,
+  chain_journal_path: Option<Path>,
+  fee_estimates_path: Option<Path>,
   wallet_path: Option<Path>,
+  wallet_meta_path: Option<Path>,
+  wallets: Option<HashMap<String, TomlWalletConfig>>,
+  wallet_backup_dir: Option<Path>,
+  wallet_backup_count: Option<uint>,
+  fee_policy: Option<FeePolicy>,
+  refuse_address_reuse: Option<bool>,
+  block_notify: Option<String>,
+  wallet_notify: Option<String>,
+  task_periods: Option<TomlTaskPeriods>,
+  log_to_file: Option<bool>,
+  log_file: Option<Path>,
+  log_max_size: Option<u64>,
+  log_files: Option<uint>,
+  log_stdout: Option<bool>,
+  log_format: Option<LogFormat>,
   debug_level: Option<DebugLevel>
 }
 
+#[deriving(Decodable)]
+struct TomlCoinjoinConfig {
+  enabled: Option<bool>,
+  denominations: Option<Vec<u64>>,
+  join_duration: Option<i64>,
+  expiry_duration: Option<i64>,
+  fee_policy: Option<FeePolicy>,
+  donation_address: Option<String>,
+  options: Option<SessionOptions>,
+  schedule: Option<Vec<ScheduledSession>>
+}
+
+#[deriving(Decodable)]
+struct TomlTaskPeriods {
+  save: Option<i64>,
+  peer_rotation: Option<i64>,
+  coinjoin_schedule: Option<i64>,
+  mempool_expiry: Option<i64>,
+  fee_estimates: Option<i64>
+}
+
+#[deriving(Decodable)]
+struct TomlWalletConfig {
+  path: Option<Path>,
+  meta_path: Option<Path>
+}
+
 /// A list of user configuration for all networks
 pub struct Config(Vec<NetworkConfig>);
 
@@ -117,7 +528,211 @@ impl Config {
   }
 }
 
-fn read_configuration(path: &Path) -> IoResult<Config> {
+/// Which settings changed when the configuration was reloaded
+pub struct ReloadReport {
+  /// Settings which were changed at runtime
+  pub applied: Vec<&'static str>,
+  /// Settings which changed, but only take effect after a restart
+  pub needs_restart: Vec<&'static str>
+}
+
+impl ToJson for ReloadReport {
+  fn to_json(&self) -> json::Json {
+    let applied: Vec<String> = self.applied.iter().map(|s| s.to_string()).collect();
+    let needs_restart: Vec<String> = self.needs_restart.iter().map(|s| s.to_string()).collect();
+    let mut obj = TreeMap::new();
+    obj.insert("applied".to_string(), applied.to_json());
+    obj.insert("restart_required".to_string(), needs_restart.to_json());
+    json::Object(obj)
+  }
+}
+
+// Copies the named fields from the new configuration to the old if they
+// differ, listing them in the report
+macro_rules! reload_fields(
+  ($old:expr, $new:expr, $list:expr, $($field:ident),+) => (
+    $(
+      if $old.$field != $new.$field {
+        $old.$field = $new.$field.clone();
+        $list.push(stringify!($field));
+      }
+    )+
+  )
+)
+
+// Lists the named fields in the report if they differ, without copying them
+macro_rules! restart_fields(
+  ($old:expr, $new:expr, $list:expr, $($field:ident),+) => (
+    $(
+      if $old.$field != $new.$field {
+        $list.push(stringify!($field));
+      }
+    )+
+  )
+)
+
+impl NetworkConfig {
+  /// Takes on those settings of `new` which are safe to change while
+  /// running, and reports which of the others differ and need a restart
+  pub fn reload(&mut self, new: &NetworkConfig) -> ReloadReport {
+    let mut applied = vec![];
+    let mut needs_restart = vec![];
+    reload_fields!(self, new, applied,
+                   debug_level, peers, rpc_user, rpc_password, credentials_file,
+                   coinjoin,
+                   wallet_rpc, fee_policy, refuse_address_reuse, task_periods, utxo_sync_memory,
+                   utxo_sync_n_blocks, log);
+    restart_fields!(self, new, needs_restart,
+                    enabled, rpc_server_addr, rpc_server_port, rpc_cookie_path, rpc_rate_limit,
+                    rpc_max_concurrent, rpc_workers, mempool_max_size, blockchain_n_full_blocks,
+                    blockchain_path, utxo_set_path, chain_journal_path, fee_estimates_path,
+                    wallets, wallet_backup_dir, wallet_backup_count, address_index,
+                    electrum_port, block_notify, wallet_notify);
+    ReloadReport { applied: applied, needs_restart: needs_restart }
+  }
+
+  /// Checks that the settings make sense, returning a description of the
+  /// first which does not
+  pub fn validate(&self) -> Result<(), String> {
+    use constants::{MIN_BLOCKCHAIN_N_FULL_BLOCKS, MIN_SAVE_FREQUENCY};
+
+    if self.utxo_sync_n_blocks == 0 {
+      return Err("utxo_sync_n_blocks must be at least 1".to_string());
+    }
+    if self.blockchain_n_full_blocks < MIN_BLOCKCHAIN_N_FULL_BLOCKS {
+      return Err(format!("blockchain_n_full_blocks must be at least {}, to follow reorgs",
+                         MIN_BLOCKCHAIN_N_FULL_BLOCKS));
+    }
+    if self.task_periods.save != 0 && self.task_periods.save < MIN_SAVE_FREQUENCY {
+      return Err(format!("task_periods.save must be 0 or at least {}s", MIN_SAVE_FREQUENCY));
+    }
+    if self.electrum_port.is_some() && !self.address_index {
+      return Err("electrum_port needs address_index to be enabled".to_string());
+    }
+    try!(self.coinjoin.options.validate()
+             .map_err(|e| format!("coinjoin.options: {}", e)));
+    for sched in self.coinjoin.schedule.iter() {
+      match sched.options {
+        Some(ref options) => {
+          try!(options.or(&self.coinjoin.options).validate()
+                      .map_err(|e| format!("coinjoin.schedule options: {}", e)));
+        }
+        None => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// Moves every file this network reads or writes into `dir`, keeping
+  /// their names, even those given their own paths in the configuration
+  pub fn set_datadir(&mut self, dir: &Path) {
+    fn move_to(path: &mut Path, dir: &Path) {
+      let moved = match path.filename() {
+        Some(name) => dir.join(name),
+        None => { return; }
+      };
+      *path = moved;
+    }
+    move_to(&mut self.rpc_cookie_path, dir);
+    move_to(&mut self.blockchain_path, dir);
+    move_to(&mut self.utxo_set_path, dir);
+    move_to(&mut self.chain_journal_path, dir);
+    move_to(&mut self.fee_estimates_path, dir);
+    move_to(&mut self.wallet_backup_dir, dir);
+    match self.log.path {
+      Some(ref mut path) => move_to(path, dir),
+      None => {}
+    }
+    for wallet in self.wallets.mut_iter() {
+      move_to(&mut wallet.path, dir);
+      move_to(&mut wallet.meta_path, dir);
+    }
+  }
+
+  /// Lists every setting as it will be used, defaults filled in and paths
+  /// resolved, for `--check-config`. The RPC password is not shown.
+  pub fn describe(&self) -> String {
+    fn opt<T: fmt::Show>(value: &Option<T>) -> String {
+      match *value {
+        Some(ref value) => value.to_string(),
+        None => "(none)".to_string()
+      }
+    }
+
+    fn line(out: &mut String, name: &str, value: String) {
+      out.push_str(format!("  {:30} {}\n", name, value).as_slice());
+    }
+
+    let mut ret = format!("[{}]\n", self.network);
+    line(&mut ret, "enabled", self.enabled.to_string());
+    line(&mut ret, "peers", self.peers.to_string());
+    line(&mut ret, "rpc_server", format!("{}:{}", self.rpc_server_addr, self.rpc_server_port));
+    line(&mut ret, "rpc_user", opt(&self.rpc_user));
+    line(&mut ret, "rpc_password", if self.rpc_password.is_some() { "(set)" } else { "(none)" }.to_string());
+    line(&mut ret, "credentials_file", opt(&self.credentials_file.as_ref().map(|p| p.display().to_string())));
+    line(&mut ret, "rpc_cookie_path", self.rpc_cookie_path.display().to_string());
+    line(&mut ret, "rpc_rate_limit", opt(&self.rpc_rate_limit));
+    line(&mut ret, "rpc_max_concurrent", self.rpc_max_concurrent.to_string());
+    line(&mut ret, "rpc_workers", self.rpc_workers.to_string());
+    line(&mut ret, "mempool_max_size", self.mempool_max_size.to_string());
+    line(&mut ret, "utxo_sync_memory", self.utxo_sync_memory.to_string());
+    line(&mut ret, "utxo_sync_n_blocks", self.utxo_sync_n_blocks.to_string());
+    line(&mut ret, "blockchain_n_full_blocks", self.blockchain_n_full_blocks.to_string());
+    line(&mut ret, "blockchain_path", self.blockchain_path.display().to_string());
+    line(&mut ret, "utxo_set_path", self.utxo_set_path.display().to_string());
+    line(&mut ret, "chain_journal_path", self.chain_journal_path.display().to_string());
+    line(&mut ret, "fee_estimates_path", self.fee_estimates_path.display().to_string());
+    line(&mut ret, "wallet_rpc", self.wallet_rpc.to_string());
+    line(&mut ret, "address_index", self.address_index.to_string());
+    line(&mut ret, "electrum_port", opt(&self.electrum_port));
+    line(&mut ret, "fee_policy", self.fee_policy.to_string());
+    line(&mut ret, "refuse_address_reuse", self.refuse_address_reuse.to_string());
+    line(&mut ret, "wallet_backup_dir", self.wallet_backup_dir.display().to_string());
+    line(&mut ret, "wallet_backup_count", self.wallet_backup_count.to_string());
+    for wallet in self.wallets.iter() {
+      line(&mut ret, format!("wallet {}", wallet.name).as_slice(),
+           format!("{} (metadata {})", wallet.path.display(), wallet.meta_path.display()));
+    }
+    line(&mut ret, "block_notify", opt(&self.block_notify));
+    line(&mut ret, "wallet_notify", opt(&self.wallet_notify));
+    line(&mut ret, "debug_level", self.debug_level.to_string());
+    line(&mut ret, "log_file", opt(&self.log.path.as_ref().map(|p| p.display().to_string())));
+    line(&mut ret, "log_max_size", self.log.max_size.to_string());
+    line(&mut ret, "log_files", self.log.keep.to_string());
+    line(&mut ret, "log_stdout", self.log.stdout.to_string());
+    line(&mut ret, "log_format", self.log.format.to_string());
+    line(&mut ret, "task_periods.save", self.task_periods.save.to_string());
+    line(&mut ret, "task_periods.peer_rotation", self.task_periods.peer_rotation.to_string());
+    line(&mut ret, "task_periods.coinjoin_schedule", self.task_periods.coinjoin_schedule.to_string());
+    line(&mut ret, "task_periods.mempool_expiry", self.task_periods.mempool_expiry.to_string());
+    line(&mut ret, "task_periods.fee_estimates", self.task_periods.fee_estimates.to_string());
+    let coinjoin = &self.coinjoin;
+    line(&mut ret, "coinjoin.enabled", coinjoin.enabled.to_string());
+    if coinjoin.enabled {
+      line(&mut ret, "coinjoin.denominations", if coinjoin.denominations.is_empty() {
+        "(any)".to_string()
+      } else {
+        coinjoin.denominations.to_string()
+      });
+      line(&mut ret, "coinjoin.join_duration", coinjoin.join_duration.to_string());
+      line(&mut ret, "coinjoin.expiry_duration", coinjoin.expiry_duration.to_string());
+      line(&mut ret, "coinjoin.fee_policy", opt(&coinjoin.fee_policy));
+      line(&mut ret, "coinjoin.donation_address", opt(&coinjoin.donation_address));
+      for session in coinjoin.schedule.iter() {
+        line(&mut ret, "coinjoin.schedule", format!("target {}, join {}s, expiry {}s, interval {}",
+             session.target,
+             session.join_duration.unwrap_or(coinjoin.join_duration),
+             session.expiry_duration.unwrap_or(coinjoin.expiry_duration),
+             opt(&session.interval)));
+      }
+    }
+    ret
+  }
+}
+
+/// Reads and parses a configuration file. Unlike `load_configuration`, a
+/// missing file is an error rather than a reason to use the defaults.
+pub fn read_configuration(path: &Path) -> IoResult<Config> {
   use serialize::Decodable;
   use toml::{Parser, Decoder, Table};
 
@@ -151,6 +766,25 @@ fn read_configuration(path: &Path) -> IoResult<Config> {
           });
         }
       };
+      // Check the file against the schema, since the decoder ignores
+      // unknown keys and cannot say where a bad value is
+      let mut error_str = String::new();
+      for diagnostic in config_schema::check(contents.as_slice(), &table).iter() {
+        let description = diagnostic.describe(path.display().to_string().as_slice());
+        if diagnostic.is_error {
+          error_str.push_str(format!("{}\n", description).as_slice());
+        } else {
+          println!("{}", description);
+        }
+      }
+      if !error_str.is_empty() {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "Invalid configuration file",
+          detail: Some(error_str)
+        });
+      }
+
       let mut d = Decoder::new(Table(table));
       let res = Decodable::decode(&mut d);
       try!(res.map_err(|err| IoError {
@@ -172,25 +806,157 @@ fn read_configuration(path: &Path) -> IoResult<Config> {
   // filled in by defaults
   let mut ret = Vec::with_capacity(decode.len());
   for (network, toml_config) in decode.move_iter() {
+    use constants::{DEFAULT_LOG_FILES, DEFAULT_LOG_MAX_SIZE};
+    use constants::DEFAULT_MEMPOOL_MAX_SIZE;
     use constants::DEFAULT_PEER_ADDR;
     use constants::DEFAULT_PEER_PORT;
+    use constants::DEFAULT_RPC_MAX_CONCURRENT;
     use constants::DEFAULT_RPC_SERVER_ADDR;
-    use constants::DEFAULT_RPC_SERVER_PORT;
+    use constants::DEFAULT_RPC_WORKERS;
+    use constants::DEFAULT_UTXO_SYNC_MEMORY;
+    use constants::{DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS, DEFAULT_UTXO_SYNC_N_BLOCKS};
+    use constants::DEFAULT_WALLET_BACKUP_COUNT;
+    use constants::DEFAULT_WALLET_NAME;
+
+    // Files which are not given their own paths go in the data directory,
+    // if there is one, rather than their usual places
+    let datadir = toml_config.datadir.clone();
+    match datadir {
+      Some(ref dir) => { try!(fs::mkdir_recursive(dir, UserDir)); }
+      None => {}
+    }
+    let here = |default: Path| in_datadir(&datadir, default);
+
+    // Collect the wallets, with the default one first
+    let mut wallets = vec![];
+    for (name, wconfig) in toml_config.wallets.unwrap_or(HashMap::new()).move_iter() {
+      if name.as_slice() == DEFAULT_WALLET_NAME {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "Wallet name is reserved for the top-level wallet",
+          detail: Some(name)
+        });
+      }
+      wallets.push(WalletConfig {
+        path: wconfig.path.unwrap_or(here(named_wallet_path(network, name.as_slice()))),
+        meta_path: wconfig.meta_path.unwrap_or(here(named_wallet_meta_path(network,
+                                                                       name.as_slice()))),
+        name: name
+      });
+    }
+    wallets.sort_by(|a, b| a.name.cmp(&b.name));
+    wallets.insert(0, WalletConfig {
+      name: DEFAULT_WALLET_NAME.to_string(),
+      path: toml_config.wallet_path.unwrap_or(here(wallet_path(network))),
+      meta_path: toml_config.wallet_meta_path.unwrap_or(here(wallet_meta_path(network)))
+    });
+
+    // The old single-peer keys give the first peer, then come any listed
+    let mut peers = vec![];
+    if toml_config.peer_addr.is_some() || toml_config.peer_port.is_some() {
+      peers.push(PeerAddress {
+        host: toml_config.peer_addr.unwrap_or(DEFAULT_PEER_ADDR.to_string()),
+        port: toml_config.peer_port.unwrap_or(DEFAULT_PEER_PORT)
+      });
+    }
+    for peer in toml_config.peers.unwrap_or(vec![]).iter() {
+      match PeerAddress::parse(peer.as_slice(), DEFAULT_PEER_PORT) {
+        Some(addr) => peers.push(addr),
+        None => {
+          return Err(IoError {
+            kind: InvalidInput,
+            desc: "Invalid peer address",
+            detail: Some(format!("{}: {}", network, peer))
+          });
+        }
+      }
+    }
+    if peers.is_empty() {
+      peers.push(PeerAddress { host: DEFAULT_PEER_ADDR.to_string(), port: DEFAULT_PEER_PORT });
+    }
+
+    // Secrets may be kept in a file of their own, so that this one can be
+    // shared without them
+    let (rpc_user, rpc_password) = match toml_config.credentials_file {
+      Some(ref path) => {
+        if toml_config.rpc_user.is_some() || toml_config.rpc_password.is_some() {
+          return Err(IoError {
+            kind: InvalidInput,
+            desc: "RPC credentials are set both here and in the credentials file",
+            detail: Some(format!("{}: {}", network, path.display()))
+          });
+        }
+        let secrets = try!(read_secrets(path));
+        (secrets.rpc_user, secrets.rpc_password)
+      }
+      None => (toml_config.rpc_user.clone(), toml_config.rpc_password.clone())
+    };
 
     ret.push(NetworkConfig {
       network: network,
-      peer_addr: toml_config.peer_addr.unwrap_or(DEFAULT_PEER_ADDR.to_string()),
-      peer_port: toml_config.peer_port.unwrap_or(DEFAULT_PEER_PORT),
+      enabled: toml_config.enabled.unwrap_or(true),
+      peers: peers,
       rpc_server_addr: toml_config.rpc_server_addr.unwrap_or(DEFAULT_RPC_SERVER_ADDR.to_string()),
-      rpc_server_port: toml_config.rpc_server_port.unwrap_or(DEFAULT_RPC_SERVER_PORT),
-      coinjoin_on: toml_config.coinjoin_on.unwrap_or(false),
+      rpc_server_port: toml_config.rpc_server_port.unwrap_or(rpc_server_port(network)),
+      rpc_user: rpc_user,
+      rpc_password: rpc_password,
+      credentials_file: toml_config.credentials_file,
+      rpc_cookie_path: toml_config.rpc_cookie_path.unwrap_or(here(rpc_cookie_path(network))),
+      rpc_rate_limit: toml_config.rpc_rate_limit,
+      rpc_max_concurrent: toml_config.rpc_max_concurrent.unwrap_or(DEFAULT_RPC_MAX_CONCURRENT),
+      rpc_workers: toml_config.rpc_workers.unwrap_or(DEFAULT_RPC_WORKERS),
+      mempool_max_size: toml_config.mempool_max_size.unwrap_or(DEFAULT_MEMPOOL_MAX_SIZE),
+      utxo_sync_memory: toml_config.utxo_sync_memory.unwrap_or(DEFAULT_UTXO_SYNC_MEMORY),
+      utxo_sync_n_blocks: toml_config.utxo_sync_n_blocks.unwrap_or(DEFAULT_UTXO_SYNC_N_BLOCKS),
+      blockchain_n_full_blocks: toml_config.blockchain_n_full_blocks
+                                           .unwrap_or(DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS),
+      coinjoin: try!(CoinjoinConfig::from_toml(network, toml_config.coinjoin,
+                                               toml_config.coinjoin_on,
+                                               toml_config.coinjoin_denominations,
+                                               toml_config.coinjoin_schedule)),
       wallet_rpc: toml_config.wallet_rpc.unwrap_or(false),
-      blockchain_path: toml_config.blockchain_path.unwrap_or(blockchain_path(network)),
-      utxo_set_path: toml_config.utxo_set_path.unwrap_or(utxo_set_path(network)),
-      wallet_path: toml_config.wallet_path.unwrap_or(wallet_path(network)),
+      address_index: toml_config.address_index.unwrap_or(false),
+      electrum_port: toml_config.electrum_port,
+      blockchain_path: toml_config.blockchain_path.unwrap_or(here(blockchain_path(network))),
+      utxo_set_path: toml_config.utxo_set_path.unwrap_or(here(utxo_set_path(network))),
+      chain_journal_path: toml_config.chain_journal_path
+                                     .unwrap_or(here(chain_journal_path(network))),
+      fee_estimates_path: toml_config.fee_estimates_path
+                                     .unwrap_or(here(fee_estimates_path(network))),
+      wallets: wallets,
+      wallet_backup_dir: toml_config.wallet_backup_dir.unwrap_or(here(wallet_backup_dir())),
+      wallet_backup_count: toml_config.wallet_backup_count.unwrap_or(DEFAULT_WALLET_BACKUP_COUNT),
+      fee_policy: toml_config.fee_policy.unwrap_or(Economic),
+      refuse_address_reuse: toml_config.refuse_address_reuse.unwrap_or(false),
+      block_notify: toml_config.block_notify,
+      wallet_notify: toml_config.wallet_notify,
+      task_periods: TaskPeriods::from_toml(toml_config.task_periods),
+      log: LogConfig {
+        path: if toml_config.log_to_file.unwrap_or(true) {
+          Some(toml_config.log_file.unwrap_or(here(log_file_path(network))))
+        } else {
+          None
+        },
+        max_size: toml_config.log_max_size.unwrap_or(DEFAULT_LOG_MAX_SIZE),
+        keep: toml_config.log_files.unwrap_or(DEFAULT_LOG_FILES),
+        stdout: toml_config.log_stdout.unwrap_or(true),
+        format: toml_config.log_format.unwrap_or(Text)
+      },
       debug_level: toml_config.debug_level.unwrap_or(Status)
     });
   }
+  for config in ret.iter() {
+    match config.validate() {
+      Ok(()) => {}
+      Err(e) => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "Invalid configuration",
+          detail: Some(format!("{}: {}", config.network, e))
+        });
+      }
+    }
+  }
   Ok(Config(ret))
 }
 
@@ -202,25 +968,66 @@ pub fn load_configuration(path: &Path) -> Option<Config> {
     Err(err) => {
       // For file not found, we use the default configuration...
       if err.kind == FileNotFound {
+        use constants::{DEFAULT_LOG_FILES, DEFAULT_LOG_MAX_SIZE};
+        use constants::DEFAULT_MEMPOOL_MAX_SIZE;
         use constants::DEFAULT_PEER_ADDR;
         use constants::DEFAULT_PEER_PORT;
+        use constants::DEFAULT_RPC_MAX_CONCURRENT;
         use constants::DEFAULT_RPC_SERVER_ADDR;
-        use constants::DEFAULT_RPC_SERVER_PORT;
+        use constants::DEFAULT_RPC_WORKERS;
+        use constants::DEFAULT_UTXO_SYNC_MEMORY;
+        use constants::{DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS, DEFAULT_UTXO_SYNC_N_BLOCKS};
+        use constants::DEFAULT_WALLET_BACKUP_COUNT;
+        use constants::DEFAULT_WALLET_NAME;
 
         println!("Did not find {}, using default configuration.", path.display());
 
         Some(Config(vec![
           NetworkConfig {
             network: Bitcoin,
-            peer_addr: DEFAULT_PEER_ADDR.to_string(),
-            peer_port: DEFAULT_PEER_PORT,
+            enabled: true,
+            peers: vec![PeerAddress { host: DEFAULT_PEER_ADDR.to_string(),
+                                      port: DEFAULT_PEER_PORT }],
             rpc_server_addr: DEFAULT_RPC_SERVER_ADDR.to_string(),
-            rpc_server_port: DEFAULT_RPC_SERVER_PORT,
-            coinjoin_on: false,
+            rpc_server_port: rpc_server_port(Bitcoin),
+            rpc_user: None,
+            rpc_password: None,
+            credentials_file: None,
+            rpc_cookie_path: rpc_cookie_path(Bitcoin),
+            rpc_rate_limit: None,
+            rpc_max_concurrent: DEFAULT_RPC_MAX_CONCURRENT,
+            rpc_workers: DEFAULT_RPC_WORKERS,
+            mempool_max_size: DEFAULT_MEMPOOL_MAX_SIZE,
+            utxo_sync_memory: DEFAULT_UTXO_SYNC_MEMORY,
+            utxo_sync_n_blocks: DEFAULT_UTXO_SYNC_N_BLOCKS,
+            blockchain_n_full_blocks: DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS,
+            coinjoin: CoinjoinConfig::default(),
             wallet_rpc: false,
+            address_index: false,
+            electrum_port: None,
             blockchain_path: blockchain_path(Bitcoin),
             utxo_set_path: utxo_set_path(Bitcoin),
-            wallet_path: wallet_path(Bitcoin),
+            chain_journal_path: chain_journal_path(Bitcoin),
+            fee_estimates_path: fee_estimates_path(Bitcoin),
+            wallets: vec![WalletConfig {
+              name: DEFAULT_WALLET_NAME.to_string(),
+              path: wallet_path(Bitcoin),
+              meta_path: wallet_meta_path(Bitcoin)
+            }],
+            wallet_backup_dir: wallet_backup_dir(),
+            wallet_backup_count: DEFAULT_WALLET_BACKUP_COUNT,
+            fee_policy: Economic,
+            refuse_address_reuse: false,
+            block_notify: None,
+            wallet_notify: None,
+            task_periods: TaskPeriods::from_toml(None),
+            log: LogConfig {
+              path: Some(log_file_path(Bitcoin)),
+              max_size: DEFAULT_LOG_MAX_SIZE,
+              keep: DEFAULT_LOG_FILES,
+              stdout: true,
+              format: Text
+            },
             debug_level: Status
           }]))
       }
@@ -233,3 +1040,154 @@ pub fn load_configuration(path: &Path) -> Option<Config> {
   }
 }
 
+
+/// Returns a configuration file which sets nothing, but lists every key
+/// with a description and its default value, for new users to start from.
+/// Testnet is listed, but disabled.
+pub fn default_configuration_text() -> String {
+  use constants::{COINJOIN_SCHEDULE_FREQUENCY, DEFAULT_SAVE_FREQUENCY};
+  use constants::{DEFAULT_COINJOIN_JOIN_DURATION, DEFAULT_COINJOIN_EXPIRY_DURATION};
+  use constants::{DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS, DEFAULT_UTXO_SYNC_N_BLOCKS};
+  use constants::{DEFAULT_LOG_FILES, DEFAULT_LOG_MAX_SIZE};
+  use constants::{DEFAULT_PEER_ADDR, DEFAULT_PEER_PORT};
+  use constants::{DEFAULT_RPC_MAX_CONCURRENT, DEFAULT_RPC_SERVER_ADDR, DEFAULT_RPC_WORKERS};
+  use constants::{FEE_ESTIMATES_FLUSH_FREQUENCY, MEMPOOL_EXPIRY_FREQUENCY};
+  use constants::DEFAULT_MEMPOOL_MAX_SIZE;
+  use constants::PEER_ROTATION_FREQUENCY;
+  use constants::DEFAULT_UTXO_SYNC_MEMORY;
+  use constants::DEFAULT_WALLET_BACKUP_COUNT;
+
+  fn quote(s: &str) -> String { format!("\"{}\"", s.escape_default()) }
+  fn path(p: Path) -> String { quote(p.display().to_string().as_slice()) }
+
+  let mut ret = String::new();
+  ret.push_str("# Configuration for the Wizards' Wallet\n\
+                #\n\
+                # Each network has its own table. Every key is optional, and is shown\n\
+                # commented out with its default value, or an example if it has none.\n");
+  for &network in [Bitcoin, BitcoinTestnet].iter() {
+    let name = network_name(network);
+    let entries: Vec<(&str, &str, String)> = vec![
+      ("Peers to connect to, as \"host\" or \"host:port\", in order of preference. \
+        The older\n# `peer_addr` and `peer_port` keys give a single peer, tried first.",
+       "peers", format!("[{}]", quote(format!("{}:{}", DEFAULT_PEER_ADDR,
+                                               DEFAULT_PEER_PORT).as_slice()))),
+      ("Address and port of the RPC server",
+       "rpc_server_addr", quote(DEFAULT_RPC_SERVER_ADDR)),
+      ("", "rpc_server_port", rpc_server_port(network).to_string()),
+      ("RPC credentials; without them, a random password is written to the cookie file",
+       "rpc_user", quote("wizard")),
+      ("", "rpc_password", quote("correct horse battery staple")),
+      ("Or a file, readable only by you, which sets `rpc_user` and `rpc_password`, so\n\
+        # that this file can be shared without them",
+       "credentials_file", quote("/path/to/credentials.toml")),
+      ("", "rpc_cookie_path", path(rpc_cookie_path(network))),
+      ("RPC calls per second allowed from each client; unlimited if not set",
+       "rpc_rate_limit", "10".to_string()),
+      ("RPC calls handled at once, and worker tasks to handle them on",
+       "rpc_max_concurrent", DEFAULT_RPC_MAX_CONCURRENT.to_string()),
+      ("", "rpc_workers", DEFAULT_RPC_WORKERS.to_string()),
+      ("Size, in bytes, past which the mempool evicts its lowest-fee transactions",
+       "mempool_max_size", DEFAULT_MEMPOOL_MAX_SIZE.to_string()),
+      ("Memory, in bytes, and number of blocks, to use for blocks in flight during UTXO sync",
+       "utxo_sync_memory", DEFAULT_UTXO_SYNC_MEMORY.to_string()),
+      ("", "utxo_sync_n_blocks", DEFAULT_UTXO_SYNC_N_BLOCKS.to_string()),
+      ("Number of recent blocks kept in full, to follow reorgs",
+       "blockchain_n_full_blocks", DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS.to_string()),
+      ("Directory for all of this network's files, except those given their own paths",
+       "datadir", quote("/path/to/datadir")),
+      ("Cache and state files", "blockchain_path", path(blockchain_path(network))),
+      ("", "utxo_set_path", path(utxo_set_path(network))),
+      ("", "chain_journal_path", path(chain_journal_path(network))),
+      ("", "fee_estimates_path", path(fee_estimates_path(network))),
+      ("The default wallet. Further wallets go in `[{network}.wallets.<name>]` tables,\n\
+        # with `path` and `meta_path` keys.",
+       "wallet_path", path(wallet_path(network))),
+      ("", "wallet_meta_path", path(wallet_meta_path(network))),
+      ("Whether the wallet RPCs are enabled", "wallet_rpc", "false".to_string()),
+      ("Whether to index the UTXO set by address, for the getaddress* RPCs, at some memory cost",
+       "address_index", "false".to_string()),
+      ("Port to serve Electrum light clients on, from the address index; off if not set.\n\
+        # It listens on `rpc_server_addr`, without authentication.",
+       "electrum_port", "50001".to_string()),
+      ("Directory for rotating wallet backups, and how many to keep; 0 disables them",
+       "wallet_backup_dir", path(wallet_backup_dir())),
+      ("", "wallet_backup_count", DEFAULT_WALLET_BACKUP_COUNT.to_string()),
+      ("Fee policy: \"economic\", \"fast\" or a number of satoshi per 1000 bytes",
+       "fee_policy", quote("economic")),
+      ("Whether to skip addresses which have already received funds, rather than warn",
+       "refuse_address_reuse", "false".to_string()),
+      ("Shell commands to run on a new tip, or a new or confirmed wallet transaction;\n\
+        # `%s` is replaced by the block hash or txid",
+       "block_notify", quote("echo %s >> blocks.log")),
+      ("", "wallet_notify", quote("echo %s >> transactions.log")),
+      ("Logging: file and rotation, stdout, format (\"text\" or \"json\") and level\n\
+        # (DEBUG, NOTE, STATUS, WARN, ERROR or FATAL)",
+       "log_to_file", "true".to_string()),
+      ("", "log_file", path(log_file_path(network))),
+      ("", "log_max_size", DEFAULT_LOG_MAX_SIZE.to_string()),
+      ("", "log_files", DEFAULT_LOG_FILES.to_string()),
+      ("", "log_stdout", "true".to_string()),
+      ("", "log_format", quote("text")),
+      ("", "debug_level", quote("STATUS"))
+    ];
+
+    ret.push_str(format!("\n[{}]\n", name).as_slice());
+    ret.push_str("# Whether to run this network, unless it is chosen with `--network`\n");
+    if network == Bitcoin {
+      ret.push_str("#enabled = true\n");
+    } else {
+      ret.push_str("enabled = false\n");
+    }
+    for &(ref doc, key, ref value) in entries.iter() {
+      if !doc.is_empty() {
+        let doc = doc.replace("{network}", name);
+        ret.push_str(format!("\n# {}\n", doc).as_slice());
+      }
+      ret.push_str(format!("#{} = {}\n", key, value).as_slice());
+    }
+
+    ret.push_str(format!("\n# Time, in s, between runs of each periodic task; 0 disables a task\n\
+                          #[{}.task_periods]\n", name).as_slice());
+    for &(key, value) in [("save", DEFAULT_SAVE_FREQUENCY),
+                          ("peer_rotation", PEER_ROTATION_FREQUENCY),
+                          ("coinjoin_schedule", COINJOIN_SCHEDULE_FREQUENCY),
+                          ("mempool_expiry", MEMPOOL_EXPIRY_FREQUENCY),
+                          ("fee_estimates", FEE_ESTIMATES_FLUSH_FREQUENCY)].iter() {
+      ret.push_str(format!("#{} = {}\n", key, value).as_slice());
+    }
+
+    ret.push_str(format!("\n# The coinjoin server: whether it runs, the output values it accepts\n\
+                          # (any if empty), and the defaults for `coinjoin_start` and scheduled\n\
+                          # sessions. The fee policy is the active wallet's, and donations go to\n\
+                          # a fresh wallet address, unless set here.\n\
+                          #[{}.coinjoin]\n\
+                          #enabled = false\n\
+                          #denominations = []\n\
+                          #join_duration = {}\n\
+                          #expiry_duration = {}\n\
+                          #fee_policy = \"economic\"\n\
+                          #donation_address = \"\"\n",
+                         name, DEFAULT_COINJOIN_JOIN_DURATION,
+                         DEFAULT_COINJOIN_EXPIRY_DURATION).as_slice());
+    ret.push_str(format!("\n# Options for every session: `blinded`, `allow_inputs_exceed_outputs`,\n\
+                          # `allow_outputs_exceed_inputs`, `min_participants`, `max_participants`\n\
+                          # and `pow_bits`\n\
+                          #[{}.coinjoin.options]\n\
+                          #min_participants = 2\n", name).as_slice());
+    ret.push_str(format!("\n# A session to reopen whenever the last one for its target finishes, at\n\
+                          # most every `interval` seconds; it may also have its own durations and\n\
+                          # an `options` table\n\
+                          #[[{}.coinjoin.schedule]]\n\
+                          #target = 100000000\n\
+                          #interval = 600\n", name).as_slice());
+  }
+  ret
+}
+
+/// Writes the default configuration file to `path`, creating its directory
+pub fn write_default_configuration(path: &Path) -> IoResult<()> {
+  try!(fs::mkdir_recursive(&path.dir_path(), UserDir));
+  let mut file = try!(File::create(path));
+  file.write_str(default_configuration_text().as_slice())
+}
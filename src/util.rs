@@ -0,0 +1,34 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Utilities
+//!
+//! Small helpers needed by several otherwise unrelated modules.
+//!
+
+/// Compares two byte strings in time independent of where they differ,
+/// for secrets such as passwords and signatures, where an early exit
+/// would tell an attacker how much of a guess was right. Only the length
+/// is leaked.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  let mut diff = 0u8;
+  for (x, y) in a.iter().zip(b.iter()) {
+    diff |= *x ^ *y;
+  }
+  diff == 0
+}
+
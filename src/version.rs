@@ -0,0 +1,66 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Version
+//!
+//! Identifies the build, for `--version`, the `getversion` RPC and the
+//! user agent we give peers. The git commit and build date are taken from
+//! the `WIZARDS_WALLET_GIT_COMMIT` and `WIZARDS_WALLET_BUILD_DATE`
+//! environment variables at compile time, if the build sets them, e.g.
+//!
+//!   WIZARDS_WALLET_GIT_COMMIT=$(git rev-parse --short HEAD) \
+//!   WIZARDS_WALLET_BUILD_DATE=$(date -u +%F) cargo build
+//!
+
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::ToJson;
+
+use bitcoin::network::constants::PROTOCOL_VERSION;
+
+/// The semantic version of the wallet
+pub static VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// The git commit the wallet was built from, if known
+pub fn git_commit() -> &'static str {
+  option_env!("WIZARDS_WALLET_GIT_COMMIT").unwrap_or("unknown")
+}
+
+/// The date the wallet was built, if known
+pub fn build_date() -> &'static str {
+  option_env!("WIZARDS_WALLET_BUILD_DATE").unwrap_or("unknown")
+}
+
+/// The user agent sent to peers, in the BIP 14 format
+pub fn user_agent() -> String {
+  format!("/wizards-wallet:{}/", VERSION)
+}
+
+/// A one-line description of the build, as printed by `--version`
+pub fn version_string() -> String {
+  format!("wizards-wallet {} (commit {}, built {}, protocol {})",
+          VERSION, git_commit(), build_date(), PROTOCOL_VERSION)
+}
+
+/// The build description as a JSON object, for the `getversion` RPC
+pub fn to_json() -> json::Json {
+  let mut obj = TreeMap::new();
+  obj.insert("version".to_string(), json::String(VERSION.to_string()));
+  obj.insert("git_commit".to_string(), json::String(git_commit().to_string()));
+  obj.insert("build_date".to_string(), json::String(build_date().to_string()));
+  obj.insert("protocol_version".to_string(), PROTOCOL_VERSION.to_json());
+  obj.insert("user_agent".to_string(), json::String(user_agent()));
+  json::Object(obj)
+}
+
@@ -17,28 +17,780 @@
 //! Functions for storing and reading data from disk are here
 //!
 
+use std::collections::TreeMap;
+use std::default::Default;
+use std::fmt;
+use std::from_str::FromStr;
 use std::io::{FileNotFound, InvalidInput, IoError, OtherIoError, IoResult};
-use std::io::{BufferedReader, BufferedWriter, File, Open, Write};
+use std::io::{BufferedReader, BufferedWriter, File, Open, Write, UserRWX};
+use std::io::fs;
 use std::str;
+use std::sync::{Arc, Mutex, RWLock};
 use std::rand::{mod, Rng};
-use serialize::Decodable;
+use serialize::{json, Decodable, Decoder, Encodable, Encoder};
+use serialize::hex::FromHex;
+use serialize::json::ToJson;
 
+use time;
 use toml;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use secp256k1::Secp256k1;
+use secp256k1::key::{Nonce, PublicKey, SecretKey};
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut, PayToPubkeyHash};
+use bitcoin::blockdata::utxoset::UtxoSet;
+use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize, serialize_hex};
+use bitcoin::util::base58::{FromBase58, ToBase58};
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::wallet::address::Address;
 use bitcoin::wallet::bip32;
-use bitcoin::wallet::wallet::Wallet;
-use bitcoin::network::constants::Network;
+use bitcoin::wallet::wallet::{mod, External, Wallet};
+use bitcoin::network::constants::{Network, Bitcoin, BitcoinTestnet};
 
-use user_data::NetworkConfig;
+use bitcoind::{Debug, Error, Status, Warning};
+use events::{BlockConnected, BlockDisconnected, Chain, EventBus, TxAccepted, TxRejected};
+use events::{WalletConfirmation, WalletTransaction};
+use timelock::Timelock;
+use constants::{ECONOMIC_CONFIRM_TARGET, ECONOMIC_FEE_RATE, FAST_CONFIRM_TARGET, FAST_FEE_RATE};
+use constants::{MAX_ADDRESS_REUSE_SKIP, SAFE_CONFIRMATIONS};
+use fee_estimator::FeeEstimator;
+use script_info;
+use user_data::{NetworkConfig, WalletConfig};
 
-/// Attempts to load a wallet from disk
-pub fn load_wallet(config: &NetworkConfig) -> IoResult<Wallet> {
-  let mut file = BufferedReader::new(try!(File::open(&config.wallet_path)));
+/// The only sighash type we produce when signing with user-supplied keys
+static SIGHASH_ALL: u8 = 1;
+
+/// A reference to a specific transaction output
+#[deriving(Clone, PartialEq, Eq, Show, Encodable, Decodable)]
+pub struct OutPoint {
+  /// The txid of the transaction containing the output
+  pub txid: Sha256dHash,
+  /// The index of the output within its transaction
+  pub vout: u32
+}
+
+impl json::ToJson for OutPoint {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("txid".to_string(), self.txid.to_json());
+    obj.insert("vout".to_string(), self.vout.to_json());
+    json::Object(obj)
+  }
+}
+
+/// How the wallet chooses fees for the transactions it creates
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum FeePolicy {
+  /// A fixed fee rate, in satoshi per 1000 bytes
+  FixedRate(u64),
+  /// A low fee rate, for transactions which are not urgent
+  Economic,
+  /// A high fee rate, for transactions which should confirm quickly
+  Fast
+}
+
+impl FeePolicy {
+  /// The fee rate, in satoshi per 1000 bytes, which this policy calls for
+  /// when there are no fee estimates
+  pub fn rate(&self) -> u64 {
+    match *self {
+      FixedRate(rate) => rate,
+      Economic => ECONOMIC_FEE_RATE,
+      Fast => FAST_FEE_RATE
+    }
+  }
+
+  /// The fee rate, in satoshi per 1000 bytes, which this policy calls for
+  /// given current fee estimates. The economic and fast policies fall back
+  /// to their fixed rates if there are no estimates yet.
+  pub fn estimated_rate(&self, estimator: &FeeEstimator) -> u64 {
+    match *self {
+      FixedRate(rate) => rate,
+      Economic => estimator.estimate(ECONOMIC_CONFIRM_TARGET).unwrap_or(ECONOMIC_FEE_RATE),
+      Fast => estimator.estimate(FAST_CONFIRM_TARGET).unwrap_or(FAST_FEE_RATE)
+    }
+  }
+
+  /// The fee, in satoshi, which this policy calls for on a transaction of
+  /// the given size in bytes
+  pub fn fee_for_size(&self, estimator: &FeeEstimator, size: u64) -> u64 {
+    (self.estimated_rate(estimator) * size + 999) / 1000
+  }
+}
+
+impl FromStr for FeePolicy {
+  fn from_str(s: &str) -> Option<FeePolicy> {
+    match s {
+      "economic" => Some(Economic),
+      "fast" => Some(Fast),
+      s => from_str::<u64>(s).map(FixedRate)
+    }
+  }
+}
+
+impl fmt::Show for FeePolicy {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      FixedRate(rate) => write!(f, "{}", rate),
+      Economic => write!(f, "economic"),
+      Fast => write!(f, "fast")
+    }
+  }
+}
+
+impl<E: Encoder<S>, S> Encodable<E, S> for FeePolicy {
+  fn encode(&self, e: &mut E) -> Result<(), S> {
+    e.emit_str(self.to_string().as_slice())
+  }
+}
+
+impl<D: Decoder<E>, E> Decodable<D, E> for FeePolicy {
+  fn decode(d: &mut D) -> Result<FeePolicy, E> {
+    let st = try!(d.read_str());
+    match from_str(st.as_slice()) {
+      Some(policy) => Ok(policy),
+      None => Err(d.error(format!("Fee policy `{}` is not \"economic\", \"fast\" \
+                                   or a number of satoshi per 1000 bytes", st).as_slice()))
+    }
+  }
+}
+
+impl json::ToJson for FeePolicy {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("policy".to_string(), json::String(self.to_string()));
+    obj.insert("rate".to_string(), self.rate().to_json());
+    json::Object(obj)
+  }
+}
+
+/// A transaction relevant to the wallet, either one we broadcast or one
+/// we received from the network which spends or pays to our outputs
+#[deriving(Clone, Encodable, Decodable)]
+pub struct WalletTx {
+  /// The txid of the transaction
+  pub txid: Sha256dHash,
+  /// Hex-encoded transaction data
+  raw: String,
+  /// The fee paid by the transaction, in satoshi, if known
+  pub fee: Option<u64>,
+  /// The index of the output which pays change back to us, if any
+  pub change_vout: Option<u32>,
+  /// The indices of all outputs which pay to us, including change
+  pub our_vouts: Vec<u32>,
+  /// Time (seconds since the epoch) at which we first saw the transaction
+  pub first_seen: i64,
+  /// Height of the block containing the transaction, or None if unconfirmed
+  pub height: Option<uint>,
+  /// A transaction which double-spends this one, if any has been seen
+  pub conflicted_by: Option<Sha256dHash>
+}
+
+impl WalletTx {
+  /// Constructs a new unconfirmed transaction record
+  pub fn new(tx: &Transaction, fee: Option<u64>, change_vout: Option<u32>,
+             our_vouts: Vec<u32>) -> WalletTx {
+    WalletTx {
+      txid: tx.bitcoin_hash(),
+      raw: serialize_hex(tx).unwrap(),
+      fee: fee,
+      change_vout: change_vout,
+      our_vouts: our_vouts,
+      first_seen: time::get_time().sec,
+      height: None,
+      conflicted_by: None
+    }
+  }
+
+  /// Decodes the stored transaction
+  pub fn transaction(&self) -> IoResult<Transaction> {
+    let raw = try!(self.raw.as_slice().from_hex().map_err(|e| IoError {
+      kind: InvalidInput,
+      desc: "wallet transaction was not valid hex",
+      detail: Some(e.to_string())
+    }));
+    deserialize(raw)
+  }
+
+  /// Whether the transaction has not yet been seen in a block, and has
+  /// not been double-spent
+  pub fn is_pending(&self) -> bool {
+    self.height.is_none() && self.conflicted_by.is_none()
+  }
+
+  /// The number of confirmations given the current tip height
+  pub fn confirmations(&self, tip_height: uint) -> uint {
+    match self.height {
+      Some(h) if h <= tip_height => tip_height + 1 - h,
+      _ => 0
+    }
+  }
+}
+
+impl json::ToJson for WalletTx {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("txid".to_string(), self.txid.to_json());
+    obj.insert("fee".to_string(), self.fee.to_json());
+    obj.insert("time".to_string(), self.first_seen.to_json());
+    obj.insert("height".to_string(), self.height.to_json());
+    obj.insert("conflicted_by".to_string(), self.conflicted_by.to_json());
+    obj.insert("hex".to_string(), json::String(self.raw.clone()));
+    json::Object(obj)
+  }
+}
+
+/// Checks whether a transaction received from the network spends or pays
+/// to the wallet, returning a record of it if so
+pub fn relevant_transaction(wallet: &Wallet, utxo_set: &UtxoSet, tx: &Transaction)
+                            -> Option<WalletTx> {
+  let our_vouts: Vec<u32> = tx.output.iter().enumerate()
+                              .filter(|&(_, out)| wallet.is_mine(&out.script_pubkey))
+                              .map(|(n, _)| n as u32).collect();
+  let ours = wallet.unspent_outputs();
+  let spends_ours = tx.input.iter().any(|i| ours.iter().any(|o| o.txid == i.prev_hash &&
+                                                              o.vout == i.prev_index));
+  if our_vouts.is_empty() && !spends_ours {
+    return None;
+  }
+
+  // We can only compute the fee if all the inputs are known
+  let mut total_in = Some(0);
+  for input in tx.input.iter() {
+    total_in = match (total_in, utxo_set.get_utxo(input.prev_hash, input.prev_index)) {
+      (Some(n), Some((_, out))) => Some(n + out.value),
+      _ => None
+    };
+  }
+  let total_out = tx.output.iter().fold(0, |acc, out| acc + out.value);
+  let fee = total_in.and_then(|n| n.checked_sub(&total_out));
+  Some(WalletTx::new(tx, fee, None, our_vouts))
+}
+
+/// A warning that a transaction seen on the network double-spends one of
+/// our transactions, or spends one of our outputs without our involvement
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Alert {
+  /// Time (seconds since the epoch) at which the alert was raised
+  pub time: i64,
+  /// The offending transaction
+  pub txid: Sha256dHash,
+  /// The output which it spends
+  pub outpoint: OutPoint,
+  /// Our transaction which it conflicts with, if any
+  pub wallet_txid: Option<Sha256dHash>,
+  /// Whether the offending transaction was seen in a block, rather than
+  /// just relayed
+  pub confirmed: bool
+}
+
+impl json::ToJson for Alert {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("time".to_string(), self.time.to_json());
+    obj.insert("txid".to_string(), self.txid.to_json());
+    obj.insert("outpoint".to_string(), self.outpoint.to_json());
+    obj.insert("wallet_txid".to_string(), self.wallet_txid.to_json());
+    obj.insert("confirmed".to_string(), self.confirmed.to_json());
+    json::Object(obj)
+  }
+}
+
+impl fmt::Show for Alert {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.wallet_txid {
+      Some(wtxid) => write!(f, "{}transaction {:x} double-spends {:x}:{} of wallet transaction {:x}",
+                            if self.confirmed { "confirmed " } else { "" },
+                            self.txid, self.outpoint.txid, self.outpoint.vout, wtxid),
+      None => write!(f, "{}transaction {:x} spends wallet output {:x}:{}",
+                     if self.confirmed { "confirmed " } else { "" },
+                     self.txid, self.outpoint.txid, self.outpoint.vout)
+    }
+  }
+}
+
+/// Returns the outpoints of every unspent output owned by the wallet
+pub fn owned_outpoints(wallet: &Wallet) -> Vec<OutPoint> {
+  wallet.unspent_outputs().iter().map(|o| OutPoint { txid: o.txid, vout: o.vout }).collect()
+}
+
+/// Wallet data which is not part of the keychain itself, stored alongside
+/// the wallet in its own file.
+#[deriving(Clone, Default, Encodable, Decodable)]
+pub struct WalletMetadata {
+  locked_outputs: Vec<OutPoint>,
+  transactions: Vec<WalletTx>,
+  fee_policy: Option<FeePolicy>,
+  alerts: Vec<Alert>,
+  used_addresses: Vec<String>,
+  timelocks: Vec<Timelock>
+}
+
+impl WalletMetadata {
+  /// Excludes an output from automatic coin selection. Returns false if
+  /// it was already locked.
+  pub fn lock_output(&mut self, out: OutPoint) -> bool {
+    if self.is_locked(&out) {
+      false
+    } else {
+      self.locked_outputs.push(out);
+      true
+    }
+  }
+
+  /// Makes an output available for coin selection again. Returns false if
+  /// it was not locked.
+  pub fn unlock_output(&mut self, out: &OutPoint) -> bool {
+    let old_len = self.locked_outputs.len();
+    self.locked_outputs.retain(|o| o != out);
+    self.locked_outputs.len() != old_len
+  }
+
+  /// Unlocks every locked output
+  pub fn unlock_all(&mut self) {
+    self.locked_outputs.clear();
+  }
+
+  /// Whether an output is excluded from automatic coin selection
+  pub fn is_locked(&self, out: &OutPoint) -> bool {
+    self.locked_outputs.iter().any(|o| o == out)
+  }
+
+  /// Accessor for the list of locked outputs
+  pub fn locked_outputs<'a>(&'a self) -> &'a [OutPoint] {
+    self.locked_outputs.as_slice()
+  }
+
+  /// The wallet's fee policy, or None to use the network default
+  pub fn fee_policy(&self) -> Option<FeePolicy> {
+    self.fee_policy.clone()
+  }
+
+  /// Sets the wallet's fee policy; None reverts to the network default
+  pub fn set_fee_policy(&mut self, policy: Option<FeePolicy>) {
+    self.fee_policy = policy;
+  }
+
+  /// Records that an address has received funds. Returns false if it was
+  /// already known to have.
+  pub fn mark_address_used(&mut self, addr: &Address) -> bool {
+    if self.is_address_used(addr) {
+      false
+    } else {
+      self.used_addresses.push(addr.to_base58check());
+      true
+    }
+  }
+
+  /// Whether an address is known to have received funds
+  pub fn is_address_used(&self, addr: &Address) -> bool {
+    let addr = addr.to_base58check();
+    self.used_addresses.iter().any(|a| *a == addr)
+  }
+
+  /// Starts watching a new timelock
+  pub fn add_timelock(&mut self, timelock: Timelock) {
+    self.timelocks.push(timelock);
+  }
+
+  /// Accessor for the wallet's timelocks
+  pub fn timelocks<'a>(&'a self) -> &'a [Timelock] {
+    self.timelocks.as_slice()
+  }
+
+  /// Mutable accessor for the wallet's timelocks
+  pub fn timelocks_mut<'a>(&'a mut self) -> &'a mut [Timelock] {
+    self.timelocks.as_mut_slice()
+  }
+
+  /// Updates every timelock with the outputs created and spent by a
+  /// confirmed transaction
+  fn timelocks_tx_confirmed(&mut self, tx: &Transaction) {
+    for timelock in self.timelocks.mut_iter() {
+      timelock.tx_confirmed(tx);
+    }
+  }
+
+  /// Undoes `timelocks_tx_confirmed` for a transaction whose block was
+  /// disconnected
+  fn timelocks_tx_unconfirmed(&mut self, tx: &Transaction) {
+    for timelock in self.timelocks.mut_iter() {
+      timelock.tx_unconfirmed(tx);
+    }
+  }
+
+  /// Records a transaction. Returns false if it was already known.
+  pub fn add_transaction(&mut self, wtx: WalletTx) -> bool {
+    if self.find_transaction(wtx.txid).is_some() {
+      false
+    } else {
+      self.transactions.push(wtx);
+      true
+    }
+  }
+
+  /// Accessor for all recorded transactions, oldest first
+  pub fn transactions<'a>(&'a self) -> &'a [WalletTx] {
+    self.transactions.as_slice()
+  }
+
+  /// Looks up a recorded transaction by txid
+  pub fn find_transaction<'a>(&'a self, txid: Sha256dHash) -> Option<&'a WalletTx> {
+    self.transactions.iter().find(|w| w.txid == txid)
+  }
+
+  /// Looks up an unconfirmed transaction by txid
+  pub fn find_pending<'a>(&'a self, txid: Sha256dHash) -> Option<&'a WalletTx> {
+    self.transactions.iter().find(|w| w.txid == txid && w.is_pending())
+  }
+
+  /// Forgets about a transaction. Returns false if it was not found.
+  pub fn remove_transaction(&mut self, txid: Sha256dHash) -> bool {
+    let old_len = self.transactions.len();
+    self.transactions.retain(|w| w.txid != txid);
+    self.transactions.len() != old_len
+  }
+
+  /// Checks a transaction which is not ours for double-spends of our
+  /// pending transactions or spends of our outputs, given the outpoints
+  /// the wallet owns. Conflicted wallet transactions are marked as such.
+  /// Returns any new alerts, which are also recorded.
+  pub fn check_double_spend(&mut self, tx: &Transaction, owned: &[OutPoint], confirmed: bool)
+                            -> Vec<Alert> {
+    let txid = tx.bitcoin_hash();
+    let now = time::get_time().sec;
+    let mut alerts = vec![];
+    for input in tx.input.iter() {
+      let outpoint = OutPoint { txid: input.prev_hash, vout: input.prev_index };
+      let mut found_wtx = false;
+      for wtx in self.transactions.mut_iter().filter(|w| w.txid != txid && w.height.is_none()) {
+        let conflicts = match wtx.transaction() {
+          Ok(wallet_tx) => wallet_tx.input.iter().any(|i| i.prev_hash == input.prev_hash &&
+                                                          i.prev_index == input.prev_index),
+          Err(_) => false
+        };
+        if conflicts {
+          found_wtx = true;
+          wtx.conflicted_by = Some(txid);
+          alerts.push(Alert { time: now, txid: txid, outpoint: outpoint.clone(),
+                              wallet_txid: Some(wtx.txid), confirmed: confirmed });
+        }
+      }
+      if !found_wtx && owned.contains(&outpoint) {
+        alerts.push(Alert { time: now, txid: txid, outpoint: outpoint,
+                            wallet_txid: None, confirmed: confirmed });
+      }
+    }
+    self.alerts.push_all(alerts.as_slice());
+    alerts
+  }
+
+  /// Accessor for all recorded alerts, oldest first
+  pub fn alerts<'a>(&'a self) -> &'a [Alert] {
+    self.alerts.as_slice()
+  }
+
+  /// Forgets all recorded alerts
+  pub fn clear_alerts(&mut self) {
+    self.alerts.clear();
+  }
+
+  /// Updates transaction records for a newly connected block: our
+  /// transactions in the block are marked confirmed, and others are checked
+  /// for double-spends. Returns any new alerts.
+  pub fn block_connected(&mut self, block: &Block, height: uint, owned: &[OutPoint])
+                         -> Vec<Alert> {
+    let mut alerts = vec![];
+    for tx in block.txdata.iter() {
+      let txid = tx.bitcoin_hash();
+      self.timelocks_tx_confirmed(tx);
+      let known = match self.transactions.mut_iter().find(|w| w.txid == txid) {
+        Some(wtx) => {
+          wtx.height = Some(height);
+          wtx.conflicted_by = None;
+          true
+        }
+        None => false
+      };
+      if !known {
+        alerts.push_all(self.check_double_spend(tx, owned, true).as_slice());
+      }
+    }
+    alerts
+  }
+
+  /// Updates transaction records for a block removed by a reorg, returning
+  /// its transactions to the pending state
+  pub fn block_disconnected(&mut self, block: &Block) {
+    // Later transactions may spend earlier ones, so undo the last first
+    for tx in block.txdata.iter().rev() {
+      self.timelocks_tx_unconfirmed(tx);
+    }
+    for tx in block.txdata.iter() {
+      let txid = tx.bitcoin_hash();
+      match self.transactions.mut_iter().find(|w| w.txid == txid) {
+        Some(wtx) => { wtx.height = None; }
+        None => {}
+      }
+    }
+  }
+
+  /// Drops unconfirmed (including conflicted) transactions first seen more
+  /// than `max_age` seconds before `now`. Returns the number dropped.
+  pub fn expire_pending(&mut self, now: i64, max_age: i64) -> uint {
+    let old_len = self.transactions.len();
+    self.transactions.retain(|w| w.height.is_some() || now - w.first_seen <= max_age);
+    old_len - self.transactions.len()
+  }
+}
+
+/// A wallet balance broken down by confirmation depth. Outputs locked
+/// against coin selection are still counted.
+#[deriving(Clone, PartialEq, Eq, Show, Default)]
+pub struct Balances {
+  /// Value of unconfirmed outputs we have created but not yet seen mined
+  pub unconfirmed: u64,
+  /// Value of outputs with at least one confirmation
+  pub confirmed: u64,
+  /// Value of outputs with at least `SAFE_CONFIRMATIONS` confirmations
+  pub safe: u64,
+  /// Value of time-locked savings outputs whose locks have not expired
+  pub timelocked: u64,
+  /// Value of time-locked savings outputs which may now be spent
+  pub timelock_expired: u64
+}
+
+impl json::ToJson for Balances {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("unconfirmed".to_string(), self.unconfirmed.to_json());
+    obj.insert("confirmed".to_string(), self.confirmed.to_json());
+    obj.insert("safe".to_string(), self.safe.to_json());
+    obj.insert("timelocked".to_string(), self.timelocked.to_json());
+    obj.insert("timelock_expired".to_string(), self.timelock_expired.to_json());
+    json::Object(obj)
+  }
+}
+
+/// Computes the wallet's balance broken down by confirmation depth, given
+/// the height of the current chain tip and its median time past. Outputs
+/// spent by pending transactions are excluded, and outputs of pending
+/// transactions which pay to us are counted as unconfirmed. Time-locked
+/// savings are counted separately, also excluding those being spent.
+pub fn balances(wallet: &Wallet, meta: &WalletMetadata, tip_height: uint,
+                median_time_past: i64) -> Balances {
+  let mut spent = vec![];
+  let mut ret: Balances = Default::default();
+  for pending in meta.transactions.iter().filter(|w| w.is_pending()) {
+    match pending.transaction() {
+      Ok(tx) => {
+        spent.extend(tx.input.iter().map(|i| OutPoint { txid: i.prev_hash,
+                                                         vout: i.prev_index }));
+        // Skip any vout out of range, as from a corrupt metadata file
+        for &n in pending.our_vouts.iter() {
+          match tx.output.as_slice().get(n as uint) {
+            Some(out) => { ret.unconfirmed += out.value; }
+            None => {}
+          }
+        }
+      }
+      Err(_) => {}
+    }
+  }
+
+  for timelock in meta.timelocks.iter() {
+    let value = timelock.outputs.iter().filter(|o| !spent.contains(&o.outpoint))
+                                       .fold(0, |acc, o| acc + o.value);
+    if timelock.is_expired(tip_height, median_time_past) {
+      ret.timelock_expired += value;
+    } else {
+      ret.timelocked += value;
+    }
+  }
+
+  for out in wallet.unspent_outputs().iter() {
+    if spent.contains(&OutPoint { txid: out.txid, vout: out.vout }) {
+      continue;
+    }
+    // An output in the tip block has one confirmation; one above the tip,
+    // which the wallet may see before the blockchain catches up, has none
+    let confs = if out.height <= tip_height { tip_height + 1 - out.height } else { 0 };
+    if confs >= 1 {
+      ret.confirmed += out.txo.value;
+    }
+    if confs >= SAFE_CONFIRMATIONS {
+      ret.safe += out.txo.value;
+    }
+  }
+  ret
+}
+
+/// Lists the wallet's outputs which may be spent: those neither locked nor
+/// spent by a pending transaction, largest first
+pub fn spendable_outputs(wallet: &Wallet, meta: &WalletMetadata) -> Vec<(OutPoint, TxOut)> {
+  let mut spent = vec![];
+  for pending in meta.transactions.iter().filter(|w| w.is_pending()) {
+    match pending.transaction() {
+      Ok(tx) => {
+        spent.extend(tx.input.iter().map(|i| OutPoint { txid: i.prev_hash,
+                                                         vout: i.prev_index }));
+      }
+      Err(_) => {}
+    }
+  }
+  let mut ret: Vec<(OutPoint, TxOut)> = wallet.unspent_outputs().iter()
+    .map(|out| (OutPoint { txid: out.txid, vout: out.vout }, out.txo.clone()))
+    .filter(|&(ref outpoint, _)| !spent.contains(outpoint) && !meta.is_locked(outpoint))
+    .collect();
+  ret.sort_by(|&(_, ref a), &(_, ref b)| b.value.cmp(&a.value));
+  ret
+}
+
+/// Splits an amount into coinjoin denominations, largest first, for
+/// joining one output at a time. Returns the denominated outputs and the
+/// leftover change, or None if this would take more than `max_outputs`
+/// outputs.
+pub fn split_denominations(amount: u64, denominations: &[u64], max_outputs: uint)
+                           -> Option<(Vec<u64>, u64)> {
+  let mut denoms = denominations.to_vec();
+  denoms.sort_by(|a, b| b.cmp(a));
+  denoms.dedup();
+
+  let mut remaining = amount;
+  let mut outputs = vec![];
+  for &denom in denoms.iter().filter(|&&d| d > 0) {
+    let count = remaining / denom;
+    if outputs.len() as u64 + count > max_outputs as u64 {
+      return None;
+    }
+    outputs.grow(count as uint, &denom);
+    remaining -= count * denom;
+  }
+  Some((outputs, remaining))
+}
+
+/// Signs every input of a transaction using the wallet's keys. `prevouts`
+/// must contain the output spent by each input, in order.
+pub fn sign_transaction(wallet: &Wallet, tx: &mut Transaction, prevouts: &[TxOut])
+                        -> Result<(), wallet::Error> {
+  for (n, prevout) in prevouts.iter().enumerate() {
+    try!(wallet.sign_input(tx, n, &prevout.script_pubkey));
+  }
+  Ok(())
+}
+
+/// A private key supplied by the user rather than held by the wallet,
+/// e.g. to `signrawtransaction`
+pub struct PrivateKey {
+  secret: SecretKey,
+  public: PublicKey
+}
+
+impl PrivateKey {
+  /// Decodes a key in wallet import format, checking it is for `network`
+  pub fn from_wif(network: Network, wif: &str) -> Option<PrivateKey> {
+    let data: Vec<u8> = match FromBase58::from_base58check(wif) {
+      Ok(data) => data,
+      Err(_) => { return None; }
+    };
+    let version = match network { Bitcoin => 0x80, BitcoinTestnet => 0xef };
+    let compressed = match data.len() {
+      33 => false,
+      34 if data[33] == 1 => true,
+      _ => { return None; }
+    };
+    if data[0] != version {
+      return None;
+    }
+    match SecretKey::from_slice(data.slice(1, 33)) {
+      Ok(secret) => {
+        let public = PublicKey::from_secret_key(&secret, compressed);
+        Some(PrivateKey { secret: secret, public: public })
+      }
+      Err(_) => None
+    }
+  }
+
+  /// Produces a signature of a 32-byte hash, with an RFC6979 deterministic
+  /// nonce, followed by the SIGHASH_ALL byte
+  fn sign(&self, secp: &Secp256k1, hash: &[u8]) -> Option<Vec<u8>> {
+    let nonce = Nonce::deterministic(hash, &self.secret);
+    match secp.sign(hash, &self.secret, &nonce) {
+      Ok(sig) => {
+        let mut ret = sig.as_slice().to_vec();
+        ret.push(SIGHASH_ALL);
+        Some(ret)
+      }
+      Err(_) => None
+    }
+  }
+}
+
+/// The SIGHASH_ALL signature hash of input `n` of `tx`, which spends an
+/// output with the given script
+fn signature_hash(tx: &Transaction, n: uint, script_pubkey: &Script) -> Vec<u8> {
+  let mut tx = tx.clone();
+  for (i, input) in tx.input.mut_iter().enumerate() {
+    input.script_sig = if i == n { script_pubkey.clone() } else { Script::new() };
+  }
+  let mut data = serialize(&tx).unwrap();
+  data.push_all([SIGHASH_ALL, 0, 0, 0]);
+  let mut ret = Vec::from_elem(32, 0u8);
+  let mut sha = Sha256::new();
+  sha.input(data.as_slice());
+  sha.result(ret.as_mut_slice());
+  sha.reset();
+  sha.input(ret.as_slice());
+  sha.result(ret.as_mut_slice());
+  ret
+}
+
+/// Signs input `n` of `tx`, which spends an output with the given script,
+/// using whichever of `keys` the script pays to. Only pay-to-pubkey and
+/// pay-to-pubkey-hash scripts are supported. Returns whether it signed.
+pub fn sign_input_with_keys(secp: &Secp256k1, tx: &mut Transaction, n: uint,
+                            script_pubkey: &Script, keys: &[PrivateKey]) -> bool {
+  let hash = signature_hash(tx, n, script_pubkey);
+  let mut script_sig = Script::new();
+  match script_info::classify(script_pubkey.as_slice()) {
+    script_info::PayToPubkey(pk) => {
+      match keys.iter().find(|k| k.public.as_slice() == pk.as_slice()) {
+        Some(key) => match key.sign(secp, hash.as_slice()) {
+          Some(sig) => { script_sig.push_slice(sig.as_slice()); }
+          None => { return false; }
+        },
+        None => { return false; }
+      }
+    }
+    script_info::PayToPubkeyHash(pkh) => {
+      match keys.iter().find(|k| script_info::hash160(k.public.as_slice()) == pkh) {
+        Some(key) => match key.sign(secp, hash.as_slice()) {
+          Some(sig) => {
+            script_sig.push_slice(sig.as_slice());
+            script_sig.push_slice(key.public.as_slice());
+          }
+          None => { return false; }
+        },
+        None => { return false; }
+      }
+    }
+    _ => { return false; }
+  }
+  tx.input.get_mut(n).script_sig = script_sig;
+  true
+}
+
+/// Reads a TOML file and decodes it into some object
+pub fn read_toml<T: Decodable<toml::Decoder, toml::DecodeError>>(path: &Path) -> IoResult<T> {
+  let mut file = BufferedReader::new(try!(File::open(path)));
   let data = try!(file.read_to_end());
   let str_data = str::from_utf8(data.as_slice());
   if str_data.is_none() {
     return Err(IoError { kind: InvalidInput,
-                         desc: "wallet file was not UTF-8", 
-                         detail: None });
+                         desc: "file was not UTF-8",
+                         detail: Some(path.display().to_string()) });
   }
   let str_data = str_data.unwrap();
 
@@ -49,25 +801,114 @@ pub fn load_wallet(config: &NetworkConfig) -> IoResult<Wallet> {
       let mut d = toml::Decoder::new(toml::Table(table));
       Decodable::decode(&mut d).map_err(|e| IoError {
         kind: InvalidInput,
-        desc: "wallet TOML did not parse to wallet",
-        detail: Some(format!("{}", e))
+        desc: "TOML did not decode to the expected structure",
+        detail: Some(format!("{}: {}", path.display(), e))
       })
     }
     None => Err(IoError {
       kind: InvalidInput,
-      desc: "could not parse wallet TOML",
-      detail: Some(format!("{}", parser.errors))
+      desc: "could not parse TOML",
+      detail: Some(format!("{}: {}", path.display(), parser.errors))
     })
   }
 }
 
-/// Saves a wallet to disk
-pub fn save_wallet(config: &NetworkConfig, wallet: &Wallet) -> IoResult<()> {
-  let mut file = BufferedWriter::new(try!(File::open_mode(&config.wallet_path, Open, Write)));
-  let data = toml::encode_str(wallet);
+/// Encodes an object as TOML and writes it to disk
+pub fn write_toml<T: Encodable<toml::Encoder, toml::Error>>(path: &Path, obj: &T) -> IoResult<()> {
+  let mut file = BufferedWriter::new(try!(File::open_mode(path, Open, Write)));
+  let data = toml::encode_str(obj);
   file.write_str(data.as_slice())
 }
 
+/// Attempts to load a wallet from disk
+pub fn load_wallet(wconfig: &WalletConfig) -> IoResult<Wallet> {
+  read_toml(&wconfig.path)
+}
+
+/// Checks that a wallet and its metadata can be read, without creating
+/// either. Returns false if the wallet does not exist yet, and so will be
+/// created on the first run.
+pub fn check_wallet(wconfig: &WalletConfig) -> IoResult<bool> {
+  let exists = match load_wallet(wconfig) {
+    Ok(_) => true,
+    Err(ref e) if e.kind == FileNotFound => false,
+    Err(e) => { return Err(e); }
+  };
+  try!(load_wallet_metadata(wconfig));
+  Ok(exists)
+}
+
+/// Saves a wallet to disk, rotating backups if they are enabled. The
+/// wallet is saved even if the backups cannot be rotated, which is only
+/// logged as a warning.
+pub fn save_wallet(config: &NetworkConfig, wconfig: &WalletConfig, wallet: &Wallet)
+                   -> IoResult<()> {
+  try!(write_toml(&wconfig.path, wallet));
+  match rotate_wallet_backups(config, wconfig) {
+    Err(e) => {
+      debug!((config.network, config.debug_level), Warning,
+             "Wallet `{}`: failed to rotate backups: {}", wconfig.name, e);
+    }
+    Ok(()) => {}
+  }
+  Ok(())
+}
+
+/// Copies the on-disk wallet and its metadata into the backup directory,
+/// keeping at most `config.wallet_backup_count` old copies of each.
+/// Backup 1 is the most recent.
+fn rotate_wallet_backups(config: &NetworkConfig, wconfig: &WalletConfig) -> IoResult<()> {
+  if config.wallet_backup_count == 0 {
+    return Ok(());
+  }
+  if !config.wallet_backup_dir.exists() {
+    try!(fs::mkdir_recursive(&config.wallet_backup_dir, UserRWX));
+  }
+  try!(rotate_backups(config, &wconfig.path, "wallet.toml"));
+  // A new wallet has no metadata yet
+  if wconfig.meta_path.exists() {
+    try!(rotate_backups(config, &wconfig.meta_path, "wallet-meta.toml"));
+  }
+  Ok(())
+}
+
+/// Copies `path` into the backup directory as backup 1, shifting the
+/// older backups of it up by one and overwriting the oldest
+fn rotate_backups(config: &NetworkConfig, path: &Path, default_name: &str) -> IoResult<()> {
+  let name = path.filename_str().unwrap_or(default_name);
+  let backup_path = |n: uint| config.wallet_backup_dir.join(format!("{}.{}", name, n));
+  for n in range(1, config.wallet_backup_count).rev() {
+    let from = backup_path(n);
+    if from.exists() {
+      try!(fs::rename(&from, &backup_path(n + 1)));
+    }
+  }
+  fs::copy(path, &backup_path(1))
+}
+
+/// Snapshots the wallet and its metadata to a user-specified path. The
+/// metadata is written alongside, with `.meta` appended to the filename.
+pub fn backup_wallet(wallet: &Wallet, meta: &WalletMetadata, path: &Path) -> IoResult<()> {
+  try!(write_toml(path, wallet));
+  let mut meta_path = path.clone();
+  meta_path.set_filename(format!("{}.meta", path.filename_display()));
+  write_toml(&meta_path, meta)
+}
+
+/// Loads the wallet metadata from disk; if there is none, returns an
+/// empty set of metadata.
+pub fn load_wallet_metadata(wconfig: &WalletConfig) -> IoResult<WalletMetadata> {
+  match read_toml(&wconfig.meta_path) {
+    Err(ref e) if e.kind == FileNotFound => Ok(Default::default()),
+    res => res
+  }
+}
+
+/// Saves the wallet metadata to disk
+pub fn save_wallet_metadata(wconfig: &WalletConfig, meta: &WalletMetadata) -> IoResult<()> {
+  write_toml(&wconfig.meta_path, meta)
+}
+
 /// Creates a new default wallet
 pub fn default_wallet(network: Network) -> Result<Wallet, bip32::Error> {
   let mut rng = try!(rand::OsRng::new().map_err(|e| bip32::RngError(format!("{}", e))));
@@ -77,8 +918,9 @@ pub fn default_wallet(network: Network) -> Result<Wallet, bip32::Error> {
 }
 
 /// Loads the wallet from disk; failing that, creates a default one
-pub fn load_or_create_wallet(config: &NetworkConfig) -> IoResult<Wallet> {
-  let wallet = load_wallet(config);
+pub fn load_or_create_wallet(config: &NetworkConfig, wconfig: &WalletConfig)
+                             -> IoResult<Wallet> {
+  let wallet = load_wallet(wconfig);
   match wallet {
     Err(err) => {
       if err.kind == FileNotFound {
@@ -88,7 +930,7 @@ pub fn load_or_create_wallet(config: &NetworkConfig) -> IoResult<Wallet> {
                                   desc: "BIP32 error",
                                   detail: Some(e.to_string()) }),
           Ok(w) => {
-            match save_wallet(config, &w) {
+            match save_wallet(config, wconfig, &w) {
               Err(e) => Err(e),
               Ok(_) => Ok(w)
             }
@@ -102,4 +944,186 @@ pub fn load_or_create_wallet(config: &NetworkConfig) -> IoResult<Wallet> {
   }
 }
 
+/// A wallet which has been loaded from disk, along with its metadata
+pub struct LoadedWallet {
+  /// The wallet's name and file locations
+  pub config: WalletConfig,
+  /// The network the wallet is on
+  pub network: Network,
+  /// The wallet itself
+  pub wallet: Wallet,
+  /// Non-keychain wallet data
+  pub meta: WalletMetadata
+}
+
+impl LoadedWallet {
+  /// Loads a wallet and its metadata from disk, creating the wallet if it
+  /// does not exist
+  pub fn load(config: &NetworkConfig, wconfig: &WalletConfig) -> IoResult<LoadedWallet> {
+    let wallet = try!(load_or_create_wallet(config, wconfig));
+    let meta = try!(load_wallet_metadata(wconfig));
+    Ok(LoadedWallet {
+      config: wconfig.clone(),
+      network: config.network,
+      wallet: wallet,
+      meta: meta
+    })
+  }
+
+  /// Saves the wallet's metadata to disk
+  pub fn save_metadata(&self) -> IoResult<()> {
+    save_wallet_metadata(&self.config, &self.meta)
+  }
+
+  /// Records every address which pays to the given outputs of a
+  /// transaction as used. Outputs the transaction does not have are skipped.
+  pub fn mark_outputs_used(&mut self, tx: &Transaction, vouts: &[u32]) {
+    let network = self.network;
+    for &n in vouts.iter() {
+      match tx.output.as_slice().get(n as uint).map(|out| out.classify(network)) {
+        Some(PayToPubkeyHash(addr)) => { self.meta.mark_address_used(&addr); }
+        _ => {}
+      }
+    }
+  }
 
+  /// Records every address which currently holds wallet funds as used
+  pub fn mark_unspent_used(&mut self) {
+    let network = self.network;
+    for out in self.wallet.unspent_outputs().iter() {
+      match out.txo.classify(network) {
+        PayToPubkeyHash(addr) => { self.meta.mark_address_used(&addr); }
+        _ => {}
+      }
+    }
+  }
+
+  /// Obtains a new address from the given account. Returns the address and
+  /// whether it has already received funds. If `refuse_reuse` is set, used
+  /// addresses are skipped over instead.
+  pub fn new_address(&mut self, account: &str, refuse_reuse: bool)
+                     -> Result<(Address, bool), wallet::Error> {
+    let mut address = try!(self.wallet.new_address(account, External));
+    if refuse_reuse {
+      let mut skipped = 0;
+      while self.meta.is_address_used(&address) && skipped < MAX_ADDRESS_REUSE_SKIP {
+        address = try!(self.wallet.new_address(account, External));
+        skipped += 1;
+      }
+    }
+    let reused = self.meta.is_address_used(&address);
+    Ok((address, reused))
+  }
+
+  /// The fee policy in effect for this wallet, falling back to the
+  /// network's configured default
+  pub fn fee_policy(&self, config: &NetworkConfig) -> FeePolicy {
+    self.meta.fee_policy().unwrap_or(config.fee_policy.clone())
+  }
+}
+
+/// Updates a wallet for a transaction seen on the network. Every
+/// transaction is checked for double-spends, but only those the mempool
+/// `accepted` are recorded: anyone can relay an invalid one paying to us.
+fn wallet_tx_seen(config: &NetworkConfig, w: &mut LoadedWallet, utxo_set: &UtxoSet,
+                  tx: &Transaction, accepted: bool, events: &EventBus) {
+  if w.meta.find_transaction(tx.bitcoin_hash()).is_none() {
+    let owned = owned_outpoints(&w.wallet);
+    for alert in w.meta.check_double_spend(tx, owned.as_slice(), false).iter() {
+      debug!((config.network, config.debug_level), Error, "Wallet `{}`: {}", w.config.name, alert);
+    }
+  }
+  if !accepted {
+    return;
+  }
+  match relevant_transaction(&w.wallet, utxo_set, tx) {
+    Some(wtx) => {
+      let txid = wtx.txid;
+      w.mark_outputs_used(tx, wtx.our_vouts.as_slice());
+      if w.meta.add_transaction(wtx) {
+        debug!((config.network, config.debug_level), Status,
+               "Received transaction {:x} for wallet `{}`", txid, w.config.name);
+        events.publish(WalletTransaction(w.config.name.clone(), txid));
+      }
+    }
+    None => {}
+  }
+}
+
+/// Subscribes the wallets to chain events and spawns a task to keep them
+/// up to date as blocks are connected and disconnected and transactions
+/// are seen.
+pub fn follow_chain(config: NetworkConfig, wallets: Vec<Arc<Mutex<LoadedWallet>>>,
+                    utxo_set: Arc<RWLock<UtxoSet>>, events: EventBus) {
+  let rx = events.subscribe(vec![Chain]);
+  spawn(proc() {
+    for event in rx.iter() {
+      match event {
+        BlockConnected(block, height) => {
+          let utxo_set = utxo_set.read();
+          for w in wallets.iter() {
+            let mut w = w.lock();
+            let owned = owned_outpoints(&w.wallet);
+            // Note which of our transactions this block confirms, for notification
+            let confirmed: Vec<Sha256dHash> = block.txdata.iter()
+                .map(|tx| tx.bitcoin_hash())
+                .filter(|txid| match w.meta.find_transaction(*txid) {
+                  Some(wtx) => wtx.height.is_none(),
+                  None => false
+                })
+                .collect();
+            for alert in w.meta.block_connected(&*block, height, owned.as_slice()).iter() {
+              debug!((config.network, config.debug_level), Error,
+                     "Wallet `{}`: {}", w.config.name, alert);
+            }
+            for txid in confirmed.move_iter() {
+              events.publish(WalletConfirmation(w.config.name.clone(), txid, height));
+            }
+            // Our transactions first seen in the block, e.g. during the
+            // initial sync, are recorded as already confirmed
+            for tx in block.txdata.iter() {
+              if w.meta.find_transaction(tx.bitcoin_hash()).is_some() {
+                continue;
+              }
+              match relevant_transaction(&w.wallet, &*utxo_set, tx) {
+                Some(mut wtx) => {
+                  let txid = wtx.txid;
+                  wtx.height = Some(height);
+                  w.mark_outputs_used(tx, wtx.our_vouts.as_slice());
+                  w.meta.add_transaction(wtx);
+                  debug!((config.network, config.debug_level), Status,
+                         "Received transaction {:x} for wallet `{}` in block {}",
+                         txid, w.config.name, height);
+                  events.publish(WalletConfirmation(w.config.name.clone(), txid, height));
+                }
+                None => {}
+              }
+            }
+          }
+        }
+        BlockDisconnected(block) => {
+          for w in wallets.iter() {
+            w.lock().meta.block_disconnected(&*block);
+          }
+        }
+        TxAccepted(tx) => {
+          let utxo_set = utxo_set.read();
+          for w in wallets.iter() {
+            wallet_tx_seen(&config, &mut *w.lock(), &*utxo_set, &*tx, true, &events);
+          }
+          debug!((config.network, config.debug_level), Debug,
+                 "Wallets checked transaction {:x}", tx.bitcoin_hash());
+        }
+        TxRejected(tx, _) => {
+          let utxo_set = utxo_set.read();
+          for w in wallets.iter() {
+            wallet_tx_seen(&config, &mut *w.lock(), &*utxo_set, &*tx, false, &events);
+          }
+          debug!((config.network, config.debug_level), Debug,
+                 "Wallets checked rejected transaction {:x}", tx.bitcoin_hash());
+        }
+        _ => {}
+      }
+    }
+  });
+}
@@ -0,0 +1,189 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Web Dashboard
+//!
+//! A read-only HTML page, served at `/dashboard` by the RPC server, showing
+//! chain status, wallet balances, recent wallet transactions and open
+//! coinjoin sessions. It is built from the results of ordinary RPC calls,
+//! so shows nothing a client could not already ask for, and reloads itself
+//! every few seconds rather than using any scripting.
+//!
+
+use serialize::json;
+
+use constants::WEB_DASHBOARD_REFRESH;
+use dashboard::{field, show, format_btc, format_duration};
+
+/// Escapes text for inclusion in HTML
+fn escape(s: &str) -> String {
+  let mut ret = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&' => ret.push_str("&amp;"),
+      '<' => ret.push_str("&lt;"),
+      '>' => ret.push_str("&gt;"),
+      '"' => ret.push_str("&quot;"),
+      c => ret.push_char(c)
+    }
+  }
+  ret
+}
+
+/// Draws a progress bar filled to `fraction`, from 0 to 1
+fn progress_bar(fraction: f64) -> String {
+  let percent = (fraction.max(0.0).min(1.0) * 100.0) as uint;
+  format!("<div class=\"bar\"><div style=\"width: {}%\"></div></div> {}%", percent, percent)
+}
+
+/// Renders the chain status section
+fn chain_section(chain: &json::Json, out: &mut String) {
+  out.push_str("<h2>Chain</h2>\n<table>\n");
+  out.push_str(format!("<tr><th>Network</th><td>{}</td></tr>\n",
+                       escape(show(chain, "chain").as_slice())).as_slice());
+  out.push_str(format!("<tr><th>Height</th><td>{}</td></tr>\n",
+                       escape(show(chain, "blocks").as_slice())).as_slice());
+  out.push_str(format!("<tr><th>Tip</th><td class=\"hash\">{}</td></tr>\n",
+                       escape(show(chain, "bestblockhash").as_slice())).as_slice());
+  out.push_str(format!("<tr><th>Difficulty</th><td>{}</td></tr>\n",
+                       escape(show(chain, "difficulty").as_slice())).as_slice());
+  match field(chain, "sync") {
+    Some(sync) => {
+      let progress = field(sync, "progress").and_then(|v| v.as_f64()).unwrap_or(0.0);
+      let eta = match field(sync, "eta").and_then(|v| v.as_i64()) {
+        Some(eta) => format!(", about {} left", format_duration(eta)),
+        None => String::new()
+      };
+      out.push_str(format!("<tr><th>Sync</th><td>{} at {} of {}{} {}</td></tr>\n",
+                           escape(show(sync, "stage").as_slice()),
+                           escape(show(sync, "height").as_slice()),
+                           escape(show(sync, "target_height").as_slice()),
+                           eta, progress_bar(progress)).as_slice());
+    }
+    None => {}
+  }
+  out.push_str("</table>\n");
+}
+
+/// Renders the balances of each wallet
+fn balances_section(wallets: &json::JsonObject, out: &mut String) {
+  out.push_str("<h2>Balances</h2>\n<table>\n");
+  out.push_str("<tr><th>Wallet</th><th>Confirmed</th><th>Safe</th><th>Unconfirmed</th>\
+                <th>Time-locked</th><th>Unlocked</th></tr>\n");
+  for (name, balances) in wallets.iter() {
+    out.push_str(format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                         escape(name.as_slice()),
+                         format_btc(balances, "confirmed"), format_btc(balances, "safe"),
+                         format_btc(balances, "unconfirmed"), format_btc(balances, "timelocked"),
+                         format_btc(balances, "timelock_expired")).as_slice());
+  }
+  out.push_str("</table>\n");
+}
+
+/// Renders the default wallet's recent transactions
+fn transactions_section(transactions: &[json::Json], out: &mut String) {
+  out.push_str("<h2>Recent transactions</h2>\n");
+  if transactions.is_empty() {
+    out.push_str("<p>None yet.</p>\n");
+    return;
+  }
+  out.push_str("<table>\n<tr><th>Txid</th><th>Confirmations</th><th>Fee</th><th>Notes</th></tr>\n");
+  for tx in transactions.iter() {
+    let notes = match field(tx, "conflicted_by") {
+      Some(&json::Null) | None => String::new(),
+      Some(txid) => format!("conflicted by {}", escape(txid.to_string().as_slice()))
+    };
+    out.push_str(format!("<tr><td class=\"hash\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                         escape(show(tx, "txid").as_slice()),
+                         escape(show(tx, "confirmations").as_slice()),
+                         format_btc(tx, "fee"), notes).as_slice());
+  }
+  out.push_str("</table>\n");
+}
+
+/// Renders the open coinjoin sessions, with how far through its join or
+/// merge window each one is
+fn coinjoin_section(list: &json::Json, out: &mut String) {
+  out.push_str("<h2>Coinjoin sessions</h2>\n");
+  let sessions = match field(list, "sessions") {
+    Some(&json::List(ref sessions)) if sessions.len() > 0 => sessions,
+    _ => {
+      out.push_str("<p>No open sessions.</p>\n");
+      return;
+    }
+  };
+  out.push_str("<table>\n<tr><th>Session</th><th>State</th><th>Amount</th>\
+                <th>Participants</th><th>Current phase</th></tr>\n");
+  for session in sessions.iter() {
+    let remaining = field(session, "time_remaining").and_then(|v| v.as_i64()).unwrap_or(0);
+    let duration = field(session, "phase_duration").and_then(|v| v.as_i64()).unwrap_or(0);
+    let elapsed = if duration > 0 { 1.0 - remaining as f64 / duration as f64 } else { 1.0 };
+    out.push_str(format!("<tr><td class=\"hash\">{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                          <td>{} {} left</td></tr>\n",
+                         escape(show(session, "id").as_slice()),
+                         escape(show(session, "state").as_slice()),
+                         format_btc(session, "target_value"),
+                         escape(show(session, "participants").as_slice()),
+                         progress_bar(elapsed), format_duration(remaining / 1000)).as_slice());
+  }
+  out.push_str("</table>\n");
+}
+
+/// Renders the dashboard from the results of `getblockchaininfo`,
+/// `getinfo`, `listtransactions` and `coinjoin_list`. Any of these may be
+/// missing, if the call failed or is disabled, and its section is left out.
+pub fn render(chain: Option<json::Json>, info: Option<json::Json>,
+              transactions: Option<json::Json>, coinjoin: Option<json::Json>) -> String {
+  let mut out = String::new();
+  out.push_str(format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                        <meta http-equiv=\"refresh\" content=\"{}\">\n\
+                        <title>Wizards' Wallet</title>\n\
+                        <style>\n\
+                        body {{ font-family: sans-serif; margin: 2em; }}\n\
+                        table {{ border-collapse: collapse; margin-bottom: 1em; }}\n\
+                        th, td {{ padding: 0.2em 0.8em; text-align: left; }}\n\
+                        .hash {{ font-family: monospace; }}\n\
+                        .bar {{ display: inline-block; width: 10em; height: 0.8em; border: 1px solid #888; }}\n\
+                        .bar div {{ height: 100%; background: #4a4; }}\n\
+                        </style>\n</head>\n<body>\n<h1>Wizards' Wallet</h1>\n",
+                       WEB_DASHBOARD_REFRESH).as_slice());
+  match info {
+    Some(ref info) => {
+      let uptime = field(info, "uptime").and_then(|v| v.as_i64()).unwrap_or(0);
+      out.push_str(format!("<p>Version {}, up {}.</p>\n", escape(show(info, "version").as_slice()),
+                           format_duration(uptime)).as_slice());
+    }
+    None => {}
+  }
+  match chain {
+    Some(ref chain) => chain_section(chain, &mut out),
+    None => {}
+  }
+  // Only present if wallet RPC is enabled
+  match info.as_ref().and_then(|info| field(info, "balances")) {
+    Some(&json::Object(ref wallets)) => balances_section(wallets, &mut out),
+    _ => {}
+  }
+  match transactions {
+    Some(json::List(ref transactions)) => transactions_section(transactions.as_slice(), &mut out),
+    _ => {}
+  }
+  match coinjoin {
+    Some(ref list) => coinjoin_section(list, &mut out),
+    None => {}
+  }
+  out.push_str("</body>\n</html>\n");
+  out
+}
+
@@ -0,0 +1,62 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Worker Pool
+//!
+//! A fixed set of tasks which run jobs handed to them, so that slow work
+//! (e.g. RPC calls) does not hold up the idle loop. Jobs are run in the
+//! order they are submitted, by whichever worker is free first. Each job
+//! runs in a task of its own, so a job which fails does not take its
+//! worker with it.
+//!
+
+use std::sync::{Arc, Mutex};
+use std::task;
+
+/// A job for a worker to run
+pub type Job = proc(): Send;
+
+/// A pool of worker tasks. Dropping it lets the workers finish their
+/// queued jobs and exit.
+pub struct WorkerPool {
+  sender: Sender<Job>
+}
+
+impl WorkerPool {
+  /// Spawns a pool of `n_workers` tasks (at least one)
+  pub fn new(n_workers: uint) -> WorkerPool {
+    let (tx, rx) = channel::<Job>();
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in range(0, if n_workers > 0 { n_workers } else { 1 }) {
+      let rx = rx.clone();
+      spawn(proc() {
+        loop {
+          // Hold the lock only while waiting for a job, not while running it
+          let job = match rx.lock().recv_opt() {
+            Ok(job) => job,
+            Err(_) => { break; }
+          };
+          // A failing job takes down only its own task, not the worker
+          let _ = task::try(job);
+        }
+      });
+    }
+    WorkerPool { sender: tx }
+  }
+
+  /// Queues a job to be run by the next free worker
+  pub fn execute(&self, job: Job) {
+    self.sender.send(job);
+  }
+}